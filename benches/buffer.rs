@@ -0,0 +1,45 @@
+//! Times inserting and deleting in the middle of a 100k char line. Run with `cargo bench`.
+
+extern crate liner;
+
+use std::time::{Duration, Instant};
+
+use liner::Buffer;
+
+const LEN: usize = 100_000;
+const EDITS: usize = 10_000;
+
+fn long_line() -> Buffer {
+    Buffer::from("{\"key\": [1, 2, 3]} ".repeat(LEN / 19 + 1).chars().take(LEN).collect::<String>())
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    println!("{:<24} {:>10} ns/edit", name, nanos / EDITS as u64);
+}
+
+fn main() {
+    let mut buf = long_line();
+    let mid = LEN / 2;
+    let start = Instant::now();
+    for i in 0..EDITS {
+        buf.insert(mid + i, &['x']);
+    }
+    report("insert at 50k chars", start.elapsed());
+
+    let start = Instant::now();
+    for i in (0..EDITS).rev() {
+        buf.remove(mid + i, mid + i + 1);
+    }
+    report("delete at 50k chars", start.elapsed());
+
+    let mut buf = long_line();
+    let start = Instant::now();
+    for i in 0..EDITS {
+        // Alternating between two spots far apart is the worst case for the gap.
+        let at = if i % 2 == 0 { 1_000 } else { LEN - 1_000 };
+        buf.insert(at, &['x']);
+    }
+    report("insert at two far spots", start.elapsed());
+    assert_eq!(buf.num_chars(), LEN + EDITS);
+}
@@ -1,20 +1,18 @@
 extern crate liner;
-extern crate termion;
 extern crate regex;
 
 use std::mem::replace;
 use std::env::{args, current_dir};
-use std::io;
 
-use liner::{Context, CursorPosition, Event, EventKind, FilenameCompleter};
-use termion::color;
+use liner::{Context, CursorPosition, Event, EventKind, FilenameCompleter, ReadLineResult};
 use regex::Regex;
 
 // This prints out the text back onto the screen
 fn highlight_dodo(s: &str) -> String {
     let reg_exp = Regex::new("(?P<k>dodo)").unwrap();
-    let format = format!("{}$k{}", color::Fg(color::Red), color::Fg(color::Reset));
-    reg_exp.replace_all(s, format.as_str()).to_string()
+    // Red, then the default color again.
+    let format = "\x1B[31m$k\x1B[39m";
+    reg_exp.replace_all(s, format).to_string()
 }
 
 fn main() {
@@ -75,9 +73,9 @@ fn main() {
             }
         });
 
-        // We are out of the lambda, and res is the result from read_line which is an Into<String>
+        // We are out of the lambda, and res is the result from read_line which is a ReadLineResult
         match res {
-            Ok(res) => {
+            Ok(ReadLineResult::Input(res)) => {
                 match res.as_str() {
                     "emacs" => {
                         con.key_bindings = liner::KeyBindings::Emacs;
@@ -102,23 +100,18 @@ fn main() {
 
                 con.history.push(res.into()).unwrap();
             }
-            // If there was an error, get what type it was(remember, we still are in the match{}
-            // from waaay above)
+            // ctrl-c pressed, just start over with a fresh prompt
+            Ok(ReadLineResult::Interrupted) => {}
+            // ctrl-d pressed
+            Ok(ReadLineResult::Eof) => {
+                println!("exiting...");
+                break;
+            }
+            // If there was an error, panic (remember, we still are in the match{} from waaay above)
             Err(e) => {
-                match e.kind() {
-                    // ctrl-c pressed
-                    io::ErrorKind::Interrupted => {}
-                    // ctrl-d pressed
-                    io::ErrorKind::UnexpectedEof => {
-                        println!("exiting...");
-                        break;
-                    }
-                    _ => {
-                        // Ensure that all writes to the history file
-                        // are written before exiting due to error.
-                        panic!("error: {:?}", e)
-                    },
-                }
+                // Ensure that all writes to the history file
+                // are written before exiting due to error.
+                panic!("error: {:?}", e)
             }
         }
 
@@ -1,19 +1,16 @@
 extern crate liner;
-extern crate termion;
 extern crate regex;
 
 use std::mem::replace;
 use std::env::{args, current_dir};
-use std::io;
 
-use liner::{Context, CursorPosition, Event, EventKind, FilenameCompleter};
-use termion::color;
+use liner::{Context, CursorPosition, Event, EventKind, FilenameCompleter, ReadLineResult};
 use regex::Regex;
 
 fn highlight_dodo(s: &str) -> String {
     let reg_exp = Regex::new("(?P<k>dodo)").unwrap();
-    let format = format!("{}$k{}", color::Fg(color::Red), color::Fg(color::Reset));
-    reg_exp.replace_all(s, format.as_str()).to_string()
+    let format = "\x1B[31m$k\x1B[39m";
+    reg_exp.replace_all(s, format).to_string()
 }
 
 fn main() {
@@ -58,7 +55,7 @@ fn main() {
         });
 
         match res {
-            Ok(res) => {
+            Ok(ReadLineResult::Input(res)) => {
                 match res.as_str() {
                     "emacs" => {
                         con.key_bindings = liner::KeyBindings::Emacs;
@@ -81,21 +78,17 @@ fn main() {
 
                 con.history.push(res.into()).unwrap();
             }
+            // ctrl-c pressed
+            Ok(ReadLineResult::Interrupted) => {}
+            // ctrl-d pressed
+            Ok(ReadLineResult::Eof) => {
+                println!("exiting...");
+                break;
+            }
             Err(e) => {
-                match e.kind() {
-                    // ctrl-c pressed
-                    io::ErrorKind::Interrupted => {}
-                    // ctrl-d pressed
-                    io::ErrorKind::UnexpectedEof => {
-                        println!("exiting...");
-                        break;
-                    }
-                    _ => {
-                        // Ensure that all writes to the history file
-                        // are written before exiting.
-                        panic!("error: {:?}", e)
-                    },
-                }
+                // Ensure that all writes to the history file
+                // are written before exiting.
+                panic!("error: {:?}", e)
             }
         }
     }
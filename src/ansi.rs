@@ -0,0 +1,45 @@
+//! The escape sequences the editor draws with.
+
+pub const CLEAR_ALL: &str = "\x1B[2J";
+pub const CLEAR_AFTER_CURSOR: &str = "\x1B[J";
+pub const CLEAR_UNTIL_NEWLINE: &str = "\x1B[K";
+
+pub const RESET: &str = "\x1B[0m";
+pub const INVERT: &str = "\x1B[7m";
+
+pub const BLACK: u8 = 0;
+pub const RED: u8 = 1;
+pub const GREEN: u8 = 2;
+pub const YELLOW: u8 = 3;
+pub const WHITE: u8 = 7;
+
+/// Sets the text color to one of the 256 colors.
+pub fn fg(color: u8) -> String {
+    format!("\x1B[38;5;{}m", color)
+}
+
+/// Sets the background color to one of the 256 colors.
+pub fn bg(color: u8) -> String {
+    format!("\x1B[48;5;{}m", color)
+}
+
+/// Moves the cursor to the 1-indexed column `x` and row `y`.
+pub fn goto(x: u16, y: u16) -> String {
+    format!("\x1B[{};{}H", y, x)
+}
+
+pub fn up(n: u16) -> String {
+    format!("\x1B[{}A", n)
+}
+
+pub fn down(n: u16) -> String {
+    format!("\x1B[{}B", n)
+}
+
+pub fn left(n: u16) -> String {
+    format!("\x1B[{}D", n)
+}
+
+pub fn right(n: u16) -> String {
+    format!("\x1B[{}C", n)
+}
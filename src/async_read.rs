@@ -0,0 +1,109 @@
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{self, Poll};
+use futures_core::Stream;
+
+use {AnyKeyMap, Context, Editor, EventHandler, Input, IntoRawMode, KeyMap, RawTerminal};
+use {ReadLineOptions, ReadLineResult};
+
+/// A line being read from a stream of input, which finishes once the line is entered or the read
+/// is ended. Returned by `Context::read_line_async()`, with the `async` feature.
+pub struct ReadLine<'a, 'h, W: Write + 'a, S> {
+    keymap: Option<AnyKeyMap<'a, W>>,
+    inputs: S,
+    handler: &'h mut EventHandler<'h, W>,
+    started: bool,
+}
+
+// Only `inputs` is ever pinned, and it has to be `Unpin` itself.
+impl<'a, 'h, W: Write + 'a, S> Unpin for ReadLine<'a, 'h, W, S> {}
+
+impl<'a, 'h, W, S> Future for ReadLine<'a, 'h, W, S>
+where
+    W: Write + 'a,
+    S: Stream<Item = io::Result<Input>> + Unpin,
+{
+    type Output = io::Result<ReadLineResult>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<ReadLineResult>> {
+        let this = self.get_mut();
+        let res = match this.poll_keys(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(res) => res,
+        };
+
+        // The terminal is put back however the read ended, errors included.
+        let mut keymap = this.keymap.take().unwrap();
+        let finished = keymap.finish();
+        Poll::Ready(res.and_then(|res| finished.map(|_| res)))
+    }
+}
+
+impl<'a, 'h, W, S> ReadLine<'a, 'h, W, S>
+where
+    W: Write + 'a,
+    S: Stream<Item = io::Result<Input>> + Unpin,
+{
+    fn poll_keys(&mut self, cx: &mut task::Context) -> Poll<io::Result<ReadLineResult>> {
+        let keymap = self.keymap.as_mut().expect("ReadLine polled after it finished");
+        if !self.started {
+            self.started = true;
+            if let Err(e) = keymap.start() {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        loop {
+            // Completions worked out in the background are shown once they are done and
+            // something else happens.
+            if let Err(e) = keymap.editor_mut().poll_completion() {
+                return Poll::Ready(Err(e));
+            }
+
+            let input = match Pin::new(&mut self.inputs).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(Ok(ReadLineResult::Eof)),
+                // Input that couldn't be made sense of is skipped rather than ending the read.
+                Poll::Ready(Some(Err(ref e))) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(Some(Ok(input))) => input,
+            };
+            match keymap.handle_input(input, &mut *self.handler) {
+                Ok(Some(res)) => return Poll::Ready(Ok(res)),
+                Ok(None) => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl Context {
+    /// Same as `Context.read_line_with_options()`, but reads from `inputs` instead of the
+    /// terminal, and returns a future that is ready once the line is read. Waiting for keys
+    /// doesn't block a thread, so it can be awaited on tokio, async-std or any other executor.
+    ///
+    /// `Context.tick_interval` isn't used, and completions worked out in the background are only
+    /// shown after the next input.
+    pub fn read_line_async<'a, 'h, W, S>(
+        &'a mut self,
+        out: W,
+        options: ReadLineOptions,
+        inputs: S,
+        handler: &'h mut EventHandler<'h, RawTerminal<W>>,
+    ) -> io::Result<ReadLine<'a, 'h, RawTerminal<W>, S>>
+    where
+        W: IntoRawMode + 'a,
+        S: Stream<Item = io::Result<Input>> + Unpin,
+    {
+        let key_bindings = options.key_bindings.unwrap_or(self.key_bindings);
+        let out = out.into_raw_mode()?;
+        let ed = Editor::with_options(out, self, options)?;
+        Ok(ReadLine {
+            keymap: Some(AnyKeyMap::new(ed, key_bindings)),
+            inputs: inputs,
+            handler: handler,
+            started: false,
+        })
+    }
+}
@@ -1,7 +1,14 @@
-use unicode_width::UnicodeWidthStr;
+use unicode_segmentation::GraphemeCursor;
+use std::cmp;
 use std::io::{self, Write};
 use std::iter::FromIterator;
-use std::fmt::{self, Write as FmtWrite};
+use std::mem;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::fmt;
+use context::get_whitespace_words;
+use editor::CursorPosition;
+use gap_buffer::GapBuffer;
+use util;
 
 /// A modification performed on a `Buffer`. These are used for the purpose of undo/redo.
 #[derive(Debug,Clone)]
@@ -34,19 +41,166 @@ impl Action {
     }
 }
 
+/// How consecutive inserts are grouped into undo steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndoPolicy {
+    /// Every insert is its own undo step.
+    PerKeystroke,
+    /// Consecutive inserts are one undo step until whitespace is typed after a word.
+    PerWord,
+    /// Consecutive inserts are one undo step, and an insert session (e.g. vi insert mode) is
+    /// undone as a whole.
+    PerInsertSession,
+}
+
+/// How serious something an `Annotation` points out is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Extra information about a range of chars in a `Buffer`, such as a highlighted search match or
+/// a diagnostic. Annotations are not part of the text: they aren't undone, aren't compared and
+/// aren't saved to history, but they move along with the text they cover when it is edited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    /// The char index the annotation starts at.
+    pub start: usize,
+    /// The char index just after the end of the annotation.
+    pub end: usize,
+    /// Escape codes to style the annotated chars with.
+    pub style: String,
+    pub severity: Option<Severity>,
+    /// What added the annotation, so it can remove its own annotations again.
+    pub tag: String,
+}
+
+impl Annotation {
+    pub fn new<S: Into<String>>(start: usize, end: usize, tag: S) -> Self {
+        Annotation {
+            start: start,
+            end: end,
+            style: String::new(),
+            severity: None,
+            tag: tag.into(),
+        }
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.start <= index && index < self.end
+    }
+
+    // Text inserted where the annotation starts goes before it and text inserted where it ends
+    // goes after it, so typing next to a highlighted word doesn't extend the highlight.
+    fn map_insert(&mut self, at: usize, len: usize) {
+        if self.end > at {
+            self.end += len;
+        }
+        if self.start >= at {
+            self.start += len;
+        }
+        self.end = cmp::max(self.start, self.end);
+    }
+
+    // Returns false if all of the annotated text was removed.
+    fn map_remove(&mut self, start: usize, end: usize) -> bool {
+        let map = |pos: usize| if pos <= start {
+            pos
+        } else if pos >= end {
+            pos - (end - start)
+        } else {
+            start
+        };
+        let was_empty = self.start == self.end;
+        self.start = map(self.start);
+        self.end = map(self.end);
+        was_empty || self.start < self.end
+    }
+}
+
 /// A buffer for text in the line editor.
 ///
-/// It keeps track of each action performed on it for use with undo/redo.
+/// It keeps track of each action performed on it for use with undo/redo. The text is kept in a
+/// gap buffer, so editing in the middle of a long line costs about as much as at its end. Clones
+/// share the text until one of them is edited, so cloning a buffer to show it is cheap.
 #[derive(Debug, Clone)]
 pub struct Buffer {
-    data: Vec<char>,
+    data: Arc<GapBuffer>,
     actions: Vec<Action>,
     undone_actions: Vec<Action>,
+    // The text as a string with the byte offset of each char, built when first needed after an
+    // edit so redrawing doesn't convert the whole buffer again on every keystroke.
+    text_cache: CacheCell,
+    // The chars in one slice, copied when first borrowed after an edit left the gap in the middle.
+    // Shared between clones like the text cache.
+    chars_cache: OnceLock<Arc<[char]>>,
+    annotations: Vec<Annotation>,
+    // The most undo steps kept, if limited.
+    undo_limit: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct TextCache {
+    text: String,
+    // The byte offset of every char, and the length of the text at the end.
+    offsets: Vec<usize>,
+}
+
+impl TextCache {
+    fn char_index(&self, offset: usize) -> usize {
+        match self.offsets.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        }
+    }
+
+    // Moves `cursor` by `count` grapheme clusters, back if `forward` is false, stopping at either
+    // end of the text. Only the text around the cursor is segmented.
+    fn move_graphemes(&self, cursor: usize, count: usize, forward: bool) -> usize {
+        let mut graphemes = GraphemeCursor::new(self.offsets[cursor], self.text.len(), true);
+        let mut offset = self.offsets[cursor];
+        for _ in 0..count {
+            let boundary = if forward {
+                graphemes.next_boundary(&self.text, 0)
+            } else {
+                graphemes.prev_boundary(&self.text, 0)
+            };
+            match boundary {
+                Ok(Some(b)) => offset = b,
+                _ => break,
+            }
+        }
+        self.char_index(offset)
+    }
+}
+
+// Holds the text cache behind a lock instead of a `RefCell`, so buffers can be shared between
+// threads.
+#[derive(Debug, Default)]
+struct CacheCell(Mutex<Option<Arc<TextCache>>>);
+
+impl CacheCell {
+    fn get(&self) -> Option<Arc<TextCache>> {
+        // The cache is only ever replaced as a whole, so it is fine even if a panic poisoned it.
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn set(&self, cache: Option<Arc<TextCache>>) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = cache;
+    }
+}
+
+impl Clone for CacheCell {
+    fn clone(&self) -> Self {
+        CacheCell(Mutex::new(self.get()))
+    }
 }
 
 impl From<Buffer> for String {
     fn from(buf: Buffer) -> Self {
-        String::from_iter(buf.data)
+        buf.with_text_cache(|cache| cache.text.clone())
     }
 }
 
@@ -64,37 +218,126 @@ impl<'a> From<&'a str> for Buffer {
 
 impl fmt::Display for Buffer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for &c in &self.data {
-            f.write_char(c)?;
-        }
-        Ok(())
+        self.with_text_cache(|cache| f.write_str(&cache.text))
+    }
+}
+
+// Only the text is kept: a deserialized buffer starts without undo history or annotations.
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Buffer {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.with_text_cache(|cache| serializer.serialize_str(&cache.text))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Buffer {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <String as ::serde::Deserialize>::deserialize(deserializer).map(Buffer::from)
     }
 }
 
 impl FromIterator<char> for Buffer {
     fn from_iter<T: IntoIterator<Item = char>>(t: T) -> Self {
         Buffer {
-            data: t.into_iter().collect(),
+            data: Arc::new(t.into_iter().collect()),
             actions: Vec::new(),
             undone_actions: Vec::new(),
+            text_cache: CacheCell::default(),
+            chars_cache: OnceLock::new(),
+            annotations: Vec::new(),
+            undo_limit: None,
+        }
+    }
+}
+
+impl Extend<char> for Buffer {
+    /// Appends the chars as a single undo step.
+    fn extend<T: IntoIterator<Item = char>>(&mut self, t: T) {
+        let chars: Vec<char> = t.into_iter().collect();
+        if !chars.is_empty() {
+            let len = self.num_chars();
+            self.insert(len, &chars);
         }
     }
 }
 
 impl Buffer {
     pub fn new() -> Self {
+        Buffer::with_capacity(0)
+    }
+
+    /// An empty buffer with room for `capacity` chars before it has to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
         Buffer {
-            data: Vec::new(),
+            data: Arc::new(GapBuffer::with_capacity(capacity)),
             actions: Vec::new(),
             undone_actions: Vec::new(),
+            text_cache: CacheCell::default(),
+            chars_cache: OnceLock::new(),
+            annotations: Vec::new(),
+            undo_limit: None,
         }
     }
 
+    /// Makes room for at least `additional` more chars, so inserting them doesn't reallocate.
+    pub fn reserve(&mut self, additional: usize) {
+        Arc::make_mut(&mut self.data).reserve(additional);
+    }
+
     pub fn clear_actions(&mut self) {
         self.actions.clear();
         self.undone_actions.clear();
     }
 
+    /// The actions that can be undone, oldest first. Groups are delimited by `StartGroup` and
+    /// `EndGroup`, and may be nested.
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    /// The actions that can be redone. The last one is redone first.
+    pub fn undone_actions(&self) -> &[Action] {
+        &self.undone_actions
+    }
+
+    /// How many times `undo()` can be called before there is nothing left to undo.
+    pub fn undo_steps(&self) -> usize {
+        Self::count_steps(&self.actions, true)
+    }
+
+    /// How many times `redo()` can be called before there is nothing left to redo.
+    pub fn redo_steps(&self) -> usize {
+        Self::count_steps(&self.undone_actions, false)
+    }
+
+    // Counts the steps `undo()` or `redo()` split `actions` into when popping them off the end:
+    // a group counts once, and groups without any edits are skipped.
+    fn count_steps(actions: &[Action], undo: bool) -> usize {
+        let mut steps = 0;
+        let mut group_nest = 0;
+        let mut group_count = 0;
+        for act in actions.iter().rev() {
+            match (act, undo) {
+                (&Action::EndGroup, true) | (&Action::StartGroup, false) => {
+                    group_nest += 1;
+                    group_count = 0;
+                }
+                (&Action::StartGroup, true) | (&Action::EndGroup, false) => group_nest -= 1,
+                _ => group_count += 1,
+            }
+            if group_nest == 0 && group_count > 0 {
+                steps += 1;
+                group_count = 0;
+            }
+        }
+        // An unfinished group is undone in one go with everything before it.
+        if group_count > 0 {
+            steps += 1;
+        }
+        steps
+    }
+
     pub fn start_undo_group(&mut self) {
         self.actions.push(Action::StartGroup);
     }
@@ -169,10 +412,57 @@ impl Buffer {
     fn push_action(&mut self, act: Action) {
         self.actions.push(act);
         self.undone_actions.clear();
+        self.trim_undo_history();
+    }
+
+    /// Limits how many undo steps are kept. When there are more, the oldest ones are forgotten.
+    /// `None` keeps all of them, which is the default.
+    pub fn set_undo_limit(&mut self, limit: Option<usize>) {
+        if limit != self.undo_limit {
+            self.undo_limit = limit;
+            self.trim_undo_history();
+        }
+    }
+
+    pub fn undo_limit(&self) -> Option<usize> {
+        self.undo_limit
+    }
+
+    fn trim_undo_history(&mut self) {
+        let limit = match self.undo_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut excess = Self::count_steps(&self.actions, true).saturating_sub(limit);
+        if excess == 0 {
+            return;
+        }
+
+        // Steps are found from the oldest action on, the same way `undo()` finds them from the
+        // newest one.
+        let mut group_nest = 0;
+        let mut group_count = 0;
+        let mut end = 0;
+        for (i, act) in self.actions.iter().enumerate() {
+            match *act {
+                Action::StartGroup => group_nest += 1,
+                Action::EndGroup => group_nest -= 1,
+                _ => group_count += 1,
+            }
+            if group_nest == 0 && group_count > 0 {
+                group_count = 0;
+                end = i + 1;
+                excess -= 1;
+                if excess == 0 {
+                    break;
+                }
+            }
+        }
+        self.actions.drain(..end);
     }
 
     pub fn last_arg(&self) -> Option<&[char]> {
-        self.data.split(|&c| c == ' ').filter(|s| !s.is_empty()).last()
+        self.as_slice().split(|&c| c == ' ').filter(|s| !s.is_empty()).last()
     }
 
     pub fn num_chars(&self) -> usize {
@@ -180,20 +470,49 @@ impl Buffer {
     }
 
     pub fn num_bytes(&self) -> usize {
-        let s: String = self.clone().into();
-        s.len()
+        self.byte_offset(self.num_chars())
+    }
+
+    /// The byte offset in the text of the char at `char_index`, or the length of the text in
+    /// bytes if it is the number of chars.
+    pub fn byte_offset(&self, char_index: usize) -> usize {
+        self.with_text_cache(|cache| cache.offsets[char_index])
+    }
+
+    /// The index of the char starting at or containing byte `offset` of the text.
+    pub fn char_index(&self, offset: usize) -> usize {
+        self.with_text_cache(|cache| cache.char_index(offset))
+    }
+
+    fn with_text_cache<T, F: FnOnce(&TextCache) -> T>(&self, f: F) -> T {
+        if let Some(cache) = self.text_cache.get() {
+            return f(&cache);
+        }
+
+        let cache = {
+            let mut text = String::with_capacity(self.data.len());
+            let mut offsets = Vec::with_capacity(self.data.len() + 1);
+            for &c in self.data.iter() {
+                offsets.push(text.len());
+                text.push(c);
+            }
+            offsets.push(text.len());
+            Arc::new(TextCache { text: text, offsets: offsets })
+        };
+        self.text_cache.set(Some(cache.clone()));
+        f(&cache)
     }
 
     pub fn char_before(&self, cursor: usize) -> Option<char> {
         if cursor == 0 {
             None
         } else {
-            self.data.get(cursor - 1).cloned()
+            self.data.get(cursor - 1)
         }
     }
 
     pub fn char_after(&self, cursor: usize) -> Option<char> {
-        self.data.get(cursor).cloned()
+        self.data.get(cursor)
     }
 
     /// Returns the number of characters removed.
@@ -217,24 +536,55 @@ impl Buffer {
         self.push_action(act);
     }
 
+    /// Inserts like `insert()`, but merges the text into the previous undo step if that was an
+    /// insert ending at `start` and `policy` allows it.
+    pub fn insert_coalesced(&mut self, start: usize, text: &[char], policy: UndoPolicy) {
+        let merge = match self.actions.last() {
+            Some(&Action::Insert { start: prev_start, text: ref prev_text })
+                if prev_start + prev_text.len() == start =>
+            {
+                match policy {
+                    UndoPolicy::PerKeystroke => false,
+                    UndoPolicy::PerWord => {
+                        let starts_space = text.first().map_or(false, |c| c.is_whitespace());
+                        let after_word = prev_text.last().map_or(false, |c| !c.is_whitespace());
+                        !(starts_space && after_word)
+                    }
+                    UndoPolicy::PerInsertSession => true,
+                }
+            }
+            _ => false,
+        };
+
+        if !merge {
+            return self.insert(start, text);
+        }
+
+        self.insert_raw(start, text);
+        if let Some(&mut Action::Insert { text: ref mut prev_text, .. }) = self.actions.last_mut() {
+            prev_text.extend_from_slice(text);
+        }
+        self.undone_actions.clear();
+    }
+
     // XXX rename, too confusing
     pub fn insert_from_buffer(&mut self, other: &Buffer) {
         let start = self.data.len();
-        self.insert(start, &other.data[start..])
+        self.insert(start, &other.data.range(start, other.data.len()))
     }
 
     pub fn copy_buffer(&mut self, other: &Buffer) {
         let data_len = self.data.len();
         self.remove(0, data_len);
-        self.insert(0, &other.data[0..])
+        self.insert(0, &other.data.to_vec())
     }
 
     pub fn range(&self, start: usize, end: usize) -> String {
-        self.data[start..end].iter().cloned().collect()
+        self.with_text_cache(|cache| cache.text[cache.offsets[start]..cache.offsets[end]].to_owned())
     }
 
     pub fn range_chars(&self, start: usize, end: usize) -> Vec<char> {
-        self.data[start..end].to_owned()
+        self.data.range(start, end)
     }
 
     pub fn width(&self) -> Vec<usize> {
@@ -242,15 +592,190 @@ impl Buffer {
     }
 
     pub fn range_width(&self, start: usize, end: usize) -> Vec<usize> {
-        self.range(start, end).split('\n').map(|s| s.width()).collect()
+        self.range(start, end).split('\n').map(util::width).collect()
+    }
+
+    /// Returns the char index where the grapheme cluster ending at `cursor` starts, or 0 if
+    /// `cursor` is at the start of the buffer.
+    pub fn grapheme_before(&self, cursor: usize) -> usize {
+        self.graphemes_before(cursor, 1)
+    }
+
+    /// Returns the char index where the grapheme cluster starting at `cursor` ends, or the number
+    /// of chars if `cursor` is at the end of the buffer.
+    pub fn grapheme_after(&self, cursor: usize) -> usize {
+        self.graphemes_after(cursor, 1)
+    }
+
+    /// Returns `cursor`, moved back to the start of the grapheme cluster it is inside of.
+    pub fn grapheme_boundary(&self, cursor: usize) -> usize {
+        self.with_text_cache(|cache| {
+            let mut graphemes = GraphemeCursor::new(cache.offsets[cursor], cache.text.len(), true);
+            match graphemes.is_boundary(&cache.text, 0) {
+                Ok(false) => cache.move_graphemes(cursor, 1, false),
+                _ => cursor,
+            }
+        })
+    }
+
+    /// Returns `cursor` moved back by `count` grapheme clusters, stopping at the start.
+    pub fn graphemes_before(&self, cursor: usize, count: usize) -> usize {
+        self.with_text_cache(|cache| cache.move_graphemes(cursor, count, false))
+    }
+
+    /// Returns `cursor` moved forward by `count` grapheme clusters, stopping at the end.
+    pub fn graphemes_after(&self, cursor: usize, count: usize) -> usize {
+        self.with_text_cache(|cache| cache.move_graphemes(cursor, count, true))
+    }
+
+    /// Removes the grapheme cluster ending at `cursor`, so a combining mark or the rest of an
+    /// emoji sequence goes along with it. Returns where the removed cluster started.
+    pub fn remove_grapheme_before(&mut self, cursor: usize) -> usize {
+        let start = self.grapheme_before(cursor);
+        self.remove(start, cursor);
+        start
+    }
+
+    /// Removes the grapheme cluster starting at `cursor`. Returns the number of chars removed.
+    pub fn remove_grapheme_after(&mut self, cursor: usize) -> usize {
+        let end = self.grapheme_after(cursor);
+        self.remove(cursor, end)
+    }
+
+    /// Inserts `text` at `cursor`, or at the start of the grapheme cluster `cursor` is inside of,
+    /// so the cluster isn't split. Returns the index just after the inserted text.
+    pub fn insert_at_grapheme(&mut self, cursor: usize, text: &[char]) -> usize {
+        let start = self.grapheme_boundary(cursor);
+        self.insert(start, text);
+        start + text.len()
+    }
+
+    /// Returns the start of the word `cursor` is in, or of the closest word to the left of it if
+    /// `cursor` is already on the left edge of a word or between words. Words are found with
+    /// `divider`, which works like `Context.word_divider_fn`.
+    pub fn prev_word_start(&self, cursor: usize, divider: &Fn(&Buffer) -> Vec<(usize, usize)>) -> Option<usize> {
+        let words = divider(self);
+        let word_index = match CursorPosition::get(cursor, &words) {
+            CursorPosition::InWord(i) | CursorPosition::OnWordRightEdge(i) => Some(i),
+            CursorPosition::OnWordLeftEdge(i) => if i > 0 { Some(i - 1) } else { Some(i) },
+            CursorPosition::InSpace(left, _) => left,
+        };
+        word_index.map(|i| words[i].0)
+    }
+
+    /// Returns the start of the first word that starts after `cursor`.
+    pub fn next_word_start(&self, cursor: usize, divider: &Fn(&Buffer) -> Vec<(usize, usize)>) -> Option<usize> {
+        divider(self).into_iter().map(|(start, _)| start).find(|&start| start > cursor)
+    }
+
+    /// Returns the end of the word `cursor` is in, or of the closest word to the right of it if
+    /// `cursor` is already on the right edge of a word or between words.
+    pub fn word_end_at(&self, cursor: usize, divider: &Fn(&Buffer) -> Vec<(usize, usize)>) -> Option<usize> {
+        let words = divider(self);
+        let word_index = match CursorPosition::get(cursor, &words) {
+            CursorPosition::InWord(i) | CursorPosition::OnWordLeftEdge(i) => Some(i),
+            CursorPosition::OnWordRightEdge(i) => if i < words.len() - 1 { Some(i + 1) } else { Some(i) },
+            CursorPosition::InSpace(_, right) => right,
+        };
+        word_index.map(|i| words[i].1)
+    }
+
+    /// Like `prev_word_start`, with WORDs: runs of chars separated by whitespace.
+    pub fn prev_big_word_start(&self, cursor: usize) -> Option<usize> {
+        self.prev_word_start(cursor, &get_whitespace_words)
+    }
+
+    /// Like `next_word_start`, with WORDs: runs of chars separated by whitespace.
+    pub fn next_big_word_start(&self, cursor: usize) -> Option<usize> {
+        self.next_word_start(cursor, &get_whitespace_words)
+    }
+
+    /// Like `word_end_at`, with WORDs: runs of chars separated by whitespace.
+    pub fn big_word_end_at(&self, cursor: usize) -> Option<usize> {
+        self.word_end_at(cursor, &get_whitespace_words)
+    }
+
+    /// Adds an annotation. Its range is clamped to the text.
+    pub fn annotate(&mut self, mut annotation: Annotation) {
+        annotation.end = cmp::min(annotation.end, self.num_chars());
+        annotation.start = cmp::min(annotation.start, annotation.end);
+        self.annotations.push(annotation);
+    }
+
+    /// The annotations on this buffer, in the order they were added.
+    pub fn annotations(&self) -> &[Annotation] {
+        &self.annotations
+    }
+
+    /// The annotations covering the char at `index`.
+    pub fn annotations_at(&self, index: usize) -> Vec<&Annotation> {
+        self.annotations.iter().filter(|a| a.contains(index)).collect()
+    }
+
+    /// Removes the annotations with the given tag.
+    pub fn remove_annotations(&mut self, tag: &str) {
+        self.annotations.retain(|a| a.tag != tag);
+    }
+
+    pub fn clear_annotations(&mut self) {
+        self.annotations.clear();
+    }
+
+    /// The number of lines, which is one more than the number of newlines.
+    pub fn num_lines(&self) -> usize {
+        self.chars().filter(|&&c| c == '\n').count() + 1
+    }
+
+    /// The start and end char index of each line, without the newline at its end.
+    pub fn line_ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        for (i, &c) in self.chars().enumerate() {
+            if c == '\n' {
+                ranges.push((start, i));
+                start = i + 1;
+            }
+        }
+        ranges.push((start, self.num_chars()));
+        ranges
+    }
+
+    /// Returns the line `offset` is on and how many chars it is from the start of that line.
+    /// Offsets past the end are treated like the end.
+    pub fn offset_to_row_col(&self, offset: usize) -> (usize, usize) {
+        let offset = cmp::min(offset, self.num_chars());
+        let mut row = 0;
+        let mut line_start = 0;
+        for (i, &c) in self.chars().enumerate().take(offset) {
+            if c == '\n' {
+                row += 1;
+                line_start = i + 1;
+            }
+        }
+        (row, offset - line_start)
+    }
+
+    /// Returns the char index `col` chars into line `row`, or the end of the line if it is shorter
+    /// than that. Returns `None` if there is no such line.
+    pub fn row_col_to_offset(&self, row: usize, col: usize) -> Option<usize> {
+        self.line_ranges().get(row).map(|&(start, end)| cmp::min(start + col, end))
     }
 
     pub fn lines(&self) -> Vec<String> {
-        self.data.split(|&c| c == '\n').map(|s| s.iter().cloned().collect()).collect()
+        self.to_string().split('\n').map(String::from).collect()
     }
 
     pub fn chars(&self) -> ::std::slice::Iter<char> {
-        self.data.iter()
+        self.as_slice().iter()
+    }
+
+    // All the chars in one slice. It is borrowed from the gap buffer while the gap is at either
+    // end, which it is after typing at the end of the line, and only copied otherwise.
+    fn as_slice(&self) -> &[char] {
+        match self.data.slice(0, self.data.len()) {
+            Some(chars) => chars,
+            None => self.chars_cache.get_or_init(|| self.data.to_vec().into()),
+        }
     }
 
     pub fn truncate(&mut self, num: usize) {
@@ -261,8 +786,7 @@ impl Buffer {
     pub fn print<W>(&self, out: &mut W) -> io::Result<()>
         where W: Write
     {
-        let string: String = self.data.iter().cloned().collect();
-        out.write_all(string.as_bytes())
+        out.write_all(self.to_string().as_bytes())
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
@@ -284,13 +808,23 @@ impl Buffer {
     }
 
     fn remove_raw(&mut self, start: usize, end: usize) -> Vec<char> {
-        self.data.drain(start..end).collect()
+        self.text_cache.set(None);
+        self.chars_cache = OnceLock::new();
+        let annotations = mem::replace(&mut self.annotations, Vec::new());
+        self.annotations = annotations
+            .into_iter()
+            .filter_map(|mut a| if a.map_remove(start, end) { Some(a) } else { None })
+            .collect();
+        Arc::make_mut(&mut self.data).remove(start, end)
     }
 
     fn insert_raw(&mut self, start: usize, text: &[char]) {
-        for (i, &c) in text.iter().enumerate() {
-            self.data.insert(start + i, c)
+        self.text_cache.set(None);
+        self.chars_cache = OnceLock::new();
+        for a in &mut self.annotations {
+            a.map_insert(start, text.len());
         }
+        Arc::make_mut(&mut self.data).insert(start, text)
     }
 
     /// Check if the other buffer has the same content as this one.
@@ -306,7 +840,7 @@ impl Buffer {
         if ! other.data.is_empty() && self_len != other_len {
             let match_let = self.data
                 .iter()
-                .zip(&other.data)
+                .zip(other.data.iter())
                 .take_while(|&(s, o)| *s == *o)
                 .count();
             match_let == other_len
@@ -318,11 +852,48 @@ impl Buffer {
     /// Check if the buffer contains pattern.
     /// Used to implement history search.
     pub fn contains(&self, pattern: &Buffer) -> bool {
-        let search_term: &[char] = &pattern.data;
-        if search_term.is_empty() {
+        let len = pattern.data.len();
+        if len == 0 || len > self.data.len() {
             return false;
         }
-        self.data.windows(search_term.len()).any(|window| window == search_term)
+        (0..self.data.len() - len + 1)
+            .any(|start| (0..len).all(|i| self.data.get(start + i) == pattern.data.get(i)))
+    }
+
+    /// Returns the index of the `count`th `ch` at or after `start`.
+    pub fn find_char_forward(&self, start: usize, ch: char, count: usize) -> Option<usize> {
+        assert!(count > 0);
+        self.chars()
+            .enumerate()
+            .skip(start)
+            .filter(|&(_, &c)| c == ch)
+            .nth(count - 1)
+            .map(|(i, _)| i)
+    }
+
+    /// Returns the index of the `count`th `ch` before `start`, going backwards.
+    pub fn find_char_backward(&self, start: usize, ch: char, count: usize) -> Option<usize> {
+        assert!(count > 0);
+        let rstart = self.num_chars().saturating_sub(start);
+        self.chars()
+            .enumerate()
+            .rev()
+            .skip(rstart)
+            .filter(|&(_, &c)| c == ch)
+            .nth(count - 1)
+            .map(|(i, _)| i)
+    }
+
+    /// Returns the char index of the first match of `pattern` at or after `start`.
+    pub fn find_str(&self, start: usize, pattern: &str) -> Option<usize> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        if start > self.num_chars() {
+            return None;
+        } else if pattern.is_empty() {
+            return Some(start);
+        }
+        let chars = self.data.range(start, self.num_chars());
+        chars.windows(pattern.len()).position(|window| window == &pattern[..]).map(|i| start + i)
     }
 
     /// Return true if the buffer is empty.
@@ -334,6 +905,211 @@ impl Buffer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use context::get_buffer_words;
+
+    #[test]
+    fn test_byte_offsets_follow_edits() {
+        let mut buf = Buffer::from("a\u{e9}b");
+        assert_eq!(buf.num_bytes(), 4);
+        assert_eq!(buf.byte_offset(2), 3);
+        assert_eq!(buf.char_index(2), 1);
+        assert_eq!(buf.range(1, 3), "\u{e9}b");
+
+        buf.insert(0, &['\u{4e16}']);
+        assert_eq!(buf.num_bytes(), 7);
+        assert_eq!(buf.byte_offset(1), 3);
+        assert_eq!(buf.range(0, 2), "\u{4e16}a");
+        buf.undo();
+        assert_eq!(buf.to_string(), "a\u{e9}b");
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        let buf = Buffer::from("ls  foo\\ bar baz");
+        let words = &get_buffer_words;
+        assert_eq!(buf.prev_word_start(0, words), Some(0));
+        assert_eq!(buf.prev_word_start(4, words), Some(0));
+        assert_eq!(buf.prev_word_start(6, words), Some(4));
+        assert_eq!(buf.next_word_start(0, words), Some(4));
+        assert_eq!(buf.next_word_start(4, words), Some(13));
+        assert_eq!(buf.next_word_start(13, words), None);
+        assert_eq!(buf.word_end_at(0, words), Some(2));
+        assert_eq!(buf.word_end_at(2, words), Some(12));
+        assert_eq!(buf.word_end_at(16, words), Some(16));
+
+        assert_eq!(buf.next_big_word_start(4), Some(9));
+        assert_eq!(buf.big_word_end_at(4), Some(8));
+        assert_eq!(buf.prev_big_word_start(12), Some(9));
+        assert_eq!(Buffer::new().big_word_end_at(0), None);
+    }
+
+    #[test]
+    fn test_grapheme_edits() {
+        // A family emoji made of three people joined by zero width joiners.
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let mut buf = Buffer::from(format!("a{}e\u{301}b", family));
+        assert_eq!(buf.graphemes_after(0, 2), 6);
+        assert_eq!(buf.graphemes_after(6, 5), 9);
+        assert_eq!(buf.graphemes_before(9, 2), 6);
+        assert_eq!(buf.graphemes_before(8, 3), 0);
+
+        assert_eq!(buf.remove_grapheme_before(8), 6);
+        assert_eq!(buf.to_string(), format!("a{}b", family));
+        assert_eq!(buf.remove_grapheme_after(1), 5);
+        assert_eq!(buf.to_string(), "ab");
+
+        let mut buf = Buffer::from("xe\u{301}");
+        assert_eq!(buf.insert_at_grapheme(2, &['y']), 2);
+        assert_eq!(buf.to_string(), "xye\u{301}");
+    }
+
+    #[test]
+    fn test_annotations_follow_edits() {
+        let mut buf = Buffer::from("echo foo bar");
+        let mut error = Annotation::new(5, 8, "lint");
+        error.severity = Some(Severity::Error);
+        buf.annotate(error);
+        buf.annotate(Annotation::new(9, 12, "search"));
+        buf.annotate(Annotation::new(10, 20, "search"));
+        assert_eq!(buf.annotations()[2].end, 12);
+
+        buf.insert(0, &['$', ' ']);
+        buf.insert(10, &['!']);
+        assert_eq!(buf.to_string(), "$ echo foo! bar");
+        assert_eq!((buf.annotations()[0].start, buf.annotations()[0].end), (7, 10));
+        assert_eq!(buf.annotations_at(7)[0].severity, Some(Severity::Error));
+        assert!(buf.annotations_at(10).is_empty());
+
+        buf.remove(6, 12);
+        assert_eq!(buf.to_string(), "$ echobar");
+        assert_eq!(buf.annotations().len(), 2);
+        assert_eq!((buf.annotations()[0].start, buf.annotations()[0].end), (6, 9));
+        buf.undo();
+        assert_eq!((buf.annotations()[0].start, buf.annotations()[0].end), (12, 15));
+
+        buf.remove_annotations("search");
+        assert!(buf.annotations().is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_roundtrip() {
+        let mut buf = Buffer::from("echo \"hi\"");
+        buf.insert(0, &['$']);
+        let json = ::serde_json::to_string(&buf).unwrap();
+        assert_eq!(json, r#""$echo \"hi\"""#);
+        let buf: Buffer = ::serde_json::from_str(&json).unwrap();
+        assert_eq!(buf.to_string(), "$echo \"hi\"");
+        assert!(!buf.clone().undo());
+    }
+
+    #[test]
+    fn test_undo_steps() {
+        let mut buf = Buffer::new();
+        buf.insert(0, &['a']);
+        buf.start_undo_group();
+        buf.insert(1, &['b']);
+        buf.insert(2, &['c']);
+        buf.end_undo_group();
+        buf.start_undo_group();
+        buf.end_undo_group();
+        assert_eq!(buf.undo_steps(), 2);
+        assert_eq!(buf.redo_steps(), 0);
+        assert_eq!(buf.actions().len(), 7);
+
+        buf.undo();
+        assert_eq!(buf.to_string(), "a");
+        assert_eq!((buf.undo_steps(), buf.redo_steps()), (1, 1));
+        match buf.undone_actions().last() {
+            Some(&Action::StartGroup) => {}
+            ref act => panic!("unexpected action: {:?}", act),
+        }
+        buf.undo();
+        assert_eq!((buf.undo_steps(), buf.redo_steps()), (0, 2));
+        buf.redo();
+        buf.redo();
+        assert_eq!(buf.to_string(), "abc");
+        assert_eq!((buf.undo_steps(), buf.redo_steps()), (2, 0));
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut buf = Buffer::with_capacity(32);
+        buf.extend("echo".chars());
+        buf.reserve(1024);
+        buf.extend(" hi\n".chars().chain("there".chars()));
+        buf.extend(Vec::new());
+        assert_eq!(buf.to_string(), "echo hi\nthere");
+        assert_eq!(buf.undo_steps(), 2);
+        buf.undo();
+        assert_eq!(buf.to_string(), "echo");
+    }
+
+    #[test]
+    fn test_rows_and_cols() {
+        let buf = Buffer::from("ab\n\ncdef");
+        assert_eq!(buf.num_lines(), 3);
+        assert_eq!(buf.line_ranges(), vec![(0, 2), (3, 3), (4, 8)]);
+        assert_eq!(buf.offset_to_row_col(2), (0, 2));
+        assert_eq!(buf.offset_to_row_col(3), (1, 0));
+        assert_eq!(buf.offset_to_row_col(6), (2, 2));
+        assert_eq!(buf.offset_to_row_col(20), (2, 4));
+        assert_eq!(buf.row_col_to_offset(0, 5), Some(2));
+        assert_eq!(buf.row_col_to_offset(2, 1), Some(5));
+        assert_eq!(buf.row_col_to_offset(3, 0), None);
+
+        assert_eq!(Buffer::new().line_ranges(), vec![(0, 0)]);
+        assert_eq!(Buffer::new().offset_to_row_col(0), (0, 0));
+    }
+
+    #[test]
+    fn test_find() {
+        let buf = Buffer::from("abcabc abc");
+        assert_eq!(buf.find_char_forward(1, 'a', 2), Some(7));
+        assert_eq!(buf.find_char_forward(8, 'a', 1), None);
+        assert_eq!(buf.find_char_backward(7, 'c', 1), Some(5));
+        assert_eq!(buf.find_char_backward(3, 'c', 2), None);
+        assert_eq!(buf.find_str(0, "abc"), Some(0));
+        assert_eq!(buf.find_str(4, "abc"), Some(7));
+        assert_eq!(buf.find_str(0, "ca"), Some(2));
+        assert_eq!(buf.find_str(8, "abc"), None);
+        assert_eq!(buf.find_str(3, ""), Some(3));
+    }
+
+    #[test]
+    fn test_undo_limit() {
+        let mut buf = Buffer::new();
+        buf.insert(0, &['a']);
+        buf.start_undo_group();
+        buf.insert(1, &['b']);
+        buf.insert(2, &['c']);
+        buf.end_undo_group();
+        buf.insert(3, &['d']);
+        buf.set_undo_limit(Some(2));
+        assert_eq!(buf.undo_steps(), 2);
+        buf.insert(4, &['e']);
+        assert_eq!(buf.undo_steps(), 2);
+        assert_eq!(buf.actions().len(), 2);
+
+        while buf.undo() {}
+        assert_eq!(buf.to_string(), "abc");
+        buf.redo();
+        buf.set_undo_limit(None);
+        buf.insert(4, &['f']);
+        assert_eq!(buf.undo_steps(), 2);
+    }
+
+    #[test]
+    fn test_clones_share_text_until_edited() {
+        let mut buf = Buffer::from("echo hello");
+        let copy = buf.clone();
+        assert!(Arc::ptr_eq(&buf.data, &copy.data));
+
+        buf.insert(10, &['!']);
+        assert!(!Arc::ptr_eq(&buf.data, &copy.data));
+        assert_eq!(buf.to_string(), "echo hello!");
+        assert_eq!(copy.to_string(), "echo hello");
+    }
 
     #[test]
     fn test_insert() {
@@ -510,6 +1286,28 @@ mod tests {
         assert_eq!(buf.contains(&buf2), true);
     }
 
+    #[test]
+    fn test_chars_after_editing_in_the_middle() {
+        let mut buf = Buffer::from("ls foobar");
+        buf.insert(6, &['d', ' ', 'b']);
+        assert_eq!(buf.chars().collect::<String>(), "ls food bbar");
+        assert_eq!(buf.last_arg(), Some(&['b', 'b', 'a', 'r'][..]));
+        assert!(buf.contains(&Buffer::from("od bb")));
+
+        buf.remove(3, 5);
+        assert_eq!(buf.chars().collect::<String>(), "ls od bbar");
+        assert!(!buf.contains(&Buffer::from("od bb ")));
+    }
+
+    #[test]
+    fn test_clones_share_the_chars() {
+        let mut buf = Buffer::from("ls foobar");
+        buf.insert(6, &['d']);
+        let chars = buf.chars().as_slice().as_ptr();
+        let clone = buf.clone();
+        assert_eq!(clone.chars().as_slice().as_ptr(), chars);
+    }
+
     #[test]
     fn test_does_not_contain() {
         let mut buf = Buffer::new();
@@ -532,4 +1330,57 @@ mod tests {
         buf.print_rest(&mut out, buf2.data.len()).unwrap();
         assert_eq!(out.len(), 4);
     }
+
+    #[test]
+    fn test_insert_coalesced_per_keystroke() {
+        let mut buf = Buffer::new();
+        for (i, c) in "ab cd".chars().enumerate() {
+            buf.insert_coalesced(i, &[c], UndoPolicy::PerKeystroke);
+        }
+        buf.undo();
+        assert_eq!(String::from(buf), "ab c");
+    }
+
+    #[test]
+    fn test_insert_coalesced_per_word() {
+        let mut buf = Buffer::new();
+        for (i, c) in "ab  cd".chars().enumerate() {
+            buf.insert_coalesced(i, &[c], UndoPolicy::PerWord);
+        }
+        assert_eq!(buf.undo(), true);
+        assert_eq!(buf.to_string(), "ab");
+        assert_eq!(buf.redo(), true);
+        assert_eq!(buf.to_string(), "ab  cd");
+        buf.undo();
+        buf.undo();
+        assert_eq!(String::from(buf), "");
+    }
+
+    #[test]
+    fn test_insert_coalesced_per_insert_session() {
+        let mut buf = Buffer::new();
+        for (i, c) in "ab cd".chars().enumerate() {
+            buf.insert_coalesced(i, &[c], UndoPolicy::PerInsertSession);
+        }
+        // an insert somewhere else starts a new undo step
+        buf.insert_coalesced(0, &['x'], UndoPolicy::PerInsertSession);
+        buf.undo();
+        assert_eq!(buf.to_string(), "ab cd");
+        buf.undo();
+        assert_eq!(String::from(buf), "");
+    }
+
+    #[test]
+    fn test_graphemes() {
+        // "e" followed by a combining acute accent, then a wide char.
+        let buf = Buffer::from("ae\u{301}\u{4e16}b");
+        assert_eq!(buf.grapheme_after(1), 3);
+        assert_eq!(buf.grapheme_before(3), 1);
+        assert_eq!(buf.grapheme_before(4), 3);
+        assert_eq!(buf.grapheme_boundary(2), 1);
+        assert_eq!(buf.grapheme_boundary(3), 3);
+        assert_eq!(buf.grapheme_after(5), 5);
+        assert_eq!(buf.width(), vec![5]);
+        assert_eq!(buf.range_width(0, 3), vec![2]);
+    }
 }
@@ -0,0 +1,204 @@
+use std::env;
+
+/// How many colors a terminal can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Colors {
+    /// No colors at all.
+    None,
+    /// The 8 basic colors and their bright versions.
+    Basic,
+    /// The 256-color palette.
+    Ansi256,
+    /// Any RGB color.
+    TrueColor,
+}
+
+/// The escape sequences a terminal is known to understand. Those it doesn't are left out, and
+/// what they are used for is done another way or not at all.
+///
+/// `Context.capabilities` is worked out from `$TERM` with `Capabilities::detect()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The colors the terminal can show. With `Colors::None`, colors are left out as if
+    /// `Context.color` was off.
+    pub colors: Colors,
+    /// Clearing the whole screen. Without it, Ctrl-L draws the prompt again on a new line.
+    pub clear_screen: bool,
+    /// Changing the shape of the cursor.
+    pub cursor_shape: bool,
+    /// Mouse reporting, for `Context.mouse`.
+    pub mouse: bool,
+    /// Bracketed paste, for `Context.bracketed_paste`.
+    pub bracketed_paste: bool,
+    /// Focus reporting, for `Context.focus_events`.
+    pub focus_events: bool,
+    /// The alternate screen, for `Context.alternate_screen`.
+    pub alternate_screen: bool,
+}
+
+impl Capabilities {
+    /// Everything an xterm can do.
+    pub fn all() -> Self {
+        Capabilities {
+            colors: Colors::TrueColor,
+            clear_screen: true,
+            cursor_shape: true,
+            mouse: true,
+            bracketed_paste: true,
+            focus_events: true,
+            alternate_screen: true,
+        }
+    }
+
+    /// Nothing but moving the cursor around the line, as on a dumb terminal.
+    pub fn none() -> Self {
+        Capabilities {
+            colors: Colors::None,
+            clear_screen: false,
+            cursor_shape: false,
+            mouse: false,
+            bracketed_paste: false,
+            focus_events: false,
+            alternate_screen: false,
+        }
+    }
+
+    /// Looks up what the terminal named by `$TERM` can do. Windows consoles don't set it, and
+    /// are taken to understand everything, except that with the `crossterm` feature, consoles
+    /// where virtual terminal processing can't be turned on only get the basics a
+    /// `LegacyConsole` can do. Terminals with colors can show any RGB color if `$COLORTERM` is
+    /// `truecolor` or `24bit`, whatever `$TERM` says.
+    pub fn detect() -> Self {
+        let mut capabilities = match env::var("TERM") {
+            Ok(term) => Capabilities::for_term(&term),
+            Err(_) if cfg!(windows) => Capabilities::windows_console(),
+            Err(_) => Capabilities::none(),
+        };
+        let truecolor = env::var("COLORTERM").map_or(false, |v| v == "truecolor" || v == "24bit");
+        if truecolor && capabilities.colors != Colors::None {
+            capabilities.colors = Colors::TrueColor;
+        }
+        capabilities
+    }
+
+    #[cfg(all(windows, feature = "crossterm"))]
+    fn windows_console() -> Self {
+        if ::windows_console::enable_virtual_terminal() {
+            Capabilities::all()
+        } else {
+            Capabilities {
+                colors: Colors::Basic,
+                clear_screen: true,
+                ..Capabilities::none()
+            }
+        }
+    }
+
+    #[cfg(not(all(windows, feature = "crossterm")))]
+    fn windows_console() -> Self {
+        Capabilities::all()
+    }
+
+    /// Looks up what the terminal named `term`, as in `$TERM`, can do. Terminals that aren't
+    /// known are only trusted with the basic colors and clearing the screen.
+    pub fn for_term(term: &str) -> Self {
+        let name = term.split('-').next().unwrap_or("");
+        let colors = if term.ends_with("-direct") || term.contains("truecolor") {
+            Colors::TrueColor
+        } else if term.contains("256color") {
+            Colors::Ansi256
+        } else {
+            Colors::Basic
+        };
+
+        match name {
+            "" | "dumb" | "unknown" => Capabilities::none(),
+            "vt52" | "vt100" | "vt102" | "vt220" | "ansi" => Capabilities {
+                colors: if name == "ansi" { Colors::Basic } else { Colors::None },
+                clear_screen: true,
+                ..Capabilities::none()
+            },
+            "linux" | "cons25" => Capabilities {
+                colors: Colors::Basic,
+                clear_screen: true,
+                ..Capabilities::none()
+            },
+            "alacritty" | "kitty" | "foot" | "wezterm" => Capabilities::all(),
+            "xterm" if term.starts_with("xterm-kitty") => Capabilities::all(),
+            "xterm" | "rxvt" | "urxvt" | "screen" | "tmux" | "st" | "konsole" | "gnome" | "vte"
+            | "iterm" | "iTerm.app" | "putty" | "mintty" | "terminator" | "eterm" => Capabilities {
+                colors: colors,
+                ..Capabilities::all()
+            },
+            _ => Capabilities {
+                colors: colors,
+                clear_screen: true,
+                ..Capabilities::none()
+            },
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::detect()
+    }
+}
+
+// The colors of the 256-color palette past the basic ones: a 6x6x6 cube, then 24 grays.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// The basic colors as xterm shows them by default.
+const BASIC_COLORS: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+fn ansi256_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0...15 => BASIC_COLORS[n as usize],
+        16...231 => {
+            let n = n - 16;
+            (CUBE_LEVELS[(n / 36) as usize], CUBE_LEVELS[(n / 6 % 6) as usize], CUBE_LEVELS[(n % 6) as usize])
+        }
+        _ => {
+            let gray = 8 + (n - 232) * 10;
+            (gray, gray, gray)
+        }
+    }
+}
+
+/// The color of the 256-color palette closest to an RGB color.
+pub fn rgb_to_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| {
+        CUBE_LEVELS.iter().enumerate()
+            .min_by_key(|&(_, &l)| (l as i32 - v as i32).abs())
+            .map_or(0, |(i, _)| i as u8)
+    };
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray = 232 + (average.saturating_sub(3) / 10).min(23) as u8;
+
+    if distance(ansi256_rgb(gray), (r, g, b)) < distance(ansi256_rgb(cube), (r, g, b)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+/// The basic color, from 0 to 15, closest to a color of the 256-color palette.
+pub fn ansi256_to_basic(n: u8) -> u8 {
+    if n < 16 {
+        return n;
+    }
+    let rgb = ansi256_rgb(n);
+    (0..16u8).min_by_key(|&i| distance(BASIC_COLORS[i as usize], rgb)).unwrap_or(7)
+}
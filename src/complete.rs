@@ -1,9 +1,120 @@
+use std::env;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use context::get_buffer_words;
+use {Buffer, History};
 
+/// A completion offered by a `LineCompleter`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Completion {
+    /// What the completion is shown as and replaces `span` with.
+    pub text: String,
+    /// The start and end of the chars in the buffer that are replaced, or `None` for the word
+    /// before the cursor.
+    pub span: Option<(usize, usize)>,
+    /// Added after `text` when this completion is the only one, such as a space after a command
+    /// or `=` after a long option.
+    pub suffix: String,
+}
+
+impl Completion {
+    pub fn new<S: Into<String>>(text: S) -> Self {
+        Completion {
+            text: text.into(),
+            span: None,
+            suffix: String::new(),
+        }
+    }
+
+    /// Replaces the chars from `start` to `end` instead of the word before the cursor.
+    pub fn with_span(mut self, start: usize, end: usize) -> Self {
+        self.span = Some((start, end));
+        self
+    }
+
+    pub fn with_suffix<S: Into<String>>(mut self, suffix: S) -> Self {
+        self.suffix = suffix.into();
+        self
+    }
+}
+
+impl From<String> for Completion {
+    fn from(text: String) -> Self {
+        Completion::new(text)
+    }
+}
+
+impl<'a> From<&'a str> for Completion {
+    fn from(text: &'a str) -> Self {
+        Completion::new(text)
+    }
+}
+
+impl AsRef<str> for Completion {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Completes the word before the cursor, given only that word.
 pub trait Completer {
     fn completions(&self, start: &str) -> Vec<String>;
 }
 
+/// Completes the word before the cursor, given the whole line, so the completions can depend on
+/// the rest of it, e.g. to tell a subcommand from its flags and arguments.
+///
+/// Every `Completer` is a `LineCompleter` that only looks at the word.
+pub trait LineCompleter {
+    /// Returns the completions for the word from `word.0` to `word.1` in `buf`, which the cursor
+    /// is at the end of. Without a word before the cursor, both are the cursor position.
+    fn complete(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Vec<String>;
+
+    /// Like `complete()`, but the completions can replace other parts of the buffer than the
+    /// word and have a suffix. By default these are the completions of `complete()`, replacing
+    /// the word.
+    fn complete_with_spans(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Vec<Completion> {
+        self.complete(buf, cursor, word).into_iter().map(Completion::from).collect()
+    }
+
+    /// Like `complete_with_spans()`, for completers that also look at the history the editor is
+    /// reading with, as it is at the time of completing. By default the history isn't used.
+    fn complete_with_history(&self, buf: &Buffer, cursor: usize, word: (usize, usize), _history: &History) -> Vec<Completion> {
+        self.complete_with_spans(buf, cursor, word)
+    }
+
+    /// Starts working out the completions in the background, for completers that can take a
+    /// while. The editor waits for them for `Context.completion_wait`, then shows that they are
+    /// coming and carries on reading keys. By default, completions are worked out right away
+    /// with `complete_with_spans()` instead.
+    fn complete_later(&self, _buf: &Buffer, _cursor: usize, _word: (usize, usize)) -> Option<PendingCompletions> {
+        None
+    }
+
+    /// Like `complete_later()`, for completers that also look at the history, as
+    /// `complete_with_history()` does. The editor starts completions with this one. By default
+    /// the history isn't used.
+    fn complete_later_with_history(&self, buf: &Buffer, cursor: usize, word: (usize, usize), _history: &History) -> Option<PendingCompletions> {
+        self.complete_later(buf, cursor, word)
+    }
+
+    /// Whether the completions are already in the order they should be listed in, such as the
+    /// best match first. Otherwise they are sorted.
+    fn keep_order(&self) -> bool {
+        false
+    }
+}
+
+impl<C: Completer> LineCompleter for C {
+    fn complete(&self, buf: &Buffer, _cursor: usize, word: (usize, usize)) -> Vec<String> {
+        self.completions(&buf.range(word.0, word.1))
+    }
+}
+
 pub struct BasicCompleter {
     prefixes: Vec<String>,
 }
@@ -35,18 +146,11 @@ impl FilenameCompleter {
 }
 
 impl Completer for FilenameCompleter {
-    fn completions(&self, mut start: &str) -> Vec<String> {
+    fn completions(&self, typed: &str) -> Vec<String> {
         // XXX: this function is really bad, TODO rewrite
 
-        let start_owned: String = if start.starts_with('\"') || start.starts_with('\'') {
-            start = &start[1..];
-            if ! start.is_empty() {
-                start = &start[..start.len() - 1];
-            }
-            start.into()
-        } else {
-            start.replace(r"\ ", " ")
-        };
+        let start_owned = unquote(typed);
+        let start = start_owned.as_str();
 
         let start_path = PathBuf::from(start_owned.as_str());
 
@@ -105,7 +209,8 @@ impl Completer for FilenameCompleter {
 
                 a.push(dir.file_name());
                 let mut s = a.to_string_lossy();
-                if dir.path().is_dir() {
+                let is_dir = dir.path().is_dir();
+                if is_dir {
                     let mut string = s.into_owned();
                     string.push_str("/");
                     s = string.into();
@@ -117,10 +222,524 @@ impl Completer for FilenameCompleter {
                 }
                 b.push(s.as_ref());
 
-                matches.push(b.to_string_lossy().replace(" ", r"\ "));
+                let mut quoted = quote_like(&b.to_string_lossy(), typed);
+                // Close the quote after a file, but not a directory that can be completed into.
+                match typed.chars().next() {
+                    Some(q) if !is_dir && (q == '"' || q == '\'') => quoted.push(q),
+                    _ => {}
+                }
+                matches.push(quoted);
             }
         }
 
         matches
     }
 }
+
+/// Removes shell quoting from `word`: the quotes around parts of it and the backslashes before
+/// escaped chars, so `"My Documents"/a\ b` becomes `My Documents/a b`. A quote that isn't closed
+/// yet, as while the word is being typed, runs to the end of it.
+pub fn unquote(word: &str) -> String {
+    let mut unquoted = String::with_capacity(word.len());
+    let mut quote = None;
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            // Only these can be escaped inside double quotes.
+            (Some('"'), '\\') => match chars.next() {
+                Some(e) if e == '"' || e == '\\' || e == '$' || e == '`' => unquoted.push(e),
+                Some(e) => {
+                    unquoted.push('\\');
+                    unquoted.push(e);
+                }
+                None => unquoted.push('\\'),
+            },
+            (Some(_), _) => unquoted.push(c),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '\\') => if let Some(e) = chars.next() {
+                unquoted.push(e);
+            },
+            (None, _) => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+/// Quotes `text` the way `typed` was typed, so a completion of it can replace it: after the same
+/// opening quote if `typed` starts with one, or with backslashes before the chars a shell treats
+/// specially otherwise. The quote is left open so more can be typed after the completion.
+pub fn quote_like(text: &str, typed: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    match typed.chars().next() {
+        Some('"') => {
+            quoted.push('"');
+            for c in text.chars() {
+                if c == '"' || c == '\\' || c == '$' || c == '`' {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+        }
+        Some('\'') => {
+            quoted.push('\'');
+            for c in text.chars() {
+                if c == '\'' {
+                    // Close the quote, add an escaped one and open it again.
+                    quoted.push_str("'\\''");
+                } else {
+                    quoted.push(c);
+                }
+            }
+        }
+        _ => for c in text.chars() {
+            match c {
+                ' ' | '\t' | '"' | '\'' | '\\' | '$' | '`' | '&' | '|' | ';' | '<' | '>' | '(' | ')' | '*' | '?' | '[' | ']' | '!' => {
+                    quoted.push('\\')
+                }
+                _ => {}
+            }
+            quoted.push(c);
+        },
+    }
+    quoted
+}
+
+/// Returns where the shell word that ends at `end` in `buf` starts: after the last whitespace
+/// before `end` that is neither quoted nor escaped.
+pub fn shell_word_start(buf: &Buffer, end: usize) -> usize {
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, &c) in buf.chars().take(end).enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some('"') if c == '\\' => escaped = true,
+            Some(_) => {}
+            None => match c {
+                '\\' => escaped = true,
+                '"' | '\'' => quote = Some(c),
+                _ if c.is_whitespace() => start = i + 1,
+                _ => {}
+            },
+        }
+    }
+    start
+}
+
+/// Completes `$VAR` and `${VAR}` at the end of the word with the names of environment variables.
+/// Words without such a reference get no completions, so this works well as the first
+/// completer of a `ChainedCompleter` with `ChainStrategy::FirstNonEmpty`.
+#[derive(Debug, Default)]
+pub struct EnvCompleter;
+
+impl EnvCompleter {
+    pub fn new() -> Self {
+        EnvCompleter
+    }
+}
+
+impl Completer for EnvCompleter {
+    fn completions(&self, start: &str) -> Vec<String> {
+        let dollar = match start.rfind('$') {
+            Some(i) => i,
+            None => return vec![],
+        };
+        let (before, reference) = (&start[..dollar], &start[dollar + 1..]);
+        let (braced, name) = if reference.starts_with('{') {
+            (true, &reference[1..])
+        } else {
+            (false, reference)
+        };
+        if !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return vec![];
+        }
+
+        let mut names: Vec<String> = env::vars_os()
+            .filter_map(|(var, _)| var.into_string().ok())
+            .filter(|var| var.starts_with(name))
+            .collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|var| if braced {
+                format!("{}${{{}}}", before, var)
+            } else {
+                format!("{}${}", before, var)
+            })
+            .collect()
+    }
+}
+
+/// Completions that are still being worked out, such as on another thread. They are cancelled
+/// when this is dropped, which happens when a key is pressed before they arrive.
+pub struct PendingCompletions {
+    receiver: Receiver<Vec<Completion>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Sends the completions for a `PendingCompletions`.
+pub struct CompletionSender {
+    sender: Sender<Vec<Completion>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CompletionSender {
+    pub fn send(self, completions: Vec<Completion>) {
+        // Nobody is waiting anymore if this fails.
+        let _ = self.sender.send(completions);
+    }
+
+    /// Whether the completions aren't wanted anymore, so working them out can stop early.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl PendingCompletions {
+    /// Returns completions that arrive when they are sent with the `CompletionSender`. If the
+    /// sender is dropped without sending anything, there are no completions.
+    pub fn channel() -> (CompletionSender, PendingCompletions) {
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let sender = CompletionSender { sender: sender, cancelled: cancelled.clone() };
+        (sender, PendingCompletions { receiver: receiver, cancelled: cancelled })
+    }
+
+    // Completions that have already arrived.
+    fn ready(completions: Vec<Completion>) -> Self {
+        let (sender, pending) = PendingCompletions::channel();
+        sender.send(completions);
+        pending
+    }
+
+    /// Works out the completions with `f` on a new thread.
+    pub fn spawn<F: FnOnce(&CompletionSender) -> Vec<Completion> + Send + 'static>(f: F) -> Self {
+        let (sender, pending) = PendingCompletions::channel();
+        thread::spawn(move || {
+            let completions = f(&sender);
+            sender.send(completions);
+        });
+        pending
+    }
+
+    /// Returns the completions if they have arrived.
+    pub fn poll(&self) -> Option<Vec<Completion>> {
+        match self.receiver.try_recv() {
+            Ok(completions) => Some(completions),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Vec::new()),
+        }
+    }
+
+    /// Waits up to `timeout` for the completions.
+    pub fn wait(&self, timeout: Duration) -> Option<Vec<Completion>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(completions) => Some(completions),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(Vec::new()),
+        }
+    }
+}
+
+impl Drop for PendingCompletions {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Completes with a function that is run on another thread, so a slow lookup, such as a query
+/// to a remote service, doesn't hold up typing. The function gets the line, the cursor and the
+/// span of the word before it, and can check the `CompletionSender` to stop early once the
+/// completions are no longer wanted.
+pub struct AsyncCompleter {
+    f: Arc<Fn(&str, usize, (usize, usize), &CompletionSender) -> Vec<Completion> + Send + Sync>,
+}
+
+impl AsyncCompleter {
+    pub fn new<F>(f: F) -> Self
+        where F: Fn(&str, usize, (usize, usize), &CompletionSender) -> Vec<Completion> + Send + Sync + 'static
+    {
+        AsyncCompleter { f: Arc::new(f) }
+    }
+}
+
+impl LineCompleter for AsyncCompleter {
+    fn complete(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Vec<String> {
+        self.complete_with_spans(buf, cursor, word).into_iter().map(|c| c.text).collect()
+    }
+
+    // Without the editor waiting for them, the completions are worked out right here.
+    fn complete_with_spans(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Vec<Completion> {
+        let (sender, _pending) = PendingCompletions::channel();
+        (self.f)(&buf.to_string(), cursor, word, &sender)
+    }
+
+    fn complete_later(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Option<PendingCompletions> {
+        let f = self.f.clone();
+        let line = buf.to_string();
+        Some(PendingCompletions::spawn(move |sender| f(&line, cursor, word, sender)))
+    }
+}
+
+/// What a `HistoryCompleter` completes the word before the cursor with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryCompletion {
+    /// Whole history entries starting with the word.
+    Commands,
+    /// Words of history entries, split at whitespace, starting with the word.
+    Tokens,
+}
+
+/// Completes from the history, newest entries first. The entries are read from
+/// `Context.history` when completing, so it only completes from the history with the editor;
+/// called without it, `complete()` has no completions.
+pub struct HistoryCompleter {
+    kind: HistoryCompletion,
+}
+
+impl HistoryCompleter {
+    pub fn new(kind: HistoryCompletion) -> Self {
+        HistoryCompleter { kind: kind }
+    }
+}
+
+impl LineCompleter for HistoryCompleter {
+    fn complete(&self, _buf: &Buffer, _cursor: usize, _word: (usize, usize)) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn complete_with_history(&self, buf: &Buffer, _cursor: usize, word: (usize, usize), history: &History) -> Vec<Completion> {
+        let word = buf.range(word.0, word.1);
+        let mut completions: Vec<Completion> = Vec::new();
+        {
+            let mut offer = |candidate: &str| {
+                if candidate.starts_with(&word[..]) && candidate != word && !completions.iter().any(|c| c.text == candidate) {
+                    completions.push(Completion::new(candidate));
+                }
+            };
+            for entry in history.iter().rev() {
+                let line = entry.to_string();
+                match self.kind {
+                    HistoryCompletion::Commands => offer(&line),
+                    HistoryCompletion::Tokens => line.split_whitespace().for_each(&mut offer),
+                }
+            }
+        }
+        completions
+    }
+
+    fn keep_order(&self) -> bool {
+        true
+    }
+}
+
+/// How a `ChainedCompleter` combines the completers in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainStrategy {
+    /// The completions of the first completer that has any.
+    FirstNonEmpty,
+    /// The completions of all completers together.
+    Merged,
+    /// The completions of the first completer for the position of the word, even if it has none.
+    PerPosition,
+}
+
+/// Which words a completer in a `ChainedCompleter` is used for, counting words like
+/// `get_buffer_words()` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionPosition {
+    Any,
+    /// The first word of the line, such as the name of a command.
+    First,
+    /// The words after the first one, such as arguments.
+    Rest,
+    /// The word with this index, starting from 0.
+    Index(usize),
+}
+
+impl CompletionPosition {
+    pub fn matches(&self, index: usize) -> bool {
+        match *self {
+            CompletionPosition::Any => true,
+            CompletionPosition::First => index == 0,
+            CompletionPosition::Rest => index > 0,
+            CompletionPosition::Index(i) => index == i,
+        }
+    }
+}
+
+/// Asks several completers for completions, e.g. one for command names and one for files.
+///
+/// ```
+/// use liner::{BasicCompleter, ChainStrategy, ChainedCompleter, CompletionPosition, FilenameCompleter};
+/// let completer = ChainedCompleter::new(ChainStrategy::PerPosition)
+///     .with_at(CompletionPosition::First, BasicCompleter::new(vec!["cat", "ls"]))
+///     .with(FilenameCompleter::new(None::<String>));
+/// ```
+pub struct ChainedCompleter {
+    strategy: ChainStrategy,
+    completers: Vec<(CompletionPosition, Box<LineCompleter + Send + Sync>)>,
+}
+
+impl ChainedCompleter {
+    pub fn new(strategy: ChainStrategy) -> Self {
+        ChainedCompleter {
+            strategy: strategy,
+            completers: Vec::new(),
+        }
+    }
+
+    /// Adds a completer for words at any position.
+    pub fn with<C: LineCompleter + Send + Sync + 'static>(self, completer: C) -> Self {
+        self.with_at(CompletionPosition::Any, completer)
+    }
+
+    /// Adds a completer that is only used for words at `position`.
+    pub fn with_at<C: LineCompleter + Send + Sync + 'static>(mut self, position: CompletionPosition, completer: C) -> Self {
+        self.completers.push((position, Box::new(completer)));
+        self
+    }
+}
+
+impl ChainedCompleter {
+    // The completers for the position of the word.
+    fn completers_at(&self, buf: &Buffer, word: (usize, usize)) -> Vec<&LineCompleter> {
+        let index = get_buffer_words(buf).into_iter().filter(|&(_, end)| end < word.0).count();
+        self.completers
+            .iter()
+            .filter(|&&(ref position, _)| position.matches(index))
+            .map(|&(_, ref completer)| &**completer as &LineCompleter)
+            .collect()
+    }
+
+    // Asks the completers for the position of the word with `complete`, as the strategy says.
+    fn complete_each<F>(&self, buf: &Buffer, word: (usize, usize), complete: F) -> Vec<Completion>
+        where F: Fn(&LineCompleter) -> Vec<Completion>
+    {
+        let mut completers = self.completers_at(buf, word).into_iter();
+
+        match self.strategy {
+            ChainStrategy::FirstNonEmpty => completers
+                .map(|c| complete(c))
+                .find(|completions| !completions.is_empty())
+                .unwrap_or_default(),
+            ChainStrategy::Merged => {
+                let mut merged: Vec<Completion> = Vec::new();
+                for completion in completers.flat_map(|c| complete(c)) {
+                    if !merged.contains(&completion) {
+                        merged.push(completion);
+                    }
+                }
+                merged
+            }
+            ChainStrategy::PerPosition => completers.next().map_or_else(Vec::new, |c| complete(c)),
+        }
+    }
+
+    // Like `complete_each()`, but starts the completers that work out their completions in the
+    // background with `later`, and combines what they send with the completions of the others.
+    // If none of them do, the completions have already arrived.
+    fn complete_each_later<F, G>(&self, buf: &Buffer, word: (usize, usize), later: F, complete: G) -> Option<PendingCompletions>
+        where F: Fn(&LineCompleter) -> Option<PendingCompletions>,
+              G: Fn(&LineCompleter) -> Vec<Completion>
+    {
+        let mut completers = self.completers_at(buf, word);
+        if self.strategy == ChainStrategy::PerPosition {
+            completers.truncate(1);
+        }
+
+        // With `FirstNonEmpty`, the completers after the first one that has completions right
+        // away aren't needed, whatever the ones before it send.
+        let mut sources = Vec::new();
+        for completer in completers {
+            match later(completer) {
+                Some(pending) => sources.push(Err(pending)),
+                None => {
+                    let completions = complete(completer);
+                    let found = !completions.is_empty();
+                    sources.push(Ok(completions));
+                    if found && self.strategy == ChainStrategy::FirstNonEmpty {
+                        break;
+                    }
+                }
+            }
+        }
+        let strategy = self.strategy;
+        if sources.iter().all(|source| source.is_ok()) {
+            let (sender, _pending) = PendingCompletions::channel();
+            return Some(PendingCompletions::ready(gather(strategy, sources, &sender)));
+        }
+        Some(PendingCompletions::spawn(move |sender| gather(strategy, sources, sender)))
+    }
+}
+
+// Waits for the completions of each source in turn and combines them as `strategy` says. The
+// sources still pending are cancelled when `sender` is.
+fn gather(strategy: ChainStrategy, sources: Vec<Result<Vec<Completion>, PendingCompletions>>, sender: &CompletionSender) -> Vec<Completion> {
+    let mut merged: Vec<Completion> = Vec::new();
+    for source in sources {
+        let completions = match source {
+            Ok(completions) => completions,
+            Err(pending) => loop {
+                if sender.is_cancelled() {
+                    return Vec::new();
+                }
+                if let Some(completions) = pending.wait(Duration::from_millis(10)) {
+                    break completions;
+                }
+            },
+        };
+        match strategy {
+            ChainStrategy::FirstNonEmpty | ChainStrategy::PerPosition => if !completions.is_empty() {
+                return completions;
+            },
+            ChainStrategy::Merged => for completion in completions {
+                if !merged.contains(&completion) {
+                    merged.push(completion);
+                }
+            },
+        }
+    }
+    merged
+}
+
+impl LineCompleter for ChainedCompleter {
+    fn complete(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Vec<String> {
+        self.complete_with_spans(buf, cursor, word).into_iter().map(|c| c.text).collect()
+    }
+
+    fn complete_with_spans(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Vec<Completion> {
+        self.complete_each(buf, word, |c| c.complete_with_spans(buf, cursor, word))
+    }
+
+    fn complete_with_history(&self, buf: &Buffer, cursor: usize, word: (usize, usize), history: &History) -> Vec<Completion> {
+        self.complete_each(buf, word, |c| c.complete_with_history(buf, cursor, word, history))
+    }
+
+    fn complete_later(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Option<PendingCompletions> {
+        self.complete_each_later(
+            buf,
+            word,
+            |c| c.complete_later(buf, cursor, word),
+            |c| c.complete_with_spans(buf, cursor, word),
+        )
+    }
+
+    fn complete_later_with_history(&self, buf: &Buffer, cursor: usize, word: (usize, usize), history: &History) -> Option<PendingCompletions> {
+        self.complete_each_later(
+            buf,
+            word,
+            |c| c.complete_later_with_history(buf, cursor, word, history),
+            |c| c.complete_with_history(buf, cursor, word, history),
+        )
+    }
+
+    fn keep_order(&self) -> bool {
+        self.completers.iter().any(|&(_, ref completer)| completer.keep_order())
+    }
+}
@@ -1,12 +1,19 @@
-use std::io::{self, stdin, stdout, Stdout, Write};
-use termion::input::TermRead;
-use termion::raw::{IntoRawMode, RawTerminal};
+use std::env;
+use std::fmt;
+use std::io::{self, stdout, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use super::*;
-use keymap;
 
 pub type ColorClosure = Box<Fn(&str) -> String>;
 
+/// Checks a line before it is accepted, returning what is wrong with it if it can't be.
+pub type Validator = Box<Fn(&str) -> Result<(), String>>;
+
+/// Changes a line as it is accepted, returning the line to use instead.
+pub type AcceptTransform = Box<Fn(&str) -> String>;
+
 /// The default for `Context.word_divider_fn`.
 pub fn get_buffer_words(buf: &Buffer) -> Vec<(usize, usize)> {
     let mut res = Vec::new();
@@ -39,6 +46,248 @@ pub fn get_buffer_words(buf: &Buffer) -> Vec<(usize, usize)> {
     res
 }
 
+/// A word divider that splits on whitespace only, without any escaping.
+pub fn get_whitespace_words(buf: &Buffer) -> Vec<(usize, usize)> {
+    let mut res = Vec::new();
+
+    let mut word_start = None;
+    for (i, &c) in buf.chars().enumerate() {
+        match word_start {
+            Some(start) if c.is_whitespace() => {
+                res.push((start, i));
+                word_start = None;
+            }
+            None if !c.is_whitespace() => word_start = Some(i),
+            _ => {}
+        }
+    }
+
+    if let Some(start) = word_start {
+        res.push((start, buf.num_chars()));
+    }
+
+    res
+}
+
+/// A word divider that follows shell tokenization: whitespace inside quotes or after a backslash
+/// does not end a word, and runs of the operators `|&;<>()` are words of their own.
+pub fn get_shell_words(buf: &Buffer) -> Vec<(usize, usize)> {
+    fn is_operator(c: char) -> bool {
+        match c {
+            '|' | '&' | ';' | '<' | '>' | '(' | ')' => true,
+            _ => false,
+        }
+    }
+
+    let mut res = Vec::new();
+
+    let mut word_start = None;
+    let mut in_operator = false;
+    let mut quote = None;
+    let mut just_had_backslash = false;
+
+    for (i, &c) in buf.chars().enumerate() {
+        if just_had_backslash {
+            just_had_backslash = false;
+            continue;
+        }
+
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else if c == '\\' && q == '"' {
+                just_had_backslash = true;
+            }
+            continue;
+        }
+
+        let ends_word = c.is_whitespace() || is_operator(c) != in_operator;
+        if let Some(start) = word_start {
+            if ends_word {
+                res.push((start, i));
+                word_start = None;
+            }
+        }
+
+        if word_start.is_none() && !c.is_whitespace() {
+            word_start = Some(i);
+            in_operator = is_operator(c);
+        }
+
+        match c {
+            '\\' => just_had_backslash = true,
+            '"' | '\'' => quote = Some(c),
+            _ => {}
+        }
+    }
+
+    if let Some(start) = word_start {
+        res.push((start, buf.num_chars()));
+    }
+
+    res
+}
+
+/// A word divider that treats each component of a path as a word, splitting on `/` as well as on
+/// whitespace.
+pub fn get_path_component_words(buf: &Buffer) -> Vec<(usize, usize)> {
+    let mut res = Vec::new();
+
+    let mut word_start = None;
+    for (i, &c) in buf.chars().enumerate() {
+        let is_divider = c == '/' || c.is_whitespace();
+        match word_start {
+            Some(start) if is_divider => {
+                res.push((start, i));
+                word_start = None;
+            }
+            None if !is_divider => word_start = Some(i),
+            _ => {}
+        }
+    }
+
+    if let Some(start) = word_start {
+        res.push((start, buf.num_chars()));
+    }
+
+    res
+}
+
+/// The outcome of a call to `Context::read_line()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadLineResult {
+    /// The line was accepted. The trailing newline is not included.
+    Input(String),
+    /// Ctrl-D was pressed on an empty line.
+    Eof,
+    /// Ctrl-C was pressed.
+    Interrupted,
+}
+
+/// What happens when Ctrl-C is pressed during a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtrlCBehavior {
+    /// End the read, returning `ReadLineResult::Interrupted`.
+    Interrupt,
+    /// Abandon the current line and start over with a fresh prompt, without ending the read.
+    ClearLine,
+    /// Handle Ctrl-C like any other key in the key bindings.
+    PassThrough,
+}
+
+/// A shape for the terminal cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    BlinkingBlock,
+    SteadyBlock,
+    BlinkingUnderline,
+    SteadyUnderline,
+    BlinkingBar,
+    SteadyBar,
+}
+
+impl fmt::Display for CursorShape {
+    /// Writes the escape sequence (DECSCUSR) that changes the cursor to this shape.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = match *self {
+            CursorShape::BlinkingBlock => 1,
+            CursorShape::SteadyBlock => 2,
+            CursorShape::BlinkingUnderline => 3,
+            CursorShape::SteadyUnderline => 4,
+            CursorShape::BlinkingBar => 5,
+            CursorShape::SteadyBar => 6,
+        };
+        write!(f, "\x1B[{} q", n)
+    }
+}
+
+/// The colors used by the editor. Each one is an escape sequence, such as `"\x1B[33m"` for yellow,
+/// `"\x1B[2m"` for faint text or `"\x1B[38;2;255;128;0m"` for an RGB color, and all attributes
+/// are reset after the text it applies to. RGB and 256-palette colors are drawn as the closest
+/// colors the terminal has, going by `Context.capabilities`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    /// Autosuggestions from history, after the typed text.
+    pub autosuggestion: String,
+    /// The line found by incremental search.
+    pub search_result: String,
+    /// The part of the line found by incremental search that the search term matched.
+    pub search_highlight: String,
+    /// The search term in the search prompt, when there are matches.
+    pub search_match: String,
+    /// The search term in the search prompt, when nothing matches.
+    pub search_no_match: String,
+    /// The highlighted entry of the completion list.
+    pub completion_highlight: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            autosuggestion: ansi::fg(ansi::YELLOW),
+            search_result: ansi::fg(ansi::YELLOW),
+            search_highlight: ansi::INVERT.to_owned(),
+            search_match: ansi::fg(ansi::GREEN),
+            search_no_match: ansi::fg(ansi::RED),
+            completion_highlight: format!("{}{}", ansi::fg(ansi::BLACK), ansi::bg(ansi::WHITE)),
+        }
+    }
+}
+
+/// How the bell is rung when something can't be done, such as moving past the end of the line or
+/// completing a word nothing matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BellStyle {
+    /// Beep.
+    Audible,
+    /// Flash the screen by briefly turning on reverse video.
+    Visible,
+    /// Don't ring the bell.
+    Off,
+}
+
+/// How long handling keys and redrawing took, for finding out why a prompt is slow, e.g. over a
+/// high-latency connection. Kept in `Context.metrics` and added to by every read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// The number of keys handled.
+    pub keys: u64,
+    /// The total time spent handling keys, including redrawing after them.
+    pub key_time: Duration,
+    /// How long the last key took to handle.
+    pub last_key_time: Duration,
+    /// The number of times the prompt and buffer were redrawn.
+    pub redraws: u64,
+    /// The total time spent working out and writing redraws.
+    pub redraw_time: Duration,
+    /// How long the last redraw took.
+    pub last_redraw_time: Duration,
+    /// The number of bytes written to the terminal by redraws.
+    pub bytes_written: u64,
+}
+
+impl Metrics {
+    /// Starts counting again from zero.
+    pub fn reset(&mut self) {
+        *self = Metrics::default();
+    }
+
+    /// Adds a key that took `time` to handle.
+    pub fn add_key(&mut self, time: Duration) {
+        self.keys += 1;
+        self.key_time += time;
+        self.last_key_time = time;
+    }
+
+    /// Adds a redraw that took `time` and wrote `bytes` to the terminal.
+    pub fn add_redraw(&mut self, time: Duration, bytes: usize) {
+        self.redraws += 1;
+        self.redraw_time += time;
+        self.last_redraw_time = time;
+        self.bytes_written += bytes as u64;
+    }
+}
+
 /// The key bindings to use.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KeyBindings {
@@ -46,24 +295,169 @@ pub enum KeyBindings {
     Emacs,
 }
 
+/// Everything that lasts from one `read_line` to the next, such as the history and the settings.
+///
+/// A context is `Send` and `Sync`, so it can be kept in an `Arc<Mutex<Context>>` and shared with
+/// other threads, e.g. one that syncs the history. That is why completers, word dividers and
+/// history hooks have to be `Send` and `Sync` as well.
 pub struct Context {
     pub history: History,
-    pub completer: Option<Box<Completer>>,
-    pub word_divider_fn: Box<Fn(&Buffer) -> Vec<(usize, usize)>>,
+    pub completer: Option<Box<LineCompleter + Send + Sync>>,
+    pub word_divider_fn: Box<Fn(&Buffer) -> Vec<(usize, usize)> + Send + Sync>,
     pub key_bindings: KeyBindings,
+    /// What Ctrl-C does with the emacs key bindings.
+    pub emacs_ctrl_c_behavior: CtrlCBehavior,
+    /// What Ctrl-C does with the vi key bindings.
+    pub vi_ctrl_c_behavior: CtrlCBehavior,
+    /// How inserts are grouped into undo steps with the emacs key bindings.
+    pub emacs_undo_policy: UndoPolicy,
+    /// How inserts are grouped into undo steps with the vi key bindings.
+    pub vi_undo_policy: UndoPolicy,
+    /// The most undo steps kept for each line being edited, or `None` to keep all of them.
+    pub undo_limit: Option<usize>,
+    /// How long to wait for completions worked out in the background before going back to
+    /// reading keys and showing that they are coming.
+    pub completion_wait: Duration,
+    /// How long to wait for completions worked out in the background before giving up on them.
+    pub completion_timeout: Duration,
+    /// How many columns apart tab stops are when showing tabs in the buffer. Tabs are shown as
+    /// `^I` if this is 0.
+    pub tab_width: usize,
+    /// Keeps each line of the buffer on a single terminal row, scrolling it sideways to follow the
+    /// cursor, instead of wrapping long lines.
+    pub horizontal_scroll: bool,
+    /// The cursor shape in vi insert mode, or `None` for the terminal's default.
+    pub vi_insert_cursor_shape: Option<CursorShape>,
+    /// The cursor shape in the other vi modes, or `None` for the terminal's default.
+    pub vi_normal_cursor_shape: Option<CursorShape>,
+    /// The cursor shape in emacs overwrite mode, toggled with the Insert key, or `None` for the
+    /// terminal's default.
+    pub emacs_overwrite_cursor_shape: Option<CursorShape>,
+    /// Lets clicking on the buffer move the cursor there. This turns on mouse reporting in the
+    /// terminal while a line is read, so selecting text with the mouse may need a modifier key.
+    pub mouse: bool,
+    /// How long to wait for a key before sending an `Idle` event and redrawing, so the prompt can
+    /// show things that change on their own, like a clock. `None` waits for keys indefinitely.
+    pub tick_interval: Option<Duration>,
+    /// Has the terminal mark pasted text, so that it is inserted in one piece after a `Paste`
+    /// event, instead of being typed key by key. A newline in it doesn't accept the line.
+    pub bracketed_paste: bool,
+    /// Has the terminal report when its window gains or loses focus, which is sent to the
+    /// handler as a `Focus` event.
+    pub focus_events: bool,
+    /// Counts and times the keys handled and the redraws done while reading lines.
+    pub metrics: Metrics,
+    /// Shows errors from event handlers as the message instead of ending the read with them.
+    pub show_handler_errors: bool,
+    /// Where keys are read from and the terminal's size and cursor position are asked for.
+    pub terminal: Box<Terminal>,
+    /// Reads lines on the terminal's alternate screen, bringing back what was on the screen once
+    /// the line is read. The line isn't left on the screen, so it is up to the program to show it.
+    pub alternate_screen: bool,
+    /// The escape sequences the terminal understands. The editor doesn't use the others, even
+    /// if asked to by the other options, and makes do without them.
+    pub capabilities: Capabilities,
+    /// The text last killed (deleted by a command that removes a word, the rest of the line or
+    /// the like), which yanking inserts again.
+    pub kill_buffer: Register,
+    /// Also copies killed text to the system clipboard with an OSC 52 escape sequence, which
+    /// works over SSH in terminals that support it.
+    pub osc52_clipboard: bool,
+    /// The most bytes of base64-encoded text to send in one OSC 52 sequence. Longer kills aren't
+    /// copied to the clipboard, since many terminals drop or cut off sequences that are too long.
+    pub osc52_max_len: usize,
+    /// With the `clipboard` feature, also copies killed text to the system clipboard directly,
+    /// and yanks from it when it has been changed by another program since the last kill.
+    /// Failing to reach the clipboard, such as without a display, is ignored.
+    pub system_clipboard: bool,
+    /// The colors the editor's own UI is drawn with.
+    pub theme: Theme,
+    /// Draws colors at all: those of the theme, the prompt and the highlighting closure. Turning
+    /// this off leaves other escape sequences alone. Off by default when the `NO_COLOR`
+    /// environment variable is set to something other than an empty string.
+    pub color: bool,
+    /// How the bell is rung when an action fails.
+    pub bell_style: BellStyle,
+    /// How many history matches are listed under the line during incremental search, starting
+    /// with the current one. The list is not shown if this is 0.
+    pub search_preview: usize,
+    /// If set, Up and Down with text typed only go through history entries that start with the
+    /// text before the cursor, and leave the cursor where it is.
+    pub history_prefix_search: bool,
+    /// Where the typed text has to be found in a history entry for it to be suggested.
+    pub autosuggest_anchor: MatchAnchor,
+    /// Where the search term has to be found in a history entry during incremental search.
+    pub search_anchor: MatchAnchor,
+    /// If set, Up with nothing typed goes through the entries added in this session before the
+    /// ones loaded from the history file.
+    pub prefer_session_history: bool,
+    /// A key that deletes the history entry being edited from the history and the history file.
+    /// Not bound if `None`.
+    pub delete_history_entry_key: Option<Key>,
+    /// How many characters have to be typed before a history entry is suggested.
+    pub autosuggest_min_chars: usize,
+    /// If set, a history entry is only suggested if all entries matching the typed text are the
+    /// same.
+    pub autosuggest_unique_only: bool,
+    /// A key that pins the history entry being edited, or unpins it. Not bound if `None`.
+    pub toggle_pin_key: Option<Key>,
 }
 
 impl Context {
+    /// Starts configuring a `Context`, beginning with the defaults of `Context::new()`.
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::new()
+    }
+
     pub fn new() -> Self {
         Context {
             history: History::new(),
             completer: None,
             word_divider_fn: Box::new(get_buffer_words),
             key_bindings: KeyBindings::Emacs,
+            emacs_ctrl_c_behavior: CtrlCBehavior::Interrupt,
+            vi_ctrl_c_behavior: CtrlCBehavior::Interrupt,
+            emacs_undo_policy: UndoPolicy::PerKeystroke,
+            vi_undo_policy: UndoPolicy::PerInsertSession,
+            undo_limit: None,
+            completion_wait: Duration::from_millis(50),
+            completion_timeout: Duration::from_secs(10),
+            tab_width: 8,
+            horizontal_scroll: false,
+            vi_insert_cursor_shape: None,
+            vi_normal_cursor_shape: None,
+            emacs_overwrite_cursor_shape: None,
+            mouse: false,
+            tick_interval: None,
+            bracketed_paste: false,
+            focus_events: false,
+            metrics: Metrics::default(),
+            show_handler_errors: false,
+            terminal: Box::new(SystemTerminal::new()),
+            alternate_screen: false,
+            // Tests check for escape sequences whatever terminal they run in.
+            capabilities: if cfg!(test) { Capabilities::all() } else { Capabilities::detect() },
+            kill_buffer: Register::default(),
+            osc52_clipboard: false,
+            osc52_max_len: 100000,
+            system_clipboard: false,
+            theme: Theme::default(),
+            color: env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()),
+            bell_style: BellStyle::Off,
+            search_preview: 0,
+            history_prefix_search: false,
+            autosuggest_anchor: MatchAnchor::Start,
+            search_anchor: MatchAnchor::Anywhere,
+            prefer_session_history: false,
+            delete_history_entry_key: None,
+            autosuggest_min_chars: 0,
+            autosuggest_unique_only: false,
+            toggle_pin_key: None,
         }
     }
 
-    /// Creates an `Editor` and feeds it keypresses from stdin until the line is entered.
+    /// Creates an `Editor` and feeds it keypresses from stdin until the line is entered,
+    /// or the read is ended by Ctrl-C or Ctrl-D.
     /// The output is stdout.
     /// The returned line has the newline removed.
     /// Before returning, will revert all changes to the history buffers.
@@ -72,7 +466,7 @@ impl Context {
         prompt: P,
         f: Option<ColorClosure>,
         handler: &mut EventHandler<RawTerminal<Stdout>>,
-    ) -> io::Result<String> {
+    ) -> io::Result<ReadLineResult> {
         self.read_line_with_init_buffer(prompt, handler, f, Buffer::new())
     }
 
@@ -93,41 +487,457 @@ impl Context {
         handler: &mut EventHandler<RawTerminal<Stdout>>,
         f: Option<ColorClosure>,
         buffer: B,
-    ) -> io::Result<String> {
+    ) -> io::Result<ReadLineResult> {
+        self.read_line_with(stdout(), prompt, handler, f, buffer, None)
+    }
+
+    /// Same as `Context.read_line_with_init_buffer()`, but starts with the cursor before char
+    /// `cursor` of the initial buffer instead of at the end of it. A `cursor` past the end is
+    /// clamped to it, and one inside a grapheme cluster is moved back to its start.
+    ///
+    /// ```no_run
+    /// use liner::Context;
+    /// let mut context = Context::new();
+    /// // Start with the cursor between the quotes.
+    /// let line = context.read_line_with_init_buffer_and_cursor("[prompt]$ ",
+    ///                                                          &mut |_| {},
+    ///                                                          None,
+    ///                                                          "git commit -m \"\"",
+    ///                                                          15);
+    /// ```
+    pub fn read_line_with_init_buffer_and_cursor<P: Into<String>, B: Into<Buffer>>(
+        &mut self,
+        prompt: P,
+        handler: &mut EventHandler<RawTerminal<Stdout>>,
+        f: Option<ColorClosure>,
+        buffer: B,
+        cursor: usize,
+    ) -> io::Result<ReadLineResult> {
+        self.read_line_with(stdout(), prompt, handler, f, buffer, Some(cursor))
+    }
+
+    /// Same as `Context.read_line_with_init_buffer()`, but draws the prompt and buffer on `out`
+    /// instead of stdout. Passing `io::stderr()` keeps stdout free for the program's own output,
+    /// so it can be piped somewhere without the prompt ending up in it.
+    ///
+    /// ```no_run
+    /// use liner::Context;
+    /// use std::io::stderr;
+    /// let mut context = Context::new();
+    /// let line = context.read_line_with_output(stderr(), "[prompt]$ ", &mut |_| {}, None, "");
+    /// ```
+    pub fn read_line_with_output<W: IntoRawMode, P: Into<String>, B: Into<Buffer>>(
+        &mut self,
+        out: W,
+        prompt: P,
+        handler: &mut EventHandler<RawTerminal<W>>,
+        f: Option<ColorClosure>,
+        buffer: B,
+    ) -> io::Result<ReadLineResult> {
+        self.read_line_with(out, prompt, handler, f, buffer, None)
+    }
+
+    /// Reads a line with the settings in `options`, drawing the prompt and buffer on `out`.
+    pub fn read_line_with_options<W: IntoRawMode>(
+        &mut self,
+        out: W,
+        options: ReadLineOptions,
+        handler: &mut EventHandler<RawTerminal<W>>,
+    ) -> io::Result<ReadLineResult> {
+        let key_bindings = options.key_bindings.unwrap_or(self.key_bindings);
         let res = {
-            let mut stdout = stdout().into_raw_mode()?;
-            let ed = Editor::new_with_init_buffer(stdout, prompt, f, self, buffer)?;
-            match self.key_bindings {
-                KeyBindings::Emacs => Self::handle_keys(keymap::Emacs::new(ed), handler),
-                KeyBindings::Vi => Self::handle_keys(keymap::Vi::new(ed), handler),
-            }
+            let out = out.into_raw_mode()?;
+            let ed = Editor::with_options(out, self, options)?;
+            Self::handle_keys(AnyKeyMap::new(ed, key_bindings), handler)
         };
 
         //self.revert_all_history();
         res
     }
 
+    /// Reads a line with the settings in `options` like `read_line_with_options()`, but leaves
+    /// raw mode to `Context.terminal` instead of putting `out` in raw mode. This is for
+    /// terminals other than the one the program runs in, such as a `ReadTerminal` on a serial
+    /// port, with `out` writing back to it.
+    ///
+    /// ```no_run
+    /// use liner::{Context, ReadLineOptions, ReadTerminal};
+    /// use std::net::TcpStream;
+    ///
+    /// let stream = TcpStream::connect("127.0.0.1:2323").unwrap();
+    /// let mut con = Context::builder()
+    ///     .terminal(ReadTerminal::new(stream.try_clone().unwrap()))
+    ///     .build()
+    ///     .unwrap();
+    /// let res = con.read_line_on(stream, ReadLineOptions::new("> "), &mut |_| {});
+    /// ```
+    pub fn read_line_on<W: Write>(
+        &mut self,
+        out: W,
+        options: ReadLineOptions,
+        handler: &mut EventHandler<W>,
+    ) -> io::Result<ReadLineResult> {
+        let key_bindings = options.key_bindings.unwrap_or(self.key_bindings);
+        let _raw = self.terminal.raw_mode()?;
+        let ed = Editor::with_options(out, self, options)?;
+        Self::handle_keys(AnyKeyMap::new(ed, key_bindings), handler)
+    }
+
+    fn read_line_with<W: IntoRawMode, P: Into<String>, B: Into<Buffer>>(
+        &mut self,
+        out: W,
+        prompt: P,
+        handler: &mut EventHandler<RawTerminal<W>>,
+        f: Option<ColorClosure>,
+        buffer: B,
+        cursor: Option<usize>,
+    ) -> io::Result<ReadLineResult> {
+        let options = ReadLineOptions {
+            prompt: prompt.into(),
+            buffer: buffer.into(),
+            cursor: cursor,
+            key_bindings: None,
+            color_closure: f,
+            mask: None,
+            hidden: false,
+            validator: None,
+            accept_transform: None,
+            echo_transform: true,
+        };
+        self.read_line_with_options(out, options, handler)
+    }
+
     fn handle_keys<'a, T, W: Write, M: KeyMap<'a, W, T>>(
         mut keymap: M,
         handler: &mut EventHandler<W>,
-    ) -> io::Result<String>
+    ) -> io::Result<ReadLineResult>
     where
         String: From<M>,
     {
-        let stdin = stdin();
-        let stdin = stdin.lock();
-        for c in stdin.keys() {
-            if keymap.handle_key(c.unwrap(), handler)? {
+        let res = keymap.start().and_then(|_| Self::read_keys(&mut keymap, handler));
+        // The terminal is put back however the read ended, errors included.
+        let finished = keymap.finish();
+        let res = res?;
+        finished?;
+        Ok(res)
+    }
+
+    fn read_keys<'a, T, W: Write, M: KeyMap<'a, W, T>>(
+        keymap: &mut M,
+        handler: &mut EventHandler<W>,
+    ) -> io::Result<ReadLineResult> {
+        let mut res = ReadLineResult::Eof;
+        loop {
+            // Keep the spinner going until the completions arrive or a key is pressed.
+            while keymap.editor().is_completion_pending()
+                && !keymap.editor_mut().context().terminal.input_ready(Duration::from_millis(100))
+            {
+                keymap.editor_mut().poll_completion()?;
+            }
+
+            // Put the screen back after a visible bell, unless a key comes first.
+            if let Some(remaining) = keymap.editor().flash_remaining() {
+                if !keymap.editor_mut().context().terminal.input_ready(remaining) {
+                    keymap.editor_mut().end_flash()?;
+                }
+            }
+
+            if let Some(interval) = keymap.editor_mut().context().tick_interval {
+                while !keymap.editor_mut().context().terminal.input_ready(interval) {
+                    keymap.editor_mut().tick(handler)?;
+                }
+            }
+
+            let input = match keymap.editor_mut().context().terminal.read_input() {
+                Some(Ok(input)) => input,
+                // Input that couldn't be made sense of is skipped rather than ending the read.
+                Some(Err(ref e)) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Some(Err(e)) => return Err(e),
+                None => break,
+            };
+            if let Some(r) = keymap.handle_input(input, handler)? {
+                res = r;
                 break;
             }
         }
-
-        Ok(keymap.into())
+        Ok(res)
     }
 
     pub fn revert_all_history(&mut self) {
-        for buf in &mut self.history.buffers {
-            buf.revert();
+        for entry in self.history.buffers.iter_mut() {
+            entry.buffer.revert();
+        }
+    }
+}
+
+/// Configures a `Context` one setting at a time.
+///
+/// ```no_run
+/// use liner::{Context, KeyBindings};
+/// let mut context = Context::builder()
+///     .key_bindings(KeyBindings::Vi)
+///     .history_file("history.txt")
+///     .max_history_size(500)
+///     .tab_width(4)
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// Settings without a method of their own can be changed with `configure()`.
+pub struct ContextBuilder {
+    context: Context,
+    history_file: Option<PathBuf>,
+}
+
+impl ContextBuilder {
+    pub fn new() -> Self {
+        ContextBuilder {
+            context: Context::new(),
+            history_file: None,
+        }
+    }
+
+    /// Uses `history` instead of an empty history.
+    pub fn history(mut self, history: History) -> Self {
+        self.context.history = history;
+        self
+    }
+
+    /// Loads the history from `path` when the context is built, and saves it there. The file is
+    /// created if it doesn't exist.
+    pub fn history_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.history_file = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// The most history entries kept in memory.
+    pub fn max_history_size(mut self, size: usize) -> Self {
+        self.context.history.set_max_buffers_size(size);
+        self
+    }
+
+    /// The most history entries written to the history file.
+    pub fn max_history_file_size(mut self, size: usize) -> Self {
+        self.context.history.set_max_file_size(size);
+        self
+    }
+
+    pub fn key_bindings(mut self, key_bindings: KeyBindings) -> Self {
+        self.context.key_bindings = key_bindings;
+        self
+    }
+
+    /// Splits the buffer into words for word motions and completion. See `get_buffer_words()`.
+    pub fn word_divider<F: Fn(&Buffer) -> Vec<(usize, usize)> + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.context.word_divider_fn = Box::new(f);
+        self
+    }
+
+    pub fn completer<C: LineCompleter + Send + Sync + 'static>(mut self, completer: C) -> Self {
+        self.context.completer = Some(Box::new(completer));
+        self
+    }
+
+    pub fn theme(mut self, theme: Theme) -> Self {
+        self.context.theme = theme;
+        self
+    }
+
+    /// Whether colors are drawn at all. See `Context.color`.
+    pub fn color(mut self, color: bool) -> Self {
+        self.context.color = color;
+        self
+    }
+
+    pub fn bell_style(mut self, bell_style: BellStyle) -> Self {
+        self.context.bell_style = bell_style;
+        self
+    }
+
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.context.tab_width = tab_width;
+        self
+    }
+
+    pub fn horizontal_scroll(mut self, horizontal_scroll: bool) -> Self {
+        self.context.horizontal_scroll = horizontal_scroll;
+        self
+    }
+
+    pub fn mouse(mut self, mouse: bool) -> Self {
+        self.context.mouse = mouse;
+        self
+    }
+
+    pub fn tick_interval(mut self, tick_interval: Option<Duration>) -> Self {
+        self.context.tick_interval = tick_interval;
+        self
+    }
+
+    pub fn bracketed_paste(mut self, bracketed_paste: bool) -> Self {
+        self.context.bracketed_paste = bracketed_paste;
+        self
+    }
+
+    pub fn focus_events(mut self, focus_events: bool) -> Self {
+        self.context.focus_events = focus_events;
+        self
+    }
+
+    pub fn show_handler_errors(mut self, show_handler_errors: bool) -> Self {
+        self.context.show_handler_errors = show_handler_errors;
+        self
+    }
+
+    pub fn terminal<T: Terminal + 'static>(mut self, terminal: T) -> Self {
+        self.context.terminal = Box::new(terminal);
+        self
+    }
+
+    pub fn alternate_screen(mut self, alternate_screen: bool) -> Self {
+        self.context.alternate_screen = alternate_screen;
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.context.capabilities = capabilities;
+        self
+    }
+
+    pub fn undo_limit(mut self, undo_limit: Option<usize>) -> Self {
+        self.context.undo_limit = undo_limit;
+        self
+    }
+
+    pub fn completion_timeout(mut self, completion_timeout: Duration) -> Self {
+        self.context.completion_timeout = completion_timeout;
+        self
+    }
+
+    pub fn history_prefix_search(mut self, history_prefix_search: bool) -> Self {
+        self.context.history_prefix_search = history_prefix_search;
+        self
+    }
+
+    pub fn autosuggest_anchor(mut self, anchor: MatchAnchor) -> Self {
+        self.context.autosuggest_anchor = anchor;
+        self
+    }
+
+    pub fn search_anchor(mut self, anchor: MatchAnchor) -> Self {
+        self.context.search_anchor = anchor;
+        self
+    }
+
+    /// Changes any other setting of the context being built.
+    pub fn configure<F: FnOnce(&mut Context)>(mut self, f: F) -> Self {
+        f(&mut self.context);
+        self
+    }
+
+    /// Returns the configured context, after loading the history file if one was given.
+    pub fn build(mut self) -> io::Result<Context> {
+        if let Some(path) = self.history_file {
+            self.context.history.set_file_name_and_load_history(path)?;
         }
+        Ok(self.context)
+    }
+}
+
+/// Settings for a single `read_line`, so one `Context` can serve prompts that are set up
+/// differently, such as a shell's command line and a yes/no question in between.
+///
+/// ```no_run
+/// use liner::{Context, KeyBindings, ReadLineOptions};
+/// use std::io::stdout;
+/// let mut context = Context::new();
+/// let options = ReadLineOptions::new("name: ")
+///     .buffer("guest")
+///     .key_bindings(KeyBindings::Emacs);
+/// let line = context.read_line_with_options(stdout(), options, &mut |_| {});
+/// ```
+pub struct ReadLineOptions {
+    pub prompt: String,
+    /// The text the line starts out with.
+    pub buffer: Buffer,
+    /// Where the cursor starts out in `buffer`, or `None` for the end of it.
+    pub cursor: Option<usize>,
+    /// The key bindings for this read, or `None` for `Context.key_bindings`.
+    pub key_bindings: Option<KeyBindings>,
+    /// Colors the buffer before it is drawn.
+    pub color_closure: Option<ColorClosure>,
+    /// What each char of the buffer is shown as instead, for passwords. See `Editor::set_mask()`.
+    pub mask: Option<char>,
+    /// Shows nothing of the buffer at all. See `Editor::set_hidden()`.
+    pub hidden: bool,
+    /// Checks the line when Enter is pressed. See `Editor::accept_line()`.
+    pub validator: Option<Validator>,
+    /// Changes the line when Enter is pressed. See `Editor::set_accept_transform()`.
+    pub accept_transform: Option<AcceptTransform>,
+    /// Whether the changed line is drawn in place of the one that was typed.
+    pub echo_transform: bool,
+}
+
+impl ReadLineOptions {
+    pub fn new<P: Into<String>>(prompt: P) -> Self {
+        ReadLineOptions {
+            prompt: prompt.into(),
+            buffer: Buffer::new(),
+            cursor: None,
+            key_bindings: None,
+            color_closure: None,
+            mask: None,
+            hidden: false,
+            validator: None,
+            accept_transform: None,
+            echo_transform: true,
+        }
+    }
+
+    /// Options for reading a password: every char typed is shown as `*`, and the history is left
+    /// alone. The line that is read should not be pushed to the history either.
+    pub fn password<P: Into<String>>(prompt: P) -> Self {
+        ReadLineOptions::new(prompt).mask('*')
+    }
+
+    pub fn buffer<B: Into<Buffer>>(mut self, buffer: B) -> Self {
+        self.buffer = buffer.into();
+        self
+    }
+
+    pub fn cursor(mut self, cursor: usize) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
+
+    pub fn key_bindings(mut self, key_bindings: KeyBindings) -> Self {
+        self.key_bindings = Some(key_bindings);
+        self
+    }
+
+    pub fn color_closure(mut self, f: ColorClosure) -> Self {
+        self.color_closure = Some(f);
+        self
+    }
+
+    pub fn mask(mut self, mask: char) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    pub fn validator<F: Fn(&str) -> Result<(), String> + 'static>(mut self, f: F) -> Self {
+        self.validator = Some(Box::new(f));
+        self
+    }
+
+    pub fn accept_transform<F: Fn(&str) -> String + 'static>(mut self, f: F, echo: bool) -> Self {
+        self.accept_transform = Some(Box::new(f));
+        self.echo_transform = echo;
+        self
     }
 }
@@ -0,0 +1,112 @@
+//! Reads keys and the cursor position through crossterm instead of from stdin,
+//! with the `crossterm` feature. Keys are converted to liner's, which the keymaps understand.
+
+use std::io;
+use std::time::Duration;
+use crossterm::{cursor, terminal, ErrorKind};
+use crossterm::event::{self, KeyCode, KeyEvent, KeyModifiers};
+use {Input, Key, RawModeGuard};
+
+fn to_io(e: ErrorKind) -> io::Error {
+    match e {
+        ErrorKind::IoError(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// Converts a key read by crossterm to the key liner would have decoded from stdin.
+pub fn key(key: KeyEvent) -> Key {
+    match key.code {
+        KeyCode::Backspace => Key::Backspace,
+        KeyCode::Enter => Key::Char('\n'),
+        KeyCode::Tab => Key::Char('\t'),
+        KeyCode::BackTab => Key::BackTab,
+        KeyCode::Left => Key::Left,
+        KeyCode::Right => Key::Right,
+        KeyCode::Up => Key::Up,
+        KeyCode::Down => Key::Down,
+        KeyCode::Home => Key::Home,
+        KeyCode::End => Key::End,
+        KeyCode::PageUp => Key::PageUp,
+        KeyCode::PageDown => Key::PageDown,
+        KeyCode::Delete => Key::Delete,
+        KeyCode::Insert => Key::Insert,
+        KeyCode::F(n) => Key::F(n),
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => Key::Ctrl(c),
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::ALT) => Key::Alt(c),
+        KeyCode::Char(c) => Key::Char(c),
+        KeyCode::Null => Key::Null,
+        KeyCode::Esc => Key::Esc,
+    }
+}
+
+/// Converts an event read by crossterm, or returns `None` for the ones liner doesn't use.
+/// Mouse positions are made 1-indexed, like the ones decoded from stdin.
+pub fn input(e: event::Event) -> Option<Input> {
+    match e {
+        event::Event::Key(k) => Some(Input::Key(key(k))),
+        event::Event::Mouse(event::MouseEvent::Down(event::MouseButton::Left, x, y, _)) => {
+            Some(Input::Click(x + 1, y + 1))
+        }
+        _ => None,
+    }
+}
+
+/// The input read from the terminal, like `InputReader` on stdin.
+pub struct Events;
+
+impl Iterator for Events {
+    type Item = io::Result<Input>;
+
+    fn next(&mut self) -> Option<io::Result<Input>> {
+        loop {
+            match event::read() {
+                Ok(e) => if let Some(e) = input(e) {
+                    return Some(Ok(e));
+                },
+                Err(e) => return Some(Err(to_io(e))),
+            }
+        }
+    }
+}
+
+/// Waits up to `timeout` for an event and returns whether there is one.
+pub fn input_ready(timeout: Duration) -> bool {
+    event::poll(timeout).unwrap_or(false)
+}
+
+/// Turns off raw mode when dropped.
+struct RawMode;
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Puts the terminal in raw mode until the returned guard is dropped.
+pub fn raw_mode() -> io::Result<RawModeGuard> {
+    terminal::enable_raw_mode().map_err(to_io)?;
+    Ok(RawModeGuard::new(RawMode))
+}
+
+/// Where the terminal cursor is, as the 1-indexed column and row.
+pub fn cursor_pos() -> io::Result<(u16, u16)> {
+    let (x, y) = cursor::position().map_err(to_io)?;
+    Ok((x + 1, y + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_match_decoded_ones() {
+        let ctrl = KeyModifiers::CONTROL;
+        assert_eq!(key(KeyEvent::new(KeyCode::Char('a'), ctrl)), Key::Ctrl('a'));
+        assert_eq!(key(KeyEvent::new(KeyCode::Char('b'), KeyModifiers::ALT)), Key::Alt('b'));
+        assert_eq!(key(KeyEvent::new(KeyCode::Char('C'), KeyModifiers::SHIFT)), Key::Char('C'));
+        assert_eq!(key(KeyEvent::new(KeyCode::Enter, KeyModifiers::empty())), Key::Char('\n'));
+        assert_eq!(key(KeyEvent::new(KeyCode::Tab, KeyModifiers::empty())), Key::Char('\t'));
+    }
+}
@@ -0,0 +1,446 @@
+//! Decodes terminal input into keys, clicks, pastes and focus changes. Escape sequences that
+//! aren't known are passed on whole as `Input::Unknown` instead of being mistaken for keys, and
+//! bytes that aren't UTF-8 are typed as U+FFFD, so nothing read can end the read.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::str;
+use Key;
+use Input;
+
+const PASTE_END: &[u8] = b"\x1B[201~";
+
+const REPLACEMENT: char = '\u{FFFD}';
+
+/// What decoding the start of the input came to.
+enum Decoded {
+    /// Something read from the first so many bytes.
+    Input(Input, usize),
+    /// The first so many bytes mean nothing on their own, like mouse movements.
+    Skip(usize),
+    /// The first so many bytes start a paste.
+    PasteStart(usize),
+    /// The input ends in the middle of something, so more has to be read.
+    Incomplete,
+}
+
+/// Decodes input given in chunks, as it is read. Escape sequences, UTF-8 chars and pastes split
+/// across chunks are held back until the rest arrives.
+#[derive(Default)]
+pub struct Decoder {
+    // Bytes that haven't been decoded yet.
+    pending: Vec<u8>,
+    // What has been pasted so far, while inside a paste.
+    paste: Option<Vec<u8>>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    /// Decodes `bytes` after what was held back from earlier chunks. An Escape at the end of
+    /// a chunk is taken to be the key on its own, as a sequence is sent all at once.
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<Input> {
+        self.pending.extend_from_slice(bytes);
+        let mut inputs = Vec::new();
+        let mut start = 0;
+        while start < self.pending.len() {
+            if let Some(mut paste) = self.paste.take() {
+                let rest = &self.pending[start..];
+                match find(rest, PASTE_END) {
+                    Some(end) => {
+                        paste.extend_from_slice(&rest[..end]);
+                        start += end + PASTE_END.len();
+                        inputs.push(Input::Paste(String::from_utf8_lossy(&paste).into_owned()));
+                    }
+                    None => {
+                        // Keep what may be the start of the end marker.
+                        let keep = (1..PASTE_END.len()).rev()
+                            .find(|&n| rest.ends_with(&PASTE_END[..n]))
+                            .unwrap_or(0);
+                        paste.extend_from_slice(&rest[..rest.len() - keep]);
+                        start = self.pending.len() - keep;
+                        self.paste = Some(paste);
+                        break;
+                    }
+                }
+                continue;
+            }
+
+            match decode(&self.pending[start..]) {
+                Decoded::PasteStart(len) => {
+                    start += len;
+                    self.paste = Some(Vec::new());
+                }
+                Decoded::Input(input, len) => {
+                    start += len;
+                    inputs.push(input);
+                }
+                Decoded::Skip(len) => start += len,
+                Decoded::Incomplete if self.pending[start..] == [0x1B] => {
+                    start += 1;
+                    inputs.push(Input::Key(Key::Esc));
+                }
+                Decoded::Incomplete => break,
+            }
+        }
+        self.pending.drain(..start);
+        inputs
+    }
+
+    /// Passes on whatever was held back, once there is no more input: the start of an escape
+    /// sequence as it is, and the start of a char as U+FFFD.
+    pub fn finish(&mut self) -> Vec<Input> {
+        let mut inputs = Vec::new();
+        if let Some(paste) = self.paste.take() {
+            let mut paste = paste;
+            paste.extend(self.pending.drain(..));
+            inputs.push(Input::Paste(String::from_utf8_lossy(&paste).into_owned()));
+        } else if self.pending.first() == Some(&0x1B) {
+            inputs.push(Input::Unknown(self.pending.drain(..).collect()));
+        } else if !self.pending.is_empty() {
+            self.pending.clear();
+            inputs.push(Input::Key(Key::Char(REPLACEMENT)));
+        }
+        inputs
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Decodes the input at the start of `bytes`, which isn't empty.
+fn decode(bytes: &[u8]) -> Decoded {
+    let key = match bytes[0] {
+        0x1B => return decode_escape(bytes),
+        b'\n' | b'\r' => Key::Char('\n'),
+        b'\t' => Key::Char('\t'),
+        0x7F => Key::Backspace,
+        0 => Key::Null,
+        c @ 0x01...0x1A => Key::Ctrl((c - 0x01 + b'a') as char),
+        c @ 0x1C...0x1F => Key::Ctrl((c - 0x1C + b'4') as char),
+        _ => return match decode_char(bytes) {
+            Char::Valid(c, len) => Decoded::Input(Input::Key(Key::Char(c)), len),
+            Char::Incomplete => Decoded::Incomplete,
+            Char::Invalid(len) => Decoded::Input(Input::Key(Key::Char(REPLACEMENT)), len),
+        },
+    };
+    Decoded::Input(Input::Key(key), 1)
+}
+
+/// The length of the UTF-8 char starting with `first`.
+fn char_len(first: u8) -> usize {
+    match first {
+        0xC0...0xDF => 2,
+        0xE0...0xEF => 3,
+        0xF0...0xF7 => 4,
+        _ => 1,
+    }
+}
+
+/// What decoding a UTF-8 char came to.
+enum Char {
+    /// A char, with its length.
+    Valid(char, usize),
+    /// The start of a char that goes on past the end of the input.
+    Incomplete,
+    /// The first so many bytes aren't a char, and are replaced with one U+FFFD.
+    Invalid(usize),
+}
+
+/// Decodes the UTF-8 char at the start of `bytes`, which isn't empty.
+fn decode_char(bytes: &[u8]) -> Char {
+    let len = char_len(bytes[0]);
+    let available = cmp::min(len, bytes.len());
+    // A byte that can't go on the char ends it early, and isn't part of it.
+    if let Some(i) = bytes[1..available].iter().position(|&b| b & 0xC0 != 0x80) {
+        return Char::Invalid(i + 1);
+    }
+    if available < len {
+        return Char::Incomplete;
+    }
+    match str::from_utf8(&bytes[..len]).ok().and_then(|s| s.chars().next()) {
+        Some(c) => Char::Valid(c, len),
+        None => Char::Invalid(len),
+    }
+}
+
+fn decode_escape(bytes: &[u8]) -> Decoded {
+    match bytes.get(1) {
+        None => Decoded::Incomplete,
+        Some(&b'[') => decode_csi(bytes),
+        Some(&b'O') => match bytes.get(2) {
+            None => Decoded::Incomplete,
+            Some(&c) => {
+                let key = match c {
+                    b'P' => Key::F(1),
+                    b'Q' => Key::F(2),
+                    b'R' => Key::F(3),
+                    b'S' => Key::F(4),
+                    b'A' => Key::Up,
+                    b'B' => Key::Down,
+                    b'C' => Key::Right,
+                    b'D' => Key::Left,
+                    b'H' => Key::Home,
+                    b'F' => Key::End,
+                    _ => return Decoded::Input(Input::Unknown(bytes[..3].to_vec()), 3),
+                };
+                Decoded::Input(Input::Key(key), 3)
+            }
+        },
+        Some(&0x1B) => Decoded::Input(Input::Key(Key::Esc), 1),
+        Some(_) => match decode_char(&bytes[1..]) {
+            Char::Valid(c, len) => Decoded::Input(Input::Key(Key::Alt(c)), 1 + len),
+            Char::Incomplete => Decoded::Incomplete,
+            Char::Invalid(_) => Decoded::Input(Input::Key(Key::Esc), 1),
+        },
+    }
+}
+
+fn decode_csi(bytes: &[u8]) -> Decoded {
+    // The old X10 mouse encoding has three raw bytes after `ESC [ M`.
+    if bytes.get(2) == Some(&b'M') {
+        if bytes.len() < 6 {
+            return Decoded::Incomplete;
+        }
+        let (button, x, y) = (bytes[3].wrapping_sub(32), bytes[4].wrapping_sub(32), bytes[5].wrapping_sub(32));
+        return mouse(button as u16, x as u16, y as u16, true, 6);
+    }
+
+    // Parameters and intermediate bytes go up to the final byte.
+    let end = match bytes[2..].iter().position(|&b| b < 0x20 || b >= 0x40) {
+        Some(i) if bytes[i + 2] >= 0x40 && bytes[i + 2] <= 0x7E => i + 3,
+        // Something other than a sequence got in the way, so only the escape is known.
+        Some(_) => return Decoded::Input(Input::Key(Key::Esc), 1),
+        None => return Decoded::Incomplete,
+    };
+    let unknown = Decoded::Input(Input::Unknown(bytes[..end].to_vec()), end);
+    let params = match str::from_utf8(&bytes[2..end - 1]) {
+        Ok(params) => params,
+        Err(_) => return unknown,
+    };
+
+    let key = match (bytes[end - 1], params) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) => Key::Home,
+        (b'F', _) => Key::End,
+        (b'Z', "") => Key::BackTab,
+        (b'I', "") => return Decoded::Input(Input::Focus(true), end),
+        (b'O', "") => return Decoded::Input(Input::Focus(false), end),
+        (b'M', _) | (b'm', _) => {
+            let sgr = params.starts_with('<');
+            let nums: Vec<u16> = params.trim_start_matches('<').split(';').filter_map(|n| n.parse().ok()).collect();
+            if nums.len() != 3 {
+                return unknown;
+            }
+            // The urxvt encoding adds 32 to the button, like X10.
+            let button = if sgr { nums[0] } else { nums[0].wrapping_sub(32) };
+            return mouse(button, nums[1], nums[2], bytes[end - 1] == b'M', end);
+        }
+        (b'~', "200") => return Decoded::PasteStart(end),
+        (b'~', n) => match n.split(';').next().unwrap_or("") {
+            "1" | "7" => Key::Home,
+            "2" => Key::Insert,
+            "3" => Key::Delete,
+            "4" | "8" => Key::End,
+            "5" => Key::PageUp,
+            "6" => Key::PageDown,
+            "11" => Key::F(1),
+            "12" => Key::F(2),
+            "13" => Key::F(3),
+            "14" => Key::F(4),
+            "15" => Key::F(5),
+            "17" => Key::F(6),
+            "18" => Key::F(7),
+            "19" => Key::F(8),
+            "20" => Key::F(9),
+            "21" => Key::F(10),
+            "23" => Key::F(11),
+            "24" => Key::F(12),
+            _ => return unknown,
+        },
+        _ => return unknown,
+    };
+    Decoded::Input(Input::Key(key), end)
+}
+
+/// The column and row in a cursor position report, `ESC [ row ; column R`, which is decoded as
+/// `Input::Unknown`.
+#[cfg_attr(any(not(unix), feature = "crossterm"), allow(dead_code))]
+pub fn cursor_report(bytes: &[u8]) -> Option<(u16, u16)> {
+    if !bytes.starts_with(b"\x1B[") || !bytes.ends_with(b"R") {
+        return None;
+    }
+    let params = str::from_utf8(&bytes[2..bytes.len() - 1]).ok()?;
+    let mut nums = params.split(';').map(|n| n.parse::<u16>());
+    match (nums.next(), nums.next(), nums.next()) {
+        (Some(Ok(row)), Some(Ok(col)), None) => Some((col, row)),
+        _ => None,
+    }
+}
+
+/// A mouse report, of which only presses of the left button are used.
+fn mouse(button: u16, x: u16, y: u16, press: bool, len: usize) -> Decoded {
+    if press && button == 0 {
+        Decoded::Input(Input::Click(x, y), len)
+    } else {
+        Decoded::Skip(len)
+    }
+}
+
+/// Reads input from a `Read`, such as stdin, decoding it with a `Decoder`.
+pub struct InputReader<R> {
+    inner: R,
+    decoder: Decoder,
+    inputs: VecDeque<Input>,
+    done: bool,
+}
+
+impl<R: Read> InputReader<R> {
+    pub fn new(inner: R) -> Self {
+        InputReader {
+            inner: inner,
+            decoder: Decoder::new(),
+            inputs: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Whether some input was decoded but not taken yet.
+    #[cfg_attr(any(not(unix), feature = "crossterm"), allow(dead_code))]
+    pub fn has_pending(&self) -> bool {
+        !self.inputs.is_empty()
+    }
+}
+
+impl<R: Read> Iterator for InputReader<R> {
+    type Item = io::Result<Input>;
+
+    fn next(&mut self) -> Option<io::Result<Input>> {
+        // Reading more than stdin buffers at once leaves nothing in its buffer, where polling
+        // the file descriptor wouldn't see it.
+        let mut chunk = [0; 8 * 1024];
+        while self.inputs.is_empty() && !self.done {
+            let n = match self.inner.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            if n == 0 {
+                self.done = true;
+                self.inputs.extend(self.decoder.finish());
+            } else {
+                self.inputs.extend(self.decoder.decode(&chunk[..n]));
+            }
+        }
+        self.inputs.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(s: &str) -> Vec<Input> {
+        s.chars().map(|c| Input::Key(Key::Char(c))).collect()
+    }
+
+    #[test]
+    fn decodes_keys() {
+        let inputs = Decoder::new().decode(b"a\x01\x7F\r\x1Bb\x1B[A\x1BOP\x1B[3~\x1B[15~\x1B[Z\xC3\xA9");
+        assert_eq!(inputs, vec![
+            Input::Key(Key::Char('a')),
+            Input::Key(Key::Ctrl('a')),
+            Input::Key(Key::Backspace),
+            Input::Key(Key::Char('\n')),
+            Input::Key(Key::Alt('b')),
+            Input::Key(Key::Up),
+            Input::Key(Key::F(1)),
+            Input::Key(Key::Delete),
+            Input::Key(Key::F(5)),
+            Input::Key(Key::BackTab),
+            Input::Key(Key::Char('é')),
+        ]);
+    }
+
+    #[test]
+    fn escape_at_the_end_is_a_key() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(b"\x1B"), vec![Input::Key(Key::Esc)]);
+        assert_eq!(decoder.decode(b"\x1B\x1B[D"), vec![Input::Key(Key::Esc), Input::Key(Key::Left)]);
+    }
+
+    #[test]
+    fn takes_out_pastes() {
+        let inputs = Decoder::new().decode(b"ab\x1B[200~one\ntwo\x1B[201~c");
+        let mut expected = keys("ab");
+        expected.push(Input::Paste("one\ntwo".to_owned()));
+        expected.extend(keys("c"));
+        assert_eq!(inputs, expected);
+    }
+
+    #[test]
+    fn holds_back_what_is_split_across_reads() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(b"x\x1B[20"), keys("x"));
+        assert_eq!(decoder.decode(b"0~hi\x1B[2"), vec![]);
+        assert_eq!(decoder.decode(b"01~\xE2\x82"), vec![Input::Paste("hi".to_owned())]);
+        assert_eq!(decoder.decode(b"\xAC"), keys("€"));
+    }
+
+    #[test]
+    fn decodes_focus_and_clicks() {
+        let inputs = Decoder::new().decode(b"\x1B[I\x1B[O\x1B[<0;3;4M\x1B[<0;3;4m\x1B[<35;5;5M\x1B[32;7;8M\x1B[M !\"");
+        assert_eq!(inputs, vec![
+            Input::Focus(true),
+            Input::Focus(false),
+            Input::Click(3, 4),
+            Input::Click(7, 8),
+            Input::Click(1, 2),
+        ]);
+    }
+
+    #[test]
+    fn passes_on_unknown_sequences() {
+        let inputs = Decoder::new().decode(b"\x1B[?62;4cq\x1B[99~");
+        let mut expected = vec![Input::Unknown(b"\x1B[?62;4c".to_vec())];
+        expected.extend(keys("q"));
+        expected.push(Input::Unknown(b"\x1B[99~".to_vec()));
+        assert_eq!(inputs, expected);
+    }
+
+    #[test]
+    fn reads_cursor_reports() {
+        let inputs = Decoder::new().decode(b"\x1B[12;40R");
+        assert_eq!(inputs, vec![Input::Unknown(b"\x1B[12;40R".to_vec())]);
+        assert_eq!(cursor_report(b"\x1B[12;40R"), Some((40, 12)));
+        assert_eq!(cursor_report(b"\x1B[99~"), None);
+        assert_eq!(cursor_report(b"\x1B[1;2;3R"), None);
+    }
+
+    #[test]
+    fn replaces_what_is_not_utf8() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(b"a\xFFb\xE2\x82c\x80"), keys("a\u{FFFD}b\u{FFFD}c\u{FFFD}"));
+        assert_eq!(decoder.decode(b"\xE2"), vec![]);
+        assert_eq!(decoder.decode(b"x"), keys("\u{FFFD}x"));
+        assert_eq!(decoder.decode(b"\xF0\x9F"), vec![]);
+        assert_eq!(decoder.finish(), keys("\u{FFFD}"));
+        assert_eq!(decoder.decode(b"\x1B[200~\xFFok\x1B[201~"), vec![Input::Paste("\u{FFFD}ok".to_owned())]);
+    }
+
+    #[test]
+    fn reads_until_the_end() {
+        let input: &[u8] = b"hi\x1B[200~unfinished";
+        let inputs: Vec<Input> = InputReader::new(input).map(|i| i.unwrap()).collect();
+        let mut expected = keys("hi");
+        expected.push(Input::Paste("unfinished".to_owned()));
+        assert_eq!(inputs, expected);
+    }
+}
@@ -1,13 +1,23 @@
 use std::cell::RefCell;
 use std::cmp;
+use std::collections::HashSet;
 use std::io::{self, Write};
-use termion::{self, clear, color, cursor};
-
-use context::ColorClosure;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use ansi;
+use complete::{shell_word_start, Completion, PendingCompletions};
+use context::{AcceptTransform, BellStyle, ColorClosure, CursorShape, ReadLineOptions, Validator};
+use register::{Register, RegisterKind};
+use terminal::RawMode;
+use capabilities::Colors;
 use Context;
 use Buffer;
+use UndoPolicy;
 use event::*;
 use util;
+#[cfg(feature = "clipboard")]
+use clipboard::{ClipboardContext, ClipboardProvider};
 
 /// Buffer for prompt writes, meant to be shared between prompt creations.
 struct LocalBuffer(pub RefCell<Vec<u8>>);
@@ -40,6 +50,128 @@ thread_local! {
     static BUFFER: LocalBuffer = LocalBuffer::new();
 }
 
+// How long the visible bell keeps the screen reversed.
+const FLASH_DURATION: Duration = Duration::from_millis(100);
+
+/// The prompt and buffer laid out by `Editor::render()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rendered {
+    /// The rows to draw, from the top, each fitting in the width given. They keep the escape
+    /// sequences for colors.
+    pub rows: Vec<String>,
+    /// The row and column in `rows` to show the cursor at.
+    pub cursor: (usize, usize),
+}
+
+/// A line of the prompt and buffer as drawn on the terminal.
+struct FrameLine {
+    text: String,
+    width: usize,
+    // The line of the buffer shown on this line, and the first column of it that is shown.
+    buf_line: Option<(usize, usize)>,
+}
+
+/// What `_display` last drew, so the next redraw only has to touch what changed.
+struct Frame {
+    lines: Vec<FrameLine>,
+    terminal_width: usize,
+    prompt_width: usize,
+    // Where the terminal cursor was left, relative to the start of the first line.
+    cursor: (usize, usize),
+}
+
+impl Frame {
+    /// The number of terminal rows taken up by the line at `index`. The last line gets an extra
+    /// row when it fills its last row, since the terminal cursor is moved onto the next one.
+    fn line_rows(&self, index: usize) -> usize {
+        let width = self.lines[index].width;
+        if index + 1 == self.lines.len() {
+            width / self.terminal_width + 1
+        } else {
+            cmp::max(1, (width + self.terminal_width - 1) / self.terminal_width)
+        }
+    }
+
+    /// The terminal row the line at `index` starts on.
+    fn line_row(&self, index: usize) -> usize {
+        (0..index).map(|i| self.line_rows(i)).sum()
+    }
+
+    /// Where the terminal cursor is left after writing out the whole frame.
+    fn end(&self) -> (usize, usize) {
+        let last = self.lines.len() - 1;
+        (self.line_row(last) + self.line_rows(last) - 1, self.lines[last].width % self.terminal_width)
+    }
+
+    /// Writes the lines from `index` onwards, starting `skip` bytes into the first one.
+    fn write_from(&self, output_buf: &LocalBuffer, index: usize, skip: usize) {
+        let text = &self.lines[index].text;
+        output_buf.append(util::sgr_codes(&text[..skip]).as_bytes());
+        output_buf.append(text[skip..].as_bytes());
+        for i in index + 1..self.lines.len() {
+            let width = self.lines[i - 1].width;
+            // A line that exactly fills its last row leaves nothing behind to clear, and clearing
+            // there would erase its last character.
+            if width == 0 || width % self.terminal_width != 0 {
+                output_buf.append(ansi::CLEAR_UNTIL_NEWLINE.as_bytes());
+            }
+            output_buf.append(b"\r\n");
+            output_buf.append(self.lines[i].text.as_bytes());
+        }
+        // Move onto the extra row of a full last line, like `line_rows` expects.
+        let width = self.lines[self.lines.len() - 1].width;
+        if width > 0 && width % self.terminal_width == 0 {
+            output_buf.append(b"\r\n");
+        }
+    }
+}
+
+/// Where the prompt and buffer are drawn on the terminal. Rows and columns are 0-indexed and rows
+/// are counted from the first row drawn on, which is where the prompt starts unless it has been
+/// scrolled out of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// The terminal row (1-indexed) the first row is drawn on, if it is known. See
+    /// `Editor::locate()`.
+    pub top: Option<usize>,
+    /// The number of terminal rows drawn on.
+    pub rows: usize,
+    /// The row and column of the cursor.
+    pub cursor: (usize, usize),
+    /// The row and column the last line of the prompt starts at, or `None` if it is scrolled out
+    /// of view.
+    pub prompt: Option<(usize, usize)>,
+}
+
+#[cfg(feature = "clipboard")]
+fn system_clipboard() -> io::Result<ClipboardContext> {
+    ClipboardProvider::new().map_err(clipboard_error)
+}
+
+#[cfg(feature = "clipboard")]
+fn clipboard_error(e: Box<::std::error::Error>) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Moves the terminal cursor from `from` to `to`, both given as (row, column).
+fn move_term_cursor(output_buf: &LocalBuffer, from: (usize, usize), to: (usize, usize)) {
+    if to.0 < from.0 {
+        output_buf.append(ansi::up((from.0 - to.0) as u16).as_bytes());
+    } else if to.0 > from.0 {
+        output_buf.append(ansi::down((to.0 - from.0) as u16).as_bytes());
+    }
+
+    if to.1 == from.1 {
+        return;
+    } else if to.1 == 0 {
+        output_buf.push(b'\r');
+    } else if to.1 < from.1 {
+        output_buf.append(ansi::left((from.1 - to.1) as u16).as_bytes());
+    } else {
+        output_buf.append(ansi::right((to.1 - from.1) as u16).as_bytes());
+    }
+}
+
 /// Represents the position of the cursor relative to words in the buffer.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CursorPosition {
@@ -87,6 +219,18 @@ impl CursorPosition {
     }
 }
 
+// What is shown under the buffer while completions are still coming, one frame at a time.
+const COMPLETION_SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+// Completions that are worked out in the background, and what they are for.
+struct PendingCompletion {
+    pending: PendingCompletions,
+    word_range: (usize, usize),
+    keep_order: bool,
+    started: Instant,
+    frame: usize,
+}
+
 /// The core line editor. Displays and provides editing for history and the new buffer.
 pub struct Editor<'a, W: Write> {
     prompt: String,
@@ -108,17 +252,86 @@ pub struct Editor<'a, W: Write> {
     // None if we're on the new buffer, else the index of history
     cur_history_loc: Option<usize>,
 
-    // The line of the cursor relative to the prompt. 1-indexed.
-    // So if the cursor is on the same line as the prompt, `term_cursor_line == 1`.
-    // If the cursor is on the line below the prompt, `term_cursor_line == 2`.
-    term_cursor_line: usize,
+    // The new buffer and the cursor in it as they were when moving to a history entry, to be
+    // put back exactly when moving back.
+    new_buf_snapshot: Option<(Buffer, usize)>,
+
+    // What was last drawn to the terminal, or None if the next redraw has to start from scratch.
+    frame: Option<Frame>,
+
+    // The first line drawn when there are more lines than fit on the terminal.
+    window_top: usize,
+
+    // How many columns the cursor's line is scrolled to the left, when lines don't wrap.
+    hscroll: usize,
+
+    // The `window_top` and `hscroll` of `render()`, kept apart so rendering doesn't scroll what is
+    // drawn on the terminal, or the other way around.
+    render_scroll: (usize, usize),
+
+    // The shape the terminal cursor was last changed to, if any.
+    cursor_shape: Option<CursorShape>,
+
+    // When the visible bell reversed the screen, if it hasn't been put back yet.
+    flash_started: Option<Instant>,
+
+    // The terminal row (1-indexed) the drawn lines start on, once it has been looked up.
+    frame_top: Option<usize>,
+
+    // Whether mouse reporting was turned on in the terminal.
+    mouse: bool,
+
+    // Whether bracketed paste was turned on in the terminal.
+    bracketed_paste: bool,
+
+    // Whether focus reporting was turned on in the terminal.
+    focus_events: bool,
+
+    // Whether the editor is drawn on the terminal's alternate screen.
+    alternate_screen: bool,
 
     // The next completion to suggest, or none
-    show_completions_hint: Option<(Vec<String>, Option<usize>)>,
+    show_completions_hint: Option<(Vec<Completion>, Option<usize>)>,
+    // The completion inserted while cycling through the list: the span it took up, the text it
+    // replaced and where the cursor was before.
+    completion_inserted: Option<(usize, usize, String, usize)>,
+    // Completions that haven't arrived yet, or none
+    pending_completion: Option<PendingCompletion>,
+
+    // A message shown under the buffer until the next keypress, or none
+    message: Option<String>,
+
+    // What each char of the buffer is shown as instead, for passwords.
+    mask: Option<char>,
+
+    // What the handler responded to the event being sent with.
+    event_action: Option<EventAction>,
+
+    // What went wrong in the handler for the event being sent.
+    event_error: Option<io::Error>,
+
+    // Checks the line before it is accepted with Enter.
+    validator: Option<Validator>,
+
+    // Changes the line when it is accepted with Enter, and whether the change is drawn.
+    accept_transform: Option<AcceptTransform>,
+    echo_transform: bool,
+
+    // Whether nothing of the buffer is shown.
+    hidden: bool,
 
     // Show autosuggestions based on history
     show_autosuggestions: bool,
 
+    // How inserts are grouped into undo steps.
+    undo_policy: UndoPolicy,
+
+    // The number of insert sessions with an open undo group on the current buffer.
+    insert_session_depth: usize,
+
+    // Overrides `Context.word_divider_fn` for this editor, if set.
+    word_divider_fn: Option<Box<Fn(&Buffer) -> Vec<(usize, usize)>>>,
+
     // if set, the cursor will not be allow to move one past the end of the line, this is necessary
     // for Vi's normal mode.
     pub no_eol: bool,
@@ -136,18 +349,16 @@ pub struct Editor<'a, W: Write> {
 }
 
 macro_rules! cur_buf_mut {
-    ($s:expr) => {
-        match $s.cur_history_loc {
-            Some(i) => {
-                $s.buffer_changed = true;
-                &mut $s.context.history[i]
-            },
-            _ => {
-                $s.buffer_changed = true;
-                &mut $s.new_buf
-            },
-        }
-    }
+    ($s:expr) => {{
+        let undo_limit = $s.context.undo_limit;
+        $s.buffer_changed = true;
+        let buf = match $s.cur_history_loc {
+            Some(i) => &mut $s.context.history[i],
+            _ => &mut $s.new_buf,
+        };
+        buf.set_undo_limit(undo_limit);
+        buf
+    }}
 }
 
 macro_rules! cur_buf {
@@ -176,17 +387,76 @@ impl<'a, W: Write> Editor<'a, W> {
         context: &'a mut Context,
         buffer: B,
     ) -> io::Result<Self> {
+        let mut options = ReadLineOptions::new(prompt).buffer(buffer);
+        options.color_closure = f;
+        Editor::with_options(out, context, options)
+    }
+
+    /// Same as `new_with_init_buffer()`, but starts with the cursor before char `cursor` of the
+    /// buffer instead of at the end of the line. A `cursor` past the end of the buffer is clamped
+    /// to it, and one inside a grapheme cluster is moved back to its start.
+    pub fn new_with_init_buffer_and_cursor<P: Into<String>, B: Into<Buffer>>(
+        out: W,
+        prompt: P,
+        f: Option<ColorClosure>,
+        context: &'a mut Context,
+        buffer: B,
+        cursor: usize,
+    ) -> io::Result<Self> {
+        let mut options = ReadLineOptions::new(prompt).buffer(buffer).cursor(cursor);
+        options.color_closure = f;
+        Editor::with_options(out, context, options)
+    }
+
+    /// Creates an editor with the prompt, buffer, cursor, color closure and masking in `options`.
+    pub fn with_options(out: W, context: &'a mut Context, options: ReadLineOptions) -> io::Result<Self> {
+        let ReadLineOptions {
+            prompt,
+            buffer,
+            cursor,
+            color_closure,
+            mask,
+            hidden,
+            validator,
+            accept_transform,
+            echo_transform,
+            ..
+        } = options;
         let mut ed = Editor {
-            prompt: prompt.into(),
+            prompt: prompt,
             cursor: 0,
             out: out,
-            closure: f,
-            new_buf: buffer.into(),
+            closure: color_closure,
+            new_buf: buffer,
             cur_history_loc: None,
+            new_buf_snapshot: None,
             context: context,
             show_completions_hint: None,
+            completion_inserted: None,
+            pending_completion: None,
+            message: None,
+            mask: mask,
+            event_action: None,
+            event_error: None,
+            validator: validator,
+            accept_transform: accept_transform,
+            echo_transform: echo_transform,
+            hidden: hidden,
             show_autosuggestions: true,
-            term_cursor_line: 1,
+            undo_policy: UndoPolicy::PerKeystroke,
+            insert_session_depth: 0,
+            word_divider_fn: None,
+            frame: None,
+            window_top: 0,
+            hscroll: 0,
+            render_scroll: (0, 0),
+            cursor_shape: None,
+            flash_started: None,
+            frame_top: None,
+            mouse: false,
+            bracketed_paste: false,
+            focus_events: false,
+            alternate_screen: false,
             no_eol: false,
             no_newline: false,
             reverse_search: false,
@@ -197,8 +467,10 @@ impl<'a, W: Write> Editor<'a, W> {
             autosuggestion: None,
         };
 
-        if !ed.new_buf.is_empty() {
-            ed.move_cursor_to_end_of_line()?;
+        match cursor {
+            Some(cursor) => ed.cursor = ed.new_buf.grapheme_boundary(cmp::min(cursor, ed.new_buf.num_chars())),
+            None if !ed.new_buf.is_empty() => ed.move_cursor_to_end_of_line()?,
+            None => {}
         }
         ed.display()?;
         Ok(ed)
@@ -208,6 +480,52 @@ impl<'a, W: Write> Editor<'a, W> {
         self.reverse_search || self.forward_search
     }
 
+    /// Sends an event of `kind` to `handler`, returning what the handler responded with, if
+    /// anything.
+    ///
+    /// If the handler failed, its error is returned, or shown as the message with
+    /// `Context.show_handler_errors`.
+    pub fn send_event(&mut self, handler: &mut EventHandler<W>, kind: EventKind) -> io::Result<Option<EventAction>> {
+        self.event_action = None;
+        self.event_error = None;
+        handler(Event::new(self, kind));
+        match self.event_error.take() {
+            Some(error) if self.context.show_handler_errors => {
+                self.message = Some(error.to_string());
+                Ok(None)
+            }
+            Some(error) => Err(error),
+            None => Ok(self.event_action.take()),
+        }
+    }
+
+    /// Sends an `Idle` event to `handler` and redraws the editor with whatever it changed.
+    /// Called after `Context.tick_interval` passes without a key.
+    pub fn tick(&mut self, handler: &mut EventHandler<W>) -> io::Result<()> {
+        self.send_event(handler, EventKind::Idle)?;
+        if self.is_completion_pending() {
+            self.poll_completion()?;
+        } else {
+            self.display()?;
+        }
+        self.flush()
+    }
+
+    /// Responds to the event being sent. See `Event::respond()`.
+    pub fn respond_to_event(&mut self, action: EventAction) {
+        self.event_action = Some(action);
+    }
+
+    /// Fails the event being sent. See `Event::fail()`.
+    pub fn fail_event(&mut self, error: io::Error) {
+        self.event_error = Some(error);
+    }
+
+    /// Whether an incremental history search is going on.
+    pub fn is_searching(&self) -> bool {
+        self.is_search()
+    }
+
     fn clear_search(&mut self) {
         self.reverse_search = false;
         self.forward_search = false;
@@ -221,16 +539,141 @@ impl<'a, W: Write> Editor<'a, W> {
     }
 
     pub fn get_words_and_cursor_position(&self) -> (Vec<(usize, usize)>, CursorPosition) {
-        let word_fn = &self.context.word_divider_fn;
-        let words = word_fn(cur_buf!(self));
+        let words = self.word_fn()(cur_buf!(self));
         let pos = CursorPosition::get(self.cursor, &words);
         (words, pos)
     }
 
+    /// Sets the word divider used by this editor for word motions and completion, overriding
+    /// `Context.word_divider_fn`. Passing `None` goes back to the context's divider.
+    pub fn set_word_divider_fn(&mut self, word_divider_fn: Option<Box<Fn(&Buffer) -> Vec<(usize, usize)>>>) {
+        self.word_divider_fn = word_divider_fn;
+    }
+
+    /// Sets how inserts are grouped into undo steps.
+    pub fn set_undo_policy(&mut self, undo_policy: UndoPolicy) {
+        self.undo_policy = undo_policy;
+    }
+
+    pub fn undo_policy(&self) -> UndoPolicy {
+        self.undo_policy
+    }
+
+    /// Marks the start of an insert session, such as vi's insert mode. With
+    /// `UndoPolicy::PerInsertSession`, everything up to `end_insert_session()` is one undo step.
+    /// The session follows the cursor when moving through history.
+    pub fn begin_insert_session(&mut self) {
+        if self.undo_policy == UndoPolicy::PerInsertSession {
+            cur_buf_mut!(self).start_undo_group();
+            self.insert_session_depth += 1;
+        }
+    }
+
+    /// Marks the end of an insert session started with `begin_insert_session()`.
+    pub fn end_insert_session(&mut self) {
+        if self.insert_session_depth > 0 {
+            cur_buf_mut!(self).end_undo_group();
+            self.insert_session_depth -= 1;
+        }
+    }
+
+    /// Switches to the new buffer (`None`) or the given history entry. Open insert sessions are
+    /// closed on the buffer being left and reopened on the one being entered, so every buffer keeps
+    /// a balanced undo stack of its own and undo keeps working after moving back to it.
+    fn set_history_loc(&mut self, loc: Option<usize>) {
+        if loc == self.cur_history_loc {
+            return;
+        }
+
+        self.close_insert_session_groups();
+        match (self.cur_history_loc, loc) {
+            (None, Some(_)) => self.new_buf_snapshot = Some((self.new_buf.clone(), self.cursor)),
+            (Some(_), None) => {
+                if let Some((buf, cursor)) = self.new_buf_snapshot.take() {
+                    self.new_buf = buf;
+                    self.cursor = cursor;
+                }
+            }
+            _ => {}
+        }
+        self.cur_history_loc = loc;
+        self.reopen_insert_session_groups();
+    }
+
+    // Puts the cursor at the end of a history entry that was moved to, or leaves it where it was
+    // in the new buffer if that was moved back to.
+    fn move_cursor_after_history_move(&mut self) -> io::Result<()> {
+        if self.cur_history_loc.is_some() {
+            self.move_cursor_to_end_of_line()
+        } else {
+            self.no_newline = true;
+            self.display()
+        }
+    }
+
+    fn close_insert_session_groups(&mut self) {
+        let buf = match self.cur_history_loc {
+            Some(i) => &mut self.context.history[i],
+            None => &mut self.new_buf,
+        };
+        for _ in 0..self.insert_session_depth {
+            buf.end_undo_group();
+        }
+    }
+
+    fn reopen_insert_session_groups(&mut self) {
+        let buf = match self.cur_history_loc {
+            Some(i) => &mut self.context.history[i],
+            None => &mut self.new_buf,
+        };
+        for _ in 0..self.insert_session_depth {
+            buf.start_undo_group();
+        }
+    }
+
+    /// Shows every char of the buffer as `mask` instead, such as `*` for a password, or shows the
+    /// buffer as it is again with `None`. While the buffer is masked, the history can't be moved
+    /// through or searched, there are no autosuggestions and killed text isn't kept.
+    pub fn set_mask(&mut self, mask: Option<char>) {
+        self.mask = mask;
+        if mask.is_some() {
+            self.clear_search();
+            self.set_history_loc(None);
+        }
+    }
+
+    pub fn mask(&self) -> Option<char> {
+        self.mask
+    }
+
+    /// Shows nothing of the buffer at all, leaving the cursor right after the prompt, like
+    /// `read -s` does. It can still be edited, and has the same restrictions as a masked buffer.
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+        if hidden {
+            self.clear_search();
+            self.set_history_loc(None);
+        }
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    // Whether the buffer is masked or hidden, so it mustn't end up anywhere else.
+    fn is_secret(&self) -> bool {
+        self.mask.is_some() || self.hidden
+    }
+
     pub fn set_prompt(&mut self, prompt: String) {
         self.prompt = prompt;
     }
 
+    /// Where the editor draws to.
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
     pub fn context(&mut self) -> &mut Context {
         self.context
     }
@@ -243,6 +686,30 @@ impl<'a, W: Write> Editor<'a, W> {
 
     // XXX: Returning a bool to indicate doneness is a bit awkward, maybe change it
     pub fn handle_newline(&mut self) -> io::Result<bool> {
+        self.newline(false)
+    }
+
+    /// Same as `handle_newline()`, but first transforms the line with the accept transform and
+    /// checks it with the validator, if there are any. A line the validator rejects isn't
+    /// accepted, and what it says is wrong is shown under the buffer until the next keypress.
+    pub fn accept_line(&mut self) -> io::Result<bool> {
+        self.newline(true)
+    }
+
+    /// Sets what changes a line when `accept_line()` accepts it, such as trimming it or expanding
+    /// abbreviations, or none. The changed line is the one that is returned. If `echo` is set, it
+    /// is also what is left on the terminal, instead of the line as it was typed.
+    pub fn set_accept_transform(&mut self, transform: Option<AcceptTransform>, echo: bool) {
+        self.accept_transform = transform;
+        self.echo_transform = echo;
+    }
+
+    /// Sets what checks a line before `accept_line()` accepts it, or none.
+    pub fn set_validator(&mut self, validator: Option<Validator>) {
+        self.validator = validator;
+    }
+
+    fn newline(&mut self, accept: bool) -> io::Result<bool> {
         if self.is_search() {
             self.accept_autosuggestion()?;
         }
@@ -258,15 +725,69 @@ impl<'a, W: Write> Editor<'a, W> {
             self.insert_after_cursor('\n')?;
             Ok(false)
         } else {
+            let mut transformed = None;
+            if accept {
+                let line = cur_buf!(self).to_string();
+                let new_line = match self.accept_transform {
+                    Some(ref transform) => transform(&line),
+                    None => line.clone(),
+                };
+                let error = match self.validator {
+                    Some(ref validator) => validator(&new_line).err(),
+                    None => None,
+                };
+                if let Some(error) = error {
+                    self.show_message(error)?;
+                    return Ok(false);
+                }
+                if new_line != line {
+                    transformed = Some(new_line.chars().collect::<Vec<char>>());
+                }
+            }
+
+            if let (Some(chars), true) = (transformed.as_ref(), self.echo_transform) {
+                let end = cur_buf!(self).num_chars();
+                self.replace_chars(0, end, chars);
+            }
             self.cursor = cur_buf!(self).num_chars();
             self.no_newline = true;
             self._display(false)?;
             self.out.write_all(b"\r\n")?;
+            // Without echoing it, the changed line is only what is returned.
+            if let (Some(chars), false) = (transformed.as_ref(), self.echo_transform) {
+                let end = cur_buf!(self).num_chars();
+                self.replace_chars(0, end, chars);
+                self.cursor = chars.len();
+            }
+            self.frame = None;
             self.show_completions_hint = None;
             Ok(true)
         }
     }
 
+    /// Abandons the current line and starts over with an empty buffer on a fresh prompt below it,
+    /// the way Ctrl-C behaves in most shells.
+    pub fn abandon_line(&mut self) -> io::Result<()> {
+        self.clear_search();
+        self.show_completions_hint = None;
+        self.cursor = cur_buf!(self).num_chars();
+        self.no_newline = true;
+        self._display(false)?;
+        self.out.write_all(b"\r\n")?;
+
+        // The new prompt starts on the row after the abandoned line.
+        if let (Some(top), Some(frame)) = (self.frame_top, self.frame.as_ref()) {
+            self.frame_top = Some(top + frame.end().0 + 1);
+        }
+        self.frame = None;
+        self.set_history_loc(None);
+        self.close_insert_session_groups();
+        self.new_buf = Buffer::new();
+        self.reopen_insert_session_groups();
+        self.cursor = 0;
+        self.display()
+    }
+
     fn search_history_loc(&self) -> Option<usize> {
         if self.history_subset_index.len() > 0 {
             self.history_subset_loc.map(|i| self.history_subset_index[i])
@@ -278,14 +799,24 @@ impl<'a, W: Write> Editor<'a, W> {
     /// Refresh incremental search, either when started or when the buffer changes.
     fn refresh_search(&mut self, forward: bool) {
         let search_history_loc = self.search_history_loc();
-        self.history_subset_index = self.context.history.search_index(&self.new_buf);
+        let anchor = self.context.search_anchor;
+        self.history_subset_index = self.context.history.search_index_anchored(&self.new_buf, anchor);
+        self.context.history.rank(&mut self.history_subset_index);
         if self.history_subset_index.len() > 0 {
             self.history_subset_loc = if forward {
                 Some(0)
             } else {
                 Some(self.history_subset_index.len() - 1)
             };
-            if let Some(target_loc) = search_history_loc {
+            if self.context.history.is_ranked() {
+                // Ranked matches aren't in order of age, so only the same entry can be kept.
+                let target = search_history_loc.and_then(|loc| {
+                    self.history_subset_index.iter().position(|&i| i == loc)
+                });
+                if target.is_some() {
+                    self.history_subset_loc = target;
+                }
+            } else if let Some(target_loc) = search_history_loc {
                 for (i, history_loc) in self.history_subset_index.iter().enumerate() {
                     if target_loc <= *history_loc {
                         if forward || target_loc == *history_loc || i == 0 {
@@ -304,7 +835,7 @@ impl<'a, W: Write> Editor<'a, W> {
 
         self.reverse_search = !forward;
         self.forward_search = forward;
-        self.cur_history_loc = None;
+        self.set_history_loc(None);
         self.no_newline = true;
         self.buffer_changed = false;
     }
@@ -314,6 +845,9 @@ impl<'a, W: Write> Editor<'a, W> {
     /// forward (top to bottom) other wise reverse (bottom to top).  It is valid to continue a
     /// search with forward changed (i.e. reverse search direction for one result).
     pub fn search(&mut self, forward: bool) -> io::Result<()> {
+        if self.is_secret() {
+            return self.bell();
+        }
         if !self.is_search() {
             self.refresh_search(forward);
         } else if self.history_subset_index.len() > 0 {
@@ -372,46 +906,56 @@ impl<'a, W: Write> Editor<'a, W> {
         Ok(did)
     }
 
-    fn print_completion_list(completions: &[String], highlighted: Option<usize>) -> io::Result<usize> {
+    fn completion_list_lines<S: AsRef<str>>(completions: &[S], highlighted: Option<usize>, highlight: &str, w: usize) -> Vec<String> {
         use std::cmp::max;
 
-        let (w, _) = termion::terminal_size()?;
-
-        // XXX wide character support
-        let max_word_size = completions.iter().fold(1, |m, x| max(m, x.chars().count()));
-        let cols = max(1, w as usize / (max_word_size));
-        let col_width = 2 + w as usize / cols;
-        let cols = max(1, w as usize / col_width);
+        // Columns are measured in terminal cells, so wide characters line up too.
+        let max_word_size = completions.iter().fold(1, |m, x| max(m, util::width(x.as_ref())));
+        let cols = max(1, w / (max_word_size));
+        let col_width = 2 + w / cols;
+        let cols = max(1, w / col_width);
 
-        let mut lines = 0;
+        let mut lines = vec![];
+        for (index, com) in completions.iter().enumerate() {
+            if index % cols == 0 {
+                lines.push(String::new());
+            }
+            let line = lines.last_mut().unwrap();
 
-        BUFFER.with(|output_buf| {
-            let mut i = 0;
-            for (index, com) in completions.iter().enumerate() {
-                if i == cols {
-                    output_buf.append(b"\r\n");
-                    lines += 1;
-                    i = 0;
-                } else if i > cols {
-                    unreachable!()
-                }
+            if Some(index) == highlighted {
+                line.push_str(highlight);
+            }
+            line.push_str(com.as_ref());
+            for _ in util::width(com.as_ref())..col_width {
+                line.push(' ');
+            }
+            if Some(index) == highlighted {
+                line.push_str(ansi::RESET);
+            }
+        }
 
-                if Some(index) == highlighted {
-                    output_buf.append(color::Black.fg_str().as_bytes());
-                    output_buf.append(color::White.bg_str().as_bytes());
-                }
-                write!(output_buf.0.borrow_mut(), "{:<1$}", com, col_width)?;
-                if Some(index) == highlighted {
-                    output_buf.append(color::Reset.bg_str().as_bytes());
-                    output_buf.append(color::Reset.fg_str().as_bytes());
-                }
+        lines
+    }
 
-                i += 1;
-            }
+    /// Shows `message` on the lines under the buffer until the next keypress, replacing any
+    /// message already shown.
+    pub fn show_message<S: Into<String>>(&mut self, message: S) -> io::Result<()> {
+        self.message = Some(message.into());
+        self.display()
+    }
 
-            Ok(lines)
-        })
+    /// The message shown under the buffer, if there is one.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|m| m.as_str())
+    }
 
+    /// Removes the message shown under the buffer, if there is one.
+    pub fn clear_message(&mut self) -> io::Result<()> {
+        if self.message.take().is_some() {
+            self.display()
+        } else {
+            Ok(())
+        }
     }
 
     pub fn skip_completions_hint(&mut self) {
@@ -419,7 +963,16 @@ impl<'a, W: Write> Editor<'a, W> {
     }
 
     pub fn complete(&mut self, handler: &mut EventHandler<W>, completion_type: CompleteType) -> io::Result<()> {
-        handler(Event::new(self, EventKind::BeforeComplete));
+        // Secret text isn't handed to completers, nor listed under the prompt.
+        if self.is_secret() {
+            return self.bell();
+        }
+
+        match self.send_event(handler, EventKind::BeforeComplete)? {
+            Some(EventAction::Cancel) => return Ok(()),
+            Some(EventAction::ReplaceBuffer(text)) => return self.set_buffer(&text),
+            _ => {}
+        }
 
         if let Some((completions, i)) = self.show_completions_hint.take() {
 
@@ -428,13 +981,13 @@ impl<'a, W: Write> Editor<'a, W> {
                 use std::cmp::max;
                 use std::cmp::min;
 
-                let (w, _) = termion::terminal_size()?;
+                let w = self.terminal_size()?.0;
 
-                // XXX wide character support
-                let max_word_size = completions.iter().fold(1, |m, x| max(m, x.chars().count()));
-                let cols_items = max(1, w as usize / (max_word_size));
-                let col_width = 2 + w as usize / cols_items;
-                let cols = max(1, w as usize / col_width);
+                // Same layout as `completion_list_lines`.
+                let max_word_size = completions.iter().fold(1, |m, x| max(m, util::width(&x.text)));
+                let cols_items = max(1, w / (max_word_size));
+                let col_width = 2 + w / cols_items;
+                let cols = max(1, w / col_width);
                 let line_count = (completions.len() as u64 / cols as u64) as usize;
 
                 match i {
@@ -476,8 +1029,15 @@ impl<'a, W: Write> Editor<'a, W> {
 
             //let i = i.map_or(0, |i| (i+2) % completions.len());
 
-            self.delete_word_before_cursor(false)?;
-            self.insert_str_after_cursor(&completions[i])?;
+            // Put back what the previous completion replaced, since spans are from before it.
+            if let Some((start, end, replaced, cursor)) = self.completion_inserted.take() {
+                let chars: Vec<char> = replaced.chars().collect();
+                self.replace_chars(start, end, &chars);
+                self.cursor = cursor;
+            }
+            let cursor = self.cursor;
+            let (start, end, replaced) = self.insert_completion(&completions[i], false)?;
+            self.completion_inserted = Some((start, end, replaced, cursor));
 
             self.show_completions_hint = Some((completions, Some(i)));
         }
@@ -487,47 +1047,120 @@ impl<'a, W: Write> Editor<'a, W> {
             return Ok(());
         }
 
-        let (word, completions) = {
-            let word_range = self.get_word_before_cursor(false);
-            let buf = cur_buf_mut!(self);
-
-            let word = match word_range {
-                Some((start, end)) => buf.range(start, end),
-                None => "".into(),
-            };
+        self.completion_inserted = None;
+        self.pending_completion = None;
+        let cursor = self.cursor;
+        let word_range = self.completion_word();
+        let (completions, keep_order) = {
+            let buf = cur_buf!(self);
 
             if let Some(ref completer) = self.context.completer {
-                let mut completions = completer.completions(word.as_ref());
-                completions.sort();
-                completions.dedup();
-                (word, completions)
+                let history = &self.context.history;
+                let completions = match completer.complete_later_with_history(buf, cursor, word_range, history) {
+                    Some(pending) => match pending.wait(self.context.completion_wait) {
+                        Some(completions) => Ok(completions),
+                        None => Err(pending),
+                    },
+                    None => Ok(completer.complete_with_history(buf, cursor, word_range, history)),
+                };
+                (completions, completer.keep_order())
             } else {
                 return Ok(());
             }
         };
 
+        match completions {
+            Ok(completions) => self.show_completions(word_range, completions, keep_order),
+            Err(pending) => {
+                self.pending_completion = Some(PendingCompletion {
+                    pending: pending,
+                    word_range: word_range,
+                    keep_order: keep_order,
+                    started: Instant::now(),
+                    frame: 0,
+                });
+                self.no_newline = true;
+                self.display()
+            }
+        }
+    }
+
+    /// Whether completions are being worked out in the background.
+    pub fn is_completion_pending(&self) -> bool {
+        self.pending_completion.is_some()
+    }
+
+    /// Checks on completions that are being worked out in the background, and shows them if they
+    /// have arrived. Rings the bell and gives up on them after `Context.completion_timeout`.
+    /// Returns whether they are still pending.
+    pub fn poll_completion(&mut self) -> io::Result<bool> {
+        let (completions, timed_out) = match self.pending_completion {
+            Some(ref mut p) => {
+                p.frame += 1;
+                (p.pending.poll(), p.started.elapsed() >= self.context.completion_timeout)
+            }
+            None => return Ok(false),
+        };
+
+        if let Some(completions) = completions {
+            let p = self.pending_completion.take().unwrap();
+            self.show_completions(p.word_range, completions, p.keep_order)?;
+        } else if timed_out {
+            self.pending_completion = None;
+            self.bell()?;
+            self.display()?;
+        } else {
+            self.display()?;
+        }
+        self.flush()?;
+        Ok(self.pending_completion.is_some())
+    }
+
+    /// Stops waiting for completions that are being worked out in the background.
+    pub fn cancel_completion(&mut self) {
+        self.pending_completion = None;
+    }
+
+    // Inserts the only completion, or their common prefix, or lists them.
+    fn show_completions(&mut self, word_range: (usize, usize), mut completions: Vec<Completion>, keep_order: bool) -> io::Result<()> {
+        if keep_order {
+            let mut seen = HashSet::new();
+            completions.retain(|c| seen.insert(c.clone()));
+        } else {
+            completions.sort();
+            completions.dedup();
+        }
+
         if completions.is_empty() {
-            // Do nothing.
             self.show_completions_hint = None;
-            Ok(())
+            self.bell()
         } else if completions.len() == 1 {
             self.show_completions_hint = None;
-            self.delete_word_before_cursor(false)?;
-            self.insert_str_after_cursor(completions[0].as_ref())
+            self.insert_completion(&completions[0], true)?;
+            Ok(())
         } else {
-            let common_prefix = util::find_longest_common_prefix(
-                &completions
-                    .iter()
-                    .map(|x| x.chars().collect())
-                    .collect::<Vec<Vec<char>>>()[..],
-            );
+            // Only completions replacing the same text can share a prefix.
+            let span = completions[0].span;
+            let common_prefix = if completions.iter().all(|c| c.span == span) {
+                util::find_longest_common_prefix(
+                    &completions
+                        .iter()
+                        .map(|x| x.text.chars().collect())
+                        .collect::<Vec<Vec<char>>>()[..],
+                )
+            } else {
+                None
+            };
 
             if let Some(p) = common_prefix {
                 let s = p.iter().cloned().collect::<String>();
+                let (start, end) = span.unwrap_or(word_range);
+                let word = cur_buf!(self).range(start, end);
 
                 if s.len() > word.len() && s.starts_with(&word[..]) {
-                    self.delete_word_before_cursor(false)?;
-                    return self.insert_str_after_cursor(s.as_ref());
+                    let prefix = Completion { text: s, span: span, suffix: String::new() };
+                    self.insert_completion(&prefix, false)?;
+                    return Ok(());
                 }
             }
 
@@ -539,6 +1172,35 @@ impl<'a, W: Write> Editor<'a, W> {
         }
     }
 
+    // The word before the cursor that is completed. It also takes in whitespace that is quoted
+    // or escaped, which the word divider may split on, as in `"My Documents/`.
+    fn completion_word(&self) -> (usize, usize) {
+        let cursor = self.cursor;
+        let (start, end) = self.get_word_before_cursor(false).unwrap_or((cursor, cursor));
+        (cmp::min(start, shell_word_start(cur_buf!(self), cursor)), end)
+    }
+
+    // Replaces the span of `completion`, or the word before the cursor, with its text and, if
+    // `with_suffix` is set, its suffix, leaving the cursor after it. Returns where the inserted
+    // text is and the text it replaced.
+    fn insert_completion(&mut self, completion: &Completion, with_suffix: bool) -> io::Result<(usize, usize, String)> {
+        let (start, end) = match completion.span {
+            Some(span) => span,
+            None => self.completion_word(),
+        };
+        let end = cmp::min(end, cur_buf!(self).num_chars());
+        let start = cmp::min(start, end);
+
+        let mut text: Vec<char> = completion.text.chars().collect();
+        if with_suffix {
+            text.extend(completion.suffix.chars());
+        }
+        let replaced = cur_buf!(self).range(start, end);
+        self.replace_chars(start, end, &text);
+        self.move_cursor_to(start + text.len())?;
+        Ok((start, start + text.len(), replaced))
+    }
+
     fn get_word_before_cursor(&self, ignore_space_before_cursor: bool) -> Option<(usize, usize)> {
         let (words, pos) = self.get_words_and_cursor_position();
         match pos {
@@ -568,55 +1230,516 @@ impl<'a, W: Write> Editor<'a, W> {
         ignore_space_before_cursor: bool,
     ) -> io::Result<()> {
         if let Some((start, _)) = self.get_word_before_cursor(ignore_space_before_cursor) {
-            let moved = cur_buf_mut!(self).remove(start, self.cursor);
-            self.cursor -= moved;
+            let end = self.cursor;
+            self.kill(start, end)?;
+            self.cursor = start;
         }
         self.no_newline = true;
         self.display()
     }
 
-    /// Clears the screen then prints the prompt and current buffer.
-    pub fn clear(&mut self) -> io::Result<()> {
-        BUFFER.with(|output_buf| {
-            output_buf.append(clear::All.as_ref());
-            output_buf.append(String::from(cursor::Goto(1,1)).as_bytes());
-        });
+    fn word_fn(&self) -> &Fn(&Buffer) -> Vec<(usize, usize)> {
+        match self.word_divider_fn {
+            Some(ref word_fn) => &**word_fn,
+            None => &*self.context.word_divider_fn,
+        }
+    }
 
-        self.term_cursor_line = 1;
-        self.no_newline = true;
-        self.clear_search();
-        self.display()
+    /// Returns the start of the word the cursor is in, or of the closest word to the left of the
+    /// cursor if it's already on the left edge of a word or between words.
+    fn prev_word_start(&self) -> Option<usize> {
+        cur_buf!(self).prev_word_start(self.cursor, self.word_fn())
     }
 
-    /// Move up (backwards) in history.
-    pub fn move_up(&mut self) -> io::Result<()> {
+    /// Returns the end of the word the cursor is in, or of the closest word to the right of the
+    /// cursor if it's already on the right edge of a word or between words.
+    fn next_word_end(&self) -> Option<usize> {
+        cur_buf!(self).word_end_at(self.cursor, self.word_fn())
+    }
 
-        if self.show_autosuggestions() {
+    /// Moves the cursor to the start of the current or previous word.
+    pub fn move_word_left(&mut self) -> io::Result<()> {
+        match self.prev_word_start() {
+            Some(pos) => self.move_cursor_to(pos),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the cursor to the end of the current or next word.
+    pub fn move_word_right(&mut self) -> io::Result<()> {
+        match self.next_word_end() {
+            Some(pos) => self.move_cursor_to(pos),
+            None => Ok(()),
+        }
+    }
+
+    /// Deletes from the cursor to the end of the current or next word. The cursor does not move.
+    pub fn delete_word_after_cursor(&mut self) -> io::Result<()> {
+        match self.next_word_end() {
+            Some(pos) => self.delete_until(pos),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the text from the cursor to the end of the current or next word with the result
+    /// of `f`, which is given each char and whether it is the first letter changed. The cursor
+    /// moves to the end of the word. The change is a single undo step.
+    fn change_word_case<F>(&mut self, f: F) -> io::Result<()>
+        where F: Fn(char, bool) -> Vec<char>
+    {
+        let end = match self.next_word_end() {
+            Some(end) => end,
+            None => return Ok(()),
+        };
+
+        let start = self.cursor;
+        let mut first = true;
+        let mut changed = Vec::new();
+        for c in cur_buf!(self).range_chars(start, end) {
+            changed.extend(f(c, first));
+            if c.is_alphabetic() {
+                first = false;
+            }
+        }
+
+        self.replace_chars(start, end, &changed);
+        self.move_cursor_to(start + changed.len())
+    }
+
+    /// Replaces the chars between `start` and `end` with `text` as a single undo step.
+    fn replace_chars(&mut self, start: usize, end: usize, text: &[char]) {
+        let buf = cur_buf_mut!(self);
+        buf.start_undo_group();
+        buf.remove(start, end);
+        buf.insert(start, text);
+        buf.end_undo_group();
+    }
+
+    /// Swaps the character before the cursor with the one under it, moving the cursor right.
+    /// At the end of the line, the two characters before the cursor are swapped instead.
+    /// Nothing happens at the start of the line or if there are fewer than two characters.
+    pub fn transpose_chars(&mut self) -> io::Result<()> {
+        let num_chars = cur_buf!(self).num_chars();
+        if self.cursor == 0 || num_chars < 2 {
             return Ok(());
         }
 
-        if self.is_search() {
+        let start = if self.cursor >= num_chars { num_chars - 2 } else { self.cursor - 1 };
+        let mut chars = cur_buf!(self).range_chars(start, start + 2);
+        chars.swap(0, 1);
+        self.replace_chars(start, start + 2, &chars);
+        self.move_cursor_to(start + 2)
+    }
+
+    /// Swaps the word before the cursor (or the word the cursor is in) with the word after it,
+    /// moving the cursor to the end of the second word. When there is no word after the cursor,
+    /// the last two words are swapped. Nothing happens if there are fewer than two words.
+    pub fn transpose_words(&mut self) -> io::Result<()> {
+        let (words, _) = self.get_words_and_cursor_position();
+        if words.len() < 2 {
+            return Ok(());
+        }
+
+        let left = match words.iter().rposition(|&(start, _)| start < self.cursor) {
+            Some(i) if i + 1 == words.len() => i - 1,
+            Some(i) => i,
+            None => 0,
+        };
+        let (left_start, left_end) = words[left];
+        let (right_start, right_end) = words[left + 1];
+
+        let mut chars = Vec::with_capacity(right_end - left_start);
+        {
+            let buf = cur_buf!(self);
+            chars.extend(buf.range_chars(right_start, right_end));
+            chars.extend(buf.range_chars(left_end, right_start));
+            chars.extend(buf.range_chars(left_start, left_end));
+        }
+        self.replace_chars(left_start, right_end, &chars);
+        self.move_cursor_to(right_end)
+    }
+
+    /// Capitalizes the current or next word from the cursor onwards: the first letter is made
+    /// uppercase and the rest lowercase. The cursor moves to the end of the word.
+    pub fn capitalize_word(&mut self) -> io::Result<()> {
+        self.change_word_case(|c, first| if first {
+            c.to_uppercase().collect()
+        } else {
+            c.to_lowercase().collect()
+        })
+    }
+
+    /// Makes the current or next word uppercase from the cursor onwards. The cursor moves to the
+    /// end of the word.
+    pub fn upcase_word(&mut self) -> io::Result<()> {
+        self.change_word_case(|c, _| c.to_uppercase().collect())
+    }
+
+    /// Makes the current or next word lowercase from the cursor onwards. The cursor moves to the
+    /// end of the word.
+    pub fn downcase_word(&mut self) -> io::Result<()> {
+        self.change_word_case(|c, _| c.to_lowercase().collect())
+    }
+
+    /// Rings the bell the way `Context.bell_style` says, to point out that something couldn't be
+    /// done.
+    pub fn bell(&mut self) -> io::Result<()> {
+        match self.context.bell_style {
+            BellStyle::Audible => self.out.write_all(b"\x07")?,
+            // The screen is put back by the next redraw or while waiting for a key, so the
+            // flash doesn't hold up the keys after it.
+            BellStyle::Visible => {
+                self.out.write_all(b"\x1B[?5h")?;
+                self.flash_started = Some(Instant::now());
+            }
+            BellStyle::Off => return Ok(()),
+        }
+        self.out.flush()
+    }
+
+    /// How much longer the screen stays reversed by the visible bell, or `None` if it isn't.
+    pub fn flash_remaining(&self) -> Option<Duration> {
+        self.flash_started.map(|started| FLASH_DURATION.checked_sub(started.elapsed()).unwrap_or_default())
+    }
+
+    /// Puts the screen back if the visible bell reversed it, however long ago that was.
+    pub fn end_flash(&mut self) -> io::Result<()> {
+        if self.flash_started.take().is_none() {
+            return Ok(());
+        }
+
+        self.out.write_all(b"\x1B[?5l")?;
+        self.out.flush()
+    }
+
+    /// Changes the shape of the terminal cursor, unless it has that shape already. `None` puts
+    /// back the terminal's default shape if it was changed.
+    pub fn set_cursor_shape(&mut self, shape: Option<CursorShape>) -> io::Result<()> {
+        if shape.is_none() {
+            return self.reset_cursor_shape();
+        } else if shape == self.cursor_shape || !self.context.capabilities.cursor_shape {
+            return Ok(());
+        }
+
+        self.cursor_shape = shape;
+        write!(self.out, "{}", shape.unwrap())?;
+        self.out.flush()
+    }
+
+    /// Puts the terminal cursor back to the terminal's default shape, if it was changed.
+    pub fn reset_cursor_shape(&mut self) -> io::Result<()> {
+        if self.cursor_shape.take().is_none() {
+            return Ok(());
+        }
+
+        self.out.write_all(b"\x1B[0 q")?;
+        self.out.flush()
+    }
+
+    /// Whether colors are drawn, which needs both `Context.color` and a terminal with colors.
+    fn draws_color(&self) -> bool {
+        self.context.color && self.context.capabilities.colors != Colors::None
+    }
+
+    /// The size of the terminal in columns and rows, taking 80 by 24 if it doesn't know.
+    fn terminal_size(&mut self) -> io::Result<(usize, usize)> {
+        let (cols, rows) = self.context.terminal.size()?;
+        let cols = if cols == 0 { 80 } else { cols as usize };
+        let rows = if rows == 0 { 24 } else { rows as usize };
+        Ok((cols, rows))
+    }
+
+    /// Asks the terminal where its cursor is, so that `geometry()` knows which terminal row the
+    /// prompt and buffer are drawn on. The row is kept up to date after that.
+    pub fn locate(&mut self) -> io::Result<()> {
+        let (_, row) = self.context.terminal.cursor_pos(&mut self.out)?;
+        let cursor_row = self.frame.as_ref().map_or(0, |f| f.cursor.0);
+        self.frame_top = Some(cmp::max(1, (row as usize).saturating_sub(cursor_row)));
+        Ok(())
+    }
+
+    /// Returns where the prompt and buffer are drawn, or `None` if nothing is drawn right now.
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.frame.as_ref().map(|frame| Geometry {
+            top: self.frame_top,
+            rows: frame.end().0 + 1,
+            cursor: frame.cursor,
+            prompt: frame.lines.iter()
+                .position(|line| line.buf_line.map_or(false, |(i, _)| i == 0))
+                .map(|i| (frame.line_row(i), 0)),
+        })
+    }
+
+    /// Turns on mouse reporting in the terminal, so that clicks can be passed to `click()`.
+    /// Does nothing on terminals without mouse reporting.
+    pub fn enable_mouse(&mut self) -> io::Result<()> {
+        if !self.context.capabilities.mouse {
+            return Ok(());
+        }
+        if self.frame_top.is_none() {
+            self.locate()?;
+        }
+        self.mouse = true;
+
+        self.out.write_all(b"\x1B[?1000h\x1B[?1002h\x1B[?1015h\x1B[?1006h")?;
+        self.out.flush()
+    }
+
+    /// Turns off mouse reporting in the terminal, if it was turned on.
+    pub fn disable_mouse(&mut self) -> io::Result<()> {
+        if !self.mouse {
+            return Ok(());
+        }
+        self.mouse = false;
+
+        self.out.write_all(b"\x1B[?1006l\x1B[?1015l\x1B[?1002l\x1B[?1000l")?;
+        self.out.flush()
+    }
+
+    /// Turns on bracketed paste in the terminal, so that pasted text is marked and can be passed
+    /// to `handle_paste()` in one piece. Does nothing on terminals without it.
+    pub fn enable_bracketed_paste(&mut self) -> io::Result<()> {
+        if !self.context.capabilities.bracketed_paste {
+            return Ok(());
+        }
+        self.bracketed_paste = true;
+        self.out.write_all(b"\x1B[?2004h")?;
+        self.out.flush()
+    }
+
+    /// Turns off bracketed paste in the terminal, if it was turned on.
+    pub fn disable_bracketed_paste(&mut self) -> io::Result<()> {
+        if !self.bracketed_paste {
+            return Ok(());
+        }
+        self.bracketed_paste = false;
+
+        self.out.write_all(b"\x1B[?2004l")?;
+        self.out.flush()
+    }
+
+    /// Turns on focus reporting in the terminal, so that the window gaining and losing focus can
+    /// be passed to `handle_focus()`. Does nothing on terminals without it.
+    pub fn enable_focus_events(&mut self) -> io::Result<()> {
+        if !self.context.capabilities.focus_events {
+            return Ok(());
+        }
+        self.focus_events = true;
+        self.out.write_all(b"\x1B[?1004h")?;
+        self.out.flush()
+    }
+
+    /// Turns off focus reporting in the terminal, if it was turned on.
+    pub fn disable_focus_events(&mut self) -> io::Result<()> {
+        if !self.focus_events {
+            return Ok(());
+        }
+        self.focus_events = false;
+
+        self.out.write_all(b"\x1B[?1004l")?;
+        self.out.flush()
+    }
+
+    /// Switches to the terminal's alternate screen and draws the prompt and buffer at the top of
+    /// it, leaving what was on the screen alone until `leave_alternate_screen()`. Does nothing on
+    /// terminals without an alternate screen.
+    pub fn enter_alternate_screen(&mut self) -> io::Result<()> {
+        if !self.context.capabilities.alternate_screen {
+            return Ok(());
+        }
+        self.alternate_screen = true;
+        self.out.write_all(b"\x1B[?1049h")?;
+        BUFFER.with(|output_buf| {
+            output_buf.append(ansi::CLEAR_ALL.as_bytes());
+            output_buf.append(ansi::goto(1, 1).as_bytes());
+        });
+
+        self.frame = None;
+        self.frame_top = Some(1);
+        self.no_newline = true;
+        self.display()
+    }
+
+    /// Switches back from the alternate screen, if the editor is drawn on it, bringing back what
+    /// was on the screen before. Nothing is drawn until the next `display()`.
+    pub fn leave_alternate_screen(&mut self) -> io::Result<()> {
+        if !self.alternate_screen {
+            return Ok(());
+        }
+        self.alternate_screen = false;
+
+        self.out.write_all(b"\x1B[?1049l")?;
+        self.frame = None;
+        self.frame_top = None;
+        self.out.flush()
+    }
+
+    /// Whether the editor is drawn on the terminal's alternate screen.
+    pub fn is_on_alternate_screen(&self) -> bool {
+        self.alternate_screen
+    }
+
+    /// Sends a `Paste` event with `text` to `handler`, then inserts it at the cursor unless the
+    /// handler cancelled it or gave other text to insert instead.
+    pub fn handle_paste(&mut self, text: String, handler: &mut EventHandler<W>) -> io::Result<()> {
+        let text = match self.send_event(handler, EventKind::Paste(text.clone()))? {
+            Some(EventAction::Cancel) => None,
+            Some(EventAction::ReplaceBuffer(replacement)) => Some(replacement),
+            _ => Some(text),
+        };
+        if let Some(text) = text {
+            self.paste(&text)?;
+        }
+        self.flush()
+    }
+
+    /// Tells handlers that the terminal window gained focus, or lost it with `false`, and redraws
+    /// the prompt and buffer, which handlers may have changed.
+    pub fn handle_focus(&mut self, focused: bool, handler: &mut EventHandler<W>) -> io::Result<()> {
+        self.send_event(handler, EventKind::Focus(focused))?;
+        self.display()?;
+        self.flush()
+    }
+
+    /// Passes input that liner doesn't know on to handlers, and otherwise ignores it.
+    pub fn handle_unknown_input(&mut self, bytes: Vec<u8>, handler: &mut EventHandler<W>) -> io::Result<()> {
+        self.send_event(handler, EventKind::UnknownInput(bytes))?;
+        self.flush()
+    }
+
+    /// Moves the cursor to where the terminal was clicked, given as the 1-indexed column and row
+    /// reported by the terminal. Clicks anywhere but on the buffer and prompt are ignored.
+    pub fn click(&mut self, x: usize, y: usize) -> io::Result<()> {
+        let (line, col) = {
+            let (top, frame) = match (self.frame_top, self.frame.as_ref()) {
+                (Some(top), Some(frame)) if self.mouse && y >= top && x > 0 => (top, frame),
+                _ => return Ok(()),
+            };
+
+            let row = y - top;
+            let index = match (0..frame.lines.len()).find(|&i| row < frame.line_row(i) + frame.line_rows(i)) {
+                Some(index) => index,
+                None => return Ok(()),
+            };
+            let (line, scroll) = match frame.lines[index].buf_line {
+                Some(buf_line) => buf_line,
+                None => return Ok(()),
+            };
+
+            let col = (row - frame.line_row(index)) * frame.terminal_width + x - 1;
+            (line, col.saturating_sub(frame.prompt_width) + scroll)
+        };
+
+        let pos = self.char_at_column(line, col);
+        self.move_cursor_to(pos)
+    }
+
+    // Returns the position of the char shown at column `col` of line `line` of the buffer, or the
+    // end of the line if it is shorter than that.
+    fn char_at_column(&self, line: usize, col: usize) -> usize {
+        let buf = cur_buf!(self);
+        let tab_width = self.context.tab_width;
+
+        let (start, end) = match buf.line_ranges().get(line) {
+            Some(&range) => range,
+            None => return buf.num_chars(),
+        };
+
+        let mut pos = start;
+        for next in start + 1..end + 1 {
+            if util::width(&util::visible(&buf.range(start, next), 0, tab_width)) > col {
+                break;
+            }
+            pos = next;
+        }
+        buf.grapheme_boundary(pos)
+    }
+
+    /// Clears the screen then prints the prompt and current buffer. Terminals that can't clear
+    /// the screen get them printed again below instead.
+    pub fn clear(&mut self) -> io::Result<()> {
+        if !self.context.capabilities.clear_screen {
+            let cursor = self.cursor;
+            self.cursor = cur_buf!(self).num_chars();
+            self.no_newline = true;
+            self._display(false)?;
+            self.out.write_all(b"\r\n")?;
+            self.frame = None;
+            self.frame_top = None;
+            self.cursor = cursor;
+            self.clear_search();
+            return self.display();
+        }
+
+        BUFFER.with(|output_buf| {
+            output_buf.append(ansi::CLEAR_ALL.as_bytes());
+            output_buf.append(ansi::goto(1, 1).as_bytes());
+        });
+
+        self.frame = None;
+        if self.frame_top.is_some() {
+            self.frame_top = Some(1);
+        }
+        self.no_newline = true;
+        self.clear_search();
+        self.display()
+    }
+
+    /// Move up (backwards) in history.
+    pub fn move_up(&mut self) -> io::Result<()> {
+
+        if self.show_autosuggestions() || self.is_secret() {
+            return Ok(());
+        }
+
+        if self.is_search() {
             self.search(false)
+        } else if self.context.history_prefix_search && self.new_buf.num_chars() > 0 {
+            match self.history_subset_loc {
+                Some(i) if i > 0 => {
+                    self.history_subset_loc = Some(i - 1);
+                    let loc = self.history_subset_index[i - 1];
+                    self.set_history_loc(Some(loc));
+                }
+                None => {
+                    let prefix = Buffer::from(self.new_buf.range(0, self.cursor));
+                    self.history_subset_index = self.context.history.get_prefix_subset(&prefix);
+                    if let Some(&loc) = self.history_subset_index.last() {
+                        self.history_subset_loc = Some(self.history_subset_index.len() - 1);
+                        self.set_history_loc(Some(loc));
+                    }
+                }
+                _ => ()
+            }
+            self.no_newline = true;
+            self.display()
         } else {
             if self.new_buf.num_chars() > 0 {
                 match self.history_subset_loc {
                     Some(i) if i > 0 => {
                         self.history_subset_loc = Some(i - 1);
-                        self.cur_history_loc = Some(self.history_subset_index[i - 1]);
+                        let loc = self.history_subset_index[i - 1];
+                        self.set_history_loc(Some(loc));
                     }
                     None => {
                         self.history_subset_index = self.context.history.get_history_subset(&self.new_buf);
                         if self.history_subset_index.len() > 0 {
                             self.history_subset_loc = Some(self.history_subset_index.len() - 1);
-                            self.cur_history_loc = Some(self.history_subset_index[self.history_subset_index.len() - 1]);
+                            let loc = self.history_subset_index[self.history_subset_index.len() - 1];
+                            self.set_history_loc(Some(loc));
                         }
                     }
                     _ => ()
                 }
             } else {
-                match self.cur_history_loc {
-                    Some(i) if i > 0 => self.cur_history_loc = Some(i - 1),
-                    None if self.context.history.len() > 0  => self.cur_history_loc = Some(self.context.history.len()-1),
+                let order = self.history_order();
+                let pos = self.cur_history_loc.and_then(|loc| order.iter().position(|&i| i == loc));
+                match pos {
+                    Some(p) if p > 0 => self.set_history_loc(Some(order[p - 1])),
+                    None if order.len() > 0 => {
+                        let loc = order[order.len() - 1];
+                        self.set_history_loc(Some(loc));
+                    }
                     _ => ()
                 }
             }
@@ -627,41 +1750,68 @@ impl<'a, W: Write> Editor<'a, W> {
     /// Move down (forwards) in history, or to the new buffer if we reach the end of history.
     pub fn move_down(&mut self) -> io::Result<()> {
 
-        if self.show_autosuggestions() {
+        if self.show_autosuggestions() || self.is_secret() {
             return Ok(());
         }
 
         if self.is_search() {
             self.search(true)
+        } else if self.context.history_prefix_search && self.new_buf.num_chars() > 0 {
+            if let Some(i) = self.history_subset_loc {
+                if i < self.history_subset_index.len() - 1 {
+                    self.history_subset_loc = Some(i + 1);
+                    let loc = self.history_subset_index[i + 1];
+                    self.set_history_loc(Some(loc));
+                } else {
+                    self.set_history_loc(None);
+                    self.history_subset_loc = None;
+                    self.history_subset_index.clear();
+                }
+            }
+            self.no_newline = true;
+            self.display()
         } else {
             if self.new_buf.num_chars() > 0 {
                 if let Some(i) = self.history_subset_loc {
                     if i < self.history_subset_index.len()-1 {
                         self.history_subset_loc = Some(i + 1);
-                        self.cur_history_loc = Some(self.history_subset_index[i + 1]);
+                        let loc = self.history_subset_index[i + 1];
+                        self.set_history_loc(Some(loc));
                     } else {
-                        self.cur_history_loc = None;
+                        self.set_history_loc(None);
                         self.history_subset_loc = None;
                         self.history_subset_index.clear();
                     }
                 }
             } else {
-                match self.cur_history_loc.take() {
-                    Some(i) if i < self.context.history.len() - 1 => self.cur_history_loc = Some(i + 1),
-                    _ => ()
-                }
+                let order = self.history_order();
+                let pos = self.cur_history_loc.and_then(|loc| order.iter().position(|&i| i == loc));
+                let loc = match pos {
+                    Some(p) if p < order.len() - 1 => Some(order[p + 1]),
+                    _ => None,
+                };
+                self.set_history_loc(loc);
             }
-            self.move_cursor_to_end_of_line()
+            self.move_cursor_after_history_move()
+        }
+    }
+
+    // The history entries in the order Up and Down go through them, Up going towards the start.
+    fn history_order(&self) -> Vec<usize> {
+        if self.context.prefer_session_history {
+            self.context.history.session_first_order()
+        } else {
+            (0..self.context.history.len()).collect()
         }
     }
 
     /// Moves to the start of history (ie. the earliest history entry).
     pub fn move_to_start_of_history(&mut self) -> io::Result<()> {
-        if self.context.history.len() > 0 {
-            self.cur_history_loc = Some(0);
+        if self.context.history.len() > 0 && !self.is_secret() {
+            self.set_history_loc(Some(0));
             self.move_cursor_to_end_of_line()
         } else {
-            self.cur_history_loc = None;
+            self.set_history_loc(None);
             self.no_newline = true;
             self.display()
         }
@@ -670,8 +1820,8 @@ impl<'a, W: Write> Editor<'a, W> {
     /// Moves to the end of history (ie. the new buffer).
     pub fn move_to_end_of_history(&mut self) -> io::Result<()> {
         if self.cur_history_loc.is_some() {
-            self.cur_history_loc = None;
-            self.move_cursor_to_end_of_line()
+            self.set_history_loc(None);
+            self.move_cursor_after_history_move()
         } else {
             self.no_newline = true;
             self.display()
@@ -693,8 +1843,9 @@ impl<'a, W: Write> Editor<'a, W> {
     /// Inserts characters directly after the cursor, moving the cursor to the right.
     pub fn insert_chars_after_cursor(&mut self, cs: &[char]) -> io::Result<()> {
         {
+            let policy = self.undo_policy;
             let buf = cur_buf_mut!(self);
-            buf.insert(self.cursor, cs);
+            buf.insert_coalesced(self.cursor, cs, policy);
         }
 
         self.cursor += cs.len();
@@ -702,13 +1853,25 @@ impl<'a, W: Write> Editor<'a, W> {
         self.display()
     }
 
+    /// Replaces the character under the cursor with `c`, moving the cursor to the right. At the
+    /// end of a line, `c` is inserted instead.
+    pub fn overwrite_after_cursor(&mut self, c: char) -> io::Result<()> {
+        let start = self.cursor;
+        let end = cur_buf!(self).grapheme_after(start);
+        if start == end || cur_buf!(self).char_after(start) == Some('\n') {
+            return self.insert_after_cursor(c);
+        }
+
+        self.replace_chars(start, end, &[c]);
+        self.move_cursor_to(start + 1)
+    }
+
     /// Deletes the character directly before the cursor, moving the cursor to the left.
     /// If the cursor is at the start of the line, nothing happens.
     pub fn delete_before_cursor(&mut self) -> io::Result<()> {
         if self.cursor > 0 {
-            let buf = cur_buf_mut!(self);
-            buf.remove(self.cursor - 1, self.cursor);
-            self.cursor -= 1;
+            let cursor = self.cursor;
+            self.cursor = cur_buf_mut!(self).remove_grapheme_before(cursor);
         }
 
         self.no_newline = true;
@@ -722,7 +1885,7 @@ impl<'a, W: Write> Editor<'a, W> {
             let buf = cur_buf_mut!(self);
 
             if self.cursor < buf.num_chars() {
-                buf.remove(self.cursor, self.cursor + 1);
+                buf.remove_grapheme_after(self.cursor);
             }
         }
         self.no_newline = true;
@@ -731,7 +1894,8 @@ impl<'a, W: Write> Editor<'a, W> {
 
     /// Deletes every character preceding the cursor until the beginning of the line.
     pub fn delete_all_before_cursor(&mut self) -> io::Result<()> {
-        cur_buf_mut!(self).remove(0, self.cursor);
+        let end = self.cursor;
+        self.kill(0, end)?;
         self.cursor = 0;
         self.no_newline = true;
         self.display()
@@ -739,55 +1903,226 @@ impl<'a, W: Write> Editor<'a, W> {
 
     /// Deletes every character after the cursor until the end of the line.
     pub fn delete_all_after_cursor(&mut self) -> io::Result<()> {
-        {
-            let buf = cur_buf_mut!(self);
-            buf.truncate(self.cursor);
-        }
+        let (start, end) = (self.cursor, cur_buf!(self).num_chars());
+        self.kill(start, end)?;
         self.no_newline = true;
         self.display()
     }
 
     /// Deletes every character from the cursor until the given position.
     pub fn delete_until(&mut self, position: usize) -> io::Result<()> {
-        {
-            let buf = cur_buf_mut!(self);
-            buf.remove(
-                cmp::min(self.cursor, position),
-                cmp::max(self.cursor, position),
-            );
-            self.cursor = cmp::min(self.cursor, position);
-        }
+        let (start, end) = (cmp::min(self.cursor, position), cmp::max(self.cursor, position));
+        self.kill(start, end)?;
+        self.cursor = start;
         self.no_newline = true;
         self.display()
     }
 
     /// Deletes every character from the cursor until the given position, inclusive.
     pub fn delete_until_inclusive(&mut self, position: usize) -> io::Result<()> {
+        let (start, end) = (cmp::min(self.cursor, position), cmp::max(self.cursor + 1, position + 1));
+        self.kill(start, end)?;
+        self.cursor = start;
+        self.no_newline = true;
+        self.display()
+    }
+
+    /// Inserts the text that was last killed at the cursor, leaving the cursor after it. Whole
+    /// lines go on a line of their own above the one the cursor is on, as with `put(false)`.
+    pub fn yank(&mut self) -> io::Result<()> {
+        self.kill_buffer_from_system_clipboard();
+        match self.context.kill_buffer.kind {
+            RegisterKind::Charwise => {
+                let text: Vec<char> = self.context.kill_buffer.text.chars().collect();
+                self.insert_chars_after_cursor(&text)
+            }
+            _ => self.put(false),
+        }
+    }
+
+    /// Puts the text that was last killed back as a single undo step. Whole lines go below the
+    /// line the cursor is on if `after` is true and above it otherwise, and the cursor moves to
+    /// the start of the first line put in. A block goes after or at the cursor's column, on the
+    /// line the cursor is on and the ones below it, which are added if there are too few. Other
+    /// text goes after or at the cursor, with the cursor left after it.
+    pub fn put(&mut self, after: bool) -> io::Result<()> {
+        self.kill_buffer_from_system_clipboard();
+        let register = self.context.kill_buffer.clone();
+        let cursor = self.cursor;
+        let buf = cur_buf_mut!(self);
+        let (row, col) = buf.offset_to_row_col(cursor);
+        let (line_start, line_end) = buf.line_ranges()[row];
+        buf.start_undo_group();
+        let new_cursor = match register.kind {
+            RegisterKind::Charwise => {
+                let at = if after { cmp::min(cursor + 1, line_end) } else { cursor };
+                let text: Vec<char> = register.text.chars().collect();
+                buf.insert(at, &text);
+                at + text.len()
+            }
+            RegisterKind::Linewise => {
+                let mut text: Vec<char> = register.text.chars().collect();
+                if after {
+                    text.insert(0, '\n');
+                    buf.insert(line_end, &text);
+                    line_end + 1
+                } else {
+                    text.push('\n');
+                    buf.insert(line_start, &text);
+                    line_start
+                }
+            }
+            RegisterKind::Blockwise => {
+                let col = if after && line_end > line_start { col + 1 } else { col };
+                for (i, block_row) in register.text.split('\n').enumerate() {
+                    let mut text: Vec<char> = Vec::new();
+                    let (start, end) = match buf.line_ranges().get(row + i) {
+                        Some(&range) => range,
+                        None => {
+                            text.push('\n');
+                            let end = buf.num_chars();
+                            (end, end)
+                        }
+                    };
+                    // Short lines are padded so every row starts at the same column.
+                    let at = cmp::min(start + col, end);
+                    text.extend(::std::iter::repeat(' ').take(start + col - at));
+                    text.extend(block_row.chars());
+                    buf.insert(at, &text);
+                }
+                line_start + col
+            }
+        };
+        buf.end_undo_group();
+        self.cursor = new_cursor;
+        self.no_newline = true;
+        self.display()
+    }
+
+    /// Kills the line the cursor is on so that it is put back as a whole line. Unless
+    /// `keep_line` is true, the line itself goes away as well when the buffer has others, and
+    /// the cursor moves to the start of the line that took its place.
+    pub fn kill_line(&mut self, keep_line: bool) -> io::Result<()> {
+        let (start, end, remove_start, remove_end) = {
+            let buf = cur_buf!(self);
+            let ranges = buf.line_ranges();
+            let row = buf.offset_to_row_col(self.cursor).0;
+            let (start, end) = ranges[row];
+            if keep_line || ranges.len() == 1 {
+                (start, end, start, end)
+            } else if row + 1 < ranges.len() {
+                (start, end, start, end + 1)
+            } else {
+                (start, end, start - 1, end)
+            }
+        };
+
+        let text = cur_buf!(self).range(start, end);
+        cur_buf_mut!(self).remove(remove_start, remove_end);
+        self.store_kill(Register::new(text, RegisterKind::Linewise))?;
+
+        let buf = cur_buf!(self);
+        let row = buf.offset_to_row_col(remove_start).0;
+        self.cursor = buf.line_ranges()[row].0;
+        self.no_newline = true;
+        self.display()
+    }
+
+    /// Inserts `text` at the cursor as a single undo step, leaving the cursor after it.
+    pub fn paste(&mut self, text: &str) -> io::Result<()> {
+        let chars: Vec<char> = text.chars().collect();
         {
+            let cursor = self.cursor;
             let buf = cur_buf_mut!(self);
-            buf.remove(
-                cmp::min(self.cursor, position),
-                cmp::max(self.cursor + 1, position + 1),
-            );
-            self.cursor = cmp::min(self.cursor, position);
+            buf.start_undo_group();
+            buf.insert(cursor, &chars);
+            buf.end_undo_group();
         }
+        self.cursor += chars.len();
         self.no_newline = true;
         self.display()
     }
 
+    /// Inserts the contents of the system clipboard at the cursor, as a single undo step.
+    #[cfg(feature = "clipboard")]
+    pub fn paste_from_clipboard(&mut self) -> io::Result<()> {
+        let text = system_clipboard()?.get_contents().map_err(clipboard_error)?;
+        self.paste(&text)
+    }
+
+    // Removes the chars from `start` to `end`, keeping them to be yanked back later.
+    fn kill(&mut self, start: usize, end: usize) -> io::Result<()> {
+        let text = cur_buf!(self).range(start, end);
+        cur_buf_mut!(self).remove(start, end);
+        if !text.is_empty() {
+            self.store_kill(Register::new(text, RegisterKind::Charwise))?;
+        }
+        Ok(())
+    }
+
+    // Keeps killed text to be yanked later. Masked text is just deleted, so it doesn't outlive
+    // the read in the kill buffer or the clipboard.
+    fn store_kill(&mut self, register: Register) -> io::Result<()> {
+        if self.is_secret() {
+            return Ok(());
+        }
+        self.copy_to_clipboard(&register.text)?;
+        self.copy_to_system_clipboard(&register.text);
+        self.context.kill_buffer = register;
+        Ok(())
+    }
+
+    // Copies `text` to the system clipboard, if that is turned on.
+    #[cfg(feature = "clipboard")]
+    fn copy_to_system_clipboard(&mut self, text: &str) {
+        if self.context.system_clipboard {
+            let _ = system_clipboard().and_then(|mut c| c.set_contents(text.to_owned()).map_err(clipboard_error));
+        }
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    fn copy_to_system_clipboard(&mut self, _: &str) {}
+
+    // Takes text copied to the system clipboard by another program as the text to yank, if
+    // reading the system clipboard is turned on.
+    #[cfg(feature = "clipboard")]
+    fn kill_buffer_from_system_clipboard(&mut self) {
+        if !self.context.system_clipboard {
+            return;
+        }
+        if let Ok(text) = system_clipboard().and_then(|mut c| c.get_contents().map_err(clipboard_error)) {
+            if !text.is_empty() && text != self.context.kill_buffer.text {
+                self.context.kill_buffer = Register::new(text, RegisterKind::Charwise);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    fn kill_buffer_from_system_clipboard(&mut self) {}
+
+    // Copies `text` to the system clipboard with an OSC 52 sequence, if that is turned on and the
+    // text isn't too long for it.
+    fn copy_to_clipboard(&mut self, text: &str) -> io::Result<()> {
+        if !self.context.osc52_clipboard {
+            return Ok(());
+        }
+
+        let encoded = util::base64(text.as_bytes());
+        if encoded.len() > self.context.osc52_max_len {
+            return Ok(());
+        }
+        write!(self.out, "\x1B]52;c;{}\x07", encoded)
+    }
+
     /// Moves the cursor to the left by `count` characters.
     /// The cursor will not go past the start of the buffer.
-    pub fn move_cursor_left(&mut self, mut count: usize) -> io::Result<()> {
+    pub fn move_cursor_left(&mut self, count: usize) -> io::Result<()> {
 
         if self.show_autosuggestions() {
            return self.display();
         }
 
-        if count > self.cursor {
-            count = self.cursor;
-        }
-
-        self.cursor -= count;
+        self.cursor = cur_buf!(self).graphemes_before(self.cursor, count);
 
         self.no_newline = true;
         self.display()
@@ -795,21 +2130,13 @@ impl<'a, W: Write> Editor<'a, W> {
 
     /// Moves the cursor to the right by `count` characters.
     /// The cursor will not go past the end of the buffer.
-    pub fn move_cursor_right(&mut self, mut count: usize) -> io::Result<()> {
+    pub fn move_cursor_right(&mut self, count: usize) -> io::Result<()> {
 
         if self.show_autosuggestions() {
             return self.display();
         }
 
-        {
-            let buf = cur_buf!(self);
-
-            if count > buf.num_chars() - self.cursor {
-                count = buf.num_chars() - self.cursor;
-            }
-
-            self.cursor += count;
-        }
+        self.cursor = cur_buf!(self).graphemes_after(self.cursor, count);
 
         self.no_newline = true;
         self.display()
@@ -862,7 +2189,83 @@ impl<'a, W: Write> Editor<'a, W> {
         cur_buf_mut!(self)
     }
 
-    /// Accept autosuggestion and copy its content into current buffer
+    /// Replaces the contents of the current buffer with `text` as a single undo step, leaving the
+    /// cursor at the end of it.
+    pub fn set_buffer(&mut self, text: &str) -> io::Result<()> {
+        let end = cur_buf!(self).num_chars();
+        self.replace_range(0, end, text)?;
+        self.move_cursor_to_end_of_line()
+    }
+
+    /// Replaces the chars from `start` to `end` of the current buffer with `text` as a single undo
+    /// step. The range is clamped to the buffer. A cursor after the range moves along with the
+    /// text after it, and a cursor inside of it moves to the end of `text`.
+    pub fn replace_range(&mut self, start: usize, end: usize, text: &str) -> io::Result<()> {
+        let end = cmp::min(end, cur_buf!(self).num_chars());
+        let start = cmp::min(start, end);
+        let chars: Vec<char> = text.chars().collect();
+        self.replace_chars(start, end, &chars);
+
+        if self.cursor >= end {
+            self.cursor = self.cursor - (end - start) + chars.len();
+        } else if self.cursor > start {
+            self.cursor = start + chars.len();
+        }
+        self.no_newline = true;
+        self.display()
+    }
+
+    /// Starts over on the new buffer with `text` in it, leaving the cursor at the end. Unlike
+    /// `set_buffer()`, this leaves the history entry being edited, if any, and forgets the undo
+    /// steps of the new buffer.
+    pub fn clear_and_set(&mut self, text: &str) -> io::Result<()> {
+        self.clear_search();
+        self.show_completions_hint = None;
+        self.set_history_loc(None);
+        self.close_insert_session_groups();
+        self.new_buf = Buffer::from(text);
+        self.reopen_insert_session_groups();
+        self.move_cursor_to_end_of_line()
+    }
+
+    /// Deletes the history entry being edited from the history and the history file, and goes
+    /// back to the new buffer. Rings the bell if no history entry is being edited.
+    pub fn delete_history_entry(&mut self) -> io::Result<()> {
+        let loc = match self.cur_history_loc {
+            Some(loc) => loc,
+            None => return self.bell(),
+        };
+        self.clear_search();
+        self.set_history_loc(None);
+        self.context.history.delete(loc)?;
+        self.move_cursor_to_end_of_line()
+    }
+
+    /// Replaces the text of the history entry being edited with `text`, in the history and the
+    /// history file. Rings the bell if no history entry is being edited.
+    pub fn replace_history_entry(&mut self, text: &str) -> io::Result<()> {
+        let loc = match self.cur_history_loc {
+            Some(loc) => loc,
+            None => return self.bell(),
+        };
+        self.close_insert_session_groups();
+        self.context.history.replace(loc, Buffer::from(text))?;
+        self.reopen_insert_session_groups();
+        self.move_cursor_to_end_of_line()
+    }
+
+    /// Pins the history entry being edited, or unpins it if it is pinned already. Rings the bell
+    /// if no history entry is being edited.
+    pub fn toggle_history_pin(&mut self) -> io::Result<()> {
+        let entry = match self.cur_history_loc {
+            Some(loc) => self.context.history[loc].to_string(),
+            None => return self.bell(),
+        };
+        self.context.history.toggle_pin(&entry);
+        Ok(())
+    }
+
+    /// Accept autosuggestion and copy its content into current buffer
     pub fn accept_autosuggestion(&mut self) -> io::Result<()> {
         if self.show_autosuggestions {
             {
@@ -870,7 +2273,7 @@ impl<'a, W: Write> Editor<'a, W> {
                 let search = self.is_search();
                 let buf = self.current_buffer_mut();
                 match autosuggestion {
-                    Some(ref x) if search => buf.copy_buffer(x),
+                    Some(ref x) if search || !x.starts_with(buf) => buf.copy_buffer(x),
                     Some(ref x) => buf.insert_from_buffer(x),
                     None => ()
                 }
@@ -887,13 +2290,27 @@ impl<'a, W: Write> Editor<'a, W> {
         let context_history = &self.context.history;
         let autosuggestion = if self.is_search() {
             self.search_history_loc().map(|i| &context_history[i])
-        } else if self.show_autosuggestions {
+        } else if self.show_autosuggestions && !self.is_secret() {
             self.cur_history_loc
                 .map(|i| &context_history[i])
                 .or_else(|| {
-                    context_history
-                        .get_newest_match(Some(context_history.len()), &self.new_buf)
-                        .map(|i| &context_history[i])
+                    if self.new_buf.num_chars() < self.context.autosuggest_min_chars {
+                        return None;
+                    }
+                    let anchor = self.context.autosuggest_anchor;
+                    let newest = context_history
+                        .best_match(&self.new_buf, anchor)
+                        .map(|i| &context_history[i]);
+                    // Any other match that differs from the newest one makes it ambiguous.
+                    let ambiguous = |newest: &Buffer| {
+                        context_history.search_index_anchored(&self.new_buf, anchor).into_iter()
+                            .map(|i| &context_history[i])
+                            .any(|b| !b.equals(newest) && !b.equals(&self.new_buf))
+                    };
+                    match newest {
+                        Some(newest) if self.context.autosuggest_unique_only && ambiguous(newest) => None,
+                        newest => newest,
+                    }
                 })
         } else {
             None
@@ -910,12 +2327,12 @@ impl<'a, W: Write> Editor<'a, W> {
         if self.is_search() {
             // If we are searching override prompt to search prompt.
             let (hplace, color) = if self.history_subset_index.len() > 0 {
-                (self.history_subset_loc.unwrap_or(0) + 1, color::Green.fg_str())
+                (self.history_subset_loc.unwrap_or(0) + 1, &self.context.theme.search_match)
             } else {
-                (0, color::Red.fg_str())
+                (0, &self.context.theme.search_no_match)
             };
             (format!("(search)'{}{}{}` ({}/{}): ",
-                     color, self.current_buffer(), color::Reset.fg_str(),
+                     color, self.current_buffer(), ansi::RESET,
                      hplace,
                      self.history_subset_index.len()),
              9)
@@ -924,205 +2341,456 @@ impl<'a, W: Write> Editor<'a, W> {
         }
     }
 
-    fn _display(&mut self, show_autosuggest: bool) -> io::Result<()> {
-        BUFFER.with(|output_buf| {
-            fn calc_width(prompt_width: usize, buf_widths: &[usize], terminal_width: usize) -> usize {
-                let mut total = 0;
-
-                for line in buf_widths {
-                    if total % terminal_width != 0 {
-                        total = ((total / terminal_width) + 1) * terminal_width;
-                    }
+    /// Lays out the prompt, the buffer and what goes under them on `terminal_width` columns,
+    /// keeping to the `terminal_height` rows around the cursor, the way `_display` draws them.
+    fn layout(&mut self, show_autosuggest: bool, terminal_width: usize, terminal_height: usize) -> Frame {
+        let (prompt, rev_prompt_width) = self.search_prompt();
 
-                    total += prompt_width + line;
-                }
+        // Only the user's prompt can have zero-width markers, not the search term.
+        let (prompt, prompt_width) = if self.is_search() {
+            let width = util::width(&util::remove_codes(util::handle_prompt(&prompt)));
+            (prompt, width)
+        } else {
+            let width = util::last_prompt_line_width(&prompt);
+            (util::split_prompt_markers(&prompt).0.into_owned(), width)
+        };
+        let prompt = if self.draws_color() {
+            prompt
+        } else {
+            util::remove_sgr_codes(&prompt).into_owned()
+        };
 
-                total
-            }
+        let buf = cur_buf!(self);
 
-            let (prompt, rev_prompt_width) = self.search_prompt();
+        // Don't let the cursor go over the end!
+        let buf_num_chars = buf.num_chars();
+        if buf_num_chars < self.cursor {
+            self.cursor = buf_num_chars;
+        }
 
-            let terminal_width = util::terminal_width()?;
-            let prompt_width = util::last_prompt_line_width(&prompt);
+        // Never leave the cursor inside of a grapheme cluster
+        self.cursor = buf.grapheme_boundary(self.cursor);
 
-            let buf = cur_buf!(self);
-            let buf_width = buf.width();
+        // Can't move past the last character in vi normal mode
+        if self.no_eol && self.cursor != 0 && self.cursor == buf_num_chars {
+            self.cursor = buf.grapheme_before(self.cursor);
+        }
 
-            // Don't let the cursor go over the end!
-            let buf_num_chars = buf.num_chars();
-            if buf_num_chars < self.cursor {
-                self.cursor = buf_num_chars;
+        // A masked buffer is drawn as one mask char for each char, so the cursor stays put. A
+        // hidden one isn't drawn at all, with the cursor after the prompt.
+        let masked;
+        let buf = match self.mask {
+            _ if self.hidden => {
+                masked = Buffer::new();
+                &masked
             }
-
-            // Can't move past the last character in vi normal mode
-            if self.no_eol && self.cursor != 0 && self.cursor == buf_num_chars {
-                self.cursor -= 1;
+            Some(mask) => {
+                masked = buf.chars().map(|&c| if c == '\n' { c } else { mask }).collect::<Buffer>();
+                &masked
             }
+            None => buf,
+        };
+        let cursor = if self.hidden { 0 } else { self.cursor };
 
-            let buf_widths = match self.autosuggestion {
-                Some(ref suggestion) => suggestion.width(),
-                None => buf_width,
-            };
-            // Width of the current buffer lines (including autosuggestion) from the start to the cursor
-            let buf_widths_to_cursor = match self.autosuggestion {
-                Some(ref suggestion) =>
-                    // Cursor might overrun autosuggestion with history search.
-                    if self.cursor < suggestion.num_chars() {
-                        suggestion.range_width(0, self.cursor)
-                    } else {
-                        buf.range_width(0, self.cursor)
-                    },
-                None => buf.range_width(0, self.cursor)
-            };
+        let mut lines = vec![];
 
-            // Total number of terminal spaces taken up by prompt and buffer
-            let new_total_width = calc_width(prompt_width, &buf_widths, terminal_width);
-            let new_total_width_to_cursor = if self.is_search() {
-                calc_width(rev_prompt_width, &buf_widths_to_cursor, terminal_width)
-            } else {
-                calc_width(prompt_width, &buf_widths_to_cursor, terminal_width)
-            };
+        // The lines of the prompt before the last one go first. They are kept while searching,
+        // which only replaces the last line.
+        let upper_prompt_lines = self.prompt.split('\n').count() - 1;
+        for line in self.prompt.split('\n').take(upper_prompt_lines) {
+            let (printed, visible) = util::split_prompt_markers(line);
+            let width = util::width(&util::remove_codes(&visible));
+            lines.push(FrameLine { text: printed.into_owned(), width: width, buf_line: None });
+        }
 
-            let new_num_lines = (new_total_width + terminal_width) / terminal_width;
+        // If we're cycling through completions, show those
+        if let Some((completions, i)) = self.show_completions_hint.as_ref() {
+            let highlight = &self.context.theme.completion_highlight;
+            for line in Self::completion_list_lines(completions, *i, highlight, terminal_width) {
+                let width = util::width(&util::remove_codes(&line));
+                lines.push(FrameLine { text: line, width: width, buf_line: None });
+            }
+        }
+        let first_buf_line = lines.len();
+
+        // If we have an autosuggestion, we make the autosuggestion the buffer we print out.
+        // We get the number of bytes in the buffer (but NOT the autosuggestion).
+        // Then, we loop and subtract from that number until it's 0, in which case we are printing
+        // the autosuggestion from here on (in a different color).
+        let suggestion = if show_autosuggest { self.autosuggestion.as_ref() } else { None };
+        let buf_lines = match suggestion {
+            Some(suggestion) => suggestion.lines(),
+            None => buf.lines(),
+        };
 
-            // Move the term cursor to the same line as the prompt.
-            if self.term_cursor_line > 1 {
-                output_buf.append(cursor::Up(self.term_cursor_line as u16 - 1).to_string().as_bytes());
+        // Control characters and tabs take up more columns than they do chars.
+        let tab_width = self.context.tab_width;
+        let shown_width = |s: &str| util::width(&util::visible(s, 0, tab_width));
+        let buf_widths: Vec<usize> = buf_lines.iter().map(|l| shown_width(l)).collect();
+        // Width of the current buffer lines (including autosuggestion) from the start to the cursor
+        let buf_widths_to_cursor: Vec<usize> = match suggestion {
+            // Cursor might overrun autosuggestion with history search.
+            Some(suggestion) if cursor < suggestion.num_chars() =>
+                suggestion.range(0, cursor),
+            _ => buf.range(0, cursor),
+        }.split('\n').map(shown_width).collect();
+        let mut buf_num_remaining_bytes = buf.num_bytes();
+
+        // Where the search term is found in the line found by incremental search, or in an
+        // autosuggestion that doesn't start with the typed text, in bytes.
+        let search_match = match suggestion {
+            Some(suggestion) if self.is_search()
+                || !(buf.is_empty() || suggestion.equals(buf) || suggestion.starts_with(buf)) => {
+                let term = buf.to_string();
+                suggestion.to_string().find(&term).map(|start| (start, start + term.len()))
             }
+            _ => None,
+        };
+        let mut line_start = 0;
+
+        let cursor_buf_line = buf_widths_to_cursor.len().saturating_sub(1);
+        let cursor_buf_col = buf_widths_to_cursor.last().cloned().unwrap_or(0);
+
+        // Without wrapping, each line gets the columns after the prompt, save for the last one
+        // so the cursor can still go after the end of the text.
+        let hscroll = self.context.horizontal_scroll;
+        let hscroll_width = cmp::max(1, terminal_width.saturating_sub(prompt_width + 1));
+        if !hscroll || self.is_search() {
+            self.hscroll = 0;
+        } else if cursor_buf_col < self.hscroll {
+            self.hscroll = cursor_buf_col;
+        } else if cursor_buf_col > self.hscroll + hscroll_width {
+            self.hscroll = cursor_buf_col - hscroll_width;
+        }
 
-            if ! self.no_newline {
-                output_buf.append("⏎".as_bytes());
-                for _ in 0..(terminal_width - 1) {
-                    output_buf.push(b' ');
+        for (i, line) in buf_lines.into_iter().enumerate() {
+            let mut text = String::new();
+
+            if let Some((start, end)) = search_match {
+                // The whole line found is shown, with the part the term matched highlighted.
+                // Autosuggestions show the matched part like typed text instead.
+                let theme = &self.context.theme;
+                let reset = ansi::RESET.to_owned();
+                let (rest_style, match_style) = if self.is_search() {
+                    (&theme.search_result, &theme.search_highlight)
+                } else {
+                    (&theme.autosuggestion, &reset)
+                };
+                let clamp = |pos: usize| cmp::min(pos.saturating_sub(line_start), line.len());
+                let (start, end) = (clamp(start), clamp(end));
+                let before = util::visible(&line[..start], 0, tab_width);
+                let matched = util::visible(&line[start..end], util::width(&before), tab_width);
+                let after_col = util::width(&before) + util::width(&matched);
+                text.push_str(rest_style);
+                text.push_str(&before);
+                if !matched.is_empty() {
+                    text.push_str(match_style);
+                    text.push_str(&matched);
+                    text.push_str(&reset);
+                    text.push_str(rest_style);
+                }
+                text.push_str(&util::visible(&line[end..], after_col, tab_width));
+                text.push_str(ansi::RESET);
+            } else {
+                let suggestion_style = if self.is_search() {
+                    &self.context.theme.search_result
+                } else {
+                    &self.context.theme.autosuggestion
+                };
+                if self.is_search() {
+                    text.push_str(suggestion_style);
+                }
+                let split = cmp::min(buf_num_remaining_bytes, line.len());
+                let typed = util::visible(&line[..split], 0, tab_width);
+                let suggested = util::visible(&line[split..], util::width(&typed), tab_width);
+                if buf_num_remaining_bytes > 0 {
+                    match self.closure {
+                        Some(ref f) => text.push_str(&f(&typed)),
+                        None => text.push_str(&typed),
+                    }
+                }
+                if buf_num_remaining_bytes == 0 || !suggested.is_empty() {
+                    text.push_str(suggestion_style);
+                    text.push_str(&suggested);
+                }
+                buf_num_remaining_bytes -= split;
+                if self.is_search() || buf_num_remaining_bytes == 0 && suggestion.is_some() {
+                    text.push_str(ansi::RESET);
                 }
             }
+            line_start += line.len() + 1;
 
-            output_buf.push(b'\r');
-            output_buf.append(clear::AfterCursor.as_ref());
-
-            // If we're cycling through completions, show those
-            let mut completion_lines = 0;
-            if let Some((completions, i)) = self.show_completions_hint.as_ref() {
-                completion_lines = 1 + Self::print_completion_list(completions, *i)?;
-                output_buf.append(b"\r\n");
+            let mut width = buf_widths.get(i).cloned().unwrap_or(0);
+            let offset = if hscroll && i == cursor_buf_line { self.hscroll } else { 0 };
+            if hscroll {
+                text = util::clip_columns(&text, offset, hscroll_width);
+                width = cmp::min(width.saturating_sub(offset), hscroll_width);
             }
 
-            // Write the prompt
-            if ! self.no_newline {
-                for line in prompt.split('\n') {
-                    output_buf.append(line.as_bytes());
-                    output_buf.append(b"\r\n");
-                }
-                output_buf.pop(); // pop the '\n'
-                output_buf.pop(); // pop the '\r'
+            // Lines after the first are lined up with the end of the prompt.
+            if i == 0 {
+                text.insert_str(0, util::handle_prompt(&prompt));
             } else {
-                output_buf.append(util::handle_prompt(&prompt).as_bytes());
+                text.insert_str(0, &" ".repeat(prompt_width));
             }
 
-            // If we have an autosuggestion, we make the autosuggestion the buffer we print out.
-            // We get the number of bytes in the buffer (but NOT the autosuggestion).
-            // Then, we loop and subtract from that number until it's 0, in which case we are printing
-            // the autosuggestion from here on (in a different color).
-            let lines = if show_autosuggest {
-                match self.autosuggestion {
-                    Some(ref suggestion) => suggestion.lines(),
-                    None => buf.lines(),
-                }
+            lines.push(FrameLine {
+                text: text,
+                width: prompt_width + width,
+                buf_line: Some((i, offset)),
+            });
+        }
+
+        // While searching, the matches from the current one on can be listed under the line.
+        if let (Some(loc), true) = (self.history_subset_loc, show_autosuggest && self.is_search()) {
+            let count = self.context.search_preview;
+            let shown: Vec<usize> = if self.reverse_search {
+                (0..loc + 1).rev().take(count).collect()
             } else {
-                buf.lines()
+                (loc..self.history_subset_index.len()).take(count).collect()
             };
-            let mut buf_num_remaining_bytes = buf.num_bytes();
-
-            let lines_len = lines.len();
-            for (i, line) in lines.into_iter().enumerate() {
-                if i > 0 {
-                    output_buf.append(cursor::Right(prompt_width as u16).to_string().as_bytes());
+            let term = self.new_buf.to_string();
+            for (n, &i) in shown.iter().enumerate() {
+                let entry = self.context.history[self.history_subset_index[i]].to_string();
+                let line_style = if n == 0 { self.context.theme.completion_highlight.clone() } else { String::new() };
+                // The part the term matched is highlighted like in the line itself.
+                let (start, end) = match entry.find(&term) {
+                    Some(start) if !term.is_empty() => (start, start + term.len()),
+                    _ => (entry.len(), entry.len()),
+                };
+                let before = util::visible(&entry[..start], 0, tab_width);
+                let matched = util::visible(&entry[start..end], util::width(&before), tab_width);
+                let after_col = util::width(&before) + util::width(&matched);
+                let after = util::visible(&entry[end..], after_col, tab_width);
+                let mut text = format!("{}{}", line_style, before);
+                if !matched.is_empty() {
+                    text.push_str(&format!("{}{}{}{}", self.context.theme.search_highlight, matched, ansi::RESET, line_style));
                 }
+                text.push_str(&after);
+                let text = util::clip_columns(&text, 0, terminal_width.saturating_sub(1));
+                let width = util::width(&util::remove_codes(&text));
+                let text = if line_style.is_empty() { text } else { format!("{}{}", text, ansi::RESET) };
+                lines.push(FrameLine { text: text, width: width, buf_line: None });
+            }
+        }
 
-                if buf_num_remaining_bytes == 0 {
-                    output_buf.append(line.as_bytes());
-                } else if line.len() > buf_num_remaining_bytes {
-                    let start = &line[..buf_num_remaining_bytes];
-                    let start = match self.closure {
-                        Some(ref f) => f(start),
-                        None => start.to_owned(),
-                    };
-                    if self.is_search() {
-                        output_buf.append(color::Yellow.fg_str().as_bytes());
-                    }
-                    output_buf.append(start.as_bytes());
-                    if !self.is_search() {
-                        output_buf.append(color::Yellow.fg_str().as_bytes());
-                    }
-                    output_buf.append(line[buf_num_remaining_bytes..].as_bytes());
-                    buf_num_remaining_bytes = 0;
-                } else {
-                    buf_num_remaining_bytes -= line.len();
-                    let written_line = match self.closure {
-                        Some(ref f) => f(&line),
-                        None => line,
-                    };
-                    if self.is_search() {
-                        output_buf.append(color::Yellow.fg_str().as_bytes());
-                    }
-                    output_buf.append(written_line.as_bytes());
-                }
+        if let (Some(pending), true) = (self.pending_completion.as_ref(), show_autosuggest) {
+            let text = format!("{} completing", COMPLETION_SPINNER[pending.frame % COMPLETION_SPINNER.len()]);
+            lines.push(FrameLine { width: util::width(&text), text: text, buf_line: None });
+        }
 
-                if i + 1 < lines_len {
-                    output_buf.append(b"\r\n");
-                }
+        // The message goes under the buffer, but not once the line is finished.
+        if let (Some(message), true) = (self.message.as_ref(), show_autosuggest) {
+            for line in message.split('\n') {
+                let width = util::width(&util::remove_codes(line));
+                lines.push(FrameLine { text: line.to_owned(), width: width, buf_line: None });
+            }
+        }
+
+        // Colors from the theme and the highlighting closure are taken out again in one go.
+        if !self.draws_color() {
+            for line in &mut lines {
+                line.text = util::remove_sgr_codes(&line.text).into_owned();
+            }
+        } else if self.context.capabilities.colors < Colors::TrueColor {
+            // RGB colors are brought down to ones the terminal has in the same pass.
+            for line in &mut lines {
+                line.text = util::downgrade_colors(&line.text, self.context.capabilities.colors).into_owned();
             }
+        }
+
+        let mut cursor_line = first_buf_line + cursor_buf_line;
+        let cursor_col = cursor_buf_col - self.hscroll + if self.is_search() {
+            rev_prompt_width
+        } else {
+            prompt_width
+        };
 
-            if self.is_currently_showing_autosuggestion() || self.is_search() {
-                output_buf.append(color::Reset.fg_str().as_bytes());
+        // Only draw a window of lines around the cursor if they don't all fit, since the
+        // terminal cursor can't be moved back up to lines that have scrolled off the screen.
+        if show_autosuggest {
+            let rows = |line: &FrameLine| line.width / terminal_width + 1;
+
+            if self.window_top > cursor_line {
+                self.window_top = cursor_line;
+            }
+            let mut used: usize = lines[self.window_top..cursor_line + 1].iter().map(&rows).sum();
+            while self.window_top < cursor_line && used > terminal_height {
+                used -= rows(&lines[self.window_top]);
+                self.window_top += 1;
             }
 
-            // at the end of the line, move the cursor down a line
-            if new_total_width % terminal_width == 0 {
-                output_buf.append(b"\r\n");
+            let mut end = cursor_line + 1;
+            while end < lines.len() && used + rows(&lines[end]) <= terminal_height {
+                used += rows(&lines[end]);
+                end += 1;
             }
+            while self.window_top > 0 && used + rows(&lines[self.window_top - 1]) <= terminal_height {
+                self.window_top -= 1;
+                used += rows(&lines[self.window_top]);
+            }
+
+            lines.truncate(end);
+            lines.drain(..self.window_top);
+            cursor_line -= self.window_top;
+        }
+
+        let mut frame = Frame {
+            lines: lines,
+            terminal_width: terminal_width,
+            prompt_width: prompt_width,
+            cursor: (0, 0),
+        };
+        frame.cursor = (
+            frame.line_row(cursor_line) + cursor_col / terminal_width,
+            cursor_col % terminal_width,
+        );
+        frame
+    }
+
+    // Draws the prompt and buffer. `show_autosuggest` is false once the line is finished, which
+    // also writes out every line, even if they don't all fit on the terminal.
+    fn _display(&mut self, show_autosuggest: bool) -> io::Result<()> {
+        if self.flash_remaining() == Some(Duration::from_millis(0)) {
+            self.end_flash()?;
+        }
+        let started = Instant::now();
+        let (terminal_width, terminal_height) = self.terminal_size()?;
+        let frame = self.layout(show_autosuggest, terminal_width, terminal_height);
+        BUFFER.with(|output_buf| {
+            match self.frame.take() {
+                Some(ref old) if old.terminal_width == terminal_width => {
+                    // Lines that haven't changed are left alone, as is the unchanged start of the
+                    // first line that has.
+                    let changed = frame.lines.iter()
+                        .zip(old.lines.iter())
+                        .position(|(new, old)| new.text != old.text);
+                    let (index, skip) = match changed {
+                        Some(i) => (i, util::common_prefix_len(&frame.lines[i].text, &old.lines[i].text)),
+                        None if frame.lines.len() == old.lines.len() => {
+                            move_term_cursor(output_buf, old.cursor, frame.cursor);
+                            (frame.lines.len(), 0)
+                        }
+                        // Carry on from the end of the last line both frames share.
+                        None => {
+                            let last = cmp::min(frame.lines.len(), old.lines.len()) - 1;
+                            (last, frame.lines[last].text.len())
+                        }
+                    };
+
+                    if index < frame.lines.len() {
+                        let line = &frame.lines[index];
+                        let mut skip_width = util::width(&util::remove_codes(&line.text[..skip]));
+                        let mut skip = skip;
+                        // The terminal cursor can't be parked past the end of a full row.
+                        if skip_width > 0 && skip_width % terminal_width == 0 {
+                            skip = 0;
+                            skip_width = 0;
+                        }
+
+                        let start = (frame.line_row(index) + skip_width / terminal_width, skip_width % terminal_width);
+                        move_term_cursor(output_buf, old.cursor, start);
+                        frame.write_from(output_buf, index, skip);
+
+                        // Nothing is left over from the old frame if its last line was rewritten
+                        // with one at least as wide.
+                        let covered = index + 1 == old.lines.len() && index + 1 == frame.lines.len() &&
+                            frame.lines[index].width >= old.lines[index].width;
+                        if !covered {
+                            output_buf.append(ansi::CLEAR_AFTER_CURSOR.as_bytes());
+                        }
+                        move_term_cursor(output_buf, frame.end(), frame.cursor);
+                    }
+                }
+                old => {
+                    // Move the term cursor to the start of the first line drawn.
+                    if let Some(old) = old {
+                        move_term_cursor(output_buf, old.cursor, (0, old.cursor.1));
+                    }
+
+                    if ! self.no_newline {
+                        output_buf.append("⏎".as_bytes());
+                        for _ in 0..(terminal_width - 1) {
+                            output_buf.push(b' ');
+                        }
+                    }
 
-            self.term_cursor_line = (new_total_width_to_cursor + terminal_width) / terminal_width;
+                    output_buf.push(b'\r');
+                    output_buf.append(ansi::CLEAR_AFTER_CURSOR.as_bytes());
 
-            // The term cursor is now on the bottom line. We may need to move the term cursor up
-            // to the line where the true cursor is.
-            let cursor_line_diff = new_num_lines as isize - self.term_cursor_line as isize;
-            if cursor_line_diff > 0 {
-                output_buf.append(cursor::Up(cursor_line_diff as u16).to_string().as_bytes());
-            } else if cursor_line_diff < 0 {
-                unreachable!();
+                    frame.write_from(output_buf, 0, 0);
+                    move_term_cursor(output_buf, frame.end(), frame.cursor);
+                }
             }
 
-            // Now that we are on the right line, we must move the term cursor left or right
-            // to match the true cursor.
-            let cursor_col_diff = new_total_width as isize - new_total_width_to_cursor as isize -
-                cursor_line_diff * terminal_width as isize;
-            if cursor_col_diff > 0 {
-                output_buf.append(cursor::Left(cursor_col_diff as u16).to_string().as_bytes());
-            } else if cursor_col_diff < 0 {
-                output_buf.append(cursor::Right((-cursor_col_diff) as u16).to_string().as_bytes());
+            // Writing past the bottom of the terminal scrolls everything up.
+            if let Some(top) = self.frame_top {
+                let bottom = top + frame.end().0;
+                if bottom > terminal_height {
+                    self.frame_top = Some(cmp::max(1, top.saturating_sub(bottom - terminal_height)));
+                }
             }
 
-            self.term_cursor_line += completion_lines;
+            self.frame = Some(frame);
 
+            let mut written = 0;
             {
                 let out = &mut self.out;
-                output_buf.extract(|b| out.write_all(b))?;
-                out.flush()
+                output_buf.extract(|b| {
+                    written = b.len();
+                    out.write_all(b)
+                })?;
+                out.flush()?;
             }
+            self.context.metrics.add_redraw(started.elapsed(), written);
+            Ok(())
         })
     }
 
     /// Deletes the displayed prompt and buffer, replacing them with the current prompt and buffer
     pub fn display(&mut self) -> io::Result<()> {
+        self.refresh_display();
+        self._display(true)
+    }
+
+    /// Lays out the prompt and buffer in `width` columns and at most `height` rows without
+    /// drawing them, for showing them somewhere other than at the bottom of the terminal, such
+    /// as in a widget of a full-screen program.
+    pub fn render(&mut self, width: usize, height: usize) -> Rendered {
+        let (width, height) = (cmp::max(1, width), cmp::max(1, height));
+        self.refresh_display();
+        mem::swap(&mut self.window_top, &mut self.render_scroll.0);
+        mem::swap(&mut self.hscroll, &mut self.render_scroll.1);
+        let frame = self.layout(true, width, height);
+        mem::swap(&mut self.window_top, &mut self.render_scroll.0);
+        mem::swap(&mut self.hscroll, &mut self.render_scroll.1);
+
+        let mut rows = vec![];
+        for (i, line) in frame.lines.iter().enumerate() {
+            for row in 0..frame.line_rows(i) {
+                rows.push(util::clip_columns(&line.text, row * width, width));
+            }
+        }
+        // Keep the cursor in view, if there are more rows than fit.
+        let (mut cursor_row, cursor_col) = frame.cursor;
+        let first = (cursor_row + 1).saturating_sub(height);
+        rows.drain(..first);
+        rows.truncate(height);
+        cursor_row -= first;
+
+        Rendered {
+            rows: rows,
+            cursor: (cursor_row, cursor_col),
+        }
+    }
+
+    fn refresh_display(&mut self) {
         if self.is_search() && self.buffer_changed {
             // Refresh incremental search.
             let forward = self.forward_search;
             self.refresh_search(forward);
         }
         self.autosuggestion = self.current_autosuggestion();
-
-        self._display(true)
     }
 }
 
@@ -1135,10 +2803,62 @@ impl<'a, W: Write> From<Editor<'a, W>> for String {
     }
 }
 
+impl<'a, W: Write + RawMode> Editor<'a, W> {
+    /// Takes the terminal out of raw mode while `f` runs, e.g. to run a command in the foreground,
+    /// then puts it back and draws the prompt and buffer again below whatever `f` printed.
+    pub fn suspend<T, F: FnOnce() -> T>(&mut self, f: F) -> io::Result<T> {
+        // Leave the line as it is, with the terminal cursor on the row after it.
+        let cursor = self.cursor;
+        self.cursor = cur_buf!(self).num_chars();
+        self.no_newline = true;
+        self._display(false)?;
+        self.out.write_all(b"\r\n")?;
+        self.frame = None;
+        self.frame_top = None;
+        self.cursor = cursor;
+
+        let (mouse, bracketed_paste) = (self.mouse, self.bracketed_paste);
+        let (focus_events, alternate_screen) = (self.focus_events, self.alternate_screen);
+        let cursor_shape = self.cursor_shape;
+        self.end_flash()?;
+        self.reset_cursor_shape()?;
+        self.disable_mouse()?;
+        self.disable_bracketed_paste()?;
+        self.disable_focus_events()?;
+        self.leave_alternate_screen()?;
+        self.out.flush()?;
+        self.out.suspend_raw_mode()?;
+
+        let res = f();
+
+        self.out.activate_raw_mode()?;
+        if alternate_screen {
+            self.enter_alternate_screen()?;
+        } else {
+            self.display()?;
+        }
+        if mouse {
+            self.enable_mouse()?;
+        }
+        if bracketed_paste {
+            self.enable_bracketed_paste()?;
+        }
+        if focus_events {
+            self.enable_focus_events()?;
+        }
+        self.set_cursor_shape(cursor_shape)?;
+        Ok(res)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::thread;
+    use {BasicCompleter, FilenameCompleter, LineCompleter};
     use Context;
+    use MatchAnchor;
+    use {HistoryEntry, HistoryLayer};
 
     #[test]
     /// test undoing delete_all_after_cursor
@@ -1233,4 +2953,1135 @@ mod tests {
         assert_eq!(ed.cursor, 1);
         assert_eq!(String::from(ed), "r");
     }
+
+    #[test]
+    fn editor_word_divider_overrides_context() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("cd /usr/lo").unwrap();
+        assert_eq!(ed.get_words_and_cursor_position().0, vec![(0, 2), (3, 10)]);
+
+        ed.set_word_divider_fn(Some(Box::new(::get_path_component_words)));
+        assert_eq!(ed.get_words_and_cursor_position().0, vec![(0, 2), (4, 7), (8, 10)]);
+
+        ed.delete_word_before_cursor(false).unwrap();
+        assert_eq!(String::from(ed), "cd /usr/");
+    }
+
+    #[test]
+    fn move_word_left_and_right() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("abc def ghi").unwrap();
+
+        ed.move_word_left().unwrap();
+        assert_eq!(ed.cursor, 8);
+        ed.move_word_left().unwrap();
+        assert_eq!(ed.cursor, 4);
+        ed.move_word_right().unwrap();
+        assert_eq!(ed.cursor, 7);
+        ed.move_word_right().unwrap();
+        assert_eq!(ed.cursor, 11);
+        ed.move_word_right().unwrap();
+        assert_eq!(ed.cursor, 11);
+    }
+
+    #[test]
+    fn delete_word_after_cursor() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("abc def ghi").unwrap();
+        ed.cursor = 3;
+
+        ed.delete_word_after_cursor().unwrap();
+        assert_eq!(ed.cursor, 3);
+        assert_eq!(String::from(ed), "abc ghi");
+    }
+
+    #[test]
+    fn change_word_case() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("hELLO big world").unwrap();
+        ed.cursor = 0;
+
+        ed.capitalize_word().unwrap();
+        assert_eq!(ed.cursor, 5);
+        ed.upcase_word().unwrap();
+        assert_eq!(ed.cursor, 9);
+        ed.move_cursor_left(3).unwrap();
+        ed.downcase_word().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "Hello big world");
+
+        ed.undo().unwrap();
+        assert_eq!(String::from(ed), "Hello BIG world");
+    }
+
+    #[test]
+    fn transpose_chars() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("abcd").unwrap();
+
+        // at the end of the line, the last two chars are swapped
+        ed.transpose_chars().unwrap();
+        assert_eq!(ed.cursor, 4);
+        assert_eq!(ed.current_buffer().to_string(), "abdc");
+
+        ed.cursor = 1;
+        ed.transpose_chars().unwrap();
+        assert_eq!(ed.cursor, 2);
+        assert_eq!(ed.current_buffer().to_string(), "badc");
+
+        ed.cursor = 0;
+        ed.transpose_chars().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "badc");
+
+        ed.undo().unwrap();
+        assert_eq!(String::from(ed), "abdc");
+    }
+
+    #[test]
+    fn transpose_words() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("one two  three").unwrap();
+
+        // at the end of the line, the last two words are swapped
+        ed.transpose_words().unwrap();
+        assert_eq!(ed.cursor, 14);
+        assert_eq!(ed.current_buffer().to_string(), "one three  two");
+
+        ed.cursor = 1;
+        ed.transpose_words().unwrap();
+        assert_eq!(ed.cursor, 9);
+        assert_eq!(ed.current_buffer().to_string(), "three one  two");
+
+        ed.undo().unwrap();
+        assert_eq!(String::from(ed), "one three  two");
+    }
+
+    /// Runs `f` on the editor and returns what it wrote to the terminal.
+    fn written<F: FnOnce(&mut Editor<Vec<u8>>)>(ed: &mut Editor<Vec<u8>>, f: F) -> String {
+        let start = ed.out.len();
+        f(ed);
+        String::from_utf8(ed.out[start..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn redraw_only_writes_appended_text() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("ab").unwrap();
+        assert_eq!(written(&mut ed, |ed| ed.insert_after_cursor('c').unwrap()), "c");
+    }
+
+    #[test]
+    fn redraw_only_moves_cursor() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("abc").unwrap();
+        assert_eq!(
+            written(&mut ed, |ed| ed.move_cursor_left(2).unwrap()),
+            ansi::left(2)
+        );
+        assert_eq!(written(&mut ed, |ed| ed.display().unwrap()), "");
+    }
+
+    #[test]
+    fn redraw_rewrites_from_first_change() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("abd").unwrap();
+        ed.move_cursor_left(1).unwrap();
+        assert_eq!(
+            written(&mut ed, |ed| ed.insert_after_cursor('c').unwrap()),
+            format!("cd{}", ansi::left(1))
+        );
+        assert_eq!(
+            written(&mut ed, |ed| ed.delete_all_after_cursor().unwrap()),
+            ansi::CLEAR_AFTER_CURSOR
+        );
+    }
+
+    #[test]
+    fn redraw_after_clear_starts_over() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("abc").unwrap();
+        let out = written(&mut ed, |ed| ed.clear().unwrap());
+        assert!(out.ends_with("promptabc"));
+    }
+
+    #[test]
+    fn cursor_moves_over_grapheme_clusters() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("ae\u{301}b").unwrap();
+        ed.move_cursor_left(2).unwrap();
+        assert_eq!(ed.cursor, 1);
+        ed.move_cursor_right(1).unwrap();
+        assert_eq!(ed.cursor, 3);
+        ed.delete_before_cursor().unwrap();
+        assert_eq!(String::from(ed), "ab");
+    }
+
+    #[test]
+    fn cursor_never_rests_inside_grapheme_cluster() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("ae\u{301}b").unwrap();
+        ed.move_cursor_to(2).unwrap();
+        assert_eq!(ed.cursor, 1);
+        ed.delete_after_cursor().unwrap();
+        assert_eq!(String::from(ed), "ab");
+    }
+
+    #[test]
+    fn initial_cursor_is_clamped_to_the_buffer() {
+        let mut context = Context::new();
+        let ed = Editor::new_with_init_buffer_and_cursor(Vec::new(), "$ ", None, &mut context, "ls", 10).unwrap();
+        assert_eq!(ed.cursor, 2);
+    }
+
+    #[test]
+    fn initial_cursor_never_rests_inside_grapheme_cluster() {
+        let mut context = Context::new();
+        let ed = Editor::new_with_init_buffer_and_cursor(Vec::new(), "$ ", None, &mut context, "ae\u{301}b", 2).unwrap();
+        assert_eq!(ed.cursor, 1);
+    }
+
+    #[test]
+    fn completion_grid_pads_by_display_width() {
+        let completions = vec!["\u{4e16}\u{754c}".to_owned(), "ab".to_owned(), "abcd".to_owned()];
+        let lines = Editor::<Vec<u8>>::completion_list_lines(&completions, None, "", 80);
+        assert_eq!(lines, vec!["\u{4e16}\u{754c}  ab    abcd  ".to_owned()]);
+    }
+
+    #[test]
+    fn control_chars_and_tabs_are_shown_expanded() {
+        let mut context = Context::new();
+        context.tab_width = 4;
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("a\x01\tb").unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.lines[0].text, "$ a^A b");
+            assert_eq!(frame.cursor, (0, 7));
+        }
+        assert_eq!(String::from(ed), "a\x01\tb");
+    }
+
+    #[test]
+    fn prompt_zero_width_markers() {
+        let mut context = Context::new();
+        let prompt = format!("\\[\x1B]0;title\x07\\]{}$\\[{}\\] ", ansi::fg(ansi::RED), "\x1B[39m");
+        let mut ed = Editor::new(Vec::new(), prompt, None, &mut context).unwrap();
+        ed.insert_str_after_cursor("ab").unwrap();
+        let frame = ed.frame.as_ref().unwrap();
+        assert_eq!(
+            frame.lines[0].text,
+            format!("\x1B]0;title\x07{}${} ab", ansi::fg(ansi::RED), "\x1B[39m")
+        );
+        assert_eq!(frame.cursor, (0, 4));
+    }
+
+    #[test]
+    fn tall_buffer_is_drawn_in_a_window() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        let lines: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        ed.insert_str_after_cursor(&lines.join("\n")).unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.lines.len(), 24);
+            assert_eq!(frame.lines[23].text, "  39");
+            assert_eq!(frame.cursor, (23, 4));
+        }
+
+        ed.move_cursor_to(0).unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.lines[0].text, "$ 0");
+            assert_eq!(frame.lines.len(), 24);
+            assert_eq!(frame.cursor, (0, 2));
+        }
+    }
+
+    #[test]
+    fn horizontal_scroll_follows_cursor() {
+        let mut context = Context::new();
+        context.horizontal_scroll = true;
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        let text: String = (0..100).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        ed.insert_str_after_cursor(&text).unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.lines.len(), 1);
+            assert_eq!(frame.lines[0].text, format!("$ {}", &text[23..]));
+            assert_eq!(frame.cursor, (0, 79));
+        }
+
+        ed.move_cursor_to(10).unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.lines[0].text, format!("$ {}", &text[10..87]));
+            assert_eq!(frame.cursor, (0, 2));
+        }
+    }
+
+    #[test]
+    fn cursor_shape_is_only_written_on_change() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        assert_eq!(written(&mut ed, |ed| ed.set_cursor_shape(None).unwrap()), "");
+        assert_eq!(written(&mut ed, |ed| ed.reset_cursor_shape().unwrap()), "");
+        assert_eq!(
+            written(&mut ed, |ed| ed.set_cursor_shape(Some(CursorShape::SteadyBar)).unwrap()),
+            "\x1B[6 q"
+        );
+        assert_eq!(written(&mut ed, |ed| ed.set_cursor_shape(Some(CursorShape::SteadyBar)).unwrap()), "");
+        assert_eq!(written(&mut ed, |ed| ed.reset_cursor_shape().unwrap()), "\x1B[0 q");
+    }
+
+    #[test]
+    fn click_moves_cursor() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        let text: String = (0..100).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        ed.insert_str_after_cursor(&text).unwrap();
+        ed.insert_str_after_cursor("\nx\ty").unwrap();
+        ed.frame_top = Some(5);
+        ed.mouse = true;
+
+        ed.click(5, 5).unwrap();
+        assert_eq!(ed.cursor, 2);
+
+        // on the row the first line wrapped onto
+        ed.click(1, 6).unwrap();
+        assert_eq!(ed.cursor, 78);
+
+        // past the end of the line
+        ed.click(50, 6).unwrap();
+        assert_eq!(ed.cursor, 100);
+
+        // inside of the tab on the second line
+        ed.click(7, 7).unwrap();
+        assert_eq!(ed.cursor, 102);
+
+        // above the prompt
+        ed.click(7, 4).unwrap();
+        assert_eq!(ed.cursor, 102);
+    }
+
+    #[test]
+    fn kill_copies_to_clipboard_with_osc52() {
+        let mut context = Context::new();
+        context.osc52_clipboard = true;
+        context.osc52_max_len = 8;
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("hello world there").unwrap();
+
+        let out = written(&mut ed, |ed| ed.delete_word_before_cursor(true).unwrap());
+        assert!(out.starts_with("\x1B]52;c;dGhlcmU=\x07"));
+
+        // too long to send
+        let out = written(&mut ed, |ed| ed.delete_all_before_cursor().unwrap());
+        assert!(!out.contains("\x1B]52"));
+        assert_eq!(ed.context().kill_buffer.text, "hello world ");
+    }
+
+    #[test]
+    fn paste_is_one_undo_step() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.set_undo_policy(UndoPolicy::PerInsertSession);
+        ed.insert_str_after_cursor("ab").unwrap();
+        ed.paste("cd ef").unwrap();
+        ed.insert_str_after_cursor("g").unwrap();
+        assert_eq!(ed.cursor, 8);
+
+        ed.undo().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "abcd ef");
+        ed.undo().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "ab");
+    }
+
+    #[test]
+    fn undo_limit_from_context() {
+        let mut context = Context::new();
+        context.undo_limit = Some(2);
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        for c in "abcd".chars() {
+            ed.insert_after_cursor(c).unwrap();
+        }
+        while ed.undo().unwrap() {}
+        assert_eq!(ed.current_buffer().to_string(), "ab");
+    }
+
+    #[test]
+    fn put_block() {
+        let mut context = Context::new();
+        context.kill_buffer = Register::new("12\n34\n56", RegisterKind::Blockwise);
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("abc\nd").unwrap();
+        ed.move_cursor_to(1).unwrap();
+        ed.put(true).unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "ab12c\nd 34\n  56");
+        assert_eq!(ed.cursor, 2);
+
+        ed.undo().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "abc\nd");
+    }
+
+    #[test]
+    fn geometry() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        let text: String = ::std::iter::repeat('a').take(100).collect();
+        ed.insert_str_after_cursor(&text).unwrap();
+        ed.move_cursor_to(10).unwrap();
+        assert_eq!(ed.geometry(), Some(Geometry {
+            top: None,
+            rows: 2,
+            cursor: (0, 12),
+            prompt: Some((0, 0)),
+        }));
+
+        ed.frame_top = Some(3);
+        ed.show_completions_hint = Some((vec!["x".into(), "y".into()], None));
+        ed.display().unwrap();
+        let geometry = ed.geometry().unwrap();
+        assert_eq!(geometry.top, Some(3));
+        assert_eq!(geometry.prompt, Some((1, 0)));
+        assert_eq!(geometry.cursor, (1, 12));
+        assert_eq!(geometry.rows, 3);
+    }
+
+    #[test]
+    fn message_under_buffer() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("ab\ncd").unwrap();
+        ed.move_cursor_to(1).unwrap();
+        ed.show_message("first\nsecond").unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            let texts: Vec<&str> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+            assert_eq!(texts, vec!["$ ab", "  cd", "first", "second"]);
+            assert_eq!(frame.cursor, (0, 3));
+        }
+
+        ed.clear_message().unwrap();
+        assert_eq!(ed.frame.as_ref().unwrap().lines.len(), 2);
+    }
+
+    #[test]
+    fn replace_range_keeps_cursor_on_text() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("ll -a foo").unwrap();
+        ed.move_cursor_to(6).unwrap();
+
+        ed.replace_range(0, 2, "ls -l").unwrap();
+        assert_eq!(ed.cursor, 9);
+        assert_eq!(ed.current_buffer().to_string(), "ls -l -a foo");
+        assert_eq!(ed.frame.as_ref().unwrap().lines[0].text, "$ ls -l -a foo");
+
+        ed.replace_range(8, 100, "bar").unwrap();
+        assert_eq!(ed.cursor, 11);
+        ed.undo().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "ls -l -a foo");
+
+        ed.set_buffer("echo").unwrap();
+        assert_eq!(ed.cursor, 4);
+        ed.undo().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "ls -l -a foo");
+
+        ed.clear_and_set("exit").unwrap();
+        assert!(!ed.undo().unwrap());
+        assert_eq!(String::from(ed), "exit");
+    }
+
+    #[test]
+    fn prefix_search_keeps_cursor() {
+        let mut context = Context::new();
+        context.history_prefix_search = true;
+        for line in &["git status", "ls", "git log", "gitk"] {
+            context.history.push(Buffer::from(*line)).unwrap();
+        }
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("git x").unwrap();
+        ed.move_cursor_to(4).unwrap();
+
+        ed.move_up().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "git log");
+        assert_eq!(ed.cursor, 4);
+        ed.move_up().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "git status");
+        ed.move_up().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "git status");
+        ed.move_down().unwrap();
+        ed.move_down().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "git x");
+        assert_eq!(ed.cursor, 4);
+    }
+
+    #[test]
+    fn autosuggestion_style_from_context() {
+        let mut context = Context::new();
+        context.theme.autosuggestion = "\x1B[2m".to_owned();
+        context.history.push(Buffer::from("hello")).unwrap();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("he").unwrap();
+        assert_eq!(ed.frame.as_ref().unwrap().lines[0].text, "$ he\x1B[2mllo\x1B[0m");
+    }
+
+    #[test]
+    fn no_color_strips_colors() {
+        let mut context = Context::new();
+        context.color = false;
+        context.history.push(Buffer::from("hello")).unwrap();
+        let prompt = format!("{}${} ", ansi::fg(ansi::RED), "\x1B[39m");
+        let mut ed = Editor::new(Vec::new(), prompt, None, &mut context).unwrap();
+        ed.insert_str_after_cursor("he").unwrap();
+        assert_eq!(ed.frame.as_ref().unwrap().lines[0].text, "$ hello");
+    }
+
+    #[test]
+    fn bell_when_nothing_completes() {
+        let mut context = Context::new();
+        context.completer = Some(Box::new(BasicCompleter::new(vec!["abc"])));
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("x").unwrap();
+        assert_eq!(written(&mut ed, |ed| ed.complete(&mut |_| {}, CompleteType::Next).unwrap()), "");
+
+        ed.context().bell_style = BellStyle::Audible;
+        assert_eq!(written(&mut ed, |ed| ed.complete(&mut |_| {}, CompleteType::Next).unwrap()), "\x07");
+
+        // The visible bell doesn't wait for the flash to be over, and the screen is put back by a
+        // redraw once it has been reversed long enough.
+        ed.context().bell_style = BellStyle::Visible;
+        assert_eq!(written(&mut ed, |ed| ed.bell().unwrap()), "\x1B[?5h");
+        assert!(!written(&mut ed, |ed| ed.display().unwrap()).contains("\x1B[?5l"));
+        thread::sleep(FLASH_DURATION);
+        assert_eq!(ed.flash_remaining(), Some(Duration::from_millis(0)));
+        assert!(written(&mut ed, |ed| ed.display().unwrap()).starts_with("\x1B[?5l"));
+        assert_eq!(ed.flash_remaining(), None);
+        assert_eq!(written(&mut ed, |ed| ed.end_flash().unwrap()), "");
+    }
+
+    #[test]
+    fn line_completer_sees_whole_line() {
+        struct GitCompleter;
+        impl LineCompleter for GitCompleter {
+            fn complete(&self, buf: &Buffer, cursor: usize, word: (usize, usize)) -> Vec<String> {
+                let word_text = buf.range(word.0, word.1);
+                let candidates: &[&str] = if word.0 == 0 {
+                    &["git"]
+                } else if buf.range(0, cursor).starts_with("git commit ") {
+                    &["--amend", "--all"]
+                } else {
+                    &["commit", "checkout"]
+                };
+                candidates.iter().filter(|c| c.starts_with(&word_text[..])).map(|&c| c.to_owned()).collect()
+            }
+        }
+
+        let mut context = Context::new();
+        context.completer = Some(Box::new(GitCompleter));
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("git com").unwrap();
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "git commit");
+        ed.insert_str_after_cursor(" --am").unwrap();
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "git commit --amend");
+    }
+
+    #[test]
+    fn secret_input_is_not_completed() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        struct Spy(Arc<AtomicBool>);
+        impl LineCompleter for Spy {
+            fn complete(&self, _: &Buffer, _: usize, _: (usize, usize)) -> Vec<String> {
+                self.0.store(true, Ordering::SeqCst);
+                vec!["hunter2".to_owned()]
+            }
+        }
+
+        let called = Arc::new(AtomicBool::new(false));
+        let mut context = Context::new();
+        context.completer = Some(Box::new(Spy(called.clone())));
+        let options = ReadLineOptions::password("password: ");
+        let mut ed = Editor::with_options(Vec::new(), &mut context, options).unwrap();
+        ed.insert_str_after_cursor("hun").unwrap();
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert!(!called.load(Ordering::SeqCst));
+        assert_eq!(ed.current_buffer().to_string(), "hun");
+        assert!(!String::from_utf8_lossy(&ed.out).contains("hunter2"));
+    }
+
+    #[test]
+    fn completions_replace_their_span() {
+        struct OptionCompleter;
+        impl LineCompleter for OptionCompleter {
+            fn complete(&self, _: &Buffer, _: usize, _: (usize, usize)) -> Vec<String> {
+                vec![]
+            }
+
+            fn complete_with_spans(&self, buf: &Buffer, _: usize, word: (usize, usize)) -> Vec<Completion> {
+                let word_text = buf.range(word.0, word.1);
+                if word_text == "--col" {
+                    vec![Completion::new("--color").with_suffix("=")]
+                } else {
+                    // Completes the command at the start of the line, wherever the cursor is.
+                    vec![
+                        Completion::new("ls").with_span(0, 1).with_suffix(" "),
+                        Completion::new("less").with_span(0, 1).with_suffix(" "),
+                    ]
+                }
+            }
+        }
+
+        let mut context = Context::new();
+        context.completer = Some(Box::new(OptionCompleter));
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("l --col").unwrap();
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "l --color=");
+        assert_eq!(ed.cursor, 10);
+
+        ed.insert_str_after_cursor(" ").unwrap();
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "l --color= ");
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "less --color= ");
+        assert_eq!(ed.cursor, 4);
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "ls --color= ");
+    }
+
+    #[test]
+    fn completions_from_another_thread() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use {AsyncCompleter, CompletionSender};
+
+        let release = Arc::new(AtomicBool::new(false));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (r, c) = (release.clone(), cancelled.clone());
+        let completer = AsyncCompleter::new(move |line: &str, _: usize, _: (usize, usize), sender: &CompletionSender| {
+            while !r.load(Ordering::SeqCst) {
+                if sender.is_cancelled() {
+                    c.store(true, Ordering::SeqCst);
+                    return vec![];
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            vec![Completion::new(format!("{}ing", line))]
+        });
+
+        let mut context = Context::new();
+        context.completer = Some(Box::new(completer));
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("slow").unwrap();
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert!(ed.is_completion_pending());
+        assert!(ed.frame.as_ref().unwrap().lines.iter().any(|l| l.text == "| completing"));
+
+        ed.cancel_completion();
+        for _ in 0..1000 {
+            if cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(cancelled.load(Ordering::SeqCst));
+
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        release.store(true, Ordering::SeqCst);
+        for _ in 0..1000 {
+            if !ed.poll_completion().unwrap() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!ed.is_completion_pending());
+        assert_eq!(ed.current_buffer().to_string(), "slowing");
+        assert!(!ed.frame.as_ref().unwrap().lines.iter().any(|l| l.text.ends_with("completing")));
+    }
+
+    #[test]
+    fn completes_quoted_words() {
+        let mut context = Context::new();
+        context.completer = Some(Box::new(FilenameCompleter::new(Some(env!("CARGO_MANIFEST_DIR")))));
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("cat \"src/lib.r").unwrap();
+        assert_eq!(ed.completion_word(), (4, 14));
+        ed.complete(&mut |_| {}, CompleteType::Next).unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "cat \"src/lib.rs\"");
+
+        ed.delete_all_before_cursor().unwrap();
+        ed.insert_str_after_cursor("cd \"My Doc").unwrap();
+        assert_eq!(ed.completion_word(), (3, 10));
+        ed.delete_all_before_cursor().unwrap();
+        ed.insert_str_after_cursor("cd My\\ Doc").unwrap();
+        assert_eq!(ed.completion_word(), (3, 10));
+    }
+
+    #[test]
+    fn editor_with_options() {
+        let mut context = Context::new();
+        let options = ReadLineOptions::new("name: ")
+            .buffer("guest")
+            .cursor(2)
+            .color_closure(Box::new(|s| s.to_uppercase()));
+        let ed = Editor::with_options(Vec::new(), &mut context, options).unwrap();
+        assert_eq!(ed.cursor, 2);
+        assert_eq!(ed.frame.as_ref().unwrap().lines[0].text, "name: GUEST");
+        assert_eq!(String::from(ed), "guest");
+    }
+
+    #[test]
+    fn masked_input() {
+        let mut context = Context::new();
+        context.history.push(Buffer::from("hunter2 in the history")).unwrap();
+        let options = ReadLineOptions::password("password: ").buffer("hunter");
+        let mut ed = Editor::with_options(Vec::new(), &mut context, options).unwrap();
+        ed.insert_str_after_cursor("2").unwrap();
+        ed.move_cursor_left(1).unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.lines[0].text, "password: *******");
+            assert_eq!(frame.cursor, (0, 16));
+        }
+
+        ed.move_up().unwrap();
+        assert_eq!(ed.current_history_location(), None);
+        assert!(!ed.is_currently_showing_autosuggestion());
+        ed.delete_all_before_cursor().unwrap();
+        assert!(ed.context.kill_buffer.is_empty());
+        assert_eq!(String::from(ed), "2");
+    }
+
+    #[test]
+    fn idle_ticks_redraw_the_prompt() {
+        use Event;
+
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "00:00 $ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("ls").unwrap();
+
+        fn handler(e: Event<Vec<u8>>) {
+            if let EventKind::Idle = e.kind {
+                e.editor.set_prompt("00:01 $ ".to_owned());
+            }
+        }
+        ed.tick(&mut handler).unwrap();
+        let frame = ed.frame.as_ref().unwrap();
+        assert_eq!(frame.lines[0].text, "00:01 $ ls");
+        assert_eq!(frame.cursor, (0, 10));
+    }
+
+    #[test]
+    fn pastes_can_be_cleaned_up() {
+        use Event;
+
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+
+        fn handler(e: Event<Vec<u8>>) {
+            if let EventKind::Paste(ref text) = e.kind {
+                let action = match text.as_str() {
+                    "rm -rf /\n" => EventAction::Cancel,
+                    _ => EventAction::ReplaceBuffer(text.replace('\n', " ")),
+                };
+                e.respond(action);
+            }
+        }
+        ed.handle_paste("echo\nhi".to_owned(), &mut handler).unwrap();
+        ed.handle_paste("rm -rf /\n".to_owned(), &mut handler).unwrap();
+        assert_eq!(String::from(ed), "echo hi");
+    }
+
+    #[test]
+    fn focus_changes_are_sent_to_handlers() {
+        use Event;
+
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.enable_focus_events().unwrap();
+        assert!(String::from_utf8_lossy(&ed.out).ends_with("\x1B[?1004h"));
+
+        fn handler(e: Event<Vec<u8>>) {
+            if let EventKind::Focus(focused) = e.kind {
+                e.editor.set_prompt(if focused { "$ " } else { "(away) $ " }.to_owned());
+            }
+        }
+        ed.handle_focus(false, &mut handler).unwrap();
+        assert!(String::from_utf8_lossy(&ed.out).contains("(away) $ "));
+        ed.handle_unknown_input(b"\x1B[?62c".to_vec(), &mut handler).unwrap();
+        assert_eq!(String::from(ed), "");
+    }
+
+    #[test]
+    fn redraws_are_counted() {
+        let mut context = Context::new();
+        {
+            let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+            ed.insert_str_after_cursor("ab").unwrap();
+            ed.move_cursor_left(1).unwrap();
+        }
+        let metrics = context.metrics.clone();
+        assert_eq!(metrics.redraws, 3);
+        assert!(metrics.bytes_written > 0);
+        assert!(metrics.redraw_time >= metrics.last_redraw_time);
+
+        context.metrics.reset();
+        assert_eq!(context.metrics.redraws, 0);
+    }
+
+    #[test]
+    fn draws_for_the_terminal_it_is_given() {
+        use {Input, RawModeGuard, Terminal};
+
+        struct SmallTerminal;
+        impl Terminal for SmallTerminal {
+            fn size(&mut self) -> io::Result<(u16, u16)> {
+                Ok((10, 5))
+            }
+            fn cursor_pos(&mut self, _: &mut Write) -> io::Result<(u16, u16)> {
+                Ok((1, 3))
+            }
+            fn raw_mode(&mut self) -> io::Result<RawModeGuard> {
+                Ok(RawModeGuard::new(()))
+            }
+            fn input_ready(&mut self, _: Duration) -> bool {
+                true
+            }
+            fn read_input(&mut self) -> Option<io::Result<Input>> {
+                None
+            }
+        }
+
+        let mut context = Context::new();
+        context.terminal = Box::new(SmallTerminal);
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("0123456789").unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.terminal_width, 10);
+            assert_eq!(frame.cursor, (1, 2));
+        }
+        ed.locate().unwrap();
+        assert_eq!(ed.geometry().unwrap().top, Some(2));
+    }
+
+    #[test]
+    fn suspend_redraws_below_what_was_printed() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct Output {
+            written: Vec<u8>,
+            raw: Rc<Cell<bool>>,
+        }
+        impl Write for Output {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.written.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl RawMode for Output {
+            fn suspend_raw_mode(&self) -> io::Result<()> {
+                self.raw.set(false);
+                Ok(())
+            }
+            fn activate_raw_mode(&self) -> io::Result<()> {
+                self.raw.set(true);
+                Ok(())
+            }
+        }
+
+        let raw = Rc::new(Cell::new(true));
+        let mut context = Context::new();
+        let out = Output { written: Vec::new(), raw: raw.clone() };
+        let mut ed = Editor::new(out, "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("vim").unwrap();
+        ed.move_cursor_left(1).unwrap();
+        ed.set_cursor_shape(Some(CursorShape::SteadyBar)).unwrap();
+        ed.out.written.clear();
+
+        let raw_while_suspended = ed.suspend(|| raw.get()).unwrap();
+        assert!(!raw_while_suspended);
+        assert!(raw.get());
+        let written = String::from_utf8_lossy(&ed.out.written).into_owned();
+        assert!(written.contains("\r\n"));
+        // The program run in between gets the terminal's own cursor, and the bar comes back after.
+        assert!(written.contains("\x1B[0 q"));
+        assert!(written.ends_with("\x1B[6 q"));
+        assert_eq!(ed.cursor_shape, Some(CursorShape::SteadyBar));
+        let frame = ed.frame.as_ref().unwrap();
+        assert_eq!(frame.lines[0].text, "$ vim");
+        assert_eq!(frame.cursor, (0, 4));
+    }
+
+    #[test]
+    fn alternate_screen() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.enter_alternate_screen().unwrap();
+        assert!(ed.is_on_alternate_screen());
+        assert_eq!(ed.geometry().unwrap().top, Some(1));
+        assert!(String::from_utf8_lossy(&ed.out).contains("\x1B[?1049h"));
+
+        ed.out.clear();
+        ed.leave_alternate_screen().unwrap();
+        assert_eq!(ed.out, b"\x1B[?1049l");
+        assert!(ed.geometry().is_none());
+    }
+
+    #[test]
+    fn dumb_terminals_get_no_escapes_they_dont_know() {
+        let mut context = Context::new();
+        context.capabilities = ::Capabilities::for_term("dumb");
+        let mut ed = Editor::new(Vec::new(), "\x1B[31m$\x1B[0m ".to_owned(), None, &mut context).unwrap();
+        ed.enable_mouse().unwrap();
+        ed.enable_bracketed_paste().unwrap();
+        ed.enter_alternate_screen().unwrap();
+        ed.set_cursor_shape(Some(CursorShape::SteadyBlock)).unwrap();
+        ed.insert_str_after_cursor("ls").unwrap();
+        ed.clear().unwrap();
+
+        let out = String::from_utf8_lossy(&ed.out).into_owned();
+        assert!(!out.contains("\x1B[?"));
+        assert!(!out.contains("\x1B[31m"));
+        assert!(!out.contains(ansi::CLEAR_ALL));
+        assert!(!out.contains(" q"));
+        assert!(!ed.is_on_alternate_screen());
+        assert!(out.ends_with("$ ls"));
+    }
+
+    #[test]
+    fn renders_into_a_rectangle() {
+        let mut context = Context::new();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("0123456789abc").unwrap();
+        assert_eq!(ed.render(8, 5), Rendered {
+            rows: vec!["$ 012345".to_owned(), "6789abc".to_owned()],
+            cursor: (1, 7),
+        });
+
+        ed.insert_str_after_cursor("\nline two").unwrap();
+        let rendered = ed.render(8, 2);
+        assert_eq!(rendered.rows, vec!["  line t".to_owned(), "wo".to_owned()]);
+        assert_eq!(rendered.cursor, (1, 2));
+        // Only rendering scrolled down, not what is drawn on the terminal.
+        assert_eq!(ed.render_scroll.0, 1);
+        assert_eq!(ed.window_top, 0);
+    }
+
+    #[test]
+    fn hidden_input() {
+        let mut context = Context::new();
+        let options = ReadLineOptions::new("passphrase: ").hidden(true);
+        let mut ed = Editor::with_options(Vec::new(), &mut context, options).unwrap();
+        ed.insert_str_after_cursor("open sesame").unwrap();
+        ed.delete_before_cursor().unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(util::remove_codes(&frame.lines[0].text), "passphrase: ");
+            assert_eq!(frame.cursor, (0, 12));
+        }
+        assert_eq!(String::from(ed), "open sesam");
+    }
+
+    #[test]
+    fn multi_line_prompt_is_redrawn() {
+        let mut context = Context::new();
+        let prompt = format!("\\[{}\\]~/src{}\n$ ", ansi::fg(ansi::GREEN), "\x1B[39m");
+        let mut ed = Editor::new(Vec::new(), prompt, None, &mut context).unwrap();
+        ed.insert_str_after_cursor("ab").unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.lines[0].text, format!("{}~/src{}", ansi::fg(ansi::GREEN), "\x1B[39m"));
+            assert_eq!(frame.lines[0].width, 5);
+            assert_eq!(frame.lines[1].text, "$ ab");
+        }
+        assert_eq!(ed.geometry().unwrap().prompt, Some((1, 0)));
+        assert_eq!(ed.geometry().unwrap().cursor, (1, 4));
+
+        let out = written(&mut ed, |ed| ed.clear().unwrap());
+        assert!(out.contains("~/src"));
+
+        // only the last line is replaced while searching
+        ed.search(false).unwrap();
+        let frame = ed.frame.as_ref().unwrap();
+        assert!(frame.lines[0].text.contains("~/src"));
+        assert!(frame.lines[1].text.starts_with("(search)"));
+    }
+
+    #[test]
+    fn search_highlights_match() {
+        let mut context = Context::new();
+        context.theme.search_highlight = "\x1B[4m".to_owned();
+        context.history.push(Buffer::from("git commit")).unwrap();
+        context.history.push(Buffer::from("ls")).unwrap();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.search(false).unwrap();
+        ed.insert_str_after_cursor("comm").unwrap();
+
+        let text = ed.frame.as_ref().unwrap().lines[0].text.clone();
+        let yellow = ansi::fg(ansi::YELLOW);
+        let expected = format!("git \x1B[4mcomm{}{}it{}", ansi::RESET, yellow, ansi::RESET);
+        assert!(text.ends_with(&expected), "{:?}", text);
+    }
+
+    #[test]
+    fn session_history_comes_first() {
+        let mut context = Context::new();
+        context.prefer_session_history = true;
+        context.history.push(Buffer::from("mine")).unwrap();
+        let mut other = HistoryEntry::new(Buffer::from("theirs"));
+        other.layer = HistoryLayer::Global;
+        context.history.push_entry(other).unwrap();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+
+        ed.move_up().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "mine");
+        ed.move_up().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "theirs");
+        ed.move_down().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "mine");
+        ed.move_down().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "");
+    }
+
+    #[test]
+    fn delete_recalled_history_entry() {
+        let mut context = Context::new();
+        context.history.push(Buffer::from("rm -rf /tpm")).unwrap();
+        context.history.push(Buffer::from("ls")).unwrap();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.move_up().unwrap();
+        ed.move_up().unwrap();
+        ed.replace_history_entry("rm -rf /tmp/x").unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "rm -rf /tmp/x");
+
+        ed.delete_history_entry().unwrap();
+        assert_eq!(ed.current_history_location(), None);
+        assert_eq!(ed.context().history.len(), 1);
+        assert_eq!(ed.context().history[0].to_string(), "ls");
+    }
+
+    #[test]
+    fn autosuggestion_thresholds() {
+        let mut context = Context::new();
+        context.autosuggest_min_chars = 2;
+        context.autosuggest_unique_only = true;
+        for line in &["cargo build", "cargo test", "git status"] {
+            context.history.push(Buffer::from(*line)).unwrap();
+        }
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("g").unwrap();
+        assert!(ed.autosuggestion.is_none());
+        ed.insert_str_after_cursor("i").unwrap();
+        assert_eq!(ed.autosuggestion.as_ref().map(|b| b.to_string()), Some("git status".to_owned()));
+
+        ed.clear_and_set("car").unwrap();
+        assert!(ed.autosuggestion.is_none());
+        ed.insert_str_after_cursor("go t").unwrap();
+        assert_eq!(ed.autosuggestion.as_ref().map(|b| b.to_string()), Some("cargo test".to_owned()));
+    }
+
+    #[test]
+    fn pinned_entries_are_suggested_first() {
+        let mut context = Context::new();
+        for line in &["cargo build --release", "ls", "cargo test"] {
+            context.history.push(Buffer::from(*line)).unwrap();
+        }
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.move_up().unwrap();
+        ed.move_up().unwrap();
+        ed.move_up().unwrap();
+        ed.toggle_history_pin().unwrap();
+        assert!(ed.context().history.is_pinned("cargo build --release"));
+
+        ed.clear_and_set("car").unwrap();
+        assert_eq!(ed.autosuggestion.as_ref().map(|b| b.to_string()), Some("cargo build --release".to_owned()));
+    }
+
+    #[test]
+    fn new_buffer_restored_after_history() {
+        let mut context = Context::new();
+        context.history.push(Buffer::from("ls")).unwrap();
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("echo hi").unwrap();
+        ed.move_cursor_to(4).unwrap();
+
+        ed.move_to_start_of_history().unwrap();
+        ed.insert_str_after_cursor(" -l").unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "ls -l");
+        ed.move_to_end_of_history().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "echo hi");
+        assert_eq!(ed.cursor, 4);
+    }
+
+    #[test]
+    fn autosuggestion_can_match_anywhere() {
+        let mut context = Context::new();
+        context.history.push(Buffer::from("git status")).unwrap();
+        context.autosuggest_anchor = MatchAnchor::Anywhere;
+        context.search_anchor = MatchAnchor::Start;
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.insert_str_after_cursor("stat").unwrap();
+        assert_eq!(ed.autosuggestion.as_ref().map(|b| b.to_string()), Some("git status".to_owned()));
+
+        ed.accept_autosuggestion().unwrap();
+        assert_eq!(ed.current_buffer().to_string(), "git status");
+
+        ed.clear_and_set("").unwrap();
+        ed.search(false).unwrap();
+        ed.insert_str_after_cursor("stat").unwrap();
+        assert!(ed.history_subset_index.is_empty());
+    }
+
+    #[test]
+    fn search_preview_lists_matches() {
+        let mut context = Context::new();
+        context.search_preview = 2;
+        for entry in &["a1", "a2", "b", "a3"] {
+            context.history.push(Buffer::from(*entry)).unwrap();
+        }
+        let mut ed = Editor::new(Vec::new(), "$ ".to_owned(), None, &mut context).unwrap();
+        ed.search(false).unwrap();
+        ed.insert_str_after_cursor("a").unwrap();
+        {
+            let frame = ed.frame.as_ref().unwrap();
+            assert_eq!(frame.lines.len(), 3);
+            let theme = &ed.context.theme;
+            let (current, matched) = (&theme.completion_highlight, &theme.search_highlight);
+            let reset = ansi::RESET;
+            assert_eq!(frame.lines[1].text, format!("{}{}a{}{}3{}", current, matched, reset, current, reset));
+            assert_eq!(frame.lines[2].text, format!("{}a{}2", matched, reset));
+            assert_eq!(frame.lines[2].width, 2);
+        }
+
+        ed.search(false).unwrap();
+        ed.search(false).unwrap();
+        let frame = ed.frame.as_ref().unwrap();
+        assert_eq!(frame.lines.len(), 2);
+        assert_eq!(frame.lines[1].width, 2);
+    }
 }
@@ -1,7 +1,11 @@
-use std::io::Write;
-use termion::event::Key;
+use std::io::{self, Write};
+use Key;
 use Editor;
 
+/// Called with each `Event` while a line is read. A handler answers an event by passing it to
+/// `Event::respond()` or `Event::fail()` rather than by returning something, so that handlers
+/// that never answer, such as `&mut |_| {}`, are written as they always were. Both take the event
+/// by value, so it can only be answered once.
 pub type EventHandler<'a, W> = FnMut(Event<W>) + 'a;
 
 pub struct Event<'a, 'out: 'a, W: Write + 'a> {
@@ -16,6 +20,32 @@ impl<'a, 'out: 'a, W: Write + 'a> Event<'a, 'out, W> {
             kind: kind,
         }
     }
+
+    /// Answers the event with `action`. Only some events act on it, as listed on `EventAction`;
+    /// it is ignored for the others.
+    pub fn respond(self, action: EventAction) {
+        self.editor.respond_to_event(action);
+    }
+
+    /// Fails the event with `error`, which ends the read with it, or is only shown as the message
+    /// with `Context.show_handler_errors`, once the handler returns. Any response is dropped.
+    pub fn fail(self, error: io::Error) {
+        self.editor.fail_event(error);
+    }
+}
+
+/// What a handler wants to happen after an event, given with `Event::respond()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventAction {
+    /// Carry on as if the handler hadn't responded.
+    Continue,
+    /// Leave the key alone for `KeyPressed` and `BeforeKey`, skip the completion for
+    /// `BeforeComplete`, undo the change for `BufferChanged` and drop the text for `Paste`.
+    Cancel,
+    /// Replace the buffer with this text instead, for `KeyPressed`, `BeforeKey`,
+    /// `BeforeComplete` and `BufferChanged`. For `LineAccepted`, this is the line that is returned,
+    /// and for `Paste`, the text that is inserted.
+    ReplaceBuffer(String),
 }
 
 #[derive(Debug)]
@@ -26,6 +56,37 @@ pub enum EventKind {
     AfterKey(Key),
     /// Sent in `Editor.complete()`, before processing the completion.
     BeforeComplete,
+    /// Sent for every keypress, before `BeforeKey`, even if a handler then changes the key.
+    KeyPressed(Key),
+    /// Sent after a key changed the text of the buffer being edited, but not when moving to
+    /// another buffer in the history.
+    BufferChanged,
+    /// Sent after a key moved to another entry in the history, or back to the new buffer with
+    /// `None`.
+    HistoryNavigated(Option<usize>),
+    /// Sent after a key started an incremental history search.
+    SearchStarted,
+    /// Sent after a key ended an incremental history search.
+    SearchEnded,
+    /// Sent after each key that doesn't end the read, before the editor is redrawn. Changes a
+    /// handler makes, such as to the prompt or the message, are drawn right away.
+    BeforeDisplay,
+    /// Sent after the editor was redrawn, before the output is flushed.
+    AfterDisplay,
+    /// Sent when the line is accepted, before it is returned.
+    LineAccepted,
+    /// Sent when no key arrived within `Context.tick_interval`. The editor is redrawn afterwards,
+    /// so a handler can update the prompt or the message.
+    Idle,
+    /// Sent with text pasted into the terminal, before it is inserted. Only pastes the terminal
+    /// marks are seen, which needs `Context.bracketed_paste`.
+    Paste(String),
+    /// Sent when the terminal window gains focus, or loses it with `false`. Needs
+    /// `Context.focus_events`. The editor is redrawn afterwards.
+    Focus(bool),
+    /// Sent with an escape sequence or bytes read from the terminal that liner doesn't know,
+    /// which are otherwise ignored.
+    UnknownInput(Vec<u8>),
 }
 
 pub enum CompleteType {
@@ -0,0 +1,231 @@
+use std::cmp;
+use std::iter::{Chain, FromIterator};
+use std::slice;
+
+// How much room is made at least when the gap is too small for an insert.
+const MIN_GAP: usize = 64;
+
+/// Chars with a gap where the last edit happened, so typing or deleting in the middle of a long
+/// line only moves the chars between the previous edit and this one.
+#[derive(Debug, Clone)]
+pub struct GapBuffer {
+    data: Vec<char>,
+    gap_start: usize,
+    gap_end: usize,
+}
+
+/// Iterator over the chars in a `GapBuffer`.
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    inner: Chain<slice::Iter<'a, char>, slice::Iter<'a, char>>,
+    len: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a char;
+
+    fn next(&mut self) -> Option<&'a char> {
+        let c = self.inner.next();
+        if c.is_some() {
+            self.len -= 1;
+        }
+        c
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<&'a char> {
+        let c = self.inner.next_back();
+        if c.is_some() {
+            self.len -= 1;
+        }
+        c
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+impl GapBuffer {
+    /// A buffer with room for `capacity` chars before it needs to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        GapBuffer { data: vec!['\0'; capacity], gap_start: 0, gap_end: capacity }
+    }
+
+    /// Makes room for at least `additional` more chars at the gap.
+    pub fn reserve(&mut self, additional: usize) {
+        self.reserve_gap(additional);
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() - (self.gap_end - self.gap_start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<char> {
+        if index < self.gap_start {
+            Some(self.data[index])
+        } else {
+            self.data.get(index + self.gap_end - self.gap_start).cloned()
+        }
+    }
+
+    /// The chars before the gap and the ones after it.
+    pub fn slices(&self) -> (&[char], &[char]) {
+        (&self.data[..self.gap_start], &self.data[self.gap_end..])
+    }
+
+    pub fn iter(&self) -> Iter {
+        let (before, after) = self.slices();
+        Iter { inner: before.iter().chain(after.iter()), len: self.len() }
+    }
+
+    /// The chars from `start` to `end`, without copying if they are all on one side of the gap.
+    pub fn slice(&self, start: usize, end: usize) -> Option<&[char]> {
+        let (before, after) = self.slices();
+        let split = before.len();
+        if end <= split {
+            Some(&before[start..end])
+        } else if start >= split {
+            Some(&after[start - split..end - split])
+        } else {
+            None
+        }
+    }
+
+    /// Copies the chars from `start` to `end`.
+    pub fn range(&self, start: usize, end: usize) -> Vec<char> {
+        if let Some(chars) = self.slice(start, end) {
+            return chars.to_vec();
+        }
+        let (before, after) = self.slices();
+        let mut chars = Vec::with_capacity(end - start);
+        chars.extend_from_slice(&before[start..]);
+        chars.extend_from_slice(&after[..end - before.len()]);
+        chars
+    }
+
+    pub fn to_vec(&self) -> Vec<char> {
+        self.range(0, self.len())
+    }
+
+    pub fn insert(&mut self, at: usize, text: &[char]) {
+        assert!(at <= self.len(), "insert at {} past the end ({})", at, self.len());
+        self.move_gap(at);
+        self.reserve_gap(text.len());
+        self.data[self.gap_start..self.gap_start + text.len()].copy_from_slice(text);
+        self.gap_start += text.len();
+    }
+
+    /// Removes the chars from `start` to `end` and returns them.
+    pub fn remove(&mut self, start: usize, end: usize) -> Vec<char> {
+        assert!(start <= end && end <= self.len(), "remove {}..{} out of bounds ({})", start, end, self.len());
+        self.move_gap(start);
+        let removed = self.data[self.gap_end..self.gap_end + end - start].to_vec();
+        self.gap_end += end - start;
+        removed
+    }
+
+    fn move_gap(&mut self, at: usize) {
+        if at < self.gap_start {
+            let moved = self.gap_start - at;
+            self.data.copy_within(at..self.gap_start, self.gap_end - moved);
+            self.gap_start -= moved;
+            self.gap_end -= moved;
+        } else if at > self.gap_start {
+            let moved = at - self.gap_start;
+            self.data.copy_within(self.gap_end..self.gap_end + moved, self.gap_start);
+            self.gap_start += moved;
+            self.gap_end += moved;
+        }
+    }
+
+    fn reserve_gap(&mut self, needed: usize) {
+        let gap = self.gap_end - self.gap_start;
+        if gap >= needed {
+            return;
+        }
+
+        // Growing by the current size keeps a run of inserts amortized linear.
+        let grow = cmp::max(needed - gap, cmp::max(MIN_GAP, self.data.len()));
+        let old_len = self.data.len();
+        self.data.resize(old_len + grow, '\0');
+        self.data.copy_within(self.gap_end..old_len, self.gap_end + grow);
+        self.gap_end += grow;
+    }
+}
+
+impl PartialEq for GapBuffer {
+    fn eq(&self, other: &GapBuffer) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl FromIterator<char> for GapBuffer {
+    fn from_iter<T: IntoIterator<Item = char>>(t: T) -> Self {
+        let data: Vec<char> = t.into_iter().collect();
+        let len = data.len();
+        GapBuffer { data: data, gap_start: len, gap_end: len }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(buf: &GapBuffer) -> String {
+        buf.iter().cloned().collect()
+    }
+
+    #[test]
+    fn edits_around_the_gap() {
+        let mut buf: GapBuffer = "hello world".chars().collect();
+        buf.insert(5, &[',']);
+        buf.insert(0, &['>', ' ']);
+        assert_eq!(text(&buf), "> hello, world");
+        assert_eq!(buf.remove(7, 9), vec![',', ' ']);
+        assert_eq!(text(&buf), "> helloworld");
+        assert_eq!(buf.len(), 12);
+        assert_eq!(buf.get(7), Some('w'));
+        assert_eq!(buf.range(4, 8).into_iter().collect::<String>(), "llow");
+    }
+
+    #[test]
+    fn slice_only_on_one_side() {
+        let mut buf: GapBuffer = "abcdef".chars().collect();
+        buf.insert(3, &['x']);
+        assert_eq!(buf.slice(0, 4), Some(&['a', 'b', 'c', 'x'][..]));
+        assert_eq!(buf.slice(4, 7), Some(&['d', 'e', 'f'][..]));
+        assert_eq!(buf.slice(2, 5), None);
+    }
+
+    #[test]
+    fn reserved_room_is_used() {
+        let mut buf = GapBuffer::with_capacity(16);
+        assert!(buf.is_empty());
+        buf.insert(0, &['a'; 10]);
+        buf.reserve(100);
+        let room = buf.data.len();
+        buf.insert(5, &['b'; 90]);
+        assert_eq!(buf.data.len(), room);
+        assert_eq!(buf.len(), 100);
+        assert_eq!(buf.get(5), Some('b'));
+    }
+
+    #[test]
+    fn grows_for_long_inserts() {
+        let mut buf = GapBuffer::with_capacity(0);
+        let chunk: Vec<char> = "0123456789".chars().collect();
+        for i in 0..100 {
+            buf.insert(i * 5, &chunk);
+        }
+        assert_eq!(buf.len(), 1000);
+        assert_eq!(buf, buf.to_vec().into_iter().collect());
+    }
+}
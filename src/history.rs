@@ -1,24 +1,364 @@
 use super::*;
 
 use std::{
-    collections::{vec_deque, VecDeque},
-    io::{BufRead, BufReader, BufWriter},
-    fs::File,
-    io::{self, Write},
-    iter::IntoIterator,
-    ops::Index,
-    ops::IndexMut,
-    path::Path,
-    //time::Duration,
+    cmp,
+    collections::{vec_deque, BTreeMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    iter::{self, IntoIterator},
+    mem,
+    ops::{Deref, DerefMut, Index, IndexMut},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 const DEFAULT_MAX_SIZE: usize = 1000;
 
+/// Starts a line of the history file holding the metadata of the entry on the next line.
+const METADATA_PREFIX: &str = "#: ";
+/// Marks pinned entries in their metadata.
+const PINNED_KEY: &str = "pinned";
+/// The names of metadata fields that aren't kept in `HistoryEntry::extra`. Names in `extra` that
+/// are the same start with `\.` in the file, so they are read back into `extra`.
+const RESERVED_KEYS: [&str; 6] = [PINNED_KEY, "exit", "duration", "cwd", "uses", "lines"];
+
+/// Holds an exclusive advisory lock on a history file until dropped, so that sessions writing to
+/// the same file take turns. The lock is taken on a `.lock` file next to it, since saving replaces
+/// the history file itself.
+struct FileLock(File);
+
+impl FileLock {
+    fn new(path: &Path) -> io::Result<Self> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let file = OpenOptions::new().write(true).create(true).open(lock_path)?;
+        FileLock::lock(&file)?;
+        Ok(FileLock(file))
+    }
+
+    #[cfg(unix)]
+    fn lock(file: &File) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { ::libc::flock(file.as_raw_fd(), ::libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn lock(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            unsafe { ::libc::flock(self.0.as_raw_fd(), ::libc::LOCK_UN) };
+        }
+    }
+}
+
+/// Where a history entry comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryLayer {
+    /// The entry was added while this program runs.
+    Session,
+    /// The entry was read from a history file shared with other sessions.
+    Global,
+}
+
+/// A line in the history, with whatever the application knows about running it.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub buffer: Buffer,
+    /// The exit status of the command.
+    pub exit_status: Option<i32>,
+    /// How long the command took to run.
+    pub duration: Option<Duration>,
+    /// The directory the command was run in.
+    pub working_dir: Option<PathBuf>,
+    /// Any other metadata the application keeps, by name. Names and values can be anything,
+    /// including the names the fields above and pins are saved under in a history file.
+    pub extra: BTreeMap<String, String>,
+    /// Whether the entry was added in this session or read from a file. Not saved to the file.
+    pub layer: HistoryLayer,
+    /// How many times the command has been run, counting earlier entries with the same text.
+    pub use_count: u32,
+}
+
+impl HistoryEntry {
+    pub fn new(buffer: Buffer) -> Self {
+        HistoryEntry {
+            buffer: buffer,
+            exit_status: None,
+            duration: None,
+            working_dir: None,
+            extra: BTreeMap::new(),
+            layer: HistoryLayer::Session,
+            use_count: 1,
+        }
+    }
+
+    // The metadata as written to the history file, or `None` if there isn't any.
+    fn metadata_line(&self, pinned: bool) -> Option<String> {
+        let mut fields = vec![];
+        if pinned {
+            fields.push(format!("{}=1", PINNED_KEY));
+        }
+        if let Some(status) = self.exit_status {
+            fields.push(format!("exit={}", status));
+        }
+        if let Some(duration) = self.duration {
+            let millis = duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64;
+            fields.push(format!("duration={}", millis));
+        }
+        if let Some(ref dir) = self.working_dir {
+            fields.push(format!("cwd={}", escape_metadata(&dir.to_string_lossy())));
+        }
+        if self.use_count != 1 {
+            fields.push(format!("uses={}", self.use_count));
+        }
+        let command = self.buffer.to_string();
+        let num_lines = command.split('\n').count();
+        if num_lines > 1 || command.starts_with(METADATA_PREFIX) {
+            fields.push(format!("lines={}", num_lines));
+        }
+        for (name, value) in &self.extra {
+            let name = if RESERVED_KEYS.contains(&name.as_str()) {
+                format!("\\.{}", name)
+            } else {
+                escape_metadata(name)
+            };
+            fields.push(format!("{}={}", name, escape_metadata(value)));
+        }
+
+        if fields.is_empty() {
+            None
+        } else {
+            Some(format!("{}{}", METADATA_PREFIX, fields.join("\t")))
+        }
+    }
+
+    // Reads the metadata back from what `metadata_line()` wrote, after the prefix, and returns
+    // how many lines the command takes up and whether it was pinned. Fields that can't be read
+    // are left out.
+    fn parse_metadata(&mut self, line: &str) -> (usize, bool) {
+        let (mut num_lines, mut pinned) = (1, false);
+        for field in line.split('\t') {
+            let mut parts = field.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                // Reserved names are matched before unescaping, so escaped ones go to `extra`.
+                (Some(name), Some(value)) => (name, unescape_metadata(value)),
+                _ => continue,
+            };
+            match name {
+                PINNED_KEY => pinned = true,
+                "exit" => self.exit_status = value.parse().ok(),
+                "duration" => self.duration = value.parse().ok().map(Duration::from_millis),
+                "cwd" => self.working_dir = Some(PathBuf::from(value)),
+                "uses" => self.use_count = value.parse().unwrap_or(1),
+                "lines" => num_lines = value.parse().unwrap_or(1),
+                _ => {
+                    self.extra.insert(unescape_metadata(name), value);
+                }
+            }
+        }
+        (num_lines, pinned)
+    }
+}
+
+impl From<Buffer> for HistoryEntry {
+    fn from(buffer: Buffer) -> Self {
+        HistoryEntry::new(buffer)
+    }
+}
+
+fn escape_metadata(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('=', "\\e")
+}
+
+fn unescape_metadata(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('e') => unescaped.push('='),
+            // Marks a name in `extra` that is reserved for a field.
+            Some('.') => {}
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+// The lines an entry takes up in the history file.
+fn entry_lines(entry: &HistoryEntry, pinned: bool) -> String {
+    let command = entry.buffer.to_string();
+    let mut lines = String::new();
+    if let Some(metadata) = entry.metadata_line(pinned) {
+        lines.push_str(&metadata);
+        lines.push('\n');
+    }
+    lines.push_str(&command);
+    lines.push('\n');
+    lines
+}
+
+// The entries in the history file at `path`, or none if there is no file yet. The lock on the
+// file has to be held.
+fn read_file(path: &Path) -> io::Result<Vec<(HistoryEntry, bool)>> {
+    match File::open(path) {
+        Ok(file) => Ok(read_entries(file)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// Reads the entries of a history file, with whether each was pinned when it was saved. Lines that
+// aren't valid UTF-8 or contain NUL bytes, as left behind by a crash in the middle of writing the
+// file, are skipped.
+fn read_entries(file: File) -> Vec<(HistoryEntry, bool)> {
+    fn entry(mut entry: HistoryEntry, pinned: bool, text: String) -> (HistoryEntry, bool) {
+        entry.buffer = Buffer::from(text);
+        (entry, pinned)
+    }
+
+    let mut entries = Vec::new();
+    let reader = BufReader::new(file);
+    let mut metadata = None;
+    // An entry spanning several lines, with its text so far and how many lines it still has.
+    let mut pending: Option<(HistoryEntry, bool, String, usize)> = None;
+    for line in reader.split(b'\n') {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = match String::from_utf8(line) {
+            Ok(ref line) if line.contains('\0') => {
+                pending = None;
+                continue;
+            }
+            Ok(line) => line,
+            Err(_) => {
+                pending = None;
+                continue;
+            }
+        };
+
+        if let Some((e, pinned, mut text, remaining)) = pending.take() {
+            text.push('\n');
+            text.push_str(&line);
+            if remaining > 1 {
+                pending = Some((e, pinned, text, remaining - 1));
+            } else {
+                entries.push(entry(e, pinned, text));
+            }
+            continue;
+        }
+
+        // The line after metadata is always a command, even if it looks like metadata.
+        let fields = match metadata.take() {
+            Some(fields) => fields,
+            None if line.starts_with(METADATA_PREFIX) => {
+                metadata = Some(line[METADATA_PREFIX.len()..].to_owned());
+                continue;
+            }
+            None => String::new(),
+        };
+        let mut e = HistoryEntry::new(Buffer::new());
+        let (num_lines, pinned) = e.parse_metadata(&fields);
+        if num_lines > 1 {
+            pending = Some((e, pinned, line, num_lines - 1));
+        } else {
+            entries.push(entry(e, pinned, line));
+        }
+    }
+    entries
+}
+
+/// History file formats of other shells, which entries can be imported from and exported to.
+///
+/// Timestamps of the entries are kept in `HistoryEntry::extra` under `time`, as seconds since the
+/// Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryFormat {
+    /// `.bash_history`, with or without the `#<time>` lines written when `HISTTIMEFORMAT` is set.
+    Bash,
+    /// `.zsh_history`, with or without `EXTENDED_HISTORY`.
+    Zsh,
+    /// `fish_history`.
+    Fish,
+}
+
+/// Something that happened to a `History`, passed to the hooks added with `History::on_event()`.
+#[derive(Debug)]
+pub enum HistoryEvent<'a> {
+    /// An entry was added.
+    Push(&'a HistoryEntry),
+    /// This many entries were read from a history file.
+    Load(usize),
+    /// The history was saved to the file at this path.
+    Save(&'a Path),
+    /// An entry was dropped because there were too many.
+    Evict(&'a HistoryEntry),
+}
+
+/// What the filter set with `History::set_filter()` does with a line about to be added.
+#[derive(Debug, Clone)]
+pub enum HistoryAction {
+    /// The line is added as it is.
+    Keep,
+    /// The line is not added.
+    Skip,
+    /// The given buffer is added instead of the line.
+    Replace(Buffer),
+}
+
+/// Where a term has to be found in a history entry for the entry to match it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchAnchor {
+    /// The entry has to start with the term.
+    Start,
+    /// The term can be anywhere in the entry.
+    Anywhere,
+}
+
+impl MatchAnchor {
+    /// Whether `tested` matches `term`. An empty term matches nothing.
+    pub fn matches(&self, tested: &Buffer, term: &Buffer) -> bool {
+        match *self {
+            MatchAnchor::Start => tested.starts_with(term) || !term.is_empty() && tested.equals(term),
+            MatchAnchor::Anywhere => tested.contains(term),
+        }
+    }
+}
+
+/// Which entries are dropped once the history has more entries than it may keep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Drop the oldest entries.
+    DropOldest,
+    /// Drop the oldest entries that aren't pinned with `History::pin()`. Pinned entries are
+    /// always kept, even if there are more of them than the history may keep. This is the
+    /// default.
+    KeepPinned,
+}
+
 /// Structure encapsulating command history
 pub struct History {
     // TODO: this should eventually be private
-    /// Vector of buffers to store history in
-    pub buffers: VecDeque<Buffer>,
+    /// The entries of the history, oldest first. Indexing gives the command of an entry.
+    pub buffers: HistoryEntries,
     /// Store a filename to save history into; if None don't save history
     file_name: Option<String>,
     /// Maximal number of buffers stored in the memory
@@ -29,17 +369,83 @@ pub struct History {
     max_file_size: usize,
     // TODO set from environment variable?
     pub append_duplicate_entries: bool,
+    /// Saves the history to the history file after every push, if there is one. Entries other
+    /// sessions added to the file since it was loaded are kept.
+    pub autosave: bool,
+    /// Appends each pushed entry to the history file right away, if there is one, instead of
+    /// rewriting the whole file. Other sessions appending to the same file are not overwritten.
+    /// The oldest entries are dropped from the file once it holds more than `max_file_size`.
+    pub inc_append: bool,
+    /// Which entries are dropped when there are too many, in memory and in the file.
+    pub eviction_policy: EvictionPolicy,
+    /// Strips escape sequences and control characters other than newlines and tabs from pushed,
+    /// loaded and imported entries, so recalling them can't mess with the terminal.
+    pub sanitize: bool,
+    /// Cuts pushed, loaded and imported entries off after this many characters.
+    pub max_entry_len: Option<usize>,
+    /// Entries that `EvictionPolicy::KeepPinned` never drops.
+    pinned: HashSet<String>,
+    /// Lines matching any of these are not added to the history.
+    ignore_fns: Vec<Box<Fn(&str) -> bool + Send + Sync>>,
+    /// Decides what happens to each line before it is added.
+    filter: Option<Box<Fn(&Buffer) -> HistoryAction + Send + Sync>>,
+    /// Scores entries, given how many entries are newer, to rank matches by.
+    scorer: Option<Box<Fn(&HistoryEntry, usize) -> f64 + Send + Sync>>,
+    /// Called with everything that happens to the history.
+    hooks: Vec<Box<Fn(&HistoryEvent) + Send + Sync>>,
+}
+
+/// The entries of a `History`, oldest first. It derefs to the `VecDeque` of entries, and indexing
+/// it gives the command of an entry, as when the history held only the commands.
+pub struct HistoryEntries(VecDeque<HistoryEntry>);
+
+impl Deref for HistoryEntries {
+    type Target = VecDeque<HistoryEntry>;
+
+    fn deref(&self) -> &VecDeque<HistoryEntry> {
+        &self.0
+    }
+}
+
+impl DerefMut for HistoryEntries {
+    fn deref_mut(&mut self) -> &mut VecDeque<HistoryEntry> {
+        &mut self.0
+    }
+}
+
+impl Index<usize> for HistoryEntries {
+    type Output = Buffer;
+
+    fn index(&self, index: usize) -> &Buffer {
+        &self.0[index].buffer
+    }
+}
+
+impl IndexMut<usize> for HistoryEntries {
+    fn index_mut(&mut self, index: usize) -> &mut Buffer {
+        &mut self.0[index].buffer
+    }
 }
 
 impl History {
     /// Create new History structure.
     pub fn new() -> History {
         History {
-            buffers: VecDeque::with_capacity(DEFAULT_MAX_SIZE),
+            buffers: HistoryEntries(VecDeque::with_capacity(DEFAULT_MAX_SIZE)),
             file_name: None,
             max_buffers_size: DEFAULT_MAX_SIZE,
             max_file_size: DEFAULT_MAX_SIZE,
             append_duplicate_entries: false,
+            autosave: false,
+            inc_append: false,
+            eviction_policy: EvictionPolicy::KeepPinned,
+            sanitize: true,
+            max_entry_len: None,
+            pinned: HashSet::new(),
+            ignore_fns: Vec::new(),
+            filter: None,
+            scorer: None,
+            hooks: Vec::new(),
         }
     }
 
@@ -54,20 +460,217 @@ impl History {
             status = format!("creating {:?}", path);
             File::create(path)?
         };
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            match line {
-                Ok(line) => self.buffers.push_back(Buffer::from(line)),
-                Err(_) => break,
-            }
-        }
+        self.load_from(file);
         self.file_name = path.to_str().map(|s| s.to_owned());
         Ok(status)
     }
 
-    /// Set maximal number of buffers stored in memory
+    /// Set the history file without loading it. With `autosave` set, the history is saved there
+    /// after every push; otherwise `commit_to_file()` does it. `None` stops saving the history.
+    pub fn set_file_name<P: AsRef<Path>>(&mut self, path: Option<P>) {
+        self.file_name = path.and_then(|p| p.as_ref().to_str().map(|s| s.to_owned()));
+    }
+
+    /// Appends the entries in the file at `path`, one per line, to the history.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let file = File::open(path)?;
+        self.load_from(file);
+        Ok(())
+    }
+
+    fn load_from(&mut self, file: File) {
+        let len_before = self.len();
+        for (entry, pinned) in read_entries(file) {
+            self.push_loaded(entry, pinned);
+        }
+        let loaded = self.len() - len_before;
+        self.fire(HistoryEvent::Load(loaded));
+        let max = self.max_buffers_size;
+        self.evict(max);
+    }
+
+    // Adds an entry read from the history file, pinning it again if it was pinned when saved.
+    // A command used again right after itself is appended again with `inc_append`, with the new
+    // use count, and replaces the entry before it.
+    fn push_loaded(&mut self, entry: HistoryEntry, pinned: bool) {
+        let mut entry = match self.sanitize_entry(entry) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if pinned {
+            self.pinned.insert(entry.buffer.to_string());
+        }
+        entry.layer = HistoryLayer::Global;
+        if !self.append_duplicate_entries {
+            if let Some(last) = self.buffers.back_mut() {
+                if last.buffer.equals(&entry.buffer) {
+                    *last = entry;
+                    return;
+                }
+            }
+        }
+        self.buffers.push_back(entry);
+    }
+
+    /// Writes the newest entries of the history to the file at `path`, one per line, replacing
+    /// its contents. Entries with metadata get a line starting with `#: ` before them that holds
+    /// it, including how many lines the command takes up if it has several. At most as many
+    /// entries are written as set with `set_max_file_size()`. The entries are written to a
+    /// temporary file first, which then replaces the file at `path`, so the history is never left
+    /// half written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let _lock = FileLock::new(path)?;
+        let kept = self.kept(self.max_file_size);
+        let entries = self.buffers.iter().zip(kept).filter(|&(_, keep)| keep).map(|(entry, _)| {
+            (entry, self.is_pinned(&entry.buffer.to_string()))
+        });
+        self.write_file(path, entries)?;
+        self.fire(HistoryEvent::Save(path));
+        Ok(())
+    }
+
+    // Writes `entries`, with whether each is pinned, to a temporary file that then replaces the
+    // file at `path`. The lock on the file has to be held.
+    fn write_file<'e, I>(&self, path: &Path, entries: I) -> io::Result<()>
+        where I: Iterator<Item = (&'e HistoryEntry, bool)>
+    {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for (entry, pinned) in entries {
+                writer.write_all(entry_lines(entry, pinned).as_bytes())?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    // Changes the entries in the history file at `path` with `f` while holding its lock. The
+    // file is read again first, so the entries other sessions appended to it are kept.
+    fn rewrite_file<F>(&self, path: &str, f: F) -> io::Result<()>
+        where F: FnOnce(&mut Vec<(HistoryEntry, bool)>)
+    {
+        let path = Path::new(path);
+        let _lock = FileLock::new(path)?;
+        let mut entries = read_file(path)?;
+        f(&mut entries);
+        self.write_file(path, entries.iter().map(|&(ref entry, pinned)| (entry, pinned)))?;
+        self.fire(HistoryEvent::Save(path));
+        Ok(())
+    }
+
+    // Saves the history to the file at `path` for `autosave` and `commit_to_file()`. Entries that
+    // other sessions added to the file are kept, after the ones read from it and before the ones
+    // added in this session, and the oldest are dropped past `max_file_size`.
+    fn sync_file(&self, path: &str) -> io::Result<()> {
+        let ours: HashSet<String> = self.buffers.iter().map(|e| e.buffer.to_string()).collect();
+        let layer = |layer| {
+            self.buffers.iter().filter(move |e| e.layer == layer).map(|e| {
+                (e.clone(), self.is_pinned(&e.buffer.to_string()))
+            })
+        };
+        self.rewrite_file(path, |entries| {
+            let theirs = mem::replace(entries, Vec::new())
+                .into_iter()
+                .filter(|&(ref e, _)| !ours.contains(&e.buffer.to_string()));
+            entries.extend(layer(HistoryLayer::Global));
+            entries.extend(theirs);
+            entries.extend(layer(HistoryLayer::Session));
+            self.trim(entries, self.max_file_size);
+        })
+    }
+
+    /// Adds the entries of a history file written by another shell after the ones already in the
+    /// history, as `load()` does.
+    pub fn import<P: AsRef<Path>>(&mut self, path: P, format: HistoryFormat) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let entries = match format {
+            HistoryFormat::Bash => parse_bash_history(&data),
+            HistoryFormat::Zsh => parse_zsh_history(&data),
+            HistoryFormat::Fish => parse_fish_history(&data),
+        };
+        let len_before = self.len();
+        for entry in entries {
+            if let Some(entry) = self.apply_filter(entry) {
+                if !self.is_ignored(&entry.buffer.to_string()) {
+                    self.push_loaded(entry, false);
+                }
+            }
+        }
+        let loaded = self.len() - len_before;
+        self.fire(HistoryEvent::Load(loaded));
+        let max = self.max_buffers_size;
+        self.evict(max);
+        Ok(())
+    }
+
+    /// Writes all entries of the history to the file at `path` in the format of another shell,
+    /// replacing its contents.
+    pub fn export<P: AsRef<Path>>(&self, path: P, format: HistoryFormat) -> io::Result<()> {
+        let mut data = String::new();
+        for entry in self.buffers.iter() {
+            let command = entry.buffer.to_string();
+            let time = entry.extra.get(TIME_KEY);
+            match format {
+                HistoryFormat::Bash => {
+                    if let Some(time) = time {
+                        data.push_str(&format!("#{}\n", time));
+                    }
+                    data.push_str(&command);
+                }
+                HistoryFormat::Zsh => {
+                    let elapsed = entry.duration.map_or(0, |d| d.as_secs());
+                    let time = time.map_or("0", |t| t.as_str());
+                    data.push_str(&format!(": {}:{};{}", time, elapsed, command.replace('\n', "\\\n")));
+                }
+                HistoryFormat::Fish => {
+                    let command = command.replace('\\', "\\\\").replace('\n', "\\n");
+                    data.push_str(&format!("- cmd: {}", command));
+                    if let Some(time) = time {
+                        data.push_str(&format!("\n  when: {}", time));
+                    }
+                }
+            }
+            data.push('\n');
+        }
+
+        let data = match format {
+            HistoryFormat::Zsh => metafy(data.as_bytes()),
+            _ => data.into_bytes(),
+        };
+        let mut file = File::create(path)?;
+        file.write_all(&data)
+    }
+
+    // Appends `entry` to the file at `path` while holding a lock on it. If the file then holds
+    // more than `max_file_size` entries, it is rewritten without the oldest.
+    fn append_to_file(&self, path: &str, entry: &HistoryEntry) -> io::Result<()> {
+        let path = Path::new(path);
+        let _lock = FileLock::new(path)?;
+        let pinned = self.is_pinned(&entry.buffer.to_string());
+        let mut entries = read_file(path)?;
+        if entries.len() < self.max_file_size {
+            let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+            return file.write_all(entry_lines(entry, pinned).as_bytes());
+        }
+        entries.push((entry.clone(), pinned));
+        self.trim(&mut entries, self.max_file_size);
+        self.write_file(path, entries.iter().map(|&(ref entry, pinned)| (entry, pinned)))
+    }
+
+    /// Set maximal number of buffers stored in memory. Entries over the limit are dropped right
+    /// away, following `eviction_policy`.
     pub fn set_max_buffers_size(&mut self, size: usize) {
         self.max_buffers_size = size;
+        self.evict(size);
+    }
+
+    /// The most entries kept in memory.
+    pub fn max_buffers_size(&self) -> usize {
+        self.max_buffers_size
     }
 
     /// Set maximal number of entries in history file
@@ -75,53 +678,378 @@ impl History {
         self.max_file_size = size;
     }
 
+    /// The most entries written to the history file.
+    pub fn max_file_size(&self) -> usize {
+        self.max_file_size
+    }
+
+    /// Pins entries equal to `entry`: they are never dropped with `EvictionPolicy::KeepPinned`,
+    /// the default, and come first in autosuggestions and search. Pins are saved along with the
+    /// entries in the history file.
+    pub fn pin<S: Into<String>>(&mut self, entry: S) {
+        self.pinned.insert(entry.into());
+    }
+
+    /// Lets entries equal to `entry` be dropped again.
+    pub fn unpin(&mut self, entry: &str) {
+        self.pinned.remove(entry);
+    }
+
+    /// Pins entries equal to `entry` if they aren't pinned, or unpins them if they are. Returns
+    /// whether they are pinned now.
+    pub fn toggle_pin(&mut self, entry: &str) -> bool {
+        if self.is_pinned(entry) {
+            self.unpin(entry);
+            false
+        } else {
+            self.pin(entry);
+            true
+        }
+    }
+
+    pub fn is_pinned(&self, entry: &str) -> bool {
+        self.pinned.contains(entry)
+    }
+
+    // Returns for each entry whether it is kept when there may only be `max` of them.
+    fn kept(&self, max: usize) -> Vec<bool> {
+        let pinned: Vec<bool> = match self.eviction_policy {
+            EvictionPolicy::DropOldest => vec![false; self.buffers.len()],
+            EvictionPolicy::KeepPinned => self.buffers.iter().map(|e| self.is_pinned(&e.buffer.to_string())).collect(),
+        };
+        keep_newest(&pinned, max)
+    }
+
+    // Drops entries of the history file, with whether each is pinned there, until there are at
+    // most `max`, following `eviction_policy`. Entries pinned in this history are kept as well.
+    fn trim(&self, entries: &mut Vec<(HistoryEntry, bool)>, max: usize) {
+        if entries.len() <= max {
+            return;
+        }
+        let pinned: Vec<bool> = match self.eviction_policy {
+            EvictionPolicy::DropOldest => vec![false; entries.len()],
+            EvictionPolicy::KeepPinned => entries.iter().map(|&(ref e, pinned)| {
+                pinned || self.is_pinned(&e.buffer.to_string())
+            }).collect(),
+        };
+        let mut kept = keep_newest(&pinned, max).into_iter();
+        entries.retain(|_| kept.next() == Some(true));
+    }
+
+    // Drops entries until there are at most `max`, following `eviction_policy`.
+    fn evict(&mut self, max: usize) {
+        if self.buffers.len() <= max {
+            return;
+        }
+
+        let kept = self.kept(max);
+        let mut evicted = vec![];
+        let mut entries = VecDeque::with_capacity(self.buffers.capacity());
+        for (entry, keep) in self.buffers.drain(..).zip(kept) {
+            if keep {
+                entries.push_back(entry);
+            } else {
+                evicted.push(entry);
+            }
+        }
+        self.buffers = HistoryEntries(entries);
+        for entry in &evicted {
+            self.fire(HistoryEvent::Evict(entry));
+        }
+    }
+
+    /// Adds a hook that is called when entries are pushed, loaded or evicted and when the
+    /// history is saved, for example to keep another store in sync.
+    pub fn on_event<F: Fn(&HistoryEvent) + Send + Sync + 'static>(&mut self, f: F) {
+        self.hooks.push(Box::new(f));
+    }
+
+    fn fire(&self, event: HistoryEvent) {
+        for hook in &self.hooks {
+            hook(&event);
+        }
+    }
+
     /// Number of items in history.
     #[inline(always)]
     pub fn len(&self) -> usize {
         self.buffers.len()
     }
 
+    /// Whether there are no items in history.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    /// Iterates over the commands in history, oldest first.
+    pub fn iter(&self) -> Iter {
+        fn buffer(entry: &HistoryEntry) -> &Buffer {
+            &entry.buffer
+        }
+        self.buffers.iter().map(buffer as fn(&HistoryEntry) -> &Buffer)
+    }
+
+    /// Iterates over the entries in history with their metadata, oldest first.
+    pub fn iter_entries(&self) -> vec_deque::Iter<HistoryEntry> {
+        self.buffers.iter()
+    }
+
+    /// The command at `index`, or `None` if there is no such entry.
+    pub fn get(&self, index: usize) -> Option<&Buffer> {
+        self.buffers.get(index).map(|e| &e.buffer)
+    }
+
+    /// The commands from `start` up to, but not including, `end`, oldest first. Indices past the
+    /// end of history are clamped.
+    pub fn range(&self, start: usize, end: usize) -> Vec<&Buffer> {
+        let end = cmp::min(end, self.len());
+        self.iter().skip(start).take(end.saturating_sub(start)).collect()
+    }
+
+    /// The `n` newest commands, oldest first.
+    pub fn newest(&self, n: usize) -> Vec<&Buffer> {
+        let len = self.len();
+        self.range(len.saturating_sub(n), len)
+    }
+
+    /// Removes the entry at `index` and returns it, or `None` if there is no such entry.
+    pub fn remove(&mut self, index: usize) -> Option<HistoryEntry> {
+        self.buffers.remove(index)
+    }
+
+    /// Removes the entry at `index` like `remove()`, and removes it from the history file too if
+    /// one is set. The file is read again first, so entries other sessions added to it are kept.
+    pub fn delete(&mut self, index: usize) -> io::Result<Option<HistoryEntry>> {
+        let entry = match self.buffers.remove(index) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if let Some(ref file_name) = self.file_name {
+            self.rewrite_file(file_name, |entries| {
+                if let Some(i) = entries.iter().rposition(|&(ref e, _)| e.buffer.equals(&entry.buffer)) {
+                    entries.remove(i);
+                }
+            })?;
+        }
+        Ok(Some(entry))
+    }
+
+    /// Replaces the command of the entry at `index`, keeping its metadata, and replaces it in the
+    /// history file too if one is set, keeping the entries other sessions added to it. Does
+    /// nothing if there is no such entry.
+    pub fn replace(&mut self, index: usize, buffer: Buffer) -> io::Result<()> {
+        let old = match self.buffers.get_mut(index) {
+            Some(entry) => mem::replace(&mut entry.buffer, buffer.clone()),
+            None => return Ok(()),
+        };
+        match self.file_name {
+            Some(ref file_name) => self.rewrite_file(file_name, |entries| {
+                if let Some(&mut (ref mut e, _)) = entries.iter_mut().rev().find(|&&mut (ref e, _)| e.buffer.equals(&old)) {
+                    e.buffer = buffer;
+                }
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes all entries from history. The history file is left alone.
+    pub fn clear(&mut self) {
+        self.buffers.clear();
+    }
+
+    /// Indices of the entries in `layer`, oldest first.
+    pub fn layer_indices(&self, layer: HistoryLayer) -> Vec<usize> {
+        (0..self.len()).filter(|&i| self.buffers.0[i].layer == layer).collect()
+    }
+
+    /// Indices of all entries in the order Up goes through them backwards when session entries
+    /// are preferred: the global entries, followed by the ones from this session.
+    pub fn session_first_order(&self) -> Vec<usize> {
+        let mut order = self.layer_indices(HistoryLayer::Global);
+        order.extend(self.layer_indices(HistoryLayer::Session));
+        order
+    }
+
+    /// The entry at `index`, with its metadata.
+    pub fn entry(&self, index: usize) -> Option<&HistoryEntry> {
+        self.buffers.get(index)
+    }
+
+    /// The entry at `index`, to add metadata to it once it is known.
+    pub fn entry_mut(&mut self, index: usize) -> Option<&mut HistoryEntry> {
+        self.buffers.get_mut(index)
+    }
+
     /// Add a command to the history buffer and remove the oldest commands when the max history
     /// size has been met. If writing to the disk is enabled, this function will be used for
     /// logging history to the designated history file.
     pub fn push(&mut self, new_item: Buffer) -> io::Result<()> {
-        // buffers[0] is the oldest entry
+        self.push_entry(HistoryEntry::new(new_item))
+    }
+
+    /// Same as `push()`, but for an entry that may carry metadata. With `inc_append`, the entry
+    /// is written to the file right away, so metadata added later is only saved by `save()`.
+    pub fn push_entry(&mut self, new_entry: HistoryEntry) -> io::Result<()> {
+        let new_entry = match self.apply_filter(new_entry) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+
+        // entries[0] is the oldest entry
         // the new entry goes to the end
+        let mut new_entry = match self.sanitize_entry(new_entry) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let line = new_entry.buffer.to_string();
         if !self.append_duplicate_entries
-            && self.buffers.back().map(|b| b.to_string()) == Some(new_item.to_string())
+            && self.buffers.back().map(|e| e.buffer.to_string()) == Some(line.clone())
         {
+            if let Some(last) = self.buffers.back_mut() {
+                last.use_count += 1;
+            }
+            return self.write_pushed(self.buffers.back());
+        }
+
+        if self.is_ignored(&line) {
             return Ok(());
         }
 
-        self.buffers.push_back(new_item);
-        while self.buffers.len() > self.max_buffers_size {
-            self.buffers.pop_front();
+        if let Some(earlier) = self.buffers.iter().rev().find(|e| e.buffer.equals(&new_entry.buffer)) {
+            new_entry.use_count += earlier.use_count;
         }
-        Ok(())
+
+        self.buffers.push_back(new_entry);
+        if let Some(entry) = self.buffers.back() {
+            self.fire(HistoryEvent::Push(entry));
+        }
+        let max = self.max_buffers_size;
+        // With `KeepPinned` and only pinned entries before it, the new entry is the one evicted.
+        let kept = self.buffers.len() <= max || self.kept(max).last() == Some(&true);
+        self.evict(max);
+
+        let pushed = if kept { self.buffers.back() } else { None };
+        self.write_pushed(pushed)
+    }
+
+    // Writes what a push changed to the history file, if there is one: `entry` is appended to it
+    // with `inc_append`, and the whole history is saved with `autosave`.
+    fn write_pushed(&self, entry: Option<&HistoryEntry>) -> io::Result<()> {
+        match (self.file_name.as_ref(), entry) {
+            (Some(file_name), Some(entry)) if self.inc_append => self.append_to_file(file_name, entry),
+            (Some(_), None) if self.inc_append => Ok(()),
+            (Some(file_name), _) if self.autosave => self.sync_file(file_name),
+            _ => Ok(()),
+        }
+    }
+
+    // `entry` as it is added to the history, following `sanitize` and `max_entry_len`, or `None`
+    // if nothing is left of it.
+    fn sanitize_entry(&self, mut entry: HistoryEntry) -> Option<HistoryEntry> {
+        if !self.sanitize && self.max_entry_len.is_none() {
+            return Some(entry);
+        }
+        let line = entry.buffer.to_string();
+        let clean = self.sanitized(&line);
+        if clean.is_empty() {
+            return None;
+        }
+        if clean != line {
+            entry.buffer = Buffer::from(clean.as_str());
+        }
+        Some(entry)
+    }
+
+    // `line` as it is added to the history, following `sanitize` and `max_entry_len`.
+    fn sanitized(&self, line: &str) -> String {
+        let mut clean: String = if self.sanitize {
+            util::remove_codes(line).chars().filter(|&c| !c.is_control() || c == '\n' || c == '\t').collect()
+        } else {
+            line.to_owned()
+        };
+        if let Some(max) = self.max_entry_len {
+            if let Some((end, _)) = clean.char_indices().nth(max) {
+                clean.truncate(end);
+            }
+        }
+        clean
+    }
+
+    /// Keeps lines matching the glob `pattern` out of the history. In the pattern, `*` matches any
+    /// run of characters and `?` any single character, so `*password*` ignores every line that
+    /// mentions a password.
+    pub fn ignore(&mut self, pattern: &str) {
+        let pattern = pattern.to_owned();
+        self.ignore_fns.push(Box::new(move |line| util::glob_match(&pattern, line)));
+    }
+
+    /// Keeps lines for which `f` returns true out of the history, such as lines matching a regex.
+    pub fn ignore_matching<F: Fn(&str) -> bool + Send + Sync + 'static>(&mut self, f: F) {
+        self.ignore_fns.push(Box::new(f));
+    }
+
+    /// Sets a filter that is called with every line before it is added to the history, before
+    /// the duplicate and ignore checks, to skip it or replace it with another buffer, for example
+    /// to redact secrets. Replaces any filter set before.
+    pub fn set_filter<F: Fn(&Buffer) -> HistoryAction + Send + Sync + 'static>(&mut self, f: F) {
+        self.filter = Some(Box::new(f));
+    }
+
+    /// Removes the filter set with `set_filter()`.
+    pub fn clear_filter(&mut self) {
+        self.filter = None;
+    }
+
+    // The entry as the filter wants it added, or `None` if it is to be skipped.
+    fn apply_filter(&self, mut entry: HistoryEntry) -> Option<HistoryEntry> {
+        let action = match self.filter {
+            Some(ref filter) => filter(&entry.buffer),
+            None => HistoryAction::Keep,
+        };
+        match action {
+            HistoryAction::Keep => Some(entry),
+            HistoryAction::Skip => None,
+            HistoryAction::Replace(buffer) => {
+                entry.buffer = buffer;
+                Some(entry)
+            }
+        }
+    }
+
+    /// Whether `line` would be kept out of the history by `ignore()` or `ignore_matching()`.
+    pub fn is_ignored(&self, line: &str) -> bool {
+        self.ignore_fns.iter().any(|f| f(line))
     }
 
     /// Removes duplicate entries in the history
     pub fn remove_duplicates(&mut self, input: &str) {
-        self.buffers.retain(|buffer| {
-            let command = buffer.lines().concat();
+        self.buffers.retain(|entry| {
+            let command = entry.buffer.lines().concat();
             command != input
         });
     }
 
-    fn get_match<I>(&self, vals: I, search_term: &Buffer) -> Option<usize>
+    fn get_match<I>(&self, vals: I, search_term: &Buffer, anchor: MatchAnchor) -> Option<usize>
         where I: Iterator<Item = usize>
     {
-        vals.filter_map(|i| self.buffers.get(i).map(|t| (i, t)))
-            .filter(|(_i, tested)| tested.starts_with(search_term))
+        vals.filter_map(|i| self.buffers.get(i).map(|t| (i, &t.buffer)))
+            .filter(|(_i, tested)| anchor.matches(tested, search_term) && !tested.equals(search_term))
             .next().map(|(i, _)| i)
     }
 
     /// Go through the history and try to find an index (newest to oldest) which starts the same
     /// as the new buffer given to this function as argument.  Starts at curr_position.  Does no wrap.
     pub fn get_newest_match(&self, curr_position: Option<usize>, new_buff: &Buffer, ) -> Option<usize> {
+        self.get_newest_match_anchored(curr_position, new_buff, MatchAnchor::Start)
+    }
+
+    /// Same as `get_newest_match()`, but the new buffer has to be found in the entry where
+    /// `anchor` says. Entries equal to the new buffer never match.
+    pub fn get_newest_match_anchored(&self, curr_position: Option<usize>, new_buff: &Buffer, anchor: MatchAnchor) -> Option<usize> {
         let pos = curr_position.unwrap_or_else(|| self.buffers.len());
         if pos > 0 {
-            self.get_match((0..pos).rev(), new_buff)
+            self.get_match((0..pos).rev(), new_buff, anchor)
         } else {
             None
         }
@@ -130,7 +1058,7 @@ impl History {
     pub fn get_history_subset(&self, search_term: &Buffer) -> Vec<usize> {
         let mut v: Vec<usize> = Vec::new();
         let mut ret: Vec<usize> = (0..self.len()).filter(|i| {
-            if let Some(tested) = self.buffers.get(*i) {
+            if let Some(tested) = self.entry(*i).map(|e| &e.buffer) {
                 let starts = tested.starts_with(search_term);
                 let contains = tested.contains(search_term);
                 if starts {
@@ -146,10 +1074,76 @@ impl History {
         ret
     }
 
+    /// Indices of the entries that start with `prefix` and are longer than it, oldest first.
+    pub fn get_prefix_subset(&self, prefix: &Buffer) -> Vec<usize> {
+        let prefix: Vec<char> = prefix.chars().cloned().collect();
+        (0..self.len()).filter(|i| {
+            let tested = &self.buffers[*i];
+            tested.num_chars() > prefix.len() && tested.chars().zip(&prefix).all(|(t, p)| t == p)
+        }).collect()
+    }
+
     pub fn search_index(&self, search_term: &Buffer) -> Vec<usize>
     {
-        (0..self.len()).filter_map(|i| self.buffers.get(i).map(|t| (i, t)))
-            .filter(|(_i, tested)| tested.contains(search_term))
+        self.search_index_anchored(search_term, MatchAnchor::Anywhere)
+    }
+
+    /// Ranks matches by the score `f` gives each entry, together with how many entries are newer
+    /// than it, instead of by age. `History::frecency` is a scorer that favors entries used
+    /// often and recently.
+    pub fn set_scorer<F: Fn(&HistoryEntry, usize) -> f64 + Send + Sync + 'static>(&mut self, f: F) {
+        self.scorer = Some(Box::new(f));
+    }
+
+    /// Goes back to ranking matches by age.
+    pub fn clear_scorer(&mut self) {
+        self.scorer = None;
+    }
+
+    /// Scores an entry by how often it was used, less the older it is.
+    pub fn frecency(entry: &HistoryEntry, age: usize) -> f64 {
+        entry.use_count as f64 / (1.0 + age as f64 / 100.0)
+    }
+
+    /// Sorts `indices` so the best match comes last, as the newest one does without a scorer.
+    /// Pinned entries go after the others.
+    pub fn rank(&self, indices: &mut Vec<usize>) {
+        if let Some(ref scorer) = self.scorer {
+            let len = self.len();
+            let score = |i: usize| scorer(&self.buffers.0[i], len - 1 - i);
+            indices.sort_by(|&a, &b| {
+                score(a).partial_cmp(&score(b)).unwrap_or(cmp::Ordering::Equal).then(a.cmp(&b))
+            });
+        }
+        if !self.pinned.is_empty() {
+            indices.sort_by_key(|&i| self.is_pinned(&self.buffers[i].to_string()));
+        }
+    }
+
+    /// Whether matches are ranked by something else than age: a scorer set with `set_scorer()`
+    /// or pinned entries.
+    pub fn is_ranked(&self) -> bool {
+        self.scorer.is_some() || !self.pinned.is_empty()
+    }
+
+    /// The index of the best entry matching `search_term` where `anchor` says: the newest
+    /// pinned one, or else the newest one or the one scoring highest if a scorer is set. Entries
+    /// equal to the term never match.
+    pub fn best_match(&self, search_term: &Buffer, anchor: MatchAnchor) -> Option<usize> {
+        if !self.is_ranked() {
+            return self.get_newest_match_anchored(None, search_term, anchor);
+        }
+        let mut indices = self.search_index_anchored(search_term, anchor);
+        indices.retain(|&i| !self.buffers[i].equals(search_term));
+        self.rank(&mut indices);
+        indices.last().cloned()
+    }
+
+    /// Indices of the entries matching `search_term` where `anchor` says, oldest first.
+    pub fn search_index_anchored(&self, search_term: &Buffer, anchor: MatchAnchor) -> Vec<usize>
+    {
+        (0..self.len()).filter_map(|i| self.buffers.get(i).map(|t| (i, &t.buffer)))
+            .filter(|(_i, tested)| anchor.matches(tested, search_term))
             .map(|(i, _)| i).collect()
     }
 
@@ -159,36 +1153,154 @@ impl History {
         self.file_name.as_ref().map(|s| s.as_str())
     }
 
+    /// Drops the entries past `max_file_size` and saves the history to its file, if there is
+    /// one, keeping the entries other sessions added to it.
     pub fn commit_to_file(&mut self) {
         if let Some(file_name) = self.file_name.clone() {
-            // Find how many bytes we need to move backwards
-            // in the file to remove all the old commands.
-            if self.buffers.len() >= self.max_file_size {
-                let pop_out = self.buffers.len() - self.max_file_size;
-                for _ in 0..pop_out {
-                    self.buffers.pop_front();
+            let max = self.max_file_size;
+            self.evict(max);
+            let _ = self.sync_file(&file_name);
+        }
+    }
+}
+
+// Returns for each entry, given whether it is pinned, whether it is kept when there may only be
+// `max` of them: all pinned ones, and the newest unpinned ones that fill up what they leave over.
+fn keep_newest(pinned: &[bool], max: usize) -> Vec<bool> {
+    let mut room = max.saturating_sub(pinned.iter().filter(|&&p| p).count());
+    let mut kept = pinned.to_vec();
+    for (keep, &pinned) in kept.iter_mut().zip(pinned.iter()).rev() {
+        if !pinned && room > 0 {
+            *keep = true;
+            room -= 1;
+        }
+    }
+    kept
+}
+
+// Key of `HistoryEntry::extra` that timestamps of other shells' history files are kept under.
+const TIME_KEY: &str = "time";
+
+// An entry read from another shell's history file.
+fn foreign_entry(command: String, time: Option<&str>) -> HistoryEntry {
+    let mut entry = HistoryEntry::new(Buffer::from(command));
+    entry.layer = HistoryLayer::Global;
+    if let Some(time) = time {
+        set_time(&mut entry, time);
+    }
+    entry
+}
+
+// Keeps `time` on the entry if it is a timestamp.
+fn set_time(entry: &mut HistoryEntry, time: &str) {
+    if !time.is_empty() && time.chars().all(|c| c.is_ascii_digit()) {
+        entry.extra.insert(TIME_KEY.to_owned(), time.to_owned());
+    }
+}
+
+fn parse_bash_history(data: &[u8]) -> Vec<HistoryEntry> {
+    let text = String::from_utf8_lossy(data);
+    let mut entries = vec![];
+    let mut time = None;
+    for line in text.lines() {
+        if line.starts_with('#') && line.len() > 1 && line[1..].chars().all(|c| c.is_ascii_digit()) {
+            time = Some(&line[1..]);
+        } else if !line.is_empty() {
+            entries.push(foreign_entry(line.to_owned(), time.take()));
+        }
+    }
+    entries
+}
+
+fn parse_zsh_history(data: &[u8]) -> Vec<HistoryEntry> {
+    let text = String::from_utf8_lossy(&unmetafy(data)).into_owned();
+    let mut entries = vec![];
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        // Extended history lines look like `: <start>:<elapsed>;<command>`.
+        let (mut command, time, elapsed) = match (line.starts_with(": "), line.find(';')) {
+            (true, Some(semicolon)) => {
+                let mut header = line[2..semicolon].splitn(2, ':');
+                let time = header.next();
+                let elapsed = header.next().and_then(|e| e.parse().ok());
+                (line[semicolon + 1..].to_owned(), time, elapsed)
+            }
+            _ => (line.to_owned(), None, None),
+        };
+        // Lines of a command are joined with a backslash at the end of all but the last one.
+        while command.ends_with('\\') {
+            match lines.next() {
+                Some(next) => {
+                    command.pop();
+                    command.push('\n');
+                    command.push_str(next);
                 }
+                None => break,
+            }
+        }
+        if !command.is_empty() {
+            let mut entry = foreign_entry(command, time);
+            entry.duration = elapsed.map(Duration::from_secs);
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+fn parse_fish_history(data: &[u8]) -> Vec<HistoryEntry> {
+    let text = String::from_utf8_lossy(data);
+    let mut entries: Vec<HistoryEntry> = vec![];
+    for line in text.lines() {
+        if line.starts_with("- cmd: ") {
+            let command = unescape_metadata(&line["- cmd: ".len()..]);
+            entries.push(foreign_entry(command, None));
+        } else if line.starts_with("  when: ") {
+            if let Some(entry) = entries.last_mut() {
+                set_time(entry, &line["  when: ".len()..]);
             }
+        }
+    }
+    entries
+}
 
-            let mut file = BufWriter::new(File::create(&file_name)
-                // It's safe to unwrap, because the file has be loaded by this time
-                .unwrap());
+// zsh writes bytes it uses internally, NUL and 0x83 to 0xa2, as 0x83 followed by the byte xor 32.
+fn metafy(data: &[u8]) -> Vec<u8> {
+    let mut metafied = Vec::with_capacity(data.len());
+    for &byte in data {
+        if byte == 0 || byte >= 0x83 && byte <= 0xa2 {
+            metafied.push(0x83);
+            metafied.push(byte ^ 32);
+        } else {
+            metafied.push(byte);
+        }
+    }
+    metafied
+}
 
-            // Write the commands to the history file.
-            for command in self.buffers.iter().cloned() {
-                let _ = file.write_all(&String::from(command).as_bytes());
-                let _ = file.write_all(b"\n");
+fn unmetafy(data: &[u8]) -> Vec<u8> {
+    let mut unmetafied = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == 0x83 {
+            if let Some(&next) = bytes.next() {
+                unmetafied.push(next ^ 32);
             }
+        } else {
+            unmetafied.push(byte);
         }
     }
+    unmetafied
 }
 
+/// Iterator over the commands in a `History`, returned by `History::iter()`.
+pub type Iter<'a> = iter::Map<vec_deque::Iter<'a, HistoryEntry>, fn(&'a HistoryEntry) -> &'a Buffer>;
+
 impl<'a> IntoIterator for &'a History {
     type Item = &'a Buffer;
-    type IntoIter = vec_deque::Iter<'a, Buffer>;
+    type IntoIter = Iter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.buffers.iter()
+        self.iter()
     }
 }
 
@@ -0,0 +1,53 @@
+/// A key, as the keymaps and key bindings see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Backspace,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    /// Shift-Tab.
+    BackTab,
+    Delete,
+    Insert,
+    /// A function key, such as `F(1)`.
+    F(u8),
+    /// A char typed on its own, including `'\n'` for Enter and `'\t'` for Tab.
+    Char(char),
+    Alt(char),
+    Ctrl(char),
+    Null,
+    Esc,
+}
+
+/// Keys read with termion, for programs that read their own, with the `termion` feature.
+#[cfg(all(unix, feature = "termion"))]
+impl From<::termion::event::Key> for Key {
+    fn from(key: ::termion::event::Key) -> Key {
+        use termion::event::Key as K;
+        match key {
+            K::Backspace => Key::Backspace,
+            K::Left => Key::Left,
+            K::Right => Key::Right,
+            K::Up => Key::Up,
+            K::Down => Key::Down,
+            K::Home => Key::Home,
+            K::End => Key::End,
+            K::PageUp => Key::PageUp,
+            K::PageDown => Key::PageDown,
+            K::BackTab => Key::BackTab,
+            K::Delete => Key::Delete,
+            K::Insert => Key::Insert,
+            K::F(n) => Key::F(n),
+            K::Char(c) => Key::Char(c),
+            K::Alt(c) => Key::Alt(c),
+            K::Ctrl(c) => Key::Ctrl(c),
+            K::Esc => Key::Esc,
+            _ => Key::Null,
+        }
+    }
+}
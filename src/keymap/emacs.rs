@@ -1,9 +1,10 @@
 use std::io::{self, Write};
-use termion::event::Key;
+use Key;
 
 use KeyMap;
 use Editor;
-use CursorPosition;
+use CtrlCBehavior;
+use CursorShape;
 
 /// Emacs keybindings for `Editor`. This is the default for `Context::read_line()`.
 ///
@@ -15,11 +16,31 @@ use CursorPosition;
 pub struct Emacs<'a, W: Write> {
     ed: Editor<'a, W>,
     last_arg_fetch_index: Option<usize>,
+    // Typed chars replace the one under the cursor instead of being inserted.
+    overwrite: bool,
 }
 
 impl<'a, W: Write> Emacs<'a, W> {
-    pub fn new(ed: Editor<'a, W>) -> Self {
-        Emacs { ed, last_arg_fetch_index: None }
+    pub fn new(mut ed: Editor<'a, W>) -> Self {
+        let undo_policy = ed.context().emacs_undo_policy;
+        ed.set_undo_policy(undo_policy);
+        Emacs { ed, last_arg_fetch_index: None, overwrite: false }
+    }
+
+    // Moves the cursor left, ringing the bell if it's at the start of the line already.
+    fn move_left(&mut self) -> io::Result<()> {
+        if self.ed.cursor() == 0 {
+            return self.ed.bell();
+        }
+        self.ed.move_cursor_left(1)
+    }
+
+    // Moves the cursor right, ringing the bell if it's at the end of the line already.
+    fn move_right(&mut self) -> io::Result<()> {
+        if self.ed.cursor() >= self.ed.current_buffer().num_chars() {
+            return self.ed.bell();
+        }
+        self.ed.move_cursor_right(1)
     }
 
     fn handle_ctrl_key(&mut self, c: char) -> io::Result<()> {
@@ -27,14 +48,16 @@ impl<'a, W: Write> Emacs<'a, W> {
             'l' => self.ed.clear(),
             'a' => self.ed.move_cursor_to_start_of_line(),
             'e' => self.ed.move_cursor_to_end_of_line(),
-            'b' => self.ed.move_cursor_left(1),
-            'f' => self.ed.move_cursor_right(1),
+            'b' => self.move_left(),
+            'f' => self.move_right(),
             'd' => self.ed.delete_after_cursor(),
             'p' => self.ed.move_up(),
             'n' => self.ed.move_down(),
             'u' => self.ed.delete_all_before_cursor(),
             'k' => self.ed.delete_all_after_cursor(),
             'w' => self.ed.delete_word_before_cursor(true),
+            't' => self.ed.transpose_chars(),
+            'y' => self.ed.yank(),
             'x' => {
                 self.ed.undo()?;
                 Ok(())
@@ -48,8 +71,13 @@ impl<'a, W: Write> Emacs<'a, W> {
             '<' => self.ed.move_to_start_of_history(),
             '>' => self.ed.move_to_end_of_history(),
             '\x7F' => self.ed.delete_word_before_cursor(true),
-            'f' => emacs_move_word(&mut self.ed, EmacsMoveDir::Right),
-            'b' => emacs_move_word(&mut self.ed, EmacsMoveDir::Left),
+            'f' => self.ed.move_word_right(),
+            'b' => self.ed.move_word_left(),
+            'd' => self.ed.delete_word_after_cursor(),
+            'c' => self.ed.capitalize_word(),
+            'u' => self.ed.upcase_word(),
+            'l' => self.ed.downcase_word(),
+            't' => self.ed.transpose_words(),
             'r' => {
                 self.ed.revert()?;
                 Ok(())
@@ -94,6 +122,18 @@ impl<'a, W: Write> Emacs<'a, W> {
 }
 
 impl<'a, W: Write> KeyMap<'a, W, Emacs<'a, W>> for Emacs<'a, W> {
+    fn ctrl_c_behavior(&mut self) -> CtrlCBehavior {
+        self.ed.context().emacs_ctrl_c_behavior
+    }
+
+    fn cursor_shape(&mut self) -> Option<CursorShape> {
+        if self.overwrite {
+            self.ed.context().emacs_overwrite_cursor_shape
+        } else {
+            None
+        }
+    }
+
     fn handle_key_core(&mut self, key: Key) -> io::Result<()> {
         match key {
             Key::Alt('.') => {},
@@ -101,11 +141,16 @@ impl<'a, W: Write> KeyMap<'a, W, Emacs<'a, W>> for Emacs<'a, W> {
         }
 
         match key {
+            Key::Char(c) if self.overwrite => self.ed.overwrite_after_cursor(c),
             Key::Char(c) => self.ed.insert_after_cursor(c),
+            Key::Insert => {
+                self.overwrite = !self.overwrite;
+                Ok(())
+            }
             Key::Alt(c) => self.handle_alt_key(c),
             Key::Ctrl(c) => self.handle_ctrl_key(c),
-            Key::Left => self.ed.move_cursor_left(1),
-            Key::Right => self.ed.move_cursor_right(1),
+            Key::Left => self.move_left(),
+            Key::Right => self.move_right(),
             Key::Up => self.ed.move_up(),
             Key::Down => self.ed.move_down(),
             Key::Home => self.ed.move_cursor_to_start_of_line(),
@@ -132,61 +177,14 @@ impl<'a, W: Write> From<Emacs<'a, W>> for String {
     }
 }
 
-#[derive(PartialEq, Clone, Copy)]
-enum EmacsMoveDir {
-    Left,
-    Right,
-}
-
-fn emacs_move_word<W: Write>(ed: &mut Editor<W>, direction: EmacsMoveDir) -> io::Result<()> {
-    let (words, pos) = ed.get_words_and_cursor_position();
-
-    let word_index = match pos {
-        CursorPosition::InWord(i) => {
-            Some(i)
-        },
-        CursorPosition::OnWordLeftEdge(mut i) => {
-            if i > 0 && direction == EmacsMoveDir::Left {
-                i -= 1;
-            }
-            Some(i)
-        },
-        CursorPosition::OnWordRightEdge(mut i) => {
-            if i < words.len() - 1 && direction == EmacsMoveDir::Right {
-                i += 1;
-            }
-            Some(i)
-        },
-        CursorPosition::InSpace(left, right) => {
-            match direction {
-                EmacsMoveDir::Left => left,
-                EmacsMoveDir::Right => right,
-            }
-        },
-    };
-
-    match word_index {
-        None => Ok(()),
-        Some(i) => {
-            let (start, end) = words[i];
-
-            let new_cursor_pos = match direction {
-                EmacsMoveDir::Left => start,
-                EmacsMoveDir::Right => end,
-            };
-
-            ed.move_cursor_to(new_cursor_pos)
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use termion::event::Key;
+    use Key;
     use Context;
     use Editor;
     use KeyMap;
+    use UndoPolicy;
     use std::io::Write;
 
     macro_rules! simulate_keys {
@@ -199,7 +197,7 @@ mod tests {
         where I: Iterator<Item = &'b Key>
     {
         for k in keys {
-            if keymap.handle_key(*k, &mut |_| {}).unwrap() {
+            if keymap.handle_key(*k, &mut |_| {}).unwrap().is_some() {
                 return true;
             }
         }
@@ -284,4 +282,203 @@ mod tests {
         assert_eq!(res.is_ok(), true);
         assert_eq!(map.ed.current_buffer().to_string(), "not empt".to_string());
     }
+
+    #[test]
+    /// ctrl-c can be configured to start over with an empty line
+    fn ctrl_c_clear_line() {
+        let mut context = Context::new();
+        context.emacs_ctrl_c_behavior = CtrlCBehavior::ClearLine;
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        map.ed.insert_str_after_cursor("abandon me").unwrap();
+
+        let res = map.handle_key(Key::Ctrl('c'), &mut |_| {});
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(map.ed.cursor(), 0);
+        assert_eq!(map.ed.current_buffer().to_string(), "".to_string());
+    }
+
+    #[test]
+    /// ctrl-c can be configured to reach the key bindings like any other key
+    fn ctrl_c_pass_through() {
+        let mut context = Context::new();
+        context.emacs_ctrl_c_behavior = CtrlCBehavior::PassThrough;
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        map.ed.insert_str_after_cursor("keep me").unwrap();
+
+        let res = map.handle_key(Key::Ctrl('c'), &mut |_| {});
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(map.ed.current_buffer().to_string(), "keep me".to_string());
+    }
+
+    #[test]
+    fn word_case_and_delete() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        map.ed.insert_str_after_cursor("one two three four").unwrap();
+
+        simulate_keys!(map, [Key::Ctrl('a'), Key::Alt('c'), Key::Alt('u'), Key::Alt('d'), Key::Alt('f'), Key::Alt('b'), Key::Alt('l')]);
+
+        assert_eq!(String::from(map), "One TWO four");
+    }
+
+    #[test]
+    fn undo_policy_from_context() {
+        let mut context = Context::new();
+        context.emacs_undo_policy = UndoPolicy::PerWord;
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+
+        simulate_keys!(map, [Key::Char('a'), Key::Char('b'), Key::Char(' '), Key::Char('c'), Key::Ctrl('x')]);
+
+        assert_eq!(String::from(map), "ab");
+    }
+
+    #[test]
+    fn kill_and_yank() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        map.ed.insert_str_after_cursor("one two three").unwrap();
+
+        simulate_keys!(map, [Key::Ctrl('w'), Key::Ctrl('a'), Key::Ctrl('y')]);
+
+        assert_eq!(String::from(map), "threeone two ");
+    }
+
+    #[test]
+    fn insert_toggles_overwrite() {
+        let mut context = Context::new();
+        assert_eq!(context.emacs_overwrite_cursor_shape, None);
+        context.emacs_overwrite_cursor_shape = Some(CursorShape::SteadyUnderline);
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        map.ed.insert_str_after_cursor("abc").unwrap();
+
+        simulate_keys!(map, [Key::Ctrl('a'), Key::Insert, Key::Char('x'), Key::Char('y')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "xyc");
+        assert_eq!(map.cursor_shape(), Some(CursorShape::SteadyUnderline));
+
+        simulate_keys!(map, [Key::Char('z'), Key::Char('w'), Key::Insert, Key::Char('v')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "xyzwv");
+        assert_eq!(map.cursor_shape(), None);
+
+        simulate_keys!(map, [Key::Ctrl('x')]);
+        assert_eq!(String::from(map), "xyzw");
+    }
+
+    #[test]
+    fn events_follow_the_session() {
+        let mut context = Context::new();
+        context.history.push("ls".into()).unwrap();
+        let mut kinds = vec![];
+        {
+            let ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+            let mut map = Emacs::new(ed);
+            map.handle_key(Key::Char('a'), &mut |e| kinds.push(format!("{:?}", e.kind))).unwrap();
+            assert_eq!(kinds, vec![
+                "KeyPressed(Char('a'))",
+                "BeforeKey(Char('a'))",
+                "BufferChanged",
+                "AfterKey(Char('a'))",
+                "BeforeDisplay",
+                "AfterDisplay",
+            ]);
+
+            kinds.clear();
+            map.handle_key(Key::Char('\n'), &mut |e| kinds.push(format!("{:?}", e.kind))).unwrap();
+            assert!(!kinds.contains(&"BeforeDisplay".to_owned()));
+            assert_eq!(kinds.last().unwrap(), "LineAccepted");
+        }
+
+        let ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        kinds.clear();
+        map.handle_key(Key::Up, &mut |e| kinds.push(format!("{:?}", e.kind))).unwrap();
+        assert!(kinds.contains(&"HistoryNavigated(Some(0))".to_owned()));
+        assert!(!kinds.contains(&"BufferChanged".to_owned()));
+        map.handle_key(Key::Ctrl('r'), &mut |e| kinds.push(format!("{:?}", e.kind))).unwrap();
+        assert!(kinds.contains(&"SearchStarted".to_owned()));
+    }
+
+    #[test]
+    fn handlers_can_cancel_and_replace() {
+        use {Event, EventAction, EventKind, ReadLineResult};
+
+        let mut context = Context::new();
+        let ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        fn handler(e: Event<Vec<u8>>) {
+            let typo = e.editor.current_buffer().to_string().ends_with("teh ");
+            match e.kind {
+                EventKind::BeforeKey(Key::Char(c)) if c.is_digit(10) => e.respond(EventAction::Cancel),
+                EventKind::BufferChanged if typo => {
+                    let fixed = e.editor.current_buffer().to_string().replace("teh ", "the ");
+                    e.respond(EventAction::ReplaceBuffer(fixed));
+                }
+                EventKind::LineAccepted => e.respond(EventAction::ReplaceBuffer("accepted".to_owned())),
+                _ => {}
+            }
+        }
+        for &k in &[Key::Char('1'), Key::Char('t'), Key::Char('e'), Key::Char('h'), Key::Char(' '), Key::Char('2')] {
+            map.handle_key(k, &mut handler).unwrap();
+        }
+        assert_eq!(map.ed.current_buffer().to_string(), "the ");
+        assert_eq!(map.handle_key(Key::Char('\n'), &mut handler).unwrap(), Some(ReadLineResult::Input("accepted".to_owned())));
+    }
+
+    #[test]
+    fn key_pressed_cancels_even_if_before_key_continues() {
+        use {Event, EventAction, EventKind};
+
+        let mut context = Context::new();
+        let ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        fn handler(e: Event<Vec<u8>>) {
+            match e.kind {
+                EventKind::KeyPressed(Key::Char('x')) => e.respond(EventAction::Cancel),
+                EventKind::BeforeKey(_) => e.respond(EventAction::Continue),
+                _ => {}
+            }
+        }
+        for &k in &[Key::Char('a'), Key::Char('x'), Key::Char('b')] {
+            map.handle_key(k, &mut handler).unwrap();
+        }
+        assert_eq!(map.ed.current_buffer().to_string(), "ab");
+    }
+
+    #[test]
+    fn handler_errors_are_returned_or_shown() {
+        use {Event, EventKind};
+
+        fn handler(e: Event<Vec<u8>>) {
+            if let EventKind::BeforeKey(Key::Char('!')) = e.kind {
+                e.fail(io::Error::new(io::ErrorKind::Other, "no bangs"));
+            }
+        }
+
+        let mut context = Context::new();
+        {
+            let ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+            let mut map = Emacs::new(ed);
+            map.handle_key(Key::Char('a'), &mut handler).unwrap();
+            let err = map.handle_key(Key::Char('!'), &mut handler).unwrap_err();
+            assert_eq!(err.to_string(), "no bangs");
+        }
+
+        context.show_handler_errors = true;
+        let ed = Editor::new(Vec::new(), "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Emacs::new(ed);
+        map.handle_key(Key::Char('!'), &mut handler).unwrap();
+        assert_eq!(map.ed.message(), Some("no bangs"));
+        assert_eq!(map.ed.current_buffer().to_string(), "!");
+    }
 }
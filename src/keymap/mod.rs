@@ -1,6 +1,12 @@
-use std::io::{self, Write, ErrorKind};
-use termion::event::Key;
+use std::io::{self, Write};
+use std::time::Instant;
+use Key;
 use Editor;
+use CtrlCBehavior;
+use CursorShape;
+use Input;
+use KeyBindings;
+use ReadLineResult;
 use event::*;
 
 pub trait KeyMap<'a, W: Write, T>: From<T> {
@@ -8,12 +14,119 @@ pub trait KeyMap<'a, W: Write, T>: From<T> {
     fn editor(&self) -> &Editor<'a, W>;
     fn editor_mut(&mut self) -> &mut Editor<'a, W>;
 
-    fn handle_key(&mut self, mut key: Key, handler: &mut EventHandler<W>) -> io::Result<bool> {
+    /// What Ctrl-C should do with these key bindings.
+    fn ctrl_c_behavior(&mut self) -> CtrlCBehavior {
+        CtrlCBehavior::Interrupt
+    }
+
+    /// The shape the terminal cursor should have right now, or `None` for the terminal's default.
+    fn cursor_shape(&mut self) -> Option<CursorShape> {
+        None
+    }
+
+    /// Gets the terminal ready for reading a line, setting the cursor shape and switching to the
+    /// alternate screen and turning on mouse reporting, bracketed paste and focus reporting if
+    /// the context asks for them.
+    fn start(&mut self) -> io::Result<()> {
+        if self.editor_mut().context().alternate_screen {
+            self.editor_mut().enter_alternate_screen()?;
+        }
+        let shape = self.cursor_shape();
+        self.editor_mut().set_cursor_shape(shape)?;
+        if self.editor_mut().context().mouse {
+            self.editor_mut().enable_mouse()?;
+        }
+        if self.editor_mut().context().bracketed_paste {
+            self.editor_mut().enable_bracketed_paste()?;
+        }
+        if self.editor_mut().context().focus_events {
+            self.editor_mut().enable_focus_events()?;
+        }
+        Ok(())
+    }
+
+    /// Puts the terminal back the way it was before `start()`. Every step is tried even if one
+    /// fails, so that the user isn't left on the alternate screen, and the first error is
+    /// returned.
+    fn finish(&mut self) -> io::Result<()> {
+        let ed = self.editor_mut();
+        let results = vec![
+            ed.disable_mouse(),
+            ed.disable_bracketed_paste(),
+            ed.disable_focus_events(),
+            ed.end_flash(),
+            ed.reset_cursor_shape(),
+            ed.leave_alternate_screen(),
+        ];
+        results.into_iter().collect()
+    }
+
+    /// Handles something read from the terminal, timing keys in `Context.metrics`.
+    ///
+    /// Returns `Ok(Some(_))` once the read is finished, like `handle_key()`.
+    fn handle_input(&mut self, input: Input, handler: &mut EventHandler<W>) -> io::Result<Option<ReadLineResult>> {
+        match input {
+            Input::Key(key) => {
+                let started = Instant::now();
+                let res = self.handle_key(key, handler)?;
+                self.editor_mut().context().metrics.add_key(started.elapsed());
+                Ok(res)
+            }
+            Input::Click(x, y) => {
+                self.editor_mut().click(x as usize, y as usize)?;
+                Ok(None)
+            }
+            Input::Paste(text) => {
+                self.editor_mut().handle_paste(text, handler)?;
+                Ok(None)
+            }
+            Input::Focus(focused) => {
+                self.editor_mut().handle_focus(focused, handler)?;
+                Ok(None)
+            }
+            Input::Unknown(bytes) => {
+                self.editor_mut().handle_unknown_input(bytes, handler)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Handles a single keypress.
+    ///
+    /// Returns `Ok(Some(_))` once the read is finished, either because the line was accepted or
+    /// because it was ended by Ctrl-C or Ctrl-D.
+    fn handle_key(&mut self, mut key: Key, handler: &mut EventHandler<W>) -> io::Result<Option<ReadLineResult>> {
         let mut done = false;
 
-        handler(Event::new(self.editor_mut(), EventKind::BeforeKey(key)));
+        // Messages only last until the next keypress, though handlers can show a new one.
+        self.editor_mut().clear_message()?;
+        // Completions still coming are for what was there before this key.
+        self.editor_mut().cancel_completion();
+
+        let text_before = self.editor().current_buffer().to_string();
+        let cursor_before = self.editor().cursor();
+        let history_loc_before = self.editor().current_history_location();
+        let searching_before = self.editor().is_searching();
+
+        let pressed = self.editor_mut().send_event(handler, EventKind::KeyPressed(key))?;
+        let before = self.editor_mut().send_event(handler, EventKind::BeforeKey(key))?;
+        // Either event can cancel or replace the key, whatever the other one responded with.
+        let action = match before {
+            None | Some(EventAction::Continue) => pressed,
+            before => before,
+        };
+        let skip_key = match action {
+            Some(EventAction::Cancel) => true,
+            Some(EventAction::ReplaceBuffer(text)) => {
+                self.editor_mut().set_buffer(&text)?;
+                true
+            }
+            _ => false,
+        };
 
         let is_empty = self.editor().current_buffer().is_empty();
+        let delete_history_entry_key = self.editor_mut().context().delete_history_entry_key;
+        let toggle_pin_key = self.editor_mut().context().toggle_pin_key;
 
         if key == Key::Ctrl('h') {
             // XXX: Might need to change this when remappable keybindings are added.
@@ -21,15 +134,24 @@ pub trait KeyMap<'a, W: Write, T>: From<T> {
         }
 
         match key {
-            Key::Ctrl('c') => {
-                self.editor_mut().handle_newline()?;
-                return Err(io::Error::new(ErrorKind::Interrupted, "ctrl-c"));
-            }
+            _ if skip_key => {}
+            Key::Ctrl('c') => match self.ctrl_c_behavior() {
+                CtrlCBehavior::Interrupt => {
+                    self.editor_mut().handle_newline()?;
+                    return Ok(Some(ReadLineResult::Interrupted));
+                }
+                CtrlCBehavior::ClearLine => self.editor_mut().abandon_line()?,
+                CtrlCBehavior::PassThrough => self.handle_key_core(key)?,
+            },
             // if the current buffer is empty, treat ctrl-d as eof
             Key::Ctrl('d') if is_empty => {
                 self.editor_mut().handle_newline()?;
-                return Err(io::Error::new(ErrorKind::UnexpectedEof, "ctrl-d"));
+                return Ok(Some(ReadLineResult::Eof));
             }
+            _ if Some(key) == delete_history_entry_key => {
+                self.editor_mut().delete_history_entry()?
+            }
+            _ if Some(key) == toggle_pin_key => self.editor_mut().toggle_history_pin()?,
             Key::Char('\t') => self.editor_mut().complete(handler, CompleteType::Next)?,
             Key::Right if self.editor().show_autosuggestions() => self.editor_mut().complete(handler, CompleteType::Next)?,
             Key::Left if self.editor().show_autosuggestions() => self.editor_mut().complete(handler, CompleteType::Prev)?,
@@ -41,7 +163,7 @@ pub trait KeyMap<'a, W: Write, T>: From<T> {
                 self.editor_mut().display()?;
             },
             Key::Char('\n') => {
-                done = self.editor_mut().handle_newline()?;
+                done = self.editor_mut().accept_line()?;
             }
             Key::Ctrl('f') if self.editor().is_currently_showing_autosuggestion() => {
                 self.editor_mut().accept_autosuggestion()?;
@@ -52,6 +174,8 @@ pub trait KeyMap<'a, W: Write, T>: From<T> {
             Key::Ctrl('s') => {
                 self.editor_mut().search(true)?;
             }
+            #[cfg(feature = "clipboard")]
+            Key::Alt('v') => self.editor_mut().paste_from_clipboard()?,
             Key::Right if self.editor().is_currently_showing_autosuggestion() &&
                           !self.editor().show_autosuggestions() &&
                           self.editor().cursor_is_at_end_of_line() => {
@@ -63,11 +187,48 @@ pub trait KeyMap<'a, W: Write, T>: From<T> {
             }
         };
 
-        handler(Event::new(self.editor_mut(), EventKind::AfterKey(key)));
+        let shape = self.cursor_shape();
+        self.editor_mut().set_cursor_shape(shape)?;
+
+        let history_loc = self.editor().current_history_location();
+        if history_loc != history_loc_before {
+            self.editor_mut().send_event(handler, EventKind::HistoryNavigated(history_loc))?;
+        } else if self.editor().current_buffer().to_string() != text_before {
+            match self.editor_mut().send_event(handler, EventKind::BufferChanged)? {
+                Some(EventAction::Cancel) => {
+                    self.editor_mut().set_buffer(&text_before)?;
+                    self.editor_mut().move_cursor_to(cursor_before)?;
+                }
+                Some(EventAction::ReplaceBuffer(text)) => self.editor_mut().set_buffer(&text)?,
+                _ => {}
+            }
+        }
+        let searching = self.editor().is_searching();
+        if searching && !searching_before {
+            self.editor_mut().send_event(handler, EventKind::SearchStarted)?;
+        } else if !searching && searching_before {
+            self.editor_mut().send_event(handler, EventKind::SearchEnded)?;
+        }
+
+        self.editor_mut().send_event(handler, EventKind::AfterKey(key))?;
+
+        if !done {
+            self.editor_mut().send_event(handler, EventKind::BeforeDisplay)?;
+            self.editor_mut().display()?;
+            self.editor_mut().send_event(handler, EventKind::AfterDisplay)?;
+        }
 
         self.editor_mut().flush()?;
 
-        Ok(done)
+        if done {
+            let line = match self.editor_mut().send_event(handler, EventKind::LineAccepted)? {
+                Some(EventAction::ReplaceBuffer(text)) => text,
+                _ => self.editor().current_buffer().to_string(),
+            };
+            Ok(Some(ReadLineResult::Input(line)))
+        } else {
+            Ok(None)
+        }
     }
 }
 
@@ -77,11 +238,71 @@ pub use vi::Vi;
 pub mod emacs;
 pub use emacs::Emacs;
 
+/// The keymap for either of the `KeyBindings`, for when which one is only known at runtime.
+pub enum AnyKeyMap<'a, W: Write> {
+    Emacs(Emacs<'a, W>),
+    Vi(Vi<'a, W>),
+}
+
+impl<'a, W: Write> AnyKeyMap<'a, W> {
+    pub fn new(ed: Editor<'a, W>, key_bindings: KeyBindings) -> Self {
+        match key_bindings {
+            KeyBindings::Emacs => AnyKeyMap::Emacs(Emacs::new(ed)),
+            KeyBindings::Vi => AnyKeyMap::Vi(Vi::new(ed)),
+        }
+    }
+}
+
+impl<'a, W: Write> KeyMap<'a, W, AnyKeyMap<'a, W>> for AnyKeyMap<'a, W> {
+    fn ctrl_c_behavior(&mut self) -> CtrlCBehavior {
+        match *self {
+            AnyKeyMap::Emacs(ref mut emacs) => emacs.ctrl_c_behavior(),
+            AnyKeyMap::Vi(ref mut vi) => vi.ctrl_c_behavior(),
+        }
+    }
+
+    fn cursor_shape(&mut self) -> Option<CursorShape> {
+        match *self {
+            AnyKeyMap::Emacs(ref mut emacs) => emacs.cursor_shape(),
+            AnyKeyMap::Vi(ref mut vi) => vi.cursor_shape(),
+        }
+    }
+
+    fn handle_key_core(&mut self, key: Key) -> io::Result<()> {
+        match *self {
+            AnyKeyMap::Emacs(ref mut emacs) => emacs.handle_key_core(key),
+            AnyKeyMap::Vi(ref mut vi) => vi.handle_key_core(key),
+        }
+    }
+
+    fn editor_mut(&mut self) -> &mut Editor<'a, W> {
+        match *self {
+            AnyKeyMap::Emacs(ref mut emacs) => emacs.editor_mut(),
+            AnyKeyMap::Vi(ref mut vi) => vi.editor_mut(),
+        }
+    }
+
+    fn editor(&self) -> &Editor<'a, W> {
+        match *self {
+            AnyKeyMap::Emacs(ref emacs) => emacs.editor(),
+            AnyKeyMap::Vi(ref vi) => vi.editor(),
+        }
+    }
+}
+
+impl<'a, W: Write> From<AnyKeyMap<'a, W>> for String {
+    fn from(keymap: AnyKeyMap<'a, W>) -> String {
+        match keymap {
+            AnyKeyMap::Emacs(emacs) => emacs.into(),
+            AnyKeyMap::Vi(vi) => vi.into(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use termion::event::Key::*;
-    use std::io::ErrorKind;
+    use Key::*;
     use Context;
 
     struct TestKeyMap<'a, W: Write> {
@@ -111,7 +332,7 @@ mod tests {
     }
 
     #[test]
-    /// when the current buffer is empty, ctrl-d generates and eof error
+    /// when the current buffer is empty, ctrl-d ends the read with eof
     fn ctrl_d_empty() {
         let mut context = Context::new();
         let out = Vec::new();
@@ -119,8 +340,7 @@ mod tests {
         let mut map = TestKeyMap::new(ed);
 
         let res = map.handle_key(Ctrl('d'), &mut |_| {});
-        assert_eq!(res.is_err(), true);
-        assert_eq!(res.err().unwrap().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(res.unwrap(), Some(ReadLineResult::Eof));
     }
 
     #[test]
@@ -133,11 +353,11 @@ mod tests {
         map.ed.insert_str_after_cursor("not empty").unwrap();
 
         let res = map.handle_key(Ctrl('d'), &mut |_| {});
-        assert_eq!(res.is_ok(), true);
+        assert_eq!(res.unwrap(), None);
     }
 
     #[test]
-    /// ctrl-c should generate an error
+    /// ctrl-c should end the read as interrupted
     fn ctrl_c() {
         let mut context = Context::new();
         let out = Vec::new();
@@ -145,7 +365,100 @@ mod tests {
         let mut map = TestKeyMap::new(ed);
 
         let res = map.handle_key(Ctrl('c'), &mut |_| {});
-        assert_eq!(res.is_err(), true);
-        assert_eq!(res.err().unwrap().kind(), ErrorKind::Interrupted);
+        assert_eq!(res.unwrap(), Some(ReadLineResult::Interrupted));
+    }
+
+    #[test]
+    /// enter should end the read with the entered line
+    fn enter_returns_input() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = TestKeyMap::new(ed);
+        map.ed.insert_str_after_cursor("some input").unwrap();
+
+        let res = map.handle_key(Char('\n'), &mut |_| {});
+        assert_eq!(res.unwrap(), Some(ReadLineResult::Input("some input".to_owned())));
+    }
+
+    #[test]
+    fn keypress_clears_message() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = TestKeyMap::new(ed);
+        map.ed.show_message("hello").unwrap();
+        assert_eq!(map.ed.geometry().unwrap().rows, 2);
+
+        map.handle_key(Char('a'), &mut |_| {}).unwrap();
+        assert_eq!(map.ed.geometry().unwrap().rows, 1);
+    }
+
+    #[test]
+    fn enter_checks_the_line_first() {
+        use ReadLineOptions;
+
+        let mut context = Context::new();
+        let options = ReadLineOptions::new("age: ").validator(|line| match line.parse::<u32>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err("not a number".to_owned()),
+        });
+        let ed = Editor::with_options(Vec::new(), &mut context, options).unwrap();
+        let mut map = TestKeyMap::new(ed);
+        map.ed.insert_str_after_cursor("forty").unwrap();
+
+        let res = map.handle_key(Char('\n'), &mut |_| {});
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(map.ed.geometry().unwrap().rows, 2);
+
+        map.ed.set_buffer("40").unwrap();
+        let res = map.handle_key(Char('\n'), &mut |_| {});
+        assert_eq!(res.unwrap(), Some(ReadLineResult::Input("40".to_owned())));
+    }
+
+    #[test]
+    fn enter_transforms_the_line() {
+        use ReadLineOptions;
+
+        let mut context = Context::new();
+        let options = ReadLineOptions::new("age: ")
+            .accept_transform(|line| line.trim().to_owned(), false)
+            .validator(|line| match line.parse::<u32>() {
+                Ok(_) => Ok(()),
+                Err(_) => Err("not a number".to_owned()),
+            });
+        let ed = Editor::with_options(Vec::new(), &mut context, options).unwrap();
+        let mut map = TestKeyMap::new(ed);
+        map.ed.insert_str_after_cursor("  40 ").unwrap();
+
+        let res = map.handle_key(Char('\n'), &mut |_| {});
+        assert_eq!(res.unwrap(), Some(ReadLineResult::Input("40".to_owned())));
+    }
+
+    #[test]
+    fn finish_leaves_the_alternate_screen_after_an_error() {
+        // Fails to turn off mouse reporting, and takes everything else.
+        struct Stubborn(Vec<u8>);
+        impl Write for Stubborn {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if buf.starts_with(b"\x1B[?1006l") {
+                    return Err(io::Error::new(io::ErrorKind::Other, "stuck"));
+                }
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut context = Context::new();
+        context.alternate_screen = true;
+        context.mouse = true;
+        let ed = Editor::new(Stubborn(Vec::new()), "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = TestKeyMap::new(ed);
+        map.start().unwrap();
+        assert_eq!(map.finish().unwrap_err().to_string(), "stuck");
+        assert!(!map.editor().is_on_alternate_screen());
+        assert!(String::from_utf8_lossy(&map.editor_mut().output_mut().0).ends_with("\x1B[?1049l"));
     }
 }
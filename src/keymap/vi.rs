@@ -1,9 +1,12 @@
 use std::{mem, cmp};
 use std::io::{self, Write};
-use termion::event::Key;
+use Key;
 
 use KeyMap;
 use Editor;
+use CtrlCBehavior;
+use CursorShape;
+use RegisterKind;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum CharMovement {
@@ -266,28 +269,6 @@ fn vi_move_word_end<W: Write>(ed: &mut Editor<W>, move_mode: ViMoveMode, directi
     ed.move_cursor_to(cursor)
 }
 
-fn find_char(buf: &::buffer::Buffer, start: usize, ch: char, count: usize) -> Option<usize> {
-    assert!(count > 0);
-    buf.chars()
-        .enumerate()
-        .skip(start)
-        .filter(|&(_, &c)| c == ch)
-        .nth(count - 1)
-        .map(|(i, _)| i)
-}
-
-fn find_char_rev(buf: &::buffer::Buffer, start: usize, ch: char, count: usize) -> Option<usize> {
-    assert!(count > 0);
-    let rstart = buf.num_chars() - start;
-    buf.chars()
-        .enumerate()
-        .rev()
-        .skip(rstart)
-        .filter(|&(_, &c)| c == ch)
-        .nth(count - 1)
-        .map(|(i, _)| i)
-}
-
 /// Vi keybindings for `Editor`.
 ///
 /// ```
@@ -311,8 +292,10 @@ pub struct Vi<'a, W: Write> {
 
 impl<'a, W: Write> Vi<'a, W> {
     pub fn new(mut ed: Editor<'a, W>) -> Self {
-        // since we start in insert mode, we need to start an undo group
-        ed.current_buffer_mut().start_undo_group();
+        let undo_policy = ed.context().vi_undo_policy;
+        ed.set_undo_policy(undo_policy);
+        // since we start in insert mode, we need to start an insert session
+        ed.begin_insert_session();
 
         Vi {
             ed: ed,
@@ -351,7 +334,9 @@ impl<'a, W: Write> Vi<'a, W> {
         self.movement_reset = mode != Insert;
         self.mode_stack.push(mode);
 
-        if mode == Insert || mode == Tilde {
+        if mode == Insert {
+            self.ed.begin_insert_session();
+        } else if mode == Tilde {
             self.ed.current_buffer_mut().start_undo_group();
         }
     }
@@ -408,7 +393,9 @@ impl<'a, W: Write> Vi<'a, W> {
         self.ed.no_eol = self.mode() == Normal;
         self.movement_reset = self.mode() != Insert;
 
-        if last_mode == Insert || last_mode == Tilde {
+        if last_mode == Insert {
+            self.ed.end_insert_session();
+        } else if last_mode == Tilde {
             self.ed.current_buffer_mut().end_undo_group();
         }
 
@@ -417,11 +404,12 @@ impl<'a, W: Write> Vi<'a, W> {
         }
     }
 
-    /// Return to normal mode.
-    fn normal_mode_abort(&mut self) {
+    /// Return to normal mode, ringing the bell since the command was invalid.
+    fn normal_mode_abort(&mut self) -> io::Result<()> {
         self.mode_stack.clear();
         self.ed.no_eol = true;
         self.count = 0;
+        self.ed.bell()
     }
 
     /// When doing a move, 0 should behave the same as 1 as far as the count goes.
@@ -503,8 +491,8 @@ impl<'a, W: Write> Vi<'a, W> {
             }
             Key::Char(c) => {
                 if self.movement_reset {
-                    self.ed.current_buffer_mut().end_undo_group();
-                    self.ed.current_buffer_mut().start_undo_group();
+                    self.ed.end_insert_session();
+                    self.ed.begin_insert_session();
                     self.last_command.clear();
                     self.movement_reset = false;
                     // vim behaves as if this was 'i'
@@ -516,8 +504,8 @@ impl<'a, W: Write> Vi<'a, W> {
             // delete and backspace need to be included in the command buffer
             Key::Backspace | Key::Delete => {
                 if self.movement_reset {
-                    self.ed.current_buffer_mut().end_undo_group();
-                    self.ed.current_buffer_mut().start_undo_group();
+                    self.ed.end_insert_session();
+                    self.ed.begin_insert_session();
                     self.last_command.clear();
                     self.movement_reset = false;
                     // vim behaves as if this was 'i'
@@ -532,22 +520,17 @@ impl<'a, W: Write> Vi<'a, W> {
                 self.movement_reset = true;
                 self.handle_key_common(key)
             }
-            // up and down require even more special handling
+            // up and down also reset the repeat count; the editor carries the insert session over
+            // to the buffer being moved to
             Key::Up => {
                 self.count = 0;
                 self.movement_reset = true;
-                self.ed.current_buffer_mut().end_undo_group();
-                self.ed.move_up()?;
-                self.ed.current_buffer_mut().start_undo_group();
-                Ok(())
+                self.ed.move_up()
             }
             Key::Down => {
                 self.count = 0;
                 self.movement_reset = true;
-                self.ed.current_buffer_mut().end_undo_group();
-                self.ed.move_down()?;
-                self.ed.current_buffer_mut().start_undo_group();
-                Ok(())
+                self.ed.move_down()
             }
             _ => self.handle_key_common(key),
         }
@@ -778,6 +761,40 @@ impl<'a, W: Write> Vi<'a, W> {
                 self.pop_mode();
                 Ok(())
             }
+            Key::Char('p') | Key::Char('P') => {
+                // update the last command state
+                self.last_insert = None;
+                self.last_command.clear();
+                self.last_command.push(key);
+                self.last_count = self.count;
+
+                let count = self.move_count();
+                self.count = 0;
+
+                if self.ed.context().kill_buffer.kind != RegisterKind::Charwise {
+                    self.ed.current_buffer_mut().start_undo_group();
+                    for _ in 0..count {
+                        self.ed.put(key == Key::Char('p'))?;
+                    }
+                    self.ed.current_buffer_mut().end_undo_group();
+                    return Ok(());
+                }
+
+                // 'p' puts the text after the char under the cursor, which can be past the end
+                self.ed.no_eol = false;
+                if key == Key::Char('p') {
+                    self.ed.move_cursor_right(1)?;
+                }
+                self.ed.current_buffer_mut().start_undo_group();
+                for _ in 0..count {
+                    self.ed.yank()?;
+                }
+                self.ed.current_buffer_mut().end_undo_group();
+                self.ed.no_eol = true;
+
+                // leave the cursor on the last char put in
+                self.ed.move_cursor_left(1)
+            }
             Key::Char('u') => {
                 let count = self.move_count();
                 self.count = 0;
@@ -800,6 +817,8 @@ impl<'a, W: Write> Vi<'a, W> {
                 }
                 Ok(())
             }
+            // not a vi command
+            Key::Char(_) => self.ed.bell(),
             _ => self.handle_key_common(key),
         }
     }
@@ -830,7 +849,7 @@ impl<'a, W: Write> Vi<'a, W> {
             }
             // not a char
             _ => {
-                self.normal_mode_abort();
+                self.normal_mode_abort()?;
             }
         };
 
@@ -876,8 +895,7 @@ impl<'a, W: Write> Vi<'a, W> {
                 // delete the whole line
                 self.count = 0;
                 self.secondary_count = 0;
-                self.ed.move_cursor_to_start_of_line()?;
-                self.ed.delete_all_after_cursor()?;
+                self.ed.kill_line(key == Key::Char('c'))?;
 
                 // return to the previous mode
                 self.pop_mode();
@@ -885,8 +903,7 @@ impl<'a, W: Write> Vi<'a, W> {
             }
             // not a delete or change command, back to normal mode
             _ => {
-                self.normal_mode_abort();
-                Ok(())
+                self.normal_mode_abort()
             }
         }
     }
@@ -908,8 +925,7 @@ impl<'a, W: Write> Vi<'a, W> {
             (_, ReverseRepeat, Some((c, RightAt))) => (Key::Char(c), LeftAt),
             // repeat with no last_char_movement, invalid
             (_, Repeat, None) | (_, ReverseRepeat, None) => {
-                self.normal_mode_abort();
-                return Ok(());
+                return self.normal_mode_abort();
             }
             // pass valid keys through as is
             (Key::Char(c), _, _) => {
@@ -920,8 +936,7 @@ impl<'a, W: Write> Vi<'a, W> {
             }
             // all other combinations are invalid, abort
             _ => {
-                self.normal_mode_abort();
-                return Ok(());
+                return self.normal_mode_abort();
             }
         };
 
@@ -931,28 +946,28 @@ impl<'a, W: Write> Vi<'a, W> {
                 match movement {
                     RightUntil => {
                         move_type = Inclusive;
-                        match find_char(self.ed.current_buffer(), self.ed.cursor() + 1, c, count) {
+                        match self.ed.current_buffer().find_char_forward(self.ed.cursor() + 1, c, count) {
                             Some(i) => self.ed.move_cursor_to(i - 1),
                             None => Ok(()),
                         }
                     }
                     RightAt => {
                         move_type = Inclusive;
-                        match find_char(self.ed.current_buffer(), self.ed.cursor() + 1, c, count) {
+                        match self.ed.current_buffer().find_char_forward(self.ed.cursor() + 1, c, count) {
                             Some(i) => self.ed.move_cursor_to(i),
                             None => Ok(()),
                         }
                     }
                     LeftUntil => {
                         move_type = Exclusive;
-                        match find_char_rev(self.ed.current_buffer(), self.ed.cursor(), c, count) {
+                        match self.ed.current_buffer().find_char_backward(self.ed.cursor(), c, count) {
                             Some(i) => self.ed.move_cursor_to(i + 1),
                             None => Ok(()),
                         }
                     }
                     LeftAt => {
                         move_type = Exclusive;
-                        match find_char_rev(self.ed.current_buffer(), self.ed.cursor(), c, count) {
+                        match self.ed.current_buffer().find_char_backward(self.ed.cursor(), c, count) {
                             Some(i) => self.ed.move_cursor_to(i),
                             None => Ok(()),
                         }
@@ -987,8 +1002,7 @@ impl<'a, W: Write> Vi<'a, W> {
 
             // not a supported command
             _ => {
-                self.normal_mode_abort();
-                Ok(())
+                self.normal_mode_abort()
             }
         };
 
@@ -998,6 +1012,17 @@ impl<'a, W: Write> Vi<'a, W> {
 }
 
 impl<'a, W: Write> KeyMap<'a, W, Vi<'a, W>> for Vi<'a, W> {
+    fn ctrl_c_behavior(&mut self) -> CtrlCBehavior {
+        self.ed.context().vi_ctrl_c_behavior
+    }
+
+    fn cursor_shape(&mut self) -> Option<CursorShape> {
+        match self.mode() {
+            Mode::Insert => self.ed.context().vi_insert_cursor_shape,
+            _ => self.ed.context().vi_normal_cursor_shape,
+        }
+    }
+
     fn handle_key_core(&mut self, key: Key) -> io::Result<()> {
         match self.mode() {
             Mode::Normal => self.handle_key_normal(key),
@@ -1028,12 +1053,13 @@ impl<'a, W: Write> From<Vi<'a, W>> for String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use termion::event::Key;
-    use termion::event::Key::*;
+    use Key;
+    use Key::*;
     use Buffer;
     use Context;
     use Editor;
     use KeyMap;
+    use UndoPolicy;
     use std::io::Write;
 
     macro_rules! simulate_keys {
@@ -1046,7 +1072,7 @@ mod tests {
         where I: Iterator<Item=&'b Key>
     {
         for k in keys {
-            if keymap.handle_key(*k, &mut |_| {}).unwrap() {
+            if keymap.handle_key(*k, &mut |_| {}).unwrap().is_some() {
                 return true;
             }
         }
@@ -3217,7 +3243,7 @@ mod tests {
         let out = Vec::new();
         let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
         ed.insert_str_after_cursor("abcdefg").unwrap();
-        assert_eq!(super::find_char(ed.current_buffer(), 0, 'd', 1), Some(3));
+        assert_eq!(ed.current_buffer().find_char_forward(0, 'd', 1), Some(3));
     }
 
     #[test]
@@ -3227,7 +3253,7 @@ mod tests {
         let out = Vec::new();
         let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
         ed.insert_str_after_cursor("abcabc").unwrap();
-        assert_eq!(super::find_char(ed.current_buffer(), 1, 'a', 1), Some(3));
+        assert_eq!(ed.current_buffer().find_char_forward(1, 'a', 1), Some(3));
     }
 
     #[test]
@@ -3237,7 +3263,7 @@ mod tests {
         let out = Vec::new();
         let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
         ed.insert_str_after_cursor("abcabc").unwrap();
-        assert_eq!(super::find_char(ed.current_buffer(), 0, 'a', 2), Some(3));
+        assert_eq!(ed.current_buffer().find_char_forward(0, 'a', 2), Some(3));
     }
 
     #[test]
@@ -3247,7 +3273,7 @@ mod tests {
         let out = Vec::new();
         let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
         ed.insert_str_after_cursor("abcdefg").unwrap();
-        assert_eq!(super::find_char(ed.current_buffer(), 0, 'z', 1), None);
+        assert_eq!(ed.current_buffer().find_char_forward(0, 'z', 1), None);
     }
 
     #[test]
@@ -3257,7 +3283,7 @@ mod tests {
         let out = Vec::new();
         let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
         ed.insert_str_after_cursor("abcdefg").unwrap();
-        assert_eq!(super::find_char_rev(ed.current_buffer(), 6, 'd', 1), Some(3));
+        assert_eq!(ed.current_buffer().find_char_backward(6, 'd', 1), Some(3));
     }
 
     #[test]
@@ -3267,7 +3293,7 @@ mod tests {
         let out = Vec::new();
         let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
         ed.insert_str_after_cursor("abcabc").unwrap();
-        assert_eq!(super::find_char_rev(ed.current_buffer(), 5, 'c', 1), Some(2));
+        assert_eq!(ed.current_buffer().find_char_backward(5, 'c', 1), Some(2));
     }
 
     #[test]
@@ -3277,7 +3303,7 @@ mod tests {
         let out = Vec::new();
         let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
         ed.insert_str_after_cursor("abcabc").unwrap();
-        assert_eq!(super::find_char_rev(ed.current_buffer(), 6, 'c', 2), Some(2));
+        assert_eq!(ed.current_buffer().find_char_backward(6, 'c', 2), Some(2));
     }
 
     #[test]
@@ -3287,7 +3313,7 @@ mod tests {
         let out = Vec::new();
         let mut ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
         ed.insert_str_after_cursor("abcdefg").unwrap();
-        assert_eq!(super::find_char_rev(ed.current_buffer(), 6, 'z', 1), None);
+        assert_eq!(ed.current_buffer().find_char_backward(6, 'z', 1), None);
     }
 
     #[test]
@@ -3797,4 +3823,135 @@ mod tests {
         ]);
         assert_eq!(map.ed.cursor(), 7);
     }
+
+    #[test]
+    /// the vi bindings have their own ctrl-c setting
+    fn ctrl_c_clear_line() {
+        let mut context = Context::new();
+        context.vi_ctrl_c_behavior = CtrlCBehavior::ClearLine;
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Vi::new(ed);
+        map.ed.insert_str_after_cursor("abandon me").unwrap();
+
+        let res = map.handle_key(Ctrl('c'), &mut |_| {});
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(map.ed.current_buffer().to_string(), "".to_string());
+    }
+
+    #[test]
+    /// with a per keystroke undo policy, insert mode is not undone as a whole
+    fn undo_policy_per_keystroke() {
+        let mut context = Context::new();
+        context.vi_undo_policy = UndoPolicy::PerKeystroke;
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Vi::new(ed);
+
+        simulate_keys!(map, [
+            Char('a'),
+            Char('b'),
+            Char('c'),
+            Esc,
+            Char('u'),
+        ]);
+        assert_eq!(String::from(map), "ab");
+    }
+
+    #[test]
+    /// moving through history in insert mode keeps each buffer's undo steps intact
+    fn undo_after_history_navigation() {
+        let mut context = Context::new();
+        context.history.push(Buffer::from("old")).unwrap();
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Vi::new(ed);
+
+        simulate_keys!(map, [
+            Up,
+            Char('e'),
+            Char('r'),
+            Down,
+            Char('n'),
+            Char('e'),
+            Char('w'),
+            Esc,
+        ]);
+        assert_eq!(map.ed.current_buffer().to_string(), "new");
+
+        simulate_keys!(map, [Char('u')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "");
+
+        simulate_keys!(map, [Char('k')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "older");
+
+        simulate_keys!(map, [Char('u')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "old");
+    }
+
+    #[test]
+    /// the cursor shape follows the mode when it is configured
+    fn cursor_shape_per_mode() {
+        let mut context = Context::new();
+        context.vi_insert_cursor_shape = Some(CursorShape::SteadyBar);
+        context.vi_normal_cursor_shape = Some(CursorShape::SteadyBlock);
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Vi::new(ed);
+        assert_eq!(map.cursor_shape(), Some(CursorShape::SteadyBar));
+
+        simulate_keys!(map, [Esc]);
+        assert_eq!(map.cursor_shape(), Some(CursorShape::SteadyBlock));
+        simulate_keys!(map, [Char('d')]);
+        assert_eq!(map.cursor_shape(), Some(CursorShape::SteadyBlock));
+        simulate_keys!(map, [Esc, Char('i')]);
+        assert_eq!(map.cursor_shape(), Some(CursorShape::SteadyBar));
+    }
+
+    #[test]
+    /// deleted text can be put back after or before the cursor
+    fn delete_and_put() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Vi::new(ed);
+        map.ed.insert_str_after_cursor("abc").unwrap();
+
+        simulate_keys!(map, [Esc, Char('0'), Char('x'), Char('p')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "bac");
+        assert_eq!(map.ed.cursor(), 1);
+
+        simulate_keys!(map, [Char('$'), Char('2'), Char('P')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "baaac");
+        assert_eq!(map.ed.cursor(), 3);
+
+        simulate_keys!(map, [Char('u')]);
+        assert_eq!(String::from(map), "bac");
+    }
+
+    #[test]
+    /// deleted lines are put back on lines of their own
+    fn delete_and_put_lines() {
+        let mut context = Context::new();
+        let out = Vec::new();
+        let ed = Editor::new(out, "prompt".to_owned(), None, &mut context).unwrap();
+        let mut map = Vi::new(ed);
+        map.ed.insert_str_after_cursor("one\ntwo\nthree").unwrap();
+        map.ed.move_cursor_to(5).unwrap();
+
+        simulate_keys!(map, [Esc, Char('d'), Char('d')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "one\nthree");
+        assert_eq!(map.ed.cursor(), 4);
+
+        simulate_keys!(map, [Char('p')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "one\nthree\ntwo");
+        assert_eq!(map.ed.cursor(), 10);
+
+        simulate_keys!(map, [Char('0'), Char('P')]);
+        assert_eq!(map.ed.current_buffer().to_string(), "two\none\nthree\ntwo");
+        assert_eq!(map.ed.cursor(), 0);
+
+        simulate_keys!(map, [Char('u')]);
+        assert_eq!(String::from(map), "one\nthree\ntwo");
+    }
 }
@@ -1,6 +1,22 @@
 extern crate bytecount;
-extern crate termion;
 extern crate unicode_width;
+extern crate unicode_segmentation;
+#[cfg(feature = "clipboard")]
+extern crate clipboard;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(all(unix, feature = "termion"))]
+extern crate termion;
+#[cfg(feature = "crossterm")]
+extern crate crossterm;
+#[cfg(windows)]
+extern crate winapi;
+#[cfg(feature = "async")]
+extern crate futures_core;
 
 mod event;
 pub use event::*;
@@ -17,12 +33,49 @@ pub use context::*;
 mod buffer;
 pub use buffer::*;
 
+mod gap_buffer;
+
 mod history;
 pub use history::*;
 
+mod register;
+pub use register::*;
+
 mod keymap;
 pub use keymap::*;
 
+mod decoder;
+
+mod key;
+pub use key::*;
+
+mod ansi;
+
+mod tty;
+pub use tty::{IntoRawMode, RawTerminal};
+
+mod terminal;
+pub use terminal::*;
+
+mod capabilities;
+pub use capabilities::*;
+
+mod session;
+pub use session::*;
+
+#[cfg(feature = "async")]
+mod async_read;
+#[cfg(feature = "async")]
+pub use async_read::*;
+
+#[cfg(feature = "crossterm")]
+mod crossterm_backend;
+
+#[cfg(windows)]
+mod windows_console;
+#[cfg(windows)]
+pub use windows_console::*;
+
 mod util;
 
 #[cfg(test)]
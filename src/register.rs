@@ -0,0 +1,38 @@
+/// How the text in a `Register` is put back into the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterKind {
+    /// The text is inserted at the cursor.
+    Charwise,
+    /// The text is one or more whole lines, put on lines of their own above or below the line
+    /// the cursor is on.
+    Linewise,
+    /// Each line of the text is a row of a rectangle, inserted at the cursor's column on the
+    /// line the cursor is on and the ones after it.
+    Blockwise,
+}
+
+/// Text that was killed or yanked, to be put back later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Register {
+    pub text: String,
+    pub kind: RegisterKind,
+}
+
+impl Register {
+    pub fn new<S: Into<String>>(text: S, kind: RegisterKind) -> Self {
+        Register {
+            text: text.into(),
+            kind: kind,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.kind == RegisterKind::Charwise
+    }
+}
+
+impl Default for Register {
+    fn default() -> Self {
+        Register::new(String::new(), RegisterKind::Charwise)
+    }
+}
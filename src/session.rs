@@ -0,0 +1,150 @@
+use std::io;
+use std::mem;
+
+use decoder::Decoder;
+use {AnyKeyMap, Context, Editor, EventHandler, Input, KeyMap, ReadLineOptions, ReadLineResult};
+use {Terminal, VirtualTerminal};
+
+/// A line read without liner doing any I/O of its own, for embedding it in GUIs, games, a
+/// terminal widget in a browser or an existing poll loop. The application hands input to
+/// `feed()`, or the bytes the terminal sent to `feed_bytes()`, and writes what `take_output()`
+/// returns to wherever the editor is shown.
+///
+/// The terminal's size is taken from `Context.terminal` if the application sets its size, as with
+/// a `VirtualTerminal`. Otherwise, such as with the terminal of `Context::new()`, the session puts
+/// an 80 by 24 `VirtualTerminal` there until the line is read. Either way, `resize()` changes the
+/// size.
+///
+/// ```
+/// use liner::{Context, Input, Key, ReadLineOptions, ReadLineResult, Session};
+///
+/// # fn main() {
+/// let mut context = Context::new();
+/// let mut session = Session::new(&mut context, ReadLineOptions::new("$ ")).unwrap();
+/// for c in "ls\n".chars() {
+///     if let Some(res) = session.feed(Input::Key(Key::Char(c)), &mut |_| {}).unwrap() {
+///         assert_eq!(res, ReadLineResult::Input("ls".to_owned()));
+///     }
+/// }
+/// let output = session.take_output();
+/// # }
+/// ```
+pub struct Session<'a> {
+    keymap: AnyKeyMap<'a, Vec<u8>>,
+    decoder: Decoder,
+    done: bool,
+    // The terminal of the context, while a `VirtualTerminal` takes its place.
+    replaced_terminal: Option<Box<Terminal>>,
+}
+
+impl<'a> Session<'a> {
+    /// Starts reading a line with the settings in `options`. The prompt is drawn to the output
+    /// straight away.
+    pub fn new(context: &'a mut Context, options: ReadLineOptions) -> io::Result<Self> {
+        let resizable = match context.terminal.size() {
+            Ok((cols, rows)) => context.terminal.resize(cols, rows),
+            Err(_) => false,
+        };
+        let replaced_terminal = if resizable {
+            None
+        } else {
+            Some(mem::replace(&mut context.terminal, Box::new(VirtualTerminal::new(80, 24))))
+        };
+
+        let key_bindings = options.key_bindings.unwrap_or(context.key_bindings);
+        // Drawing to a `Vec` for a `VirtualTerminal` doesn't fail, so a replaced terminal isn't
+        // lost here.
+        let ed = Editor::with_options(Vec::new(), context, options)?;
+        let mut session = Session {
+            keymap: AnyKeyMap::new(ed, key_bindings),
+            decoder: Decoder::new(),
+            done: false,
+            replaced_terminal: replaced_terminal,
+        };
+        // Turning on mouse reporting can fail after the cursor shape was set.
+        if let Err(e) = session.keymap.start() {
+            let _ = session.finish();
+            return Err(e);
+        }
+        Ok(session)
+    }
+
+    // Puts the terminal back the way it was before the session, including the terminal of the
+    // context if it was replaced.
+    fn finish(&mut self) -> io::Result<()> {
+        self.done = true;
+        let finished = self.keymap.finish();
+        if let Some(terminal) = self.replaced_terminal.take() {
+            self.editor_mut().context().terminal = terminal;
+        }
+        finished
+    }
+
+    /// Handles something the application read, returning `Ok(Some(_))` once the line is read.
+    /// Input after that, or after an error, is ignored.
+    pub fn feed(&mut self, input: Input, handler: &mut EventHandler<Vec<u8>>) -> io::Result<Option<ReadLineResult>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        // An error ends the read like a result does, and the terminal is put back either way.
+        let res = self.keymap.handle_input(input, handler);
+        if let Ok(None) = res {
+            return res;
+        }
+        let finished = self.finish();
+        let res = res?;
+        finished?;
+        Ok(res)
+    }
+
+    /// Decodes bytes the terminal sent, such as the data of an xterm.js `onData` event, and
+    /// handles the input in them like `feed()`. Escape sequences and chars split across calls
+    /// are put back together.
+    pub fn feed_bytes(&mut self, bytes: &[u8], handler: &mut EventHandler<Vec<u8>>) -> io::Result<Option<ReadLineResult>> {
+        let mut res = None;
+        for input in self.decoder.decode(bytes) {
+            if let Some(r) = self.feed(input, handler)? {
+                res = Some(r);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Gives the terminal a new size and redraws the editor for it.
+    pub fn resize(&mut self, cols: u16, rows: u16) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        if !self.editor_mut().context().terminal.resize(cols, rows) {
+            return Err(io::Error::new(io::ErrorKind::Other, "the size of this terminal can't be set"));
+        }
+        self.editor_mut().display()
+    }
+
+    /// Returns what the editor has drawn since the last call, to be written to the terminal.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(self.keymap.editor_mut().output_mut(), Vec::new())
+    }
+
+    /// Whether the line has been read.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn editor(&self) -> &Editor<'a, Vec<u8>> {
+        self.keymap.editor()
+    }
+
+    pub fn editor_mut(&mut self) -> &mut Editor<'a, Vec<u8>> {
+        self.keymap.editor_mut()
+    }
+}
+
+impl<'a> Drop for Session<'a> {
+    fn drop(&mut self) {
+        if let Some(terminal) = self.replaced_terminal.take() {
+            self.editor_mut().context().terminal = terminal;
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::any::Any;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+use decoder::InputReader;
+use Key;
+
+#[cfg(not(feature = "crossterm"))]
+use std::collections::VecDeque;
+#[cfg(not(feature = "crossterm"))]
+use std::io::Stdin;
+#[cfg(all(unix, not(feature = "crossterm")))]
+use decoder;
+use tty;
+#[cfg(not(feature = "crossterm"))]
+use tty::IntoRawMode;
+#[cfg(all(unix, not(feature = "crossterm")))]
+use util;
+
+/// How long to wait for the terminal to report the cursor position.
+#[cfg(all(unix, not(feature = "crossterm")))]
+const CURSOR_POS_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Something read from the terminal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// A keypress.
+    Key(Key),
+    /// A left click, at the 1-indexed column and row.
+    Click(u16, u16),
+    /// Text pasted into the terminal in one piece, with bracketed paste.
+    Paste(String),
+    /// The terminal window gained focus, or lost it with `false`, with focus reporting turned on.
+    Focus(bool),
+    /// An escape sequence or bytes that aren't anything liner knows, as they were read.
+    Unknown(Vec<u8>),
+}
+
+/// Keeps the terminal in raw mode until it is dropped. See `Terminal::raw_mode()`.
+// What it holds is only ever dropped, never read.
+pub struct RawModeGuard(#[allow(dead_code)] Box<Any + Send>);
+
+impl RawModeGuard {
+    /// Creates a guard around `restore`, which puts the terminal back the way it was when it is
+    /// dropped.
+    pub fn new<T: Any + Send>(restore: T) -> Self {
+        RawModeGuard(Box::new(restore))
+    }
+}
+
+/// An output that can take the terminal out of raw mode for a while. See `Editor::suspend()`.
+pub trait RawMode {
+    fn suspend_raw_mode(&self) -> io::Result<()>;
+    fn activate_raw_mode(&self) -> io::Result<()>;
+}
+
+/// Outputs put in raw mode with termion, for programs that do that themselves, with the
+/// `termion` feature.
+#[cfg(all(unix, feature = "termion"))]
+impl<W: Write> RawMode for ::termion::raw::RawTerminal<W> {
+    fn suspend_raw_mode(&self) -> io::Result<()> {
+        ::termion::raw::RawTerminal::suspend_raw_mode(self)
+    }
+
+    fn activate_raw_mode(&self) -> io::Result<()> {
+        ::termion::raw::RawTerminal::activate_raw_mode(self)
+    }
+}
+
+/// What the editor needs from the terminal it runs on, apart from somewhere to write its output:
+/// its size, where the cursor is, raw mode and the keys typed.
+///
+/// `Context.terminal` is a `SystemTerminal` unless it is replaced, e.g. with another backend or a
+/// mock in tests.
+pub trait Terminal: Send + Sync {
+    /// The size of the terminal in columns and rows. Zero is taken to mean unknown.
+    fn size(&mut self) -> io::Result<(u16, u16)>;
+
+    /// Where the cursor is, as the 1-indexed column and row. A request for it may be written to
+    /// `out`, the editor's output.
+    fn cursor_pos(&mut self, out: &mut Write) -> io::Result<(u16, u16)>;
+
+    /// Puts the terminal in raw mode until the returned guard is dropped.
+    fn raw_mode(&mut self) -> io::Result<RawModeGuard>;
+
+    /// Waits up to `timeout` for input and returns whether there is some. Terminals that can't
+    /// tell always say there is.
+    fn input_ready(&mut self, timeout: Duration) -> bool;
+
+    /// Reads the next input, or returns `None` once there is no more. Errors of the kind
+    /// `InvalidData` are taken to be about input that couldn't be decoded, and the editor skips
+    /// them; any other error ends the read.
+    fn read_input(&mut self) -> Option<io::Result<Input>>;
+
+    /// Gives the terminal a new size, for terminals whose size the application sets rather than
+    /// asks a real terminal for. Returns whether it took the size, which it doesn't by default.
+    fn resize(&mut self, _cols: u16, _rows: u16) -> bool {
+        false
+    }
+}
+
+/// The terminal on stdin and stdout. Input is decoded by liner, or read through crossterm with
+/// the `crossterm` feature.
+pub struct SystemTerminal {
+    #[cfg(not(feature = "crossterm"))]
+    input: InputReader<Stdin>,
+    // Input read while waiting for the cursor position, which comes next.
+    #[cfg(not(feature = "crossterm"))]
+    queued: VecDeque<Input>,
+}
+
+impl SystemTerminal {
+    #[cfg(not(feature = "crossterm"))]
+    pub fn new() -> Self {
+        SystemTerminal {
+            input: InputReader::new(io::stdin()),
+            queued: VecDeque::new(),
+        }
+    }
+
+    #[cfg(feature = "crossterm")]
+    pub fn new() -> Self {
+        SystemTerminal {}
+    }
+}
+
+impl Default for SystemTerminal {
+    fn default() -> Self {
+        SystemTerminal::new()
+    }
+}
+
+#[cfg(not(feature = "crossterm"))]
+impl Terminal for SystemTerminal {
+    fn size(&mut self) -> io::Result<(u16, u16)> {
+        if cfg!(test) {
+            Ok((80, 24))
+        } else {
+            tty::terminal_size()
+        }
+    }
+
+    #[cfg(unix)]
+    fn cursor_pos(&mut self, out: &mut Write) -> io::Result<(u16, u16)> {
+        out.write_all(b"\x1B[6n")?;
+        out.flush()?;
+        while self.input.has_pending() || util::stdin_ready(CURSOR_POS_TIMEOUT) {
+            match self.input.next() {
+                Some(Ok(Input::Unknown(bytes))) => match decoder::cursor_report(&bytes) {
+                    Some(pos) => return Ok(pos),
+                    None => self.queued.push_back(Input::Unknown(bytes)),
+                },
+                Some(Ok(input)) => self.queued.push_back(input),
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::Other, "the terminal didn't report the cursor position"))
+    }
+
+    #[cfg(windows)]
+    fn cursor_pos(&mut self, _: &mut Write) -> io::Result<(u16, u16)> {
+        tty::cursor_pos()
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn cursor_pos(&mut self, _: &mut Write) -> io::Result<(u16, u16)> {
+        Err(io::Error::new(io::ErrorKind::Other, "the cursor position of this terminal is unknown"))
+    }
+
+    fn raw_mode(&mut self) -> io::Result<RawModeGuard> {
+        Ok(RawModeGuard::new(io::stdout().into_raw_mode()?))
+    }
+
+    #[cfg(unix)]
+    fn input_ready(&mut self, timeout: Duration) -> bool {
+        !self.queued.is_empty() || self.input.has_pending() || util::stdin_ready(timeout)
+    }
+
+    #[cfg(not(unix))]
+    fn input_ready(&mut self, _: Duration) -> bool {
+        true
+    }
+
+    fn read_input(&mut self) -> Option<io::Result<Input>> {
+        match self.queued.pop_front() {
+            Some(input) => Some(Ok(input)),
+            None => self.input.next(),
+        }
+    }
+}
+
+#[cfg(feature = "crossterm")]
+impl Terminal for SystemTerminal {
+    fn size(&mut self) -> io::Result<(u16, u16)> {
+        if cfg!(test) {
+            Ok((80, 24))
+        } else {
+            tty::terminal_size()
+        }
+    }
+
+    fn cursor_pos(&mut self, out: &mut Write) -> io::Result<(u16, u16)> {
+        out.flush()?;
+        ::crossterm_backend::cursor_pos()
+    }
+
+    fn raw_mode(&mut self) -> io::Result<RawModeGuard> {
+        ::crossterm_backend::raw_mode()
+    }
+
+    fn input_ready(&mut self, timeout: Duration) -> bool {
+        ::crossterm_backend::input_ready(timeout)
+    }
+
+    fn read_input(&mut self) -> Option<io::Result<Input>> {
+        ::crossterm_backend::Events.next()
+    }
+}
+
+/// A terminal at the other end of any `Read`, such as a serial port, the master side of a pty or
+/// a network connection, with the editor's output going back to it. Keys are decoded from what
+/// is read, the same way as from stdin.
+///
+/// Nothing is known about the terminal but what it is told: its size is 80 by 24 until
+/// `set_size()` says otherwise, raw mode is left to whoever set up the connection, and asking for
+/// the cursor position fails, so clicks can't be used. Since a `Read` can't be polled, completions
+/// are only shown once the next key is read.
+pub struct ReadTerminal<R> {
+    input: Mutex<InputReader<R>>,
+    size: (u16, u16),
+}
+
+impl<R: Read> ReadTerminal<R> {
+    pub fn new(input: R) -> Self {
+        ReadTerminal {
+            input: Mutex::new(InputReader::new(input)),
+            size: (0, 0),
+        }
+    }
+
+    /// Sets the size of the terminal in columns and rows, e.g. after it was resized.
+    pub fn set_size(&mut self, cols: u16, rows: u16) {
+        self.size = (cols, rows);
+    }
+}
+
+impl<R: Read + Send> Terminal for ReadTerminal<R> {
+    fn size(&mut self) -> io::Result<(u16, u16)> {
+        Ok(self.size)
+    }
+
+    fn cursor_pos(&mut self, _: &mut Write) -> io::Result<(u16, u16)> {
+        Err(io::Error::new(io::ErrorKind::Other, "the cursor position of this terminal is unknown"))
+    }
+
+    fn raw_mode(&mut self) -> io::Result<RawModeGuard> {
+        Ok(RawModeGuard::new(()))
+    }
+
+    fn input_ready(&mut self, _: Duration) -> bool {
+        true
+    }
+
+    fn read_input(&mut self) -> Option<io::Result<Input>> {
+        self.input.get_mut().unwrap().next()
+    }
+
+    fn resize(&mut self, cols: u16, rows: u16) -> bool {
+        self.set_size(cols, rows);
+        true
+    }
+}
+
+/// A terminal liner knows nothing about but what it is told, such as an xterm.js widget in a
+/// browser, for use with a `Session`. Input is fed to the session and output taken from it, so
+/// nothing is read here, and the size is the one it was last given.
+pub struct VirtualTerminal {
+    size: (u16, u16),
+}
+
+impl VirtualTerminal {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        VirtualTerminal { size: (cols, rows) }
+    }
+}
+
+impl Terminal for VirtualTerminal {
+    fn size(&mut self) -> io::Result<(u16, u16)> {
+        Ok(self.size)
+    }
+
+    fn cursor_pos(&mut self, _: &mut Write) -> io::Result<(u16, u16)> {
+        Err(io::Error::new(io::ErrorKind::Other, "the cursor position of this terminal is unknown"))
+    }
+
+    fn raw_mode(&mut self) -> io::Result<RawModeGuard> {
+        Ok(RawModeGuard::new(()))
+    }
+
+    fn input_ready(&mut self, _: Duration) -> bool {
+        false
+    }
+
+    fn read_input(&mut self) -> Option<io::Result<Input>> {
+        None
+    }
+
+    fn resize(&mut self, cols: u16, rows: u16) -> bool {
+        self.size = (cols, rows);
+        true
+    }
+}
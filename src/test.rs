@@ -3,7 +3,10 @@ use context;
 
 use std::env;
 use std::fs;
-use std::io::{BufReader, BufRead, Write};
+use std::io::{self, BufReader, BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 fn assert_cursor_pos(s: &str, cursor: usize, expected_pos: CursorPosition) {
     let buf = Buffer::from(s.to_owned());
@@ -36,6 +39,25 @@ fn test_get_cursor_position() {
     }
 }
 
+fn assert_words(word_fn: fn(&Buffer) -> Vec<(usize, usize)>, s: &str, expected: &[&str]) {
+    let buf = Buffer::from(s.to_owned());
+    let words: Vec<String> = word_fn(&buf)
+        .into_iter()
+        .map(|(start, end)| buf.range(start, end))
+        .collect();
+    assert_eq!(words, expected, "buffer: {:?}", s);
+}
+
+#[test]
+fn test_word_dividers() {
+    assert_words(context::get_whitespace_words, " ls\t-la  foo\\ bar", &["ls", "-la", "foo\\", "bar"]);
+
+    assert_words(context::get_shell_words, "cat \"My Documents/a b\"|grep x\\ y", &["cat", "\"My Documents/a b\"", "|", "grep", "x\\ y"]);
+    assert_words(context::get_shell_words, "a&&b 'c;d' >e", &["a", "&&", "b", "'c;d'", ">", "e"]);
+
+    assert_words(context::get_path_component_words, "cd /usr/local/", &["cd", "usr", "local"]);
+}
+
 fn assert_buffer_actions(start: &str, expected: &str, actions: &[Action]) {
     let mut buf = Buffer::from(start.to_owned());
     for a in actions {
@@ -132,3 +154,860 @@ fn test_reading_from_file() {
     assert_eq!(String::from(h.buffers[2].clone()), "c".to_string());
     assert_eq!(String::from(h.buffers[3].clone()), "d".to_string());
 }
+
+#[test]
+fn test_common_prefix_len_avoids_escapes() {
+    assert_eq!(util::common_prefix_len("abc", "abd"), 2);
+    assert_eq!(util::common_prefix_len("ab", "abc"), 2);
+    assert_eq!(util::common_prefix_len("a\x1B[31mb", "a\x1B[32mb"), 1);
+    assert_eq!(util::common_prefix_len("a\x1B[31mb", "a\x1B[31mc"), 6);
+    assert_eq!(util::sgr_codes("a\x1B[31mb\x1B[2Dc\x1B[0m"), "\x1B[31m\x1B[0m");
+}
+
+#[test]
+fn test_prompt_markers() {
+    assert_eq!(util::last_prompt_line_width("\\[\x1B[1m\\]bold\\[\x1B[0m\\]> "), 6);
+    assert_eq!(util::last_prompt_line_width("\x01\x1B[1m\x02bold\x01\x1B[0m\x02> "), 6);
+    assert_eq!(util::split_prompt_markers("a\\[b\\]c").0, "abc");
+    assert_eq!(util::split_prompt_markers("plain\\n> ").0, "plain\\n> ");
+}
+
+#[test]
+fn test_clip_columns() {
+    assert_eq!(util::clip_columns("abcdef", 2, 3), "cde");
+    assert_eq!(util::clip_columns("a\x1B[31mbc\x1B[0md", 1, 2), "\x1B[31mbc\x1B[0m");
+    assert_eq!(util::clip_columns("a\u{4e16}b", 2, 2), "b");
+    assert_eq!(util::clip_columns("e\u{301}x", 0, 1), "e\u{301}");
+}
+
+#[test]
+fn test_base64() {
+    assert_eq!(util::base64(b""), "");
+    assert_eq!(util::base64(b"f"), "Zg==");
+    assert_eq!(util::base64(b"fo"), "Zm8=");
+    assert_eq!(util::base64(b"foo"), "Zm9v");
+    assert_eq!(util::base64(b"foobar"), "Zm9vYmFy");
+}
+
+#[test]
+fn test_remove_sgr_codes() {
+    assert_eq!(util::remove_sgr_codes("plain"), "plain");
+    assert_eq!(util::remove_sgr_codes("\x1B[31ma\x1B[2Db\x1B[0m"), "a\x1B[2Db");
+    assert_eq!(util::remove_sgr_codes("\x1B[38;2;1;2;3mx"), "x");
+}
+
+#[test]
+fn test_save_and_load() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file789.txt");
+
+    let mut h = History::new();
+    h.set_max_file_size(2);
+    for entry in &["a", "b", "c"] {
+        h.push(Buffer::from(*entry)).unwrap();
+    }
+    h.save(&tmp_file).unwrap();
+
+    let mut h = History::new();
+    h.load(&tmp_file).unwrap();
+    assert_eq!(h.len(), 2);
+    assert_eq!(String::from(h.buffers[1].clone()), "c".to_string());
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_entry_metadata_is_saved() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file796.txt");
+
+    let mut h = History::new();
+    let mut entry = HistoryEntry::new(Buffer::from("make\ttest"));
+    entry.exit_status = Some(2);
+    entry.duration = Some(Duration::from_millis(1500));
+    entry.working_dir = Some(PathBuf::from("/tmp/a\tb"));
+    entry.extra.insert("host".to_owned(), "x=y".to_owned());
+    h.push_entry(entry).unwrap();
+    h.push(Buffer::from("ls")).unwrap();
+    h.save(&tmp_file).unwrap();
+
+    let mut h = History::new();
+    h.load(&tmp_file).unwrap();
+    assert_eq!(h.len(), 2);
+    let entry = h.entry(0).unwrap();
+    assert_eq!(String::from(entry.buffer.clone()), "make\ttest".to_string());
+    assert_eq!(entry.exit_status, Some(2));
+    assert_eq!(entry.duration, Some(Duration::from_millis(1500)));
+    assert_eq!(entry.working_dir, Some(PathBuf::from("/tmp/a\tb")));
+    assert_eq!(entry.extra.get("host").map(|s| s.as_str()), Some("x=y"));
+    assert_eq!(h.entry(1).unwrap().exit_status, None);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_extra_metadata_with_reserved_names() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file786.txt");
+
+    let mut h = History::new();
+    let mut entry = HistoryEntry::new(Buffer::from("make"));
+    entry.extra.insert("pinned".to_owned(), "no".to_owned());
+    entry.extra.insert("exit".to_owned(), "maybe".to_owned());
+    h.push_entry(entry).unwrap();
+    h.save(&tmp_file).unwrap();
+
+    let mut h = History::new();
+    h.load(&tmp_file).unwrap();
+    let entry = h.entry(0).unwrap();
+    assert!(!h.is_pinned("make"));
+    assert_eq!(entry.exit_status, None);
+    assert_eq!(entry.extra.get("pinned").map(|s| s.as_str()), Some("no"));
+    assert_eq!(entry.extra.get("exit").map(|s| s.as_str()), Some("maybe"));
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_import_and_export_foreign_history() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file797.txt");
+
+    fs::write(&tmp_file, b": 1600000000:3;make\\\ntest\n: 1600000010:0;caf\xc3\x83\x82\n").unwrap();
+    let mut h = History::new();
+    h.import(&tmp_file, HistoryFormat::Zsh).unwrap();
+    assert_eq!(h.len(), 2);
+    assert_eq!(String::from(h[0].clone()), "make\ntest".to_string());
+    assert_eq!(String::from(h[1].clone()), "caf\u{e2}".to_string());
+    assert_eq!(h.entry(0).unwrap().duration, Some(Duration::from_secs(3)));
+    assert_eq!(h.entry(1).unwrap().extra.get("time").map(|s| s.as_str()), Some("1600000010"));
+
+    h.export(&tmp_file, HistoryFormat::Fish).unwrap();
+    let fish = fs::read_to_string(&tmp_file).unwrap();
+    assert!(fish.starts_with("- cmd: make\\ntest\n  when: 1600000000\n"), "{:?}", fish);
+    let mut fish_h = History::new();
+    fish_h.import(&tmp_file, HistoryFormat::Fish).unwrap();
+    assert_eq!(String::from(fish_h[0].clone()), "make\ntest".to_string());
+
+    h.export(&tmp_file, HistoryFormat::Bash).unwrap();
+    let mut bash_h = History::new();
+    bash_h.import(&tmp_file, HistoryFormat::Bash).unwrap();
+    assert_eq!(bash_h.len(), 3);
+    assert_eq!(bash_h.entry(2).unwrap().extra.get("time").map(|s| s.as_str()), Some("1600000010"));
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_history_filter() {
+    let mut h = History::new();
+    h.set_filter(|buf| {
+        let line = buf.to_string();
+        if line.starts_with(' ') {
+            HistoryAction::Skip
+        } else if line.contains("token=") {
+            HistoryAction::Replace(Buffer::from("curl -H token=***"))
+        } else {
+            HistoryAction::Keep
+        }
+    });
+    for line in &["ls", " secret", "curl -H token=abc"] {
+        h.push(Buffer::from(*line)).unwrap();
+    }
+    let entries: Vec<String> = h.into_iter().map(|b| b.to_string()).collect();
+    assert_eq!(entries, vec!["ls", "curl -H token=***"]);
+
+    h.clear_filter();
+    h.push(Buffer::from(" secret")).unwrap();
+    assert_eq!(h.len(), 3);
+}
+
+#[test]
+fn test_history_layers() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file798.txt");
+    fs::write(&tmp_file, "old\n").unwrap();
+
+    let mut h = History::new();
+    h.load(&tmp_file).unwrap();
+    h.push(Buffer::from("new")).unwrap();
+    assert_eq!(h.layer_indices(HistoryLayer::Global), vec![0]);
+    assert_eq!(h.layer_indices(HistoryLayer::Session), vec![1]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_history_queries() {
+    let mut h = History::new();
+    assert!(h.is_empty());
+    for line in &["a", "b", "c", "d"] {
+        h.push(Buffer::from(*line)).unwrap();
+    }
+    let lines = |bufs: Vec<&Buffer>| bufs.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+    assert_eq!(lines(h.range(1, 3)), vec!["b", "c"]);
+    assert_eq!(lines(h.range(2, 10)), vec!["c", "d"]);
+    assert_eq!(lines(h.newest(2)), vec!["c", "d"]);
+    assert!(h.get(4).is_none());
+
+    assert_eq!(h.remove(0).map(|e| e.buffer.to_string()), Some("a".to_owned()));
+    assert_eq!(h.get(0).map(|b| b.to_string()), Some("b".to_owned()));
+    assert_eq!(h.iter().count(), 3);
+
+    h.clear();
+    assert!(h.is_empty());
+}
+
+#[test]
+fn test_frecency_ranking() {
+    let mut h = History::new();
+    h.append_duplicate_entries = true;
+    for line in &["make test", "make build", "make test", "ls", "make clean"] {
+        h.push(Buffer::from(*line)).unwrap();
+    }
+    assert_eq!(h.entry(2).unwrap().use_count, 2);
+    assert_eq!(h.best_match(&Buffer::from("make"), MatchAnchor::Start), Some(4));
+
+    h.set_scorer(History::frecency);
+    assert_eq!(h.best_match(&Buffer::from("make"), MatchAnchor::Start), Some(2));
+    let mut indices = h.search_index(&Buffer::from("make"));
+    h.rank(&mut indices);
+    assert_eq!(indices.last(), Some(&2));
+}
+
+#[test]
+fn test_multi_line_entries_roundtrip() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file799.txt");
+
+    let mut h = History::new();
+    for line in &["for x in a b\ndo\n  echo $x\ndone", "#: not metadata", "ls"] {
+        h.push(Buffer::from(*line)).unwrap();
+    }
+    h.save(&tmp_file).unwrap();
+
+    let mut h = History::new();
+    h.load(&tmp_file).unwrap();
+    let entries: Vec<String> = h.into_iter().map(|b| b.to_string()).collect();
+    assert_eq!(entries, vec!["for x in a b\ndo\n  echo $x\ndone", "#: not metadata", "ls"]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_entries_sanitized_on_push() {
+    let mut h = History::new();
+    h.max_entry_len = Some(8);
+    h.push(Buffer::from("echo \x1B]0;pwned\x07\x1B[2Jhi\r\tx")).unwrap();
+    h.push(Buffer::from("\x1B[A")).unwrap();
+    h.push(Buffer::from("abcdefghijkl")).unwrap();
+    let entries: Vec<String> = h.into_iter().map(|b| b.to_string()).collect();
+    assert_eq!(entries, vec!["echo hi\t", "abcdefgh"]);
+}
+
+#[test]
+fn test_entries_sanitized_on_load() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file787.txt");
+    fs::write(&tmp_file, "ls \x1B[31mred\x1B[0m\n\x1B[A\nabcdefghijkl\n").unwrap();
+
+    let mut h = History::new();
+    h.max_entry_len = Some(8);
+    h.load(&tmp_file).unwrap();
+    h.import(&tmp_file, HistoryFormat::Bash).unwrap();
+    let entries: Vec<String> = h.into_iter().map(|b| b.to_string()).collect();
+    assert_eq!(entries, vec!["ls red", "abcdefgh", "ls red", "abcdefgh"]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_pins_are_saved() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file800.txt");
+
+    let mut h = History::new();
+    h.push(Buffer::from("make")).unwrap();
+    h.push(Buffer::from("ls")).unwrap();
+    assert!(h.toggle_pin("make"));
+    h.save(&tmp_file).unwrap();
+
+    let mut h = History::new();
+    h.load(&tmp_file).unwrap();
+    assert!(h.is_pinned("make"));
+    assert!(!h.is_pinned("ls"));
+    assert!(h.entry(0).unwrap().extra.is_empty());
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_context_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Context>();
+    assert_send_sync::<History>();
+    assert_send_sync::<Buffer>();
+
+    let context = Arc::new(Mutex::new(Context::new()));
+    let pusher = context.clone();
+    ::std::thread::spawn(move || {
+        pusher.lock().unwrap().history.push(Buffer::from("from another thread")).unwrap();
+    }).join().unwrap();
+    assert_eq!(context.lock().unwrap().history.len(), 1);
+}
+
+#[test]
+fn test_history_events() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file801.txt");
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut h = History::new();
+    h.set_max_buffers_size(2);
+    {
+        let events = events.clone();
+        h.on_event(move |event| {
+            let event = match *event {
+                HistoryEvent::Push(entry) => format!("push {}", entry.buffer),
+                HistoryEvent::Load(n) => format!("load {}", n),
+                HistoryEvent::Save(_) => "save".to_owned(),
+                HistoryEvent::Evict(entry) => format!("evict {}", entry.buffer),
+            };
+            events.lock().unwrap().push(event);
+        });
+    }
+    for line in &["a", "b", "c"] {
+        h.push(Buffer::from(*line)).unwrap();
+    }
+    h.save(&tmp_file).unwrap();
+    h.load(&tmp_file).unwrap();
+    h.import(&tmp_file, HistoryFormat::Bash).unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec!["push a", "push b", "push c", "evict a", "save", "load 2", "evict b", "evict c", "load 2", "evict b", "evict c"]
+    );
+    assert_eq!(h.entry(1).unwrap().layer, HistoryLayer::Global);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_autosave_on_push() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file790.txt");
+
+    let mut h = History::new();
+    h.set_file_name(Some(&tmp_file));
+    h.autosave = true;
+    h.push(Buffer::from("a")).unwrap();
+    h.push(Buffer::from("b")).unwrap();
+
+    let f = fs::File::open(&tmp_file).unwrap();
+    let lines: Vec<String> = BufReader::new(f).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["a", "b"]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_inc_append_keeps_other_sessions() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file791.txt");
+    let _ = fs::remove_file(&tmp_file);
+
+    let mut first = History::new();
+    let mut second = History::new();
+    for h in &mut [&mut first, &mut second] {
+        h.set_file_name(Some(&tmp_file));
+        h.inc_append = true;
+    }
+    first.push(Buffer::from("a")).unwrap();
+    second.push(Buffer::from("b")).unwrap();
+    first.push(Buffer::from("c")).unwrap();
+
+    let f = fs::File::open(&tmp_file).unwrap();
+    let lines: Vec<String> = BufReader::new(f).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["a", "b", "c"]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_inc_append_writes_what_was_pushed() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file793.txt");
+    let _ = fs::remove_file(&tmp_file);
+
+    let mut h = History::new();
+    h.set_file_name(Some(&tmp_file));
+    h.inc_append = true;
+    h.eviction_policy = EvictionPolicy::KeepPinned;
+    h.pin("make");
+    h.set_max_buffers_size(1);
+    h.push(Buffer::from("make")).unwrap();
+    h.push(Buffer::from("make")).unwrap();
+    // Evicted right away, since the only room is taken by a pinned entry.
+    h.push(Buffer::from("ls")).unwrap();
+
+    let mut loaded = History::new();
+    loaded.load(&tmp_file).unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded.entry(0).unwrap().buffer.to_string(), "make");
+    assert_eq!(loaded.entry(0).unwrap().use_count, 2);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_delete_and_replace_keep_other_sessions() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file794.txt");
+    let _ = fs::remove_file(&tmp_file);
+
+    let mut first = History::new();
+    let mut second = History::new();
+    for h in &mut [&mut first, &mut second] {
+        h.set_file_name(Some(&tmp_file));
+        h.inc_append = true;
+    }
+    first.push(Buffer::from("a")).unwrap();
+    first.push(Buffer::from("b")).unwrap();
+    second.push(Buffer::from("c")).unwrap();
+    first.delete(0).unwrap();
+    first.replace(0, Buffer::from("d")).unwrap();
+
+    let f = fs::File::open(&tmp_file).unwrap();
+    let lines: Vec<String> = BufReader::new(f).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["d", "c"]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_inc_append_keeps_max_file_size() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file795.txt");
+    let _ = fs::remove_file(&tmp_file);
+
+    let mut h = History::new();
+    h.set_file_name(Some(&tmp_file));
+    h.inc_append = true;
+    h.set_max_file_size(3);
+    h.pin("a");
+    for line in &["a", "b", "c", "d", "e"] {
+        h.push(Buffer::from(*line)).unwrap();
+    }
+
+    let f = fs::File::open(&tmp_file).unwrap();
+    let lines: Vec<String> = BufReader::new(f).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["#: pinned=1", "a", "d", "e"]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_autosave_keeps_other_sessions() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file788.txt");
+    fs::write(&tmp_file, "a\n").unwrap();
+
+    let mut first = History::new();
+    let mut second = History::new();
+    for h in &mut [&mut first, &mut second] {
+        h.set_file_name_and_load_history(&tmp_file).unwrap();
+        h.autosave = true;
+    }
+    first.push(Buffer::from("b")).unwrap();
+    second.push(Buffer::from("c")).unwrap();
+    first.push(Buffer::from("d")).unwrap();
+
+    let f = fs::File::open(&tmp_file).unwrap();
+    let lines: Vec<String> = BufReader::new(f).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["a", "c", "b", "d"]);
+
+    second.set_max_file_size(3);
+    second.commit_to_file();
+    let f = fs::File::open(&tmp_file).unwrap();
+    let lines: Vec<String> = BufReader::new(f).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["b", "d", "c"]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_loading_skips_corrupt_lines() {
+    let mut tmp_file = env::temp_dir();
+    tmp_file.push("liner_test_file792.txt");
+    fs::write(&tmp_file, b"a\n\xFF\xFE\nb\n\0\0\0\nc\n").unwrap();
+
+    let mut h = History::new();
+    h.load(&tmp_file).unwrap();
+    let entries: Vec<String> = h.into_iter().map(|b| b.to_string()).collect();
+    assert_eq!(entries, vec!["a", "b", "c"]);
+
+    fs::remove_file(tmp_file).unwrap();
+}
+
+#[test]
+fn test_eviction_keeps_pinned() {
+    let mut h = History::new();
+    h.eviction_policy = EvictionPolicy::KeepPinned;
+    h.pin("make");
+    h.set_max_buffers_size(3);
+    for entry in &["make", "a", "b", "c", "d"] {
+        h.push(Buffer::from(*entry)).unwrap();
+    }
+    let entries: Vec<String> = h.into_iter().map(|b| b.to_string()).collect();
+    assert_eq!(entries, vec!["make", "c", "d"]);
+
+    h.eviction_policy = EvictionPolicy::DropOldest;
+    h.set_max_buffers_size(2);
+    let entries: Vec<String> = h.into_iter().map(|b| b.to_string()).collect();
+    assert_eq!(entries, vec!["c", "d"]);
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(util::glob_match("ls*", "ls -la"));
+    assert!(util::glob_match("*password*", "export PASSWORD=x; echo password"));
+    assert!(util::glob_match("a?c", "abc"));
+    assert!(!util::glob_match("ls*", "cd ls"));
+    assert!(!util::glob_match("a?c", "ac"));
+}
+
+#[test]
+fn test_history_ignore_patterns() {
+    let mut h = History::new();
+    h.ignore("ls*");
+    h.ignore_matching(|line| line.starts_with(' '));
+    for entry in &["ls -la", "make", " secret", "cd ls"] {
+        h.push(Buffer::from(*entry)).unwrap();
+    }
+    let entries: Vec<String> = h.into_iter().map(|b| b.to_string()).collect();
+    assert_eq!(entries, vec!["make", "cd ls"]);
+}
+
+#[test]
+fn test_context_builder() {
+    let mut file = env::temp_dir();
+    file.push("liner_test_context_builder.txt");
+    fs::write(&file, "ls\npwd\n").unwrap();
+
+    let context = Context::builder()
+        .key_bindings(KeyBindings::Vi)
+        .history_file(&file)
+        .max_history_size(1)
+        .tab_width(4)
+        .word_divider(get_whitespace_words)
+        .configure(|c| c.search_preview = 3)
+        .build()
+        .unwrap();
+    assert_eq!(context.key_bindings, KeyBindings::Vi);
+    assert_eq!(context.tab_width, 4);
+    assert_eq!(context.search_preview, 3);
+    assert_eq!(context.history.len(), 1);
+    assert_eq!(context.history[0].to_string(), "pwd");
+    assert_eq!((context.word_divider_fn)(&Buffer::from("a\\ b")), vec![(0, 2), (3, 4)]);
+
+    fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn test_chained_completers() {
+    let commands = || BasicCompleter::new(vec!["ls", "less"]);
+    let files = || BasicCompleter::new(vec!["lib.rs", "main.rs"]);
+    let buf = Buffer::from("ls l");
+
+    let per_position = ChainedCompleter::new(ChainStrategy::PerPosition)
+        .with_at(CompletionPosition::First, commands())
+        .with_at(CompletionPosition::Rest, files());
+    assert_eq!(per_position.complete(&buf, 1, (0, 1)), vec!["ls", "less"]);
+    assert_eq!(per_position.complete(&buf, 4, (3, 4)), vec!["lib.rs"]);
+
+    let first = ChainedCompleter::new(ChainStrategy::FirstNonEmpty)
+        .with(BasicCompleter::new(vec!["x"]))
+        .with(files());
+    assert_eq!(first.complete(&buf, 4, (3, 4)), vec!["lib.rs"]);
+
+    let merged = ChainedCompleter::new(ChainStrategy::Merged)
+        .with(commands())
+        .with(files())
+        .with(commands());
+    assert_eq!(merged.complete(&buf, 4, (3, 4)), vec!["ls", "less", "lib.rs"]);
+}
+
+#[test]
+fn test_chained_completers_wait_for_background_ones() {
+    fn texts(pending: Option<PendingCompletions>) -> Vec<String> {
+        let completions = pending.unwrap().wait(Duration::from_secs(5)).unwrap();
+        completions.into_iter().map(|c| c.text).collect()
+    }
+    let files = || AsyncCompleter::new(|_: &str, _: usize, _: (usize, usize), _: &CompletionSender| {
+        vec![Completion::new("lib.rs")]
+    });
+    let buf = Buffer::from("ls l");
+    let mut h = History::new();
+    h.push(Buffer::from("ls lib")).unwrap();
+
+    let first = ChainedCompleter::new(ChainStrategy::FirstNonEmpty)
+        .with(BasicCompleter::new(vec!["x"]))
+        .with(files())
+        .with(BasicCompleter::new(vec!["less"]));
+    assert_eq!(texts(first.complete_later(&buf, 4, (3, 4))), vec!["lib.rs"]);
+
+    let merged = ChainedCompleter::new(ChainStrategy::Merged)
+        .with(HistoryCompleter::new(HistoryCompletion::Tokens))
+        .with(files())
+        .with(BasicCompleter::new(vec!["lib.rs", "less"]));
+    assert_eq!(texts(merged.complete_later_with_history(&buf, 4, (3, 4), &h)), vec!["ls", "lib", "lib.rs", "less"]);
+
+    let sync = ChainedCompleter::new(ChainStrategy::FirstNonEmpty).with(BasicCompleter::new(vec!["less"]));
+    assert_eq!(texts(sync.complete_later(&buf, 4, (3, 4))), vec!["less"]);
+}
+
+#[test]
+fn test_env_completer() {
+    env::set_var("LINER_TEST_ENV_COMPLETER", "1");
+    let completer = EnvCompleter::new();
+    assert_eq!(completer.completions("$LINER_TEST_ENV_C"), vec!["$LINER_TEST_ENV_COMPLETER"]);
+    assert_eq!(completer.completions("a=${LINER_TEST_ENV_"), vec!["a=${LINER_TEST_ENV_COMPLETER}"]);
+    assert!(completer.completions("LINER_TEST_ENV_C").is_empty());
+    assert!(completer.completions("${LINER_TEST_ENV_COMPLETER}/").is_empty());
+
+    let chained = ChainedCompleter::new(ChainStrategy::FirstNonEmpty)
+        .with(EnvCompleter::new())
+        .with(BasicCompleter::new(vec!["$LINER_TEST_ENV_OTHER"]));
+    let buf = Buffer::from("echo $LINER_TEST_ENV_");
+    assert_eq!(chained.complete(&buf, 21, (5, 21)), vec!["$LINER_TEST_ENV_COMPLETER"]);
+}
+
+#[test]
+fn test_shell_quoting() {
+    assert_eq!(unquote(r#""My Documents"/a\ b"#), "My Documents/a b");
+    assert_eq!(unquote(r#"'it'\''s'"#), "it's");
+    assert_eq!(unquote(r#""unclosed \"q"#), "unclosed \"q");
+    assert_eq!(quote_like("My Documents/", "\"My"), "\"My Documents/");
+    assert_eq!(quote_like("it's", "'i"), r#"'it'\''s"#);
+    assert_eq!(quote_like("a b(1)", "a"), r"a\ b\(1\)");
+
+    let buf = Buffer::from(r#"cd "My Doc" x\ y"#);
+    assert_eq!(shell_word_start(&buf, 10), 3);
+    assert_eq!(shell_word_start(&buf, 16), 12);
+}
+
+#[test]
+fn test_history_completer() {
+    fn texts(completions: Vec<Completion>) -> Vec<String> {
+        completions.into_iter().map(|c| c.text).collect()
+    }
+
+    let mut h = History::new();
+    h.push(Buffer::from("git commit -m fix")).unwrap();
+    h.push(Buffer::from("git checkout main")).unwrap();
+    let commands = HistoryCompleter::new(HistoryCompletion::Commands);
+    let tokens = HistoryCompleter::new(HistoryCompletion::Tokens);
+
+    let buf = Buffer::from("git");
+    assert_eq!(
+        texts(commands.complete_with_history(&buf, 3, (0, 3), &h)),
+        vec!["git checkout main", "git commit -m fix"]
+    );
+    assert!(commands.complete(&buf, 3, (0, 3)).is_empty());
+    let buf = Buffer::from("git c");
+    assert_eq!(texts(tokens.complete_with_history(&buf, 5, (4, 5), &h)), vec!["checkout", "commit"]);
+    assert!(tokens.keep_order());
+
+    // Whatever changed the history, only what is in it now is offered.
+    h.replace(1, Buffer::from("git clone url")).unwrap();
+    h.remove(0);
+    assert_eq!(texts(tokens.complete_with_history(&buf, 5, (4, 5), &h)), vec!["clone"]);
+    h.clear();
+    assert!(tokens.complete_with_history(&buf, 5, (4, 5), &h).is_empty());
+}
+
+#[test]
+fn test_session_is_fed_by_the_application() {
+    use Key;
+
+    let mut context = Context::new();
+    let mut session = Session::new(&mut context, ReadLineOptions::new("$ ")).unwrap();
+    assert!(String::from_utf8(session.take_output()).unwrap().contains("$ "));
+
+    for c in "ls".chars() {
+        assert_eq!(session.feed(Input::Key(Key::Char(c)), &mut |_| {}).unwrap(), None);
+    }
+    session.feed(Input::Paste(" -l".to_owned()), &mut |_| {}).unwrap();
+    assert!(String::from_utf8(session.take_output()).unwrap().contains("-l"));
+    assert!(session.take_output().is_empty());
+
+    let res = session.feed(Input::Key(Key::Char('\n')), &mut |_| {}).unwrap();
+    assert_eq!(res, Some(ReadLineResult::Input("ls -l".to_owned())));
+    assert!(session.is_done());
+    assert_eq!(session.feed(Input::Key(Key::Char('x')), &mut |_| {}).unwrap(), None);
+    drop(session);
+    // The terminal of the context is back once the line is read.
+    assert!(!context.terminal.resize(10, 5));
+}
+
+#[test]
+fn test_session_resizes_its_terminal() {
+    let mut context = Context::new();
+    {
+        let mut session = Session::new(&mut context, ReadLineOptions::new("$ ")).unwrap();
+        assert_eq!(session.editor_mut().context().terminal.size().unwrap(), (80, 24));
+        session.resize(10, 5).unwrap();
+        assert_eq!(session.editor_mut().context().terminal.size().unwrap(), (10, 5));
+    }
+    assert!(!context.terminal.resize(10, 5));
+
+    let mut context = Context::builder().terminal(VirtualTerminal::new(20, 5)).build().unwrap();
+    {
+        let mut session = Session::new(&mut context, ReadLineOptions::new("$ ")).unwrap();
+        session.resize(30, 6).unwrap();
+    }
+    assert_eq!(context.terminal.size().unwrap(), (30, 6));
+}
+
+#[test]
+fn test_capabilities_for_term() {
+    assert_eq!(Capabilities::for_term("dumb"), Capabilities::none());
+    assert_eq!(Capabilities::for_term(""), Capabilities::none());
+
+    let vt100 = Capabilities::for_term("vt100");
+    assert_eq!(vt100.colors, Colors::None);
+    assert!(vt100.clear_screen && !vt100.mouse && !vt100.cursor_shape);
+
+    let linux = Capabilities::for_term("linux");
+    assert_eq!(linux.colors, Colors::Basic);
+    assert!(!linux.alternate_screen && !linux.bracketed_paste);
+
+    assert_eq!(Capabilities::for_term("xterm"), Capabilities { colors: Colors::Basic, ..Capabilities::all() });
+    assert_eq!(Capabilities::for_term("screen-256color").colors, Colors::Ansi256);
+    assert_eq!(Capabilities::for_term("xterm-direct").colors, Colors::TrueColor);
+    assert_eq!(Capabilities::for_term("xterm-kitty"), Capabilities::all());
+    assert_eq!(Capabilities::for_term("alacritty"), Capabilities::all());
+
+    let unknown = Capabilities::for_term("something-256color");
+    assert_eq!(unknown.colors, Colors::Ansi256);
+    assert!(unknown.clear_screen && !unknown.mouse);
+}
+
+#[test]
+fn test_downgrade_colors() {
+    assert_eq!(rgb_to_ansi256(255, 0, 0), 196);
+    assert_eq!(rgb_to_ansi256(128, 128, 128), 244);
+    assert_eq!(ansi256_to_basic(196), 9);
+    assert_eq!(ansi256_to_basic(4), 4);
+
+    let text = "\x1B[1;38;2;255;0;0mred\x1B[48;5;21m\x1B[2Kblue\x1B[0m";
+    assert_eq!(util::downgrade_colors(text, Colors::TrueColor), text);
+    assert_eq!(util::downgrade_colors(text, Colors::Ansi256),
+               "\x1B[1;38;5;196mred\x1B[48;5;21m\x1B[2Kblue\x1B[0m");
+    assert_eq!(util::downgrade_colors(text, Colors::Basic),
+               "\x1B[1;91mred\x1B[44m\x1B[2Kblue\x1B[0m");
+}
+
+#[test]
+fn test_read_line_from_any_reader() {
+    let input: &'static [u8] = b"echo hi\x1B[D\x1B[Do\n";
+    let mut terminal = ReadTerminal::new(input);
+    terminal.set_size(40, 10);
+    let mut context = Context::builder().terminal(terminal).build().unwrap();
+    let mut out = Vec::new();
+    let res = context.read_line_on(&mut out, ReadLineOptions::new("> "), &mut |_| {}).unwrap();
+    assert_eq!(res, ReadLineResult::Input("echo ohi".to_owned()));
+    assert!(String::from_utf8_lossy(&out).contains("> "));
+}
+
+#[test]
+fn test_invalid_utf8_does_not_end_the_read() {
+    let input: &'static [u8] = b"a\xFF\x1B[<64;1;1Mb\xE2\x82\xAC\n";
+    let mut context = Context::builder().terminal(ReadTerminal::new(input)).build().unwrap();
+    let res = context.read_line_on(Vec::new(), ReadLineOptions::new("> "), &mut |_| {}).unwrap();
+    assert_eq!(res, ReadLineResult::Input("a\u{FFFD}b€".to_owned()));
+}
+
+#[test]
+fn test_session_is_fed_bytes() {
+    let mut context = Context::builder().terminal(VirtualTerminal::new(20, 5)).build().unwrap();
+    let mut session = Session::new(&mut context, ReadLineOptions::new("$ ")).unwrap();
+    assert_eq!(session.feed_bytes(b"echo h\xC3", &mut |_| {}).unwrap(), None);
+    assert_eq!(session.feed_bytes(b"\xA9\x1B[", &mut |_| {}).unwrap(), None);
+    assert_eq!(session.feed_bytes(b"D\x1B[Dx", &mut |_| {}).unwrap(), None);
+    session.take_output();
+
+    session.resize(10, 5).unwrap();
+    assert!(String::from_utf8(session.take_output()).unwrap().contains("$ echo xh"));
+
+    let res = session.feed_bytes(b"\r", &mut |_| {}).unwrap();
+    assert_eq!(res, Some(ReadLineResult::Input("echo xhé".to_owned())));
+}
+
+#[test]
+fn test_terminal_is_put_back_when_a_handler_fails() {
+    use Key;
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn handler(e: Event<Shared>) {
+        if let EventKind::BeforeKey(Key::Char('!')) = e.kind {
+            e.fail(io::Error::new(io::ErrorKind::Other, "no bangs"));
+        }
+    }
+
+    let input: &'static [u8] = b"a!b\n";
+    let mut context = Context::builder()
+        .terminal(ReadTerminal::new(input))
+        .alternate_screen(true)
+        .mouse(true)
+        .bracketed_paste(true)
+        .build()
+        .unwrap();
+    let out = Arc::new(Mutex::new(Vec::new()));
+    let err = context.read_line_on(Shared(out.clone()), ReadLineOptions::new("> "), &mut handler).unwrap_err();
+    assert_eq!(err.to_string(), "no bangs");
+
+    let out = out.lock().unwrap();
+    let out = String::from_utf8_lossy(&out);
+    let enabled = out.find("\x1B[?2004h").unwrap();
+    assert!(out[enabled..].contains("\x1B[?1000l"));
+    assert!(out[enabled..].contains("\x1B[?2004l"));
+    assert!(out[enabled..].contains("\x1B[?1049l"));
+}
+
+#[test]
+fn test_cursor_shape_is_reset_when_a_session_fails() {
+    fn handler(e: Event<Vec<u8>>) {
+        if let EventKind::BeforeKey(_) = e.kind {
+            e.fail(io::Error::new(io::ErrorKind::Other, "no keys"));
+        }
+    }
+
+    let mut context = Context::builder()
+        .terminal(VirtualTerminal::new(80, 24))
+        .key_bindings(KeyBindings::Vi)
+        .build()
+        .unwrap();
+    context.vi_insert_cursor_shape = Some(CursorShape::SteadyBar);
+    let mut session = Session::new(&mut context, ReadLineOptions::new("> ")).unwrap();
+    assert!(String::from_utf8(session.take_output()).unwrap().contains("\x1B[6 q"));
+    assert!(session.feed_bytes(b"a", &mut handler).is_err());
+    assert!(String::from_utf8(session.take_output()).unwrap().ends_with("\x1B[0 q"));
+}
+
+#[cfg(windows)]
+#[test]
+fn test_read_line_through_the_console_output() {
+    let input: &'static [u8] = b"dir\x1B[Dx\r";
+    let mut context = Context::builder().terminal(ReadTerminal::new(input)).build().unwrap();
+    let res = context.read_line_on(console_output(), ReadLineOptions::new("> "), &mut |_| {}).unwrap();
+    assert_eq!(res, ReadLineResult::Input("dixr".to_owned()));
+}
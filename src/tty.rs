@@ -0,0 +1,311 @@
+//! Raw mode, the size and the cursor position of the terminal the program runs in, with termios
+//! on unix and the Console API on Windows. On unix, the terminal is `/dev/tty`, so they still work
+//! when stdout is piped and the editor draws on stderr.
+
+use std::io::{self, Write};
+use std::ops;
+use terminal::RawMode;
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    pub type Mode = libc::termios;
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Calls `f` with the terminal the program runs in, whatever stdin and stdout are redirected
+    // to, or with stdin if there is no controlling terminal.
+    fn with_tty<T, F: FnOnce(libc::c_int) -> io::Result<T>>(f: F) -> io::Result<T> {
+        match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+            Ok(tty) => f(tty.as_raw_fd()),
+            Err(_) => f(libc::STDIN_FILENO),
+        }
+    }
+
+    pub fn get_mode() -> io::Result<Mode> {
+        with_tty(|fd| unsafe {
+            let mut mode = mem::zeroed();
+            check(libc::tcgetattr(fd, &mut mode))?;
+            Ok(mode)
+        })
+    }
+
+    pub fn set_mode(mode: &Mode) -> io::Result<()> {
+        with_tty(|fd| unsafe { check(libc::tcsetattr(fd, libc::TCSANOW, mode)) })
+    }
+
+    pub fn make_raw(mode: &mut Mode) {
+        unsafe { libc::cfmakeraw(mode) }
+    }
+
+    pub fn terminal_size() -> io::Result<(u16, u16)> {
+        with_tty(|fd| unsafe {
+            let mut size: libc::winsize = mem::zeroed();
+            check(libc::ioctl(fd, libc::TIOCGWINSZ, &mut size))?;
+            Ok((size.ws_col, size.ws_row))
+        })
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::io;
+    use std::mem;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
+    use winapi::um::processenv::GetStdHandle;
+    use winapi::um::winbase::{STD_INPUT_HANDLE, STD_OUTPUT_HANDLE};
+    use winapi::um::wincon::{GetConsoleScreenBufferInfo, CONSOLE_SCREEN_BUFFER_INFO};
+    use winapi::um::wincon::{ENABLE_ECHO_INPUT, ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT};
+    use winapi::um::wincon::ENABLE_VIRTUAL_TERMINAL_PROCESSING;
+    #[cfg(not(feature = "crossterm"))]
+    use winapi::um::wincon::ENABLE_VIRTUAL_TERMINAL_INPUT;
+
+    /// The modes of the console's input and, if stdout is the console, its output.
+    #[derive(Clone, Copy)]
+    pub struct Mode {
+        input: DWORD,
+        output: Option<DWORD>,
+    }
+
+    fn check(ok: i32) -> io::Result<()> {
+        if ok == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_mode() -> io::Result<Mode> {
+        let (mut input, mut output) = (0, 0);
+        unsafe {
+            check(GetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), &mut input))?;
+            let output = match GetConsoleMode(GetStdHandle(STD_OUTPUT_HANDLE), &mut output) {
+                0 => None,
+                _ => Some(output),
+            };
+            Ok(Mode { input: input, output: output })
+        }
+    }
+
+    pub fn set_mode(mode: &Mode) -> io::Result<()> {
+        unsafe {
+            // Consoles before Windows 10 don't know virtual terminal processing, and are drawn on
+            // with a `LegacyConsole` instead.
+            if let Some(output) = mode.output {
+                SetConsoleMode(GetStdHandle(STD_OUTPUT_HANDLE), output);
+            }
+            check(SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), mode.input))
+        }
+    }
+
+    pub fn make_raw(mode: &mut Mode) {
+        mode.input &= !(ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT);
+        // Keys are decoded from stdin like on other terminals, unless crossterm reads them.
+        #[cfg(not(feature = "crossterm"))]
+        {
+            mode.input |= ENABLE_VIRTUAL_TERMINAL_INPUT;
+        }
+        mode.output = mode.output.map(|output| output | ENABLE_VIRTUAL_TERMINAL_PROCESSING);
+    }
+
+    fn info() -> io::Result<CONSOLE_SCREEN_BUFFER_INFO> {
+        unsafe {
+            let mut info: CONSOLE_SCREEN_BUFFER_INFO = mem::zeroed();
+            check(GetConsoleScreenBufferInfo(GetStdHandle(STD_OUTPUT_HANDLE), &mut info))?;
+            Ok(info)
+        }
+    }
+
+    pub fn terminal_size() -> io::Result<(u16, u16)> {
+        let window = info()?.srWindow;
+        Ok(((window.Right - window.Left + 1) as u16, (window.Bottom - window.Top + 1) as u16))
+    }
+
+    #[cfg(not(feature = "crossterm"))]
+    pub fn cursor_pos() -> io::Result<(u16, u16)> {
+        let info = info()?;
+        let (cursor, window) = (info.dwCursorPosition, info.srWindow);
+        Ok(((cursor.X - window.Left + 1) as u16, (cursor.Y - window.Top + 1) as u16))
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod sys {
+    use std::io;
+
+    pub type Mode = ();
+
+    pub fn get_mode() -> io::Result<Mode> {
+        Ok(())
+    }
+
+    pub fn set_mode(_: &Mode) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn make_raw(_: &mut Mode) {}
+
+    pub fn terminal_size() -> io::Result<(u16, u16)> {
+        Err(io::Error::new(io::ErrorKind::Other, "the size of this terminal is unknown"))
+    }
+}
+
+pub use self::sys::terminal_size;
+#[cfg(all(windows, not(feature = "crossterm")))]
+pub use self::sys::cursor_pos;
+
+/// An output to the terminal, which is kept in raw mode until it is dropped.
+pub struct RawTerminal<W: Write> {
+    prev_mode: sys::Mode,
+    output: W,
+}
+
+impl<W: Write> RawTerminal<W> {
+    /// Puts the terminal back the way it was before raw mode, until `activate_raw_mode()`.
+    pub fn suspend_raw_mode(&self) -> io::Result<()> {
+        sys::set_mode(&self.prev_mode)
+    }
+
+    pub fn activate_raw_mode(&self) -> io::Result<()> {
+        let mut mode = sys::get_mode()?;
+        sys::make_raw(&mut mode);
+        sys::set_mode(&mode)
+    }
+}
+
+impl<W: Write> Drop for RawTerminal<W> {
+    fn drop(&mut self) {
+        let _ = sys::set_mode(&self.prev_mode);
+    }
+}
+
+impl<W: Write> ops::Deref for RawTerminal<W> {
+    type Target = W;
+
+    fn deref(&self) -> &W {
+        &self.output
+    }
+}
+
+impl<W: Write> ops::DerefMut for RawTerminal<W> {
+    fn deref_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+}
+
+impl<W: Write> Write for RawTerminal<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl<W: Write> RawMode for RawTerminal<W> {
+    fn suspend_raw_mode(&self) -> io::Result<()> {
+        RawTerminal::suspend_raw_mode(self)
+    }
+
+    fn activate_raw_mode(&self) -> io::Result<()> {
+        RawTerminal::activate_raw_mode(self)
+    }
+}
+
+/// Puts the terminal in raw mode for as long as an output to it is kept.
+pub trait IntoRawMode: Write + Sized {
+    fn into_raw_mode(self) -> io::Result<RawTerminal<Self>>;
+}
+
+impl<W: Write> IntoRawMode for W {
+    fn into_raw_mode(self) -> io::Result<RawTerminal<W>> {
+        let prev_mode = sys::get_mode()?;
+        let mut mode = prev_mode;
+        sys::make_raw(&mut mode);
+        sys::set_mode(&mode)?;
+        Ok(RawTerminal {
+            prev_mode: prev_mode,
+            output: self,
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::ffi::{CStr, CString};
+    use std::fs::OpenOptions;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+    use libc;
+
+    const CHILD: &str = "LINER_TEST_TTY_CHILD";
+
+    fn is_canonical() -> bool {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty").unwrap();
+        let mut mode: libc::termios = unsafe { mem::zeroed() };
+        assert_eq!(unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut mode) }, 0);
+        mode.c_lflag & libc::ICANON != 0
+    }
+
+    #[test]
+    fn raw_mode_and_size_work_when_stdout_is_piped() {
+        if env::var_os(CHILD).is_some() {
+            // Run again below, with stdout piped and a pty of 123 by 45 as the only terminal.
+            let mut out = io::stdout().into_raw_mode().unwrap();
+            assert!(!is_canonical());
+            assert_eq!(terminal_size().unwrap(), (123, 45));
+            out.write_all(b"<drawn>").unwrap();
+            drop(out);
+            assert!(is_canonical());
+            return;
+        }
+
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        assert!(master >= 0);
+        let slave = unsafe {
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            let size = libc::winsize { ws_row: 45, ws_col: 123, ws_xpixel: 0, ws_ypixel: 0 };
+            assert_eq!(libc::ioctl(master, libc::TIOCSWINSZ, &size), 0);
+            CString::from(CStr::from_ptr(libc::ptsname(master)))
+        };
+        let mut child = Command::new(env::current_exe().unwrap());
+        child.args(&["--exact", "tty::tests::raw_mode_and_size_work_when_stdout_is_piped", "--nocapture"])
+            .env(CHILD, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        unsafe {
+            child.pre_exec(move || {
+                // The pty becomes the controlling terminal of a new session.
+                libc::setsid();
+                let fd = libc::open(slave.as_ptr(), libc::O_RDWR);
+                if fd < 0 || libc::ioctl(fd, libc::TIOCSCTTY, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let output = child.output().unwrap();
+        unsafe { libc::close(master) };
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(String::from_utf8_lossy(&output.stdout).contains("<drawn>"));
+    }
+}
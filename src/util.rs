@@ -1,12 +1,63 @@
-use std::{
-    borrow::Cow,
-    io
-};
+use std::borrow::Cow;
+use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::*;
+use capabilities::{ansi256_to_basic, rgb_to_ansi256, Colors};
 
 pub fn last_prompt_line_width<S: AsRef<str>>(s: S) -> usize {
     let last_prompt_line_width = handle_prompt(s.as_ref());
-    remove_codes(last_prompt_line_width).width()
+    width(&remove_codes(&split_prompt_markers(last_prompt_line_width).1))
+}
+
+/// Splits a prompt containing readline-style zero-width markers into the text to print, with the
+/// markers removed, and the text that takes up columns, with everything between the markers
+/// removed as well. Parts of the prompt between `\[` and `\]` (or `\x01` and `\x02`) are
+/// assumed to be non-printing, such as escape sequences that `remove_codes` doesn't know about.
+pub fn split_prompt_markers(prompt: &str) -> (Cow<str>, Cow<str>) {
+    if !prompt.contains("\\[") && !prompt.contains('\x01') {
+        return (Cow::Borrowed(prompt), Cow::Borrowed(prompt));
+    }
+
+    let mut printed = String::with_capacity(prompt.len());
+    let mut visible = String::with_capacity(prompt.len());
+    let mut zero_width = false;
+    let mut chars = prompt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'[') => {
+                chars.next();
+                zero_width = true;
+            }
+            '\\' if chars.peek() == Some(&']') => {
+                chars.next();
+                zero_width = false;
+            }
+            '\x01' => zero_width = true,
+            '\x02' => zero_width = false,
+            _ => {
+                printed.push(c);
+                if !zero_width {
+                    visible.push(c);
+                }
+            }
+        }
+    }
+    (Cow::Owned(printed), Cow::Owned(visible))
+}
+
+/// Returns the number of terminal columns `s` takes up. Each grapheme cluster is as wide as its
+/// first char, so combining marks and joined emoji sequences don't add to the width, and an
+/// emoji presentation selector makes it two columns wide.
+pub fn width(s: &str) -> usize {
+    s.graphemes(true)
+        .map(|g| {
+            let first = g.chars().next().and_then(|c| c.width()).unwrap_or(0);
+            if g.contains('\u{FE0F}') {
+                2
+            } else {
+                first
+            }
+        })
+        .sum()
 }
 
 pub fn find_longest_common_prefix<T: Clone + Eq>(among: &[Vec<T>]) -> Option<Vec<T>> {
@@ -86,6 +137,295 @@ pub fn remove_codes(input: &str) -> Cow<str> {
     }
 }
 
+/// Returns `s` the way it is shown on the terminal, starting at column `col`: control characters
+/// become `^X` and tabs are padded with spaces to the next multiple of `tab_width` columns.
+pub fn visible(s: &str, mut col: usize, tab_width: usize) -> Cow<str> {
+    if !s.chars().any(|c| c < ' ' || c == '\x7F') {
+        return Cow::Borrowed(s);
+    }
+
+    let mut shown = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\t' if tab_width > 0 => {
+                let spaces = tab_width - col % tab_width;
+                for _ in 0..spaces {
+                    shown.push(' ');
+                }
+                col += spaces;
+            }
+            '\x00' ... '\x1F' => {
+                shown.push('^');
+                shown.push((c as u8 + 0x40) as char);
+                col += 2;
+            }
+            '\x7F' => {
+                shown.push_str("^?");
+                col += 2;
+            }
+            _ => {
+                shown.push(c);
+                col += c.width().unwrap_or(0);
+            }
+        }
+    }
+    Cow::Owned(shown)
+}
+
+/// Returns the part of `s` from column `start` that fits in `width` columns. Escape sequences are
+/// all kept so colors still apply, and wide chars that would straddle either edge are left out.
+pub fn clip_columns(s: &str, start: usize, width: usize) -> String {
+    let mut clipped = String::with_capacity(s.len());
+    let mut state = AnsiState::Norm;
+    let mut col = 0;
+    let mut kept = false;
+    for c in s.chars() {
+        match state {
+            AnsiState::Norm if c != '\x1B' => {
+                let w = c.width().unwrap_or(0);
+                // Zero-width chars go along with the char they are attached to.
+                if w > 0 {
+                    kept = col >= start && col + w <= start + width;
+                }
+                if kept {
+                    clipped.push(c);
+                }
+                col += w;
+                continue;
+            }
+            AnsiState::Norm => state = AnsiState::Esc,
+            AnsiState::Esc => match c {
+                '[' => state = AnsiState::Csi,
+                ']' => state = AnsiState::Osc,
+                _ => state = AnsiState::Norm,
+            },
+            AnsiState::Csi => match c {
+                'A' ... 'Z' | 'a' ... 'z' => state = AnsiState::Norm,
+                _ => (),
+            },
+            AnsiState::Osc => match c {
+                '\x07' => state = AnsiState::Norm,
+                _ => (),
+            },
+        }
+        clipped.push(c);
+    }
+    clipped
+}
+
+/// Returns the length in bytes of the longest common prefix of `a` and `b` that does not end
+/// inside an escape sequence.
+pub fn common_prefix_len(a: &str, b: &str) -> usize {
+    let mut s = AnsiState::Norm;
+    let mut safe = 0;
+    for ((i, c), d) in a.char_indices().zip(b.chars()) {
+        if c != d {
+            break;
+        }
+        s = match s {
+            AnsiState::Norm => match c {
+                '\x1B' => AnsiState::Esc,
+                _ => AnsiState::Norm,
+            },
+            AnsiState::Esc => match c {
+                '[' => AnsiState::Csi,
+                ']' => AnsiState::Osc,
+                _ => AnsiState::Norm,
+            },
+            AnsiState::Csi => match c {
+                'A' ... 'Z' | 'a' ... 'z' => AnsiState::Norm,
+                _ => AnsiState::Csi,
+            },
+            AnsiState::Osc => match c {
+                '\x07' => AnsiState::Norm,
+                _ => AnsiState::Osc,
+            },
+        };
+        if let AnsiState::Norm = s {
+            safe = i + c.len_utf8();
+        }
+    }
+    safe
+}
+
+/// Returns the SGR (color and style) escape sequences in `input`, in order.
+pub fn sgr_codes(input: &str) -> String {
+    let mut codes = String::new();
+    let mut rest = input;
+    while let Some(start) = rest.find("\x1B[") {
+        rest = &rest[start..];
+        match rest[2..].find(|c: char| c.is_ascii_alphabetic()) {
+            Some(end) => {
+                let end = end + 3;
+                if rest[..end].ends_with('m') {
+                    codes.push_str(&rest[..end]);
+                }
+                rest = &rest[end..];
+            }
+            None => break,
+        }
+    }
+    codes
+}
+
+/// Returns `input` without its SGR (color and style) escape sequences, keeping other escape
+/// sequences such as cursor movements.
+pub fn remove_sgr_codes(input: &str) -> Cow<str> {
+    if !input.contains("\x1B[") {
+        return Cow::Borrowed(input);
+    }
+
+    let mut clean = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("\x1B[") {
+        clean.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match rest[2..].find(|c: char| c.is_ascii_alphabetic()) {
+            Some(end) => {
+                let end = end + 3;
+                if !rest[..end].ends_with('m') {
+                    clean.push_str(&rest[..end]);
+                }
+                rest = &rest[end..];
+            }
+            None => break,
+        }
+    }
+    clean.push_str(rest);
+    Cow::Owned(clean)
+}
+
+/// Returns `input` with the colors of its SGR escape sequences changed to the closest ones a
+/// terminal with `colors` can show: RGB colors to the 256-color palette, and those to the basic
+/// colors. Removing colors altogether is left to `remove_sgr_codes`.
+pub fn downgrade_colors(input: &str, colors: Colors) -> Cow<str> {
+    if colors >= Colors::TrueColor || !input.contains("\x1B[") {
+        return Cow::Borrowed(input);
+    }
+
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("\x1B[") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest[2..].find(|c: char| c.is_ascii_alphabetic()) {
+            Some(end) => end + 3,
+            None => break,
+        };
+        if rest[..end].ends_with('m') {
+            out.push_str("\x1B[");
+            out.push_str(&downgrade_sgr_params(&rest[2..end - 1], colors));
+            out.push('m');
+        } else {
+            out.push_str(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+fn downgrade_sgr_params(params: &str, colors: Colors) -> String {
+    let params: Vec<&str> = params.split(';').collect();
+    let mut out: Vec<String> = Vec::with_capacity(params.len());
+    let mut i = 0;
+    while i < params.len() {
+        let param = params[i];
+        i += 1;
+        if param != "38" && param != "48" {
+            out.push(param.to_owned());
+            continue;
+        }
+
+        let num = |j: usize| params.get(j).and_then(|p| p.parse::<u8>().ok());
+        let n = match params.get(i).map(|p| *p) {
+            Some("2") => match (num(i + 1), num(i + 2), num(i + 3)) {
+                (Some(r), Some(g), Some(b)) => {
+                    i += 4;
+                    rgb_to_ansi256(r, g, b)
+                }
+                _ => {
+                    out.push(param.to_owned());
+                    continue;
+                }
+            },
+            Some("5") if num(i + 1).is_some() => {
+                i += 2;
+                num(i - 1).unwrap()
+            }
+            _ => {
+                out.push(param.to_owned());
+                continue;
+            }
+        };
+
+        if colors >= Colors::Ansi256 {
+            out.push(format!("{};5;{}", param, n));
+        } else {
+            let n = ansi256_to_basic(n);
+            let base = match (param, n < 8) {
+                ("38", true) => 30,
+                ("38", false) => 90 - 8,
+                (_, true) => 40,
+                (_, false) => 100 - 8,
+            };
+            out.push((base + n as u32).to_string());
+        }
+    }
+    out.join(";")
+}
+
+/// Returns whether all of `text` matches the glob `pattern`, where `*` matches any run of chars
+/// and `?` any single char.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Where to go back to when a mismatch follows the last `*`: just after it in the pattern,
+    // and one char further into the text than last time.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&'*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Encodes `bytes` as standard base64, with padding.
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
 /// Returns the last prompt line.
 pub fn handle_prompt(full_prompt: &str) -> &str {
     if let Some(index) = full_prompt.rfind('\n') {
@@ -96,14 +436,11 @@ pub fn handle_prompt(full_prompt: &str) -> &str {
     }
 }
 
-pub fn terminal_width() -> io::Result<usize> {
-    if cfg!(test) {
-        Ok(80 as usize)
-    } else {
-        let (mut size_col, _) = ::termion::terminal_size()?;
-        if size_col == 0 {
-            size_col = 80;
-        }
-        Ok(size_col as usize)
-    }
+/// Waits up to `timeout` for input on stdin and returns whether there is some.
+#[cfg(all(unix, not(feature = "crossterm")))]
+pub fn stdin_ready(timeout: ::std::time::Duration) -> bool {
+    let millis = timeout.as_secs() as i64 * 1000 + timeout.subsec_millis() as i64;
+    let mut fds = ::libc::pollfd { fd: 0, events: ::libc::POLLIN, revents: 0 };
+    unsafe { ::libc::poll(&mut fds, 1, millis as ::libc::c_int) > 0 }
 }
+
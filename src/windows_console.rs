@@ -0,0 +1,223 @@
+//! Output to Windows consoles. Newer consoles understand the same escape sequences as other
+//! terminals once virtual terminal processing is turned on, which raw mode does. Older ones don't,
+//! so the escape sequences the editor writes are carried out with Console API calls.
+
+use std::cmp;
+use std::io::{self, Stdout, Write};
+use std::mem;
+use winapi::shared::minwindef::DWORD;
+use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
+use winapi::um::processenv::GetStdHandle;
+use winapi::um::winbase::STD_OUTPUT_HANDLE;
+use winapi::um::wincon::{
+    FillConsoleOutputAttribute, FillConsoleOutputCharacterW, GetConsoleScreenBufferInfo,
+    SetConsoleCursorPosition, SetConsoleTextAttribute, CONSOLE_SCREEN_BUFFER_INFO,
+    ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+};
+use winapi::um::wincon::{BACKGROUND_INTENSITY, COMMON_LVB_REVERSE_VIDEO, FOREGROUND_INTENSITY};
+use winapi::um::wincontypes::COORD;
+use winapi::um::winnt::HANDLE;
+
+fn check(ok: i32) -> io::Result<()> {
+    if ok == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Turns on virtual terminal processing for stdout, and returns whether it is a console that has
+/// it. Output that isn't a console, such as a pipe or a terminal emulator like mintty, is left as
+/// it is.
+pub fn enable_virtual_terminal() -> bool {
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode: DWORD = 0;
+        if GetConsoleMode(handle, &mut mode) == 0 {
+            return false;
+        }
+        mode & ENABLE_VIRTUAL_TERMINAL_PROCESSING != 0
+            || SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0
+    }
+}
+
+fn is_console() -> bool {
+    let mut mode: DWORD = 0;
+    unsafe { GetConsoleMode(GetStdHandle(STD_OUTPUT_HANDLE), &mut mode) != 0 }
+}
+
+/// Where the editor's output goes on Windows: a `LegacyConsole` on consoles without virtual
+/// terminal processing, and stdout everywhere else. Pass it to `Context::read_line_with_output()`
+/// to draw on older consoles as well.
+///
+/// ```no_run
+/// use liner::{console_output, Context};
+/// let mut context = Context::new();
+/// let line = context.read_line_with_output(console_output(), "$ ", &mut |_| {}, None, "");
+/// ```
+pub fn console_output() -> Box<Write + Send> {
+    if !is_console() || enable_virtual_terminal() {
+        Box::new(io::stdout())
+    } else {
+        Box::new(LegacyConsole::new())
+    }
+}
+
+/// Stdout on a console without virtual terminal processing, such as conhost before Windows 10.
+/// Text is written as it is, and the cursor movements, clearing and colors liner uses are done
+/// with Console API calls. Other escape sequences are left out.
+pub struct LegacyConsole {
+    out: Stdout,
+    handle: HANDLE,
+    // The attributes text had to begin with, which resetting goes back to.
+    default_attributes: u16,
+    attributes: u16,
+    // The start of an escape sequence split across writes.
+    pending: Vec<u8>,
+}
+
+// The handle is only a reference to stdout.
+unsafe impl Send for LegacyConsole {}
+
+impl LegacyConsole {
+    pub fn new() -> Self {
+        let handle = unsafe { GetStdHandle(STD_OUTPUT_HANDLE) };
+        let attributes = LegacyConsole::info(handle).map_or(0x07, |info| info.wAttributes);
+        LegacyConsole {
+            out: io::stdout(),
+            handle: handle,
+            default_attributes: attributes,
+            attributes: attributes,
+            pending: Vec::new(),
+        }
+    }
+
+    fn info(handle: HANDLE) -> io::Result<CONSOLE_SCREEN_BUFFER_INFO> {
+        unsafe {
+            let mut info: CONSOLE_SCREEN_BUFFER_INFO = mem::zeroed();
+            check(GetConsoleScreenBufferInfo(handle, &mut info))?;
+            Ok(info)
+        }
+    }
+
+    fn move_to(&mut self, x: i16, y: i16) -> io::Result<()> {
+        let info = LegacyConsole::info(self.handle)?;
+        let x = x.max(0).min(info.dwSize.X - 1);
+        let y = y.max(0).min(info.dwSize.Y - 1);
+        unsafe { check(SetConsoleCursorPosition(self.handle, COORD { X: x, Y: y })) }
+    }
+
+    // Blanks `len` cells from `from` on.
+    fn blank(&mut self, from: COORD, len: u32) -> io::Result<()> {
+        let mut written = 0;
+        unsafe {
+            check(FillConsoleOutputCharacterW(self.handle, ' ' as u16, len, from, &mut written))?;
+            check(FillConsoleOutputAttribute(self.handle, self.attributes, len, from, &mut written))
+        }
+    }
+
+    fn csi(&mut self, params: &str, command: u8) -> io::Result<()> {
+        let nums: Vec<u16> = params.split(';').map(|n| n.parse().unwrap_or(0)).collect();
+        let n = |i: usize| cmp::max(1, nums.get(i).cloned().unwrap_or(0)) as i16;
+        let info = LegacyConsole::info(self.handle)?;
+        let cursor = info.dwCursorPosition;
+        match command {
+            b'A' => self.move_to(cursor.X, cursor.Y - n(0)),
+            b'B' => self.move_to(cursor.X, cursor.Y + n(0)),
+            b'C' => self.move_to(cursor.X + n(0), cursor.Y),
+            b'D' => self.move_to(cursor.X - n(0), cursor.Y),
+            b'G' => self.move_to(n(0) - 1, cursor.Y),
+            b'H' => self.move_to(n(1) - 1, info.srWindow.Top + n(0) - 1),
+            b'J' => {
+                let cells = info.dwSize.X as u32 * info.dwSize.Y as u32;
+                match nums[0] {
+                    2 => {
+                        let top = COORD { X: 0, Y: info.srWindow.Top };
+                        let before = top.Y as u32 * info.dwSize.X as u32;
+                        self.blank(top, cells - before)
+                    }
+                    _ => {
+                        let before = cursor.Y as u32 * info.dwSize.X as u32 + cursor.X as u32;
+                        self.blank(cursor, cells - before)
+                    }
+                }
+            }
+            b'K' => self.blank(cursor, (info.dwSize.X - cursor.X) as u32),
+            b'm' => self.sgr(&nums),
+            // Modes, cursor shapes and the like are left out.
+            _ => Ok(()),
+        }
+    }
+
+    fn sgr(&mut self, nums: &[u16]) -> io::Result<()> {
+        // ANSI orders the colors red, green, blue and the console blue, green, red.
+        const COLORS: [u16; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+        let mut attributes = self.attributes;
+        let mut i = 0;
+        while i < nums.len() {
+            match nums[i] {
+                0 => attributes = self.default_attributes,
+                1 => attributes |= FOREGROUND_INTENSITY,
+                7 => attributes |= COMMON_LVB_REVERSE_VIDEO,
+                22 => attributes &= !FOREGROUND_INTENSITY,
+                27 => attributes &= !COMMON_LVB_REVERSE_VIDEO,
+                n @ 30...37 => attributes = attributes & !0x07 | COLORS[(n - 30) as usize],
+                39 => attributes = attributes & !0x0F | self.default_attributes & 0x0F,
+                n @ 40...47 => attributes = attributes & !0x70 | COLORS[(n - 40) as usize] << 4,
+                49 => attributes = attributes & !0xF0 | self.default_attributes & 0xF0,
+                n @ 90...97 => attributes = attributes & !0x0F | COLORS[(n - 90) as usize] | FOREGROUND_INTENSITY,
+                n @ 100...107 => {
+                    attributes = attributes & !0xF0 | COLORS[(n - 100) as usize] << 4 | BACKGROUND_INTENSITY
+                }
+                // Extended colors are brought down to the basic ones before they are written.
+                38 | 48 => i += if nums.get(i + 1) == Some(&2) { 4 } else { 2 },
+                _ => {}
+            }
+            i += 1;
+        }
+        self.attributes = attributes;
+        unsafe { check(SetConsoleTextAttribute(self.handle, attributes)) }
+    }
+}
+
+impl Write for LegacyConsole {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let pending = mem::replace(&mut self.pending, Vec::new());
+        let mut rest = &pending[..];
+        while !rest.is_empty() {
+            let esc = rest.iter().position(|&b| b == 0x1B).unwrap_or(rest.len());
+            self.out.write_all(&rest[..esc])?;
+            rest = &rest[esc..];
+            if rest.is_empty() {
+                break;
+            }
+
+            // Text has to be on the console before the cursor is moved.
+            self.out.flush()?;
+            match rest.get(1) {
+                Some(&b'[') => match rest[2..].iter().position(|&b| b >= 0x40 && b <= 0x7E) {
+                    Some(end) => {
+                        let params = String::from_utf8_lossy(&rest[2..end + 2]).into_owned();
+                        self.csi(&params, rest[end + 2])?;
+                        rest = &rest[end + 3..];
+                    }
+                    None => break,
+                },
+                // Operating system commands, such as for the clipboard, end with BEL or ST.
+                Some(&b']') => match rest.iter().position(|&b| b == 0x07 || b == b'\\') {
+                    Some(end) => rest = &rest[end + 1..],
+                    None => break,
+                },
+                Some(_) => rest = &rest[2..],
+                None => break,
+            }
+        }
+        self.pending = rest.to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}